@@ -8,14 +8,30 @@ use rusty_ffmpeg::{
 };
 
 use std::{
-    collections::HashMap,
-    default,
-    ffi::{CStr, CString},
-    fmt, marker, mem, ptr, slice,
-    sync::Mutex,
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    default, env, error,
+    ffi::{CStr, CString, OsStr, OsString},
+    fmt::{self, Write as _},
+    fs,
+    io::{self, Write as _},
+    marker, mem,
+    path::{Path, PathBuf},
+    ptr, slice,
+    str::{self, FromStr},
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-use crate::ffmpeg::OptionsContext;
+use crate::{
+    dict::{DictFlag, Dictionary},
+    ffmpeg::{OptionsContext, Session},
+    json::Json,
+    stream_specifier::{StreamInfo, StreamSpecifier},
+};
 
 enum OptGroup {
     GroupOutfile = 0,
@@ -48,15 +64,10 @@ bitflags! {
     }
 }
 
-static mut format_opts: *mut ffi::AVDictionary = ptr::null_mut();
-static mut codec_opts: *mut ffi::AVDictionary = ptr::null_mut();
-static mut sws_dict: *mut ffi::AVDictionary = ptr::null_mut();
-static mut swr_opts: *mut ffi::AVDictionary = ptr::null_mut();
-static mut resample_opts: *mut ffi::AVDictionary = ptr::null_mut();
-
+/// `off` is the offset of the destination field, in `OptionsContext` for
+/// `OPT_OFFSET`/`OPT_SPEC` options and in `Session` for the others.
 pub union OptionOperation {
-    pub dst_ptr: *mut c_void,
-    pub func_arg: fn(*mut c_void, &str, &str) -> i64,
+    pub func_arg: fn(&mut Session, Option<&mut OptionsContext>, &str, &str) -> i64,
     pub off: usize,
 }
 
@@ -105,20 +116,425 @@ pub struct OptionGroupDef<'global> {
 pub struct OptionKV<'global> {
     pub opt: &'global OptionDef<'global>,
     pub key: String,
-    pub val: String,
+    /// The argument as given, it's only valid UTF-8 for a non-string option.
+    pub val: OsString,
+    /// Index of the option name in argv, after the `@file` expansion, used
+    /// for error reporting.
+    pub index: usize,
+    /// Where the option name is, if it's from an `@file`.
+    pub location: Option<ArgLocation>,
+}
+
+/// Errors produced while splitting the command line and applying the options.
+///
+/// Every variant carries the argv index of the offending option (not of its
+/// argument), so callers can point the user at the exact word that is wrong.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    /// Neither an option, a group separator nor an AVOption.
+    UnknownOption { index: usize, opt: String },
+    /// The option requires an argument but the command line ends.
+    MissingArgument { index: usize, opt: String },
+    /// The argument is not a number, or not within `min..=max`.
+    InvalidNumber {
+        index: usize,
+        opt: String,
+        arg: String,
+        min: f64,
+        max: f64,
+        error: NumberError,
+    },
+    /// The argument is not a valid duration or date.
+    InvalidTime {
+        index: usize,
+        opt: String,
+        arg: String,
+        is_duration: bool,
+        error: TimeError,
+    },
+    /// An input option is applied to an output file or vice versa.
+    WrongGroup {
+        index: usize,
+        opt: String,
+        help: String,
+        group: String,
+        arg: String,
+    },
+    /// libav rejected the AVOption, `error` is the `av_err2str()` message.
+    AVOption {
+        index: usize,
+        opt: String,
+        arg: String,
+        error: String,
+    },
+    /// The stream specifier after `:` in the option name is malformed.
+    InvalidStreamSpecifier {
+        index: usize,
+        opt: String,
+        reason: String,
+    },
+    /// The argument can't be passed to libav, e.g. it contains a NUL byte.
+    /// `opt` is empty for filenames.
+    InvalidArgument {
+        index: usize,
+        opt: String,
+        arg: String,
+        error: String,
+    },
+    /// The option callback rejected its argument.
+    OptionFailed {
+        index: usize,
+        opt: String,
+        arg: String,
+        error: String,
+    },
+    /// An `OPT_EXIT` option (e.g. `-h`) has been handled, the program should exit.
+    ExitRequested { index: usize, opt: String },
+    /// An input or output file cannot be opened.
+    OpenFile { filename: String, error: String },
+    /// A `-filter_complex` graph cannot be parsed, or is not fully connected.
+    FilterGraph { error: String },
+    /// An `@file` cannot be read or split, `location` is where the error is
+    /// if it's in an argument file.
+    ArgFile {
+        location: Option<ArgLocation>,
+        error: String,
+    },
+}
+
+impl ParseError {
+    /// Index of the offending option in argv, if the error comes from one.
+    pub fn index(&self) -> Option<usize> {
+        match self {
+            ParseError::UnknownOption { index, .. }
+            | ParseError::MissingArgument { index, .. }
+            | ParseError::InvalidNumber { index, .. }
+            | ParseError::InvalidTime { index, .. }
+            | ParseError::WrongGroup { index, .. }
+            | ParseError::AVOption { index, .. }
+            | ParseError::InvalidStreamSpecifier { index, .. }
+            | ParseError::InvalidArgument { index, .. }
+            | ParseError::OptionFailed { index, .. }
+            | ParseError::ExitRequested { index, .. } => Some(*index),
+            ParseError::OpenFile { .. }
+            | ParseError::FilterGraph { .. }
+            | ParseError::ArgFile { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnknownOption { opt, .. } => write!(f, "Unrecognized option '{}'.", opt),
+            ParseError::MissingArgument { opt, .. } => {
+                write!(f, "Missing argument for option '{}'.", opt)
+            }
+            ParseError::InvalidNumber {
+                opt,
+                arg,
+                min,
+                max,
+                error: NumberError::OutOfRange,
+                ..
+            } => write!(
+                f,
+                "The value for {} was {} which is not within {} - {}",
+                opt, arg, min, max
+            ),
+            ParseError::InvalidNumber {
+                opt,
+                arg,
+                error: NumberError::NotInteger,
+                ..
+            } => write!(f, "Expected integer for {} but found {}", opt, arg),
+            ParseError::InvalidNumber { opt, arg, .. } => {
+                write!(f, "Expected number for {} but found: {}", opt, arg)
+            }
+            ParseError::InvalidTime {
+                opt,
+                arg,
+                is_duration,
+                ..
+            } => write!(
+                f,
+                "Invalid {} specification for {}: {}",
+                if *is_duration { "duration" } else { "date" },
+                opt,
+                arg
+            ),
+            ParseError::WrongGroup {
+                opt,
+                help,
+                group,
+                arg,
+                ..
+            } => write!(
+                f,
+                "Option {} ({}) cannot be applied to {} {} -- you are trying to apply an \
+                 input option to an output file or vice versa. Move this option before the \
+                 file it belongs to.",
+                opt, help, group, arg
+            ),
+            ParseError::AVOption {
+                opt, arg, error, ..
+            } => write!(
+                f,
+                "Error parsing option '{}' with argument '{}': {}",
+                opt, arg, error
+            ),
+            ParseError::InvalidStreamSpecifier { opt, reason, .. } => {
+                write!(f, "Invalid stream specifier in '{}': {}", opt, reason)
+            }
+            ParseError::InvalidArgument {
+                opt, arg, error, ..
+            } if opt.is_empty() => write!(f, "Invalid argument '{}': {}", arg, error),
+            ParseError::InvalidArgument {
+                opt, arg, error, ..
+            } => write!(
+                f,
+                "Invalid argument '{}' for option '{}': {}",
+                arg, opt, error
+            ),
+            ParseError::OptionFailed {
+                opt, arg, error, ..
+            } => write!(
+                f,
+                "Failed to set value '{}' for option '{}': {}",
+                arg, opt, error
+            ),
+            ParseError::ExitRequested { opt, .. } => write!(f, "Exit requested by '{}'.", opt),
+            ParseError::OpenFile { filename, error } => write!(f, "{}: {}", filename, error),
+            ParseError::FilterGraph { error } => write!(f, "{}", error),
+            ParseError::ArgFile {
+                location: Some(location),
+                error,
+            } => write!(f, "{}: {}", location, error),
+            ParseError::ArgFile {
+                location: None,
+                error,
+            } => write!(f, "{}", error),
+        }
+    }
+}
+
+impl error::Error for ParseError {}
+
+/// Where an argument read from an `@file` comes from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArgLocation {
+    pub file: String,
+    /// 1-based line where the argument starts.
+    pub line: usize,
+}
+
+impl fmt::Display for ArgLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+/// Split the content of an argument file into arguments with their line.
+///
+/// Arguments are separated by whitespace and quoted like in a shell: `'...'`
+/// is literal, `\` escapes `"`, `\`, `$` and `` ` `` in `"..."`, and any
+/// character outside of quotes, a `\` before a newline joins the lines. A
+/// `#` at the start of an argument comments out the rest of the line. The
+/// content isn't required to be UTF-8, arguments are kept as bytes.
+fn split_argfile(bytes: &[u8]) -> Result<Vec<(Vec<u8>, usize)>, (usize, String)> {
+    let mut args = vec![];
+    let mut line = 1;
+    let mut i = 0;
+    loop {
+        // Skip the whitespace, line continuations and comments between arguments.
+        while let Some(&c) = bytes.get(i) {
+            match (c, bytes.get(i + 1)) {
+                (b'\n', _) => line += 1,
+                (b'\\', Some(b'\n')) => {
+                    line += 1;
+                    i += 1;
+                }
+                (b'#', _) => {
+                    while bytes.get(i + 1).map_or(false, |&c| c != b'\n') {
+                        i += 1;
+                    }
+                }
+                (c, _) if c.is_ascii_whitespace() => {}
+                _ => break,
+            }
+            i += 1;
+        }
+        if i == bytes.len() {
+            return Ok(args);
+        }
+
+        let start = line;
+        let unterminated = |quote| Err((start, format!("Unterminated {} quote", quote)));
+        let mut arg = vec![];
+        while let Some(&c) = bytes.get(i).filter(|c| !c.is_ascii_whitespace()) {
+            i += 1;
+            match c {
+                b'\'' => loop {
+                    match bytes.get(i) {
+                        Some(b'\'') => break,
+                        Some(&c) => {
+                            line += (c == b'\n') as usize;
+                            arg.push(c);
+                        }
+                        None => return unterminated("single"),
+                    }
+                    i += 1;
+                },
+                b'"' => loop {
+                    match (bytes.get(i), bytes.get(i + 1)) {
+                        (Some(b'"'), _) => break,
+                        (Some(b'\\'), Some(b'\n')) => {
+                            line += 1;
+                            i += 1;
+                        }
+                        (Some(b'\\'), Some(&c)) if b"\"\\$`".contains(&c) => {
+                            arg.push(c);
+                            i += 1;
+                        }
+                        (Some(&c), _) => {
+                            line += (c == b'\n') as usize;
+                            arg.push(c);
+                        }
+                        (None, _) => return unterminated("double"),
+                    }
+                    i += 1;
+                },
+                b'\\' => {
+                    match bytes.get(i) {
+                        Some(b'\n') => line += 1,
+                        Some(&c) => arg.push(c),
+                        // A trailing `\` is kept as is.
+                        None => {
+                            arg.push(b'\\');
+                            continue;
+                        }
+                    }
+                    i += 1;
+                    continue;
+                }
+                c => {
+                    arg.push(c);
+                    continue;
+                }
+            }
+            // Jump over the closing quote.
+            i += 1;
+        }
+        args.push((arg, start));
+    }
+}
+
+/// Read the arguments of the argument file `path` into `args`, `from` is the
+/// location of the `@file` argument if it's itself in an argument file.
+fn expand_argfile(
+    path: &Path,
+    from: Option<&ArgLocation>,
+    stack: &mut Vec<PathBuf>,
+    args: &mut Vec<OsString>,
+    locations: &mut Vec<Option<ArgLocation>>,
+) -> Result<(), ParseError> {
+    let error = |error: String| ParseError::ArgFile {
+        location: from.cloned(),
+        error,
+    };
+    let read_error = |e: io::Error| {
+        error(format!(
+            "Cannot read argument file '{}': {}",
+            path.display(),
+            e
+        ))
+    };
+    let canonical = fs::canonicalize(path).map_err(read_error)?;
+    if stack.contains(&canonical) {
+        return Err(error(format!(
+            "Argument file '{}' includes itself",
+            path.display()
+        )));
+    }
+    let content = fs::read(path).map_err(read_error)?;
+    let file = path.display().to_string();
+    let file_args = split_argfile(&content).map_err(|(line, error)| ParseError::ArgFile {
+        location: Some(ArgLocation {
+            file: file.clone(),
+            line,
+        }),
+        error,
+    })?;
+
+    stack.push(canonical);
+    for (arg, line) in file_args {
+        let location = ArgLocation {
+            file: file.clone(),
+            line,
+        };
+        let arg = bytes_os_string(arg);
+        match strip_os_prefix(&arg, "@") {
+            Some(literal) if strip_os_prefix(literal, "@").is_some() => {
+                args.push(literal.to_owned());
+                locations.push(Some(location));
+            }
+            Some(nested) => {
+                // Relative to the directory of the including file.
+                let nested = path.parent().unwrap_or_else(|| Path::new("")).join(nested);
+                expand_argfile(&nested, Some(&location), stack, args, locations)?;
+            }
+            None => {
+                args.push(arg);
+                locations.push(Some(location));
+            }
+        }
+    }
+    stack.pop();
+    Ok(())
+}
+
+/// Replace the `@file` arguments by the arguments read from the files,
+/// recursively, see `split_argfile()` for the syntax. `@@` escapes a
+/// leading `@`, e.g. `-i @@in.mp4` opens `@in.mp4`. Returns the arguments
+/// with the location of each one read from a file.
+pub fn expand_argfiles(
+    args: &[OsString],
+) -> Result<(Vec<OsString>, Vec<Option<ArgLocation>>), ParseError> {
+    let mut expanded = vec![];
+    let mut locations = vec![];
+    for (i, arg) in args.iter().enumerate() {
+        match strip_os_prefix(arg, "@") {
+            Some(literal) if i > 0 && strip_os_prefix(literal, "@").is_some() => {
+                expanded.push(literal.to_owned());
+                locations.push(None);
+            }
+            Some(path) if i > 0 => expand_argfile(
+                Path::new(path),
+                None,
+                &mut vec![],
+                &mut expanded,
+                &mut locations,
+            )?,
+            _ => {
+                expanded.push(arg.clone());
+                locations.push(None);
+            }
+        }
+    }
+    Ok((expanded, locations))
 }
 
 // TODO maybe split the lifetime here
 #[derive(Debug, Clone)]
 pub struct OptionGroup<'global> {
     pub group_def: &'global OptionGroupDef<'global>,
-    pub arg: String,
+    pub arg: OsString,
     pub opts: Vec<OptionKV<'global>>,
-    pub codec_opts: *mut ffi::AVDictionary,
-    pub format_opts: *mut ffi::AVDictionary,
-    pub resample_opts: *mut ffi::AVDictionary,
-    pub sws_dict: *mut ffi::AVDictionary,
-    pub swr_opts: *mut ffi::AVDictionary,
+    pub codec_opts: Dictionary,
+    pub format_opts: Dictionary,
+    pub resample_opts: Dictionary,
+    pub sws_dict: Dictionary,
+    pub swr_opts: Dictionary,
 }
 
 impl<'global> OptionGroup<'global> {
@@ -130,13 +546,13 @@ impl<'global> OptionGroup<'global> {
         };
         OptionGroup {
             group_def: &GLOBAL_GROUP,
-            arg: String::new(),
+            arg: OsString::new(),
             opts: vec![],
-            codec_opts: ptr::null_mut(),
-            format_opts: ptr::null_mut(),
-            resample_opts: ptr::null_mut(),
-            sws_dict: ptr::null_mut(),
-            swr_opts: ptr::null_mut(),
+            codec_opts: Dictionary::new(),
+            format_opts: Dictionary::new(),
+            resample_opts: Dictionary::new(),
+            sws_dict: Dictionary::new(),
+            swr_opts: Dictionary::new(),
         }
     }
 
@@ -150,13 +566,13 @@ impl<'global> OptionGroup<'global> {
         };
         OptionGroup {
             group_def: &NEVER_USE_GROUP,
-            arg: String::new(),
+            arg: OsString::new(),
             opts: vec![],
-            codec_opts: ptr::null_mut(),
-            format_opts: ptr::null_mut(),
-            resample_opts: ptr::null_mut(),
-            sws_dict: ptr::null_mut(),
-            swr_opts: ptr::null_mut(),
+            codec_opts: Dictionary::new(),
+            format_opts: Dictionary::new(),
+            resample_opts: Dictionary::new(),
+            sws_dict: Dictionary::new(),
+            swr_opts: Dictionary::new(),
         }
     }
 }
@@ -180,32 +596,74 @@ pub struct OptionParseContext<'global> {
     /// use create a placeholder. More attractive option is changing the
     /// cur_group from OptionGroup to tuple (arg: String, opts: Vec<OptionKV>).
     pub cur_group: OptionGroup<'global>,
+    /// Locations of the arguments read from `@file`s, indexed like argv.
+    pub locations: Vec<Option<ArgLocation>>,
 }
 
-pub union SpecifierOptValue {
-    pub str: *mut u8,
-    pub i: isize,
-    pub i64: i64,
-    pub ui64: u64,
-    pub f: f32,
-    pub dbl: f64,
+/// Value of an `OPT_SPEC` option, the variant is decided by the type flag of
+/// the option.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SpecifierOptValue {
+    /// `OPT_STRING`
+    Str(OsString),
+    /// `OPT_INT` and `OPT_BOOL`
+    Int(isize),
+    /// `OPT_INT64`
+    Int64(i64),
+    /// `OPT_FLOAT`
+    Float(f32),
+    /// `OPT_DOUBLE`
+    Double(f64),
+    /// `OPT_TIME`, in microseconds
+    Time(i64),
 }
 
-impl fmt::Debug for SpecifierOptValue {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("(Union)SpecifierOptValue")
-            .field("val", unsafe { &self.i })
-            .finish()
+impl SpecifierOptValue {
+    /// `None` if it's not a string or not valid UTF-8.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            SpecifierOptValue::Str(s) => s.to_str(),
+            _ => None,
+        }
     }
-}
 
-impl default::Default for SpecifierOptValue {
-    fn default() -> Self {
-        SpecifierOptValue { i: 0 }
+    pub fn as_os_str(&self) -> Option<&OsStr> {
+        match self {
+            SpecifierOptValue::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<isize> {
+        match self {
+            SpecifierOptValue::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            SpecifierOptValue::Int64(i) | SpecifierOptValue::Time(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_f32(&self) -> Option<f32> {
+        match self {
+            SpecifierOptValue::Float(f) => Some(*f),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            SpecifierOptValue::Double(d) => Some(*d),
+            _ => None,
+        }
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct SpecifierOpt {
     pub specifier: String,
     pub u: SpecifierOptValue,
@@ -213,204 +671,793 @@ pub struct SpecifierOpt {
 
 /// This function accepts moved Option value with the OptionsContext it references to unchanged.
 pub fn parse_optgroup<'ctxt>(
+    session: &mut Session,
     mut optctx: Option<&mut OptionsContext>,
     g: &OptionGroup,
-) -> Result<(), ()> {
+) -> Result<(), ParseError> {
     debug!(
         "Parsing a group of options: {} {}.",
-        g.group_def.name, g.arg
+        g.group_def.name,
+        g.arg.to_string_lossy()
     );
     g.opts
         .iter()
         .map(|o| {
             if !g.group_def.flags.is_empty() && !g.group_def.flags.intersects(o.opt.flags) {
-                error!(
-                    "Option {} ({}) cannot be applied to \
-                   {} {} -- you are trying to apply an input option to an \
-                   output file or vice versa. Move this option before the \
-                   file it belongs to.",
-                    o.key, o.opt.help, g.group_def.name, g.arg
-                );
-                Err(())
+                let err = ParseError::WrongGroup {
+                    index: o.index,
+                    opt: o.key.clone(),
+                    help: o.opt.help.to_owned(),
+                    group: g.group_def.name.to_owned(),
+                    arg: g.arg.to_string_lossy().into_owned(),
+                };
+                error!("{}", err);
+                Err(err)
             } else {
                 debug!(
                     "Applying option {} ({}) with argument {}.",
-                    o.key, o.opt.help, o.val
+                    o.key,
+                    o.opt.help,
+                    o.val.to_string_lossy()
                 );
-                write_option(&mut optctx, o.opt, &o.key, &o.val)
+                let ret = write_option(session, &mut optctx, o.opt, o.index, &o.key, &o.val);
+                if let (Err(_), Some(location)) = (&ret, &o.location) {
+                    error!("Option '{}' is at {}.", o.key, location);
+                }
+                ret
             }
         })
-        .collect::<Result<Vec<_>, ()>>()?;
+        .collect::<Result<Vec<_>, _>>()?;
     debug!("Successfully parsed a group of options.");
     Ok(())
 }
 
-/// `context` is the `opt`, `num_str` is usually the `arg`
+/// Check the values of the options of `g` like [`parse_optgroup()`], without
+/// applying anything: the callback options, e.g. `-loglevel`, `-report` or
+/// `-timelimit`, change the whole process so they are skipped.
+pub fn check_optgroup(g: &OptionGroup) -> Result<(), ParseError> {
+    let checked = OptionGroup {
+        opts: g
+            .opts
+            .iter()
+            .filter(|o| !is_callback(o.opt))
+            .cloned()
+            .collect(),
+        ..g.clone()
+    };
+    parse_optgroup(&mut Session::default(), None, &checked)
+}
+
+/// Why `parse_number()` rejects a number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NumberError {
+    /// Not a number, or followed by something else.
+    Invalid,
+    /// Not within the range of the option.
+    OutOfRange,
+    /// Has a fraction but the option takes an integer.
+    NotInteger,
+}
+
+/// `strtod()`, the number at the start of `p` and its length.
+fn strtod(p: &[u8]) -> Option<(f64, usize)> {
+    let start = p.iter().take_while(|&&c| is_space(c)).count();
+    let (sign, start) = match p.get(start) {
+        Some(b'-') => (-1., start + 1),
+        Some(b'+') => (1., start + 1),
+        _ => (1., start),
+    };
+    let rest = &p[start..];
+    let starts_with = |s: &[u8]| rest.len() >= s.len() && rest[..s.len()].eq_ignore_ascii_case(s);
+    let digits = |from: usize, radix: u32| {
+        rest.get(from..).map_or(0, |r| {
+            r.iter()
+                .take_while(|&&c| (c as char).is_digit(radix))
+                .count()
+        })
+    };
+    // The length of `digits[.digits]` at `from`, 0 if there's no digit.
+    let mantissa = |from: usize, radix: u32| {
+        let int = digits(from, radix);
+        match rest.get(from + int) {
+            Some(b'.') => match digits(from + int + 1, radix) {
+                0 if int == 0 => 0,
+                frac => int + 1 + frac,
+            },
+            _ => int,
+        }
+    };
+    // The length of `e[+|-]digits` at `from`, 0 if there's no exponent.
+    let exponent = |from: usize, e: u8| {
+        if rest.get(from).map(u8::to_ascii_lowercase) != Some(e) {
+            return 0;
+        }
+        let sign = match rest.get(from + 1) {
+            Some(b'-') | Some(b'+') => 1,
+            _ => 0,
+        };
+        match digits(from + 1 + sign, 10) {
+            0 => 0,
+            n => 1 + sign + n,
+        }
+    };
+
+    if starts_with(b"infinity") {
+        Some((sign * f64::INFINITY, start + 8))
+    } else if starts_with(b"inf") {
+        Some((sign * f64::INFINITY, start + 3))
+    } else if starts_with(b"nan") {
+        // The optional `(n-char-sequence)` is part of it.
+        let seq = rest[3..]
+            .iter()
+            .skip(1)
+            .take_while(|c| c.is_ascii_alphanumeric() || **c == b'_')
+            .count();
+        let len = match (rest.get(3), rest.get(4 + seq)) {
+            (Some(b'('), Some(b')')) => 3 + seq + 2,
+            _ => 3,
+        };
+        Some((f64::NAN, start + len))
+    } else if starts_with(b"0x") && mantissa(2, 16) > 0 {
+        let len = 2 + mantissa(2, 16);
+        let exp_len = exponent(len, b'p');
+        // 64 bits and a sticky bit are enough for `u64 as f64` to round right.
+        let (mut m, mut exp, mut point) = (0u64, 0i32, false);
+        for &c in &rest[2..len] {
+            match (c as char).to_digit(16) {
+                None => point = true,
+                Some(d) if m >> 60 == 0 => {
+                    m = m * 16 + d as u64;
+                    exp -= if point { 4 } else { 0 };
+                }
+                Some(d) => {
+                    m |= (d != 0) as u64;
+                    exp += if point { 0 } else { 4 };
+                }
+            }
+        }
+        let e = rest[len..len + exp_len]
+            .iter()
+            .filter(|c| c.is_ascii_digit())
+            .fold(0i32, |e, &c| {
+                e.saturating_mul(10).saturating_add((c - b'0') as i32)
+            });
+        let exp = if rest.get(len + 1) == Some(&b'-') {
+            exp.saturating_sub(e)
+        } else {
+            exp.saturating_add(e)
+        };
+        // In two steps so that `2^exp` alone doesn't overflow or underflow.
+        let d = if m == 0 {
+            0.
+        } else {
+            m as f64 * 2f64.powi(exp / 2) * 2f64.powi(exp - exp / 2)
+        };
+        Some((sign * d, start + len + exp_len))
+    } else {
+        let len = mantissa(0, 10);
+        if len == 0 {
+            return None;
+        }
+        let len = len + exponent(len, b'e');
+        // It's all ASCII and in the grammar of `f64::from_str()`.
+        let d: f64 = str::from_utf8(&rest[..len]).unwrap().parse().unwrap();
+        Some((sign * d, start + len))
+    }
+}
+
+/// `ff_exp10()`, computed the same way to get the same results.
+fn exp10(x: f64) -> f64 {
+    (std::f64::consts::LOG2_10 * x).exp2()
+}
+
+/// Rust version of `av_strtod()`, returns the number and the rest of
+/// `numstr`, `None` if it doesn't start with a number.
+///
+/// The number can be followed by `dB`, or a SI prefix from `y` (10^-24) to
+/// `Y` (10^24) which is a power of 1024 when followed by `i`, and then by
+/// `B` which multiplies it by 8. Like the C one, a leading `0x` is an
+/// unsigned hexadecimal integer. The SI prefixes scale by `ff_exp10()` like
+/// the C one, so `2.5M` is 2499999.9999999995 rather than 2500000.
+pub fn av_strtod(numstr: &str) -> Option<(f64, &str)> {
+    let p = numstr.as_bytes();
+    let (mut d, mut next) = if p.len() >= 2 && p[0] == b'0' && (p[1] | 0x20) == b'x' {
+        let len = p[2..].iter().take_while(|c| c.is_ascii_hexdigit()).count();
+        if len == 0 {
+            (0., 1)
+        } else {
+            // `strtoul()` saturates.
+            let n = p[2..2 + len].iter().fold(Some(0u64), |n, &c| {
+                n?.checked_mul(16)?
+                    .checked_add((c as char).to_digit(16).unwrap() as u64)
+            });
+            (n.unwrap_or(u64::MAX) as f64, 2 + len)
+        }
+    } else {
+        strtod(p)?
+    };
+
+    let si_prefix = |c| match c {
+        b'y' => -24,
+        b'z' => -21,
+        b'a' => -18,
+        b'f' => -15,
+        b'p' => -12,
+        b'n' => -9,
+        b'u' => -6,
+        b'm' => -3,
+        b'c' => -2,
+        b'd' => -1,
+        b'h' => 2,
+        b'k' | b'K' => 3,
+        b'M' => 6,
+        b'G' => 9,
+        b'T' => 12,
+        b'P' => 15,
+        b'E' => 18,
+        b'Z' => 21,
+        b'Y' => 24,
+        _ => 0,
+    };
+    if p[next..].starts_with(b"dB") {
+        // Decibels, not decibytes.
+        d = exp10(d / 20.);
+        next += 2;
+    } else if let Some(e) = p.get(next).map(|&c| si_prefix(c)).filter(|&e| e != 0) {
+        if p.get(next + 1) == Some(&b'i') {
+            d *= 2f64.powf(e as f64 / 0.3);
+            next += 2;
+        } else {
+            d *= exp10(e as f64);
+            next += 1;
+        }
+    }
+    if p.get(next) == Some(&b'B') {
+        d *= 8.;
+        next += 1;
+    }
+    Some((d, &numstr[next..]))
+}
+
+/// The whole of `numstr` as a number, see `av_strtod()` for the syntax.
+pub fn parse_number_str(numstr: &str) -> Result<f64, NumberError> {
+    match av_strtod(numstr) {
+        Some((d, "")) => Ok(d),
+        _ => Err(NumberError::Invalid),
+    }
+}
+
+/// `context` is the `opt`, `num_str` is usually the `arg`, `index` is the
+/// position of `opt` in argv. `OPT_INT` and `OPT_INT64` also reject numbers
+/// with a fraction.
 pub fn parse_number(
+    index: usize,
     context: &str,
     numstr: &str,
     num_type: OptionFlag,
     min: f64,
     max: f64,
-) -> Result<f64, String> {
-    let numstr_ptr = CString::new(numstr).unwrap().as_ptr();
-    let mut tail: *mut libc::c_char = ptr::null_mut();
-    let d = unsafe { ffi::av_strtod(numstr_ptr, &mut tail) };
-    let error = if tail.is_null() {
-        format!("Expected number for {} but found: {}", context, numstr)
+) -> Result<f64, ParseError> {
+    let error = |error| ParseError::InvalidNumber {
+        index,
+        opt: context.to_owned(),
+        arg: numstr.to_owned(),
+        min,
+        max,
+        error,
+    };
+    let d = parse_number_str(numstr).map_err(error)?;
+    if d < min || d > max {
+        Err(error(NumberError::OutOfRange))
+    } else if (num_type == OptionFlag::OPT_INT64 && d as i64 as f64 != d)
+        || (num_type == OptionFlag::OPT_INT && d as i32 as f64 != d)
+    {
+        Err(error(NumberError::NotInteger))
     } else {
-        if d < min || d > max {
-            format!(
-                "The value for {} was {} which is not within {} - {}",
-                context, numstr, min, max
-            )
-        } else if num_type == OptionFlag::OPT_INT64 && d as i64 as f64 != d {
-            format!("Expected int64 for {} but found {}", context, numstr)
-        } else if num_type == OptionFlag::OPT_INT && d as isize as f64 != d {
-            format!("Expected int for {} but found {}", context, numstr)
+        Ok(d)
+    }
+}
+
+/// Why `parse_time_str()` rejects a time, the errors of `av_parse_time()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeError {
+    /// `AVERROR(EINVAL)`, neither a duration nor a date.
+    Invalid,
+    /// `AVERROR(ERANGE)`, the microseconds don't fit in `i64`.
+    OutOfRange,
+}
+
+/// The fields of `struct tm` used by `parse_time_str()`.
+#[derive(Debug, Default, Clone, Copy)]
+struct Tm {
+    /// Years since 1900.
+    year: i32,
+    /// 0-based month.
+    mon: i32,
+    mday: i32,
+    hour: i32,
+    min: i32,
+    sec: i32,
+}
+
+/// `av_isspace()`, unlike `u8::is_ascii_whitespace()` it includes `\v`.
+fn is_space(c: u8) -> bool {
+    c == b' ' || (b'\t'..=b'\r').contains(&c)
+}
+
+fn skip_space(p: &[u8]) -> &[u8] {
+    let n = p.iter().take_while(|&&c| is_space(c)).count();
+    &p[n..]
+}
+
+/// Read at most `len_max` digits as a number within `min..=max`.
+fn date_get_num(p: &mut &[u8], min: i32, max: i32, len_max: usize) -> Option<i32> {
+    let len = p
+        .iter()
+        .take(len_max)
+        .take_while(|c| c.is_ascii_digit())
+        .count();
+    let val = p[..len]
+        .iter()
+        .fold(0, |val, &c| val * 10 + (c - b'0') as i32);
+    if len == 0 || val < min || val > max {
+        return None;
+    }
+    *p = &p[len..];
+    Some(val)
+}
+
+/// Rust version of `av_small_strptime()` with the conversions used by
+/// `parse_time_str()`, returns the rest of `p`. A space in `fmt` skips any
+/// whitespace, the fields of `dt` stay set when it fails halfway.
+fn small_strptime<'a>(mut p: &'a [u8], fmt: &str, dt: &mut Tm) -> Option<&'a [u8]> {
+    let mut fmt = fmt.bytes();
+    while let Some(c) = fmt.next() {
+        if c != b'%' {
+            if is_space(c) {
+                p = skip_space(p);
+            } else if p.first() == Some(&c) {
+                p = &p[1..];
+            } else {
+                return None;
+            }
+            continue;
+        }
+        match fmt.next()? {
+            b'H' => dt.hour = date_get_num(&mut p, 0, 23, 2)?,
+            b'J' => dt.hour = date_get_num(&mut p, 0, i32::MAX, 4)?,
+            b'M' => dt.min = date_get_num(&mut p, 0, 59, 2)?,
+            b'S' => dt.sec = date_get_num(&mut p, 0, 59, 2)?,
+            b'Y' => dt.year = date_get_num(&mut p, 0, 9999, 4)? - 1900,
+            b'm' => dt.mon = date_get_num(&mut p, 1, 12, 2)? - 1,
+            b'd' => dt.mday = date_get_num(&mut p, 1, 31, 2)?,
+            _ => return None,
+        }
+    }
+    Some(p)
+}
+
+/// `strtoll(p, &end, 10)`, `None` if there's no number.
+fn strtoll(p: &[u8]) -> Option<(Result<i64, TimeError>, &[u8])> {
+    let p = skip_space(p);
+    let (sign, p) = match p.first() {
+        Some(b'-') => (-1, &p[1..]),
+        Some(b'+') => (1, &p[1..]),
+        _ => (1, p),
+    };
+    let len = p.iter().take_while(|c| c.is_ascii_digit()).count();
+    if len == 0 {
+        return None;
+    }
+    // Stops growing once it's out of range, so it can't overflow either.
+    let n = p[..len].iter().fold(0i128, |n, &c| {
+        if n > i64::MAX as i128 + 1 {
+            n
         } else {
-            return Ok(d);
+            n * 10 + (c - b'0') as i128
         }
+    }) * sign;
+    let n = if n < i64::MIN as i128 || n > i64::MAX as i128 {
+        Err(TimeError::OutOfRange)
+    } else {
+        Ok(n as i64)
     };
-    Err(error)
+    Some((n, &p[len..]))
 }
 
-fn parse_time(context: &str, timestr: &str, is_duration: bool) -> Result<i64, String> {
-    let mut us = 0;
-    let timestr_ptr = CString::new(timestr).unwrap().as_ptr();
-    if unsafe { ffi::av_parse_time(&mut us, timestr_ptr, if is_duration { 1 } else { 0 }) } > 0 {
-        Err(format!(
-            "Invalid {} specification for {}: {}",
-            if is_duration { "duration" } else { "date" },
-            context,
-            timestr
-        ))
+/// Rust version of `av_timegm()`, the seconds since the epoch of a UTC time.
+fn timegm(dt: &Tm) -> i64 {
+    let (mut y, mut m, d) = (dt.year as i64 + 1900, dt.mon as i64 + 1, dt.mday as i64);
+    if m < 3 {
+        m += 12;
+        y -= 1;
+    }
+    86400 * (d + (153 * m - 457) / 5 + 365 * y + y / 4 - y / 100 + y / 400 - 719469)
+        + 3600 * dt.hour as i64
+        + 60 * dt.min as i64
+        + dt.sec as i64
+}
+
+/// The seconds since the epoch of a local time.
+fn mktime(dt: &Tm) -> i64 {
+    unsafe {
+        let mut tm: libc::tm = mem::zeroed();
+        tm.tm_year = dt.year;
+        tm.tm_mon = dt.mon;
+        tm.tm_mday = dt.mday;
+        tm.tm_hour = dt.hour;
+        tm.tm_min = dt.min;
+        tm.tm_sec = dt.sec;
+        tm.tm_isdst = -1;
+        libc::mktime(&mut tm) as i64
+    }
+}
+
+/// The date of `now` in UTC or local time.
+fn today(now: i64, utc: bool) -> Tm {
+    let now = now as libc::time_t;
+    let tm = unsafe {
+        let mut tm = mem::zeroed();
+        if utc {
+            libc::gmtime_r(&now, &mut tm);
+        } else {
+            libc::localtime_r(&now, &mut tm);
+        }
+        tm
+    };
+    Tm {
+        year: tm.tm_year,
+        mon: tm.tm_mon,
+        mday: tm.tm_mday,
+        ..Tm::default()
+    }
+}
+
+/// Rust version of `av_parse_time()`, returns microseconds.
+///
+/// A duration is `[-][HH:]MM:SS[.m...]` or `[-]S+[.m...][s|ms|us]`. A date
+/// is `now` or `[(YYYY-MM-DD|YYYYMMDD)[T|t| ]]HH:MM:SS[.m...][Z|z]`, today
+/// when the day is missing, and is in local time unless it's followed by `Z`
+/// or a `(+|-)HH[[:]MM]` offset, it's then the time since the epoch.
+pub fn parse_time_str(timestr: &str, is_duration: bool) -> Result<i64, TimeError> {
+    let mut p = timestr.as_bytes();
+    let mut dt = Tm::default();
+    let mut negative = false;
+    let mut suffix = 1_000_000;
+    let mut t;
+    let mut today_date = false;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_micros() as i64);
+
+    if !is_duration {
+        if timestr.eq_ignore_ascii_case("now") {
+            return Ok(now);
+        }
+        match small_strptime(p, "%Y - %m - %d", &mut dt)
+            .or_else(|| small_strptime(p, "%Y%m%d", &mut dt))
+        {
+            Some(q) => p = q,
+            None => today_date = true,
+        }
+        p = match p.first() {
+            Some(b'T') | Some(b't') => &p[1..],
+            _ => skip_space(p),
+        };
+        p = small_strptime(p, "%H:%M:%S", &mut dt)
+            .or_else(|| small_strptime(p, "%H%M%S", &mut dt))
+            .ok_or(TimeError::Invalid)?;
+        t = 0;
+    } else {
+        if p.first() == Some(&b'-') {
+            negative = true;
+            p = &p[1..];
+        }
+        if let Some(q) = small_strptime(p, "%J:%M:%S", &mut dt) {
+            p = q;
+            t = dt.hour as i64 * 3600 + dt.min as i64 * 60 + dt.sec as i64;
+        } else if let Some(q) = small_strptime(p, "%M:%S", &mut dt) {
+            p = q;
+            t = dt.min as i64 * 60 + dt.sec as i64;
+        } else {
+            let (n, q) = strtoll(p).ok_or(TimeError::Invalid)?;
+            t = n?;
+            p = q;
+        }
+    }
+
+    // the .m... part
+    let mut microseconds = 0;
+    if p.first() == Some(&b'.') {
+        p = &p[1..];
+        let mut n = 100_000;
+        while let Some(c) = p.first().filter(|c| n >= 1 && c.is_ascii_digit()) {
+            microseconds += n * (c - b'0') as i64;
+            n /= 10;
+            p = &p[1..];
+        }
+        p = &p[p.iter().take_while(|c| c.is_ascii_digit()).count()..];
+    }
+
+    if is_duration {
+        if p.starts_with(b"ms") {
+            suffix = 1000;
+            microseconds /= 1000;
+            p = &p[2..];
+        } else if p.starts_with(b"us") {
+            suffix = 1;
+            microseconds = 0;
+            p = &p[2..];
+        } else if p.first() == Some(&b's') {
+            p = &p[1..];
+        }
     } else {
-        Ok(us)
+        let mut is_utc = p.first() == Some(&b'Z') || p.first() == Some(&b'z');
+        if is_utc {
+            p = &p[1..];
+        }
+        let mut tzoffset = 0;
+        if let (false, false, Some(&sign)) = (today_date, is_utc, p.first()) {
+            if sign == b'+' || sign == b'-' {
+                let q = &p[1..];
+                let mut tz = Tm::default();
+                p = small_strptime(q, "%H:%M", &mut tz)
+                    .or_else(|| small_strptime(q, "%H%M", &mut tz))
+                    .or_else(|| small_strptime(q, "%H", &mut tz))
+                    .ok_or(TimeError::Invalid)?;
+                let sign = if sign == b'+' { -1 } else { 1 };
+                tzoffset = sign * (tz.hour as i64 * 60 + tz.min as i64) * 60;
+                is_utc = true;
+            }
+        }
+        if today_date {
+            dt = Tm {
+                hour: dt.hour,
+                min: dt.min,
+                sec: dt.sec,
+                ..today(now / 1_000_000, is_utc)
+            };
+        }
+        t = if is_utc { timegm(&dt) } else { mktime(&dt) } + tzoffset;
+    }
+
+    if !p.is_empty() {
+        return Err(TimeError::Invalid);
+    }
+    if i64::MAX / suffix < t {
+        return Err(TimeError::OutOfRange);
     }
+    t = t.wrapping_mul(suffix);
+    if i64::MAX - microseconds < t {
+        return Err(TimeError::OutOfRange);
+    }
+    t += microseconds;
+    Ok(if negative { t.wrapping_neg() } else { t })
+}
+
+pub fn parse_time(
+    index: usize,
+    context: &str,
+    timestr: &str,
+    is_duration: bool,
+) -> Result<i64, ParseError> {
+    parse_time_str(timestr, is_duration).map_err(|error| ParseError::InvalidTime {
+        index,
+        opt: context.to_owned(),
+        arg: timestr.to_owned(),
+        is_duration,
+        error,
+    })
+}
+
+/// Apply a single option to the `optctx` (or to the `session` when the option
+/// is not per-file). `index` is the position of `opt` in argv.
+/// Whether `po` is applied by its `func_arg` callback rather than stored.
+fn is_callback(po: &OptionDef) -> bool {
+    !po.flags.intersects(
+        OptionFlag::OPT_STRING
+            | OptionFlag::OPT_BOOL
+            | OptionFlag::OPT_INT
+            | OptionFlag::OPT_INT64
+            | OptionFlag::OPT_TIME
+            | OptionFlag::OPT_FLOAT
+            | OptionFlag::OPT_DOUBLE,
+    )
 }
 
-/// If failed, panic with some description.
-/// TODO: change this function to return corresponding Result later
 fn write_option(
+    session: &mut Session,
     optctx: &mut Option<&mut OptionsContext>,
     po: &OptionDef,
+    index: usize,
     opt: &str,
-    arg: &str,
-) -> Result<(), ()> {
-    let dst: *mut c_void = if po
-        .flags
-        .intersects(OptionFlag::OPT_OFFSET | OptionFlag::OPT_SPEC)
-    {
-        if let &mut Some(ref mut optctx) = optctx {
-            *optctx as *mut _ as *mut c_void
-        } else {
-            panic!("some option contains OPT_OFFSET or OPT_SPEC but in global_opts")
+    os_arg: &OsStr,
+) -> Result<(), ParseError> {
+    // Only the per-stream strings, e.g. `-metadata`, are kept as any bytes.
+    let arg = match os_arg.to_str() {
+        Some(arg) => arg,
+        None if po
+            .flags
+            .contains(OptionFlag::OPT_STRING | OptionFlag::OPT_SPEC) =>
+        {
+            ""
+        }
+        None => {
+            let err = ParseError::OptionFailed {
+                index,
+                opt: opt.to_owned(),
+                arg: os_arg.to_string_lossy().into_owned(),
+                error: "Invalid UTF-8 in the argument".to_owned(),
+            };
+            error!("{}", err);
+            return Err(err);
         }
-    } else {
-        unsafe { po.u.dst_ptr }
     };
-
-    if po.flags.contains(OptionFlag::OPT_SPEC) {
-        let so = dst as *mut Vec<SpecifierOpt>;
-        let so = unsafe { so.as_mut() }.unwrap();
-        let s = opt.find(':').map_or("", |i| &opt[i + 1..]);
-        so.push(SpecifierOpt {
-            specifier: s.to_owned(),
-            u: Default::default(),
-        });
-    }
-
-    if po.flags.contains(OptionFlag::OPT_STRING) {
-        let dst = dst as *mut String;
-        let dst = unsafe { dst.as_mut() }.unwrap();
-        *dst = arg.to_owned();
+    let value = if po.flags.contains(OptionFlag::OPT_STRING) {
+        SpecifierOptValue::Str(os_arg.to_owned())
     } else if po
         .flags
-        .intersects(OptionFlag::OPT_STRING | OptionFlag::OPT_INT)
+        .intersects(OptionFlag::OPT_BOOL | OptionFlag::OPT_INT)
     {
-        let dst = dst as *mut isize;
-        let dst = unsafe { dst.as_mut() }.unwrap();
-        // IMPROVEMENT FFmpeg uses i32::{MIN, MAX} here but it's int though many
-        // c compiler still treat int as 32bit, but I think for Rust age, we
-        // need to change it.
-        *dst = parse_number(
+        SpecifierOptValue::Int(parse_number(
+            index,
             opt,
             arg,
-            OptionFlag::OPT_INT64,
-            isize::MIN as f64,
-            isize::MAX as f64,
-        )
-        .unwrap() as isize;
+            OptionFlag::OPT_INT,
+            i32::MIN as f64,
+            i32::MAX as f64,
+        )? as isize)
     } else if po.flags.contains(OptionFlag::OPT_INT64) {
-        let dst = dst as *mut i64;
-        let dst = unsafe { dst.as_mut() }.unwrap();
-        *dst = parse_number(
+        SpecifierOptValue::Int64(parse_number(
+            index,
             opt,
             arg,
             OptionFlag::OPT_INT64,
             i64::MIN as f64,
             i64::MAX as f64,
-        )
-        .unwrap() as i64;
+        )? as i64)
     } else if po.flags.contains(OptionFlag::OPT_TIME) {
-        let dst = dst as *mut i64;
-        let dst = unsafe { dst.as_mut() }.unwrap();
-        *dst = parse_time(opt, arg, true).unwrap();
+        SpecifierOptValue::Time(parse_time(index, opt, arg, true)?)
     } else if po.flags.contains(OptionFlag::OPT_FLOAT) {
-        let dst = dst as *mut f32;
-        let dst = unsafe { dst.as_mut() }.unwrap();
-        *dst = parse_number(
+        SpecifierOptValue::Float(parse_number(
+            index,
             opt,
             arg,
-            OptionFlag::OPT_INT64,
-            i64::MIN as f64,
-            i64::MAX as f64,
-        )
-        .unwrap() as f32;
+            OptionFlag::OPT_FLOAT,
+            f32::MIN as f64,
+            f32::MAX as f64,
+        )? as f32)
     } else if po.flags.contains(OptionFlag::OPT_DOUBLE) {
-        let dst = dst as *mut f64;
-        let dst = unsafe { dst.as_mut() }.unwrap();
-        *dst = parse_number(
+        SpecifierOptValue::Double(parse_number(
+            index,
             opt,
             arg,
-            OptionFlag::OPT_INT64,
-            i64::MIN as f64,
-            i64::MAX as f64,
-        )
-        .unwrap();
-    } else if unsafe { po.u.off } != 0 {
-        let optctx = if let &mut Some(ref mut optctx) = optctx {
-            *optctx as *mut _ as *mut c_void
-        } else {
-            ptr::null_mut()
-        };
+            OptionFlag::OPT_DOUBLE,
+            f64::NEG_INFINITY,
+            f64::INFINITY,
+        )?)
+    } else {
         let func = unsafe { po.u.func_arg };
-        let ret = func(optctx, opt, arg);
-        // TODO av_err2str() still haven't been implemented
+        let ret = func(session, optctx.as_deref_mut(), opt, arg);
         if ret < 0 {
-            error!(
-                "Failed to set value '{}' for option '{}': {}",
-                arg, opt, "av_err2str()"
-            );
-            return Err(());
+            let err = ParseError::OptionFailed {
+                index,
+                opt: opt.to_owned(),
+                arg: arg.to_owned(),
+                error: av_err2str(ret as libc::c_int),
+            };
+            error!("{}", err);
+            return Err(err);
         }
-    }
-    if po.flags.contains(OptionFlag::OPT_EXIT) {
-        panic!("exit as required");
-    }
-    Ok(())
-}
-
-// TODO the Err in returned Result need to be a ERROR enum
-pub fn split_commandline<'ctxt, 'global>(
-    octx: &'ctxt mut OptionParseContext<'global>,
-    args: &[String],
-    options: &'global [OptionDef],
-    groups: &'global [OptionGroupDef],
-) -> Result<(), libc::c_int> {
-    let argv = args;
-    let argc = argv.len();
+        if po.flags.contains(OptionFlag::OPT_EXIT) {
+            return Err(ParseError::ExitRequested {
+                index,
+                opt: opt.to_owned(),
+            });
+        }
+        return Ok(());
+    };
+
+    let base: *mut u8 = if po
+        .flags
+        .intersects(OptionFlag::OPT_OFFSET | OptionFlag::OPT_SPEC)
+    {
+        if let &mut Some(ref mut optctx) = optctx {
+            *optctx as *mut OptionsContext as *mut u8
+        } else {
+            let err = ParseError::OptionFailed {
+                index,
+                opt: opt.to_owned(),
+                arg: arg.to_owned(),
+                error: "Per-file option used without a file".to_owned(),
+            };
+            error!("{}", err);
+            return Err(err);
+        }
+    } else {
+        session as *mut Session as *mut u8
+    };
+    let dst = unsafe { base.add(po.u.off) } as *mut c_void;
+
+    if po.flags.contains(OptionFlag::OPT_SPEC) {
+        // The specifier of `-c:v:0` is `v:0`
+        let so = unsafe { (dst as *mut Vec<SpecifierOpt>).as_mut() }.unwrap();
+        let s = opt.find(':').map_or("", |i| &opt[i + 1..]);
+        so.push(SpecifierOpt {
+            specifier: s.to_owned(),
+            u: value,
+        });
+    } else {
+        // The type of the destination field is decided by the type flag.
+        unsafe {
+            match value {
+                // It has been checked to be UTF-8 above.
+                SpecifierOptValue::Str(s) => *(dst as *mut String) = s.into_string().unwrap(),
+                SpecifierOptValue::Int(i) => *(dst as *mut isize) = i,
+                SpecifierOptValue::Int64(i) | SpecifierOptValue::Time(i) => *(dst as *mut i64) = i,
+                SpecifierOptValue::Float(f) => *(dst as *mut f32) = f,
+                SpecifierOptValue::Double(d) => *(dst as *mut f64) = d,
+            }
+        }
+    }
+    if po.flags.contains(OptionFlag::OPT_EXIT) {
+        return Err(ParseError::ExitRequested {
+            index,
+            opt: opt.to_owned(),
+        });
+    }
+    Ok(())
+}
+
+/// Apply `opt` as if it was on the command line, for the options which are
+/// aliases of others, e.g. `-vtag` of `-tag:v`. Returns 0 or an `AVERROR`
+/// like the `func_arg` callbacks.
+pub fn parse_option(
+    session: &mut Session,
+    mut optctx: Option<&mut OptionsContext>,
+    opt: &str,
+    arg: &str,
+    options: &[OptionDef],
+) -> i64 {
+    let po = match find_option(options, opt) {
+        Some(po) => po,
+        None => {
+            error!("Unrecognized option '{}'", opt);
+            return AVERROR(ffi::EINVAL as i32) as i64;
+        }
+    };
+    match write_option(session, &mut optctx, po, 0, opt, OsStr::new(arg)) {
+        Ok(()) => 0,
+        // Already logged by `write_option()`.
+        Err(ParseError::OptionFailed { .. }) => AVERROR(ffi::EINVAL as i32) as i64,
+        Err(e) => {
+            error!("{}", e);
+            AVERROR(ffi::EINVAL as i32) as i64
+        }
+    }
+}
+
+/// Arguments end up as C strings, so they can't contain a NUL byte.
+fn check_nul(index: usize, opt: &str, arg: &OsStr) -> Result<(), ParseError> {
+    if os_bytes(arg).contains(&0) {
+        return Err(ParseError::InvalidArgument {
+            index,
+            opt: opt.to_owned(),
+            arg: arg.to_string_lossy().into_owned(),
+            error: "Argument contains a NUL byte".to_owned(),
+        });
+    }
+    Ok(())
+}
+
+pub fn split_commandline<'ctxt, 'global>(
+    octx: &'ctxt mut OptionParseContext<'global>,
+    args: &[OsString],
+    options: &'global [OptionDef],
+    groups: &'global [OptionGroupDef],
+) -> Result<(), ParseError> {
+    let argv = args;
+    let argc = argv.len();
 
     // No app arguments preparation, and the init_parse_context is moved outside.
 
@@ -422,9 +1469,10 @@ pub fn split_commandline<'ctxt, 'global>(
     // consider using `Iterator::nth()` to replace the `while` with `for`
     while optindex < argc {
         let opt = &argv[optindex];
+        let opt_index = optindex;
         optindex += 1;
 
-        debug!("Reading option '{}' ...", opt);
+        debug!("Reading option '{}' ...", opt.to_string_lossy());
 
         if opt == "--" {
             dashdash = Some(optindex);
@@ -432,7 +1480,12 @@ pub fn split_commandline<'ctxt, 'global>(
         }
 
         // unnamed group separators, e.g. output filename
-        if !opt.starts_with('-') || opt.len() <= 1 || dashdash == Some(optindex - 1) {
+        let opt_bytes = os_bytes(opt);
+        if opt_bytes.first() != Some(&b'-')
+            || opt_bytes.len() <= 1
+            || dashdash == Some(optindex - 1)
+        {
+            check_nul(opt_index, "", opt)?;
             // IMPROVEMENT original FFmpeg uses 0 rather than enum value here.
             finish_group(octx, OptGroup::GroupOutfile as usize, opt);
             debug!(
@@ -442,6 +1495,12 @@ pub fn split_commandline<'ctxt, 'global>(
             continue;
         }
 
+        // Option names are ASCII, only their arguments can be any bytes.
+        let opt = match opt.to_str() {
+            Some(opt) if opt.split(':').next().unwrap().is_ascii() && !opt.contains('\0') => opt,
+            _ => return Err(unknown_option(octx, opt_index, &opt.to_string_lossy()[1..])),
+        };
+
         // Jump over prefix `-`
         let opt = &opt[1..];
 
@@ -449,14 +1508,21 @@ pub fn split_commandline<'ctxt, 'global>(
         if let Some(group_idx) = match_group_separator(groups, opt) {
             let arg = match argv.get(optindex) {
                 Some(arg) => arg,
-                None => return Err(AVERROR(libc::EINVAL)),
+                None => {
+                    return Err(ParseError::MissingArgument {
+                        index: opt_index,
+                        opt: opt.to_owned(),
+                    })
+                }
             };
             optindex += 1;
+            check_nul(opt_index, opt, arg)?;
 
             finish_group(octx, group_idx, arg);
             debug!(
                 " matched as {} with argument '{}'.",
-                groups[group_idx].name, arg
+                groups[group_idx].name,
+                arg.to_string_lossy()
             );
             continue;
         }
@@ -466,24 +1532,29 @@ pub fn split_commandline<'ctxt, 'global>(
             let arg = if po.flags.intersects(OptionFlag::OPT_EXIT) {
                 // Optional argument, e.g. -h
 
-                // Yes, we cannot use unwrap_or() here because a coercion needed.
-                let arg = match argv.get(optindex) {
-                    Some(x) => x,
-                    None => "",
-                };
+                let arg = argv.get(optindex).map_or(OsStr::new(""), |x| x.as_os_str());
                 optindex += 1;
                 arg
             } else if po.flags.intersects(OptionFlag::HAS_ARG) {
                 let arg = match argv.get(optindex) {
-                    Some(x) => x,
-                    None => return Err(AVERROR(libc::EINVAL)),
+                    Some(x) => x.as_os_str(),
+                    None => {
+                        return Err(ParseError::MissingArgument {
+                            index: opt_index,
+                            opt: opt.to_owned(),
+                        })
+                    }
                 };
                 optindex += 1;
                 arg
             } else {
-                "1"
+                OsStr::new("1")
             };
-            add_opt(octx, po, opt, arg);
+            check_nul(opt_index, opt, arg)?;
+            if po.flags.contains(OptionFlag::OPT_SPEC) {
+                check_option_specifier(po.name, opt, opt_index)?;
+            }
+            add_opt(octx, po, opt, arg, opt_index);
             debug!(
                 " matched as option '{}' ({}) with argument '{:?}'.",
                 po.name, po.help, arg
@@ -492,20 +1563,36 @@ pub fn split_commandline<'ctxt, 'global>(
         }
 
         // AVOptions
-        if let Some(arg) = argv.get(optindex) {
+        if let Some(os_arg) = argv.get(optindex) {
+            check_nul(opt_index, opt, os_arg)?;
             // Process common options and process AVOption by the way(the
             // function name is not that self-explaining), **where some global
             // option directory is fulfilled**(this is extremely weird for me to
             // understand).
-            let ret = opt_default(ptr::null_mut(), opt, arg);
+            let arg = os_arg.to_string_lossy();
+            let ret = opt_default(&mut octx.cur_group, opt, &arg);
+            if ret >= 0 && os_arg.to_str().is_none() {
+                return Err(ParseError::AVOption {
+                    index: opt_index,
+                    opt: opt.to_owned(),
+                    arg: arg.into_owned(),
+                    error: "Invalid UTF-8 in the argument".to_owned(),
+                });
+            }
             if ret >= 0 {
+                check_option_specifier("", opt, opt_index)?;
                 debug!(" matched as AVOption '{}' with argument '{}'.", opt, arg);
                 optindex += 1;
                 continue;
             }
             if ret != AVERROR_OPTION_NOT_FOUND {
-                debug!("Error parsing option '{}' with argument '{}'.\n", opt, arg);
-                return Err(ret);
+                debug!("Error parsing option '{}' with argument '{}'.", opt, arg);
+                return Err(ParseError::AVOption {
+                    index: opt_index,
+                    opt: opt.to_owned(),
+                    arg: arg.into_owned(),
+                    error: av_err2str(ret),
+                });
             }
         }
 
@@ -513,6 +1600,7 @@ pub fn split_commandline<'ctxt, 'global>(
         if opt.starts_with("no") {
             if let Some(po) = find_option(options, &opt[2..]) {
                 if po.flags.contains(OptionFlag::OPT_BOOL) {
+                    add_opt(octx, po, opt, OsStr::new("0"), opt_index);
                     debug!(
                         " matched as option '{}' ({}) with argument 0.",
                         po.name, po.help
@@ -522,14 +1610,13 @@ pub fn split_commandline<'ctxt, 'global>(
             }
         }
 
-        error!("Unrecognized option '{}'.", opt);
-        return Err(AVERROR_OPTION_NOT_FOUND);
+        return Err(unknown_option(octx, opt_index, opt));
     }
 
     if !octx.cur_group.opts.is_empty()
-        || unsafe { !codec_opts.is_null() }
-        || unsafe { !format_opts.is_null() }
-        || unsafe { !resample_opts.is_null() }
+        || !octx.cur_group.codec_opts.is_empty()
+        || !octx.cur_group.format_opts.is_empty()
+        || !octx.cur_group.resample_opts.is_empty()
     {
         debug!("Trailing option(s) found in the command: may be ignored.");
     }
@@ -538,26 +1625,49 @@ pub fn split_commandline<'ctxt, 'global>(
     Ok(())
 }
 
-fn opt_default(_: *mut c_void, opt: &str, arg: &str) -> i32 {
+fn unknown_option(octx: &OptionParseContext, index: usize, opt: &str) -> ParseError {
+    let err = ParseError::UnknownOption {
+        index,
+        opt: opt.to_owned(),
+    };
+    match octx.locations.get(index).cloned().flatten() {
+        Some(location) => error!("{}: {}", location, err),
+        None => error!("{}", err),
+    }
+    err
+}
+
+/// Set an AVOption into the dictionaries of `g`.
+pub fn opt_default(g: &mut OptionGroup, opt: &str, arg: &str) -> i32 {
     if opt == "debug" || opt == "fdebug" {
-        // TODO implement equivalent function of av_log_set_level()
-        info!("debug is currently not implemented, debug is the default");
+        let _ = set_loglevel("debug");
+    }
+    // libav can't see past a NUL byte.
+    if opt.contains('\0') || arg.contains('\0') {
+        return AVERROR(ffi::EINVAL as i32);
     }
     let opt_stripped = CString::new(opt.split(':').next().unwrap()).unwrap();
     // This is unicode-safe because it's only used when first char is ascii.
     let opt_nohead = opt.get(1..).map(|x| CString::new(x).unwrap());
 
     let opt_c = CString::new(opt).unwrap();
-    let arg_c = CString::new(arg).unwrap();
-
-    let (opt_ptr, arg_ptr) = (opt_c.as_ptr(), arg_c.as_ptr());
+    let opt_ptr = opt_c.as_ptr();
 
     let mut cc = unsafe { ffi::avcodec_get_class() };
     let mut fc = unsafe { ffi::avformat_get_class() };
-    /* Currently not supported, they seems to be used less often.
-    let sc = sws_get_class();
-    let swr_class = swr_get_class();
-    */
+    let mut sc = unsafe { ffi::sws_get_class() };
+    let mut swr_class = unsafe { ffi::swr_get_class() };
+
+    // A flags value like `+foo` or `-bar` adds to what was already set.
+    let dict_flags = |o: *const ffi::AVOption| {
+        if unsafe { (*o).type_ } == ffi::AVOptionType_AV_OPT_TYPE_FLAGS
+            && (arg.starts_with('-') || arg.starts_with('+'))
+        {
+            DictFlag::APPEND
+        } else {
+            DictFlag::NONE
+        }
+    };
 
     let mut consumed = false;
 
@@ -581,16 +1691,7 @@ fn opt_default(_: *mut c_void, opt: &str, arg: &str) -> i32 {
         );
         !o.is_null()
     }) {
-        // Shouldn't be null, so unwrap.
-        let o = unsafe { o.as_ref() }.unwrap();
-        let flags = if o.type_ == ffi::AVOptionType_AV_OPT_TYPE_FLAGS
-            && (arg.starts_with('-') || arg.starts_with('+'))
-        {
-            ffi::AV_DICT_APPEND
-        } else {
-            0
-        };
-        unsafe { ffi::av_dict_set(&mut codec_opts as *mut _, opt_ptr, arg_ptr, flags as _) };
+        g.codec_opts.set(opt, arg, dict_flags(o));
         consumed = true;
     }
     let o = opt_find(
@@ -600,19 +1701,62 @@ fn opt_default(_: *mut c_void, opt: &str, arg: &str) -> i32 {
         0,
         ffi::AV_OPT_SEARCH_CHILDREN | ffi::AV_OPT_SEARCH_FAKE_OBJ,
     );
-    if let Some(o) = unsafe { o.as_ref() } {
-        let flags = if o.type_ == ffi::AVOptionType_AV_OPT_TYPE_FLAGS
-            && (arg.starts_with('-') || arg.starts_with('+'))
-        {
-            ffi::AV_DICT_APPEND
-        } else {
-            0
-        };
-        unsafe { ffi::av_dict_set(&mut format_opts as *mut _, opt_ptr, arg_ptr, flags as _) };
+    if !o.is_null() {
+        g.format_opts.set(opt, arg, dict_flags(o));
         consumed = true;
     }
 
-    // TODO: init things about SWRESAMPLE SWSCALE
+    let arg_c = CString::new(arg).unwrap();
+    let search_flags = ffi::AV_OPT_SEARCH_CHILDREN | ffi::AV_OPT_SEARCH_FAKE_OBJ;
+    let o = opt_find(
+        &mut sc as *mut _ as *mut c_void,
+        opt_ptr,
+        ptr::null(),
+        0,
+        search_flags,
+    );
+    if !consumed && !o.is_null() {
+        let ret = unsafe {
+            let sws = ffi::sws_alloc_context();
+            let ret = ffi::av_opt_set(sws as *mut c_void, opt_ptr, arg_c.as_ptr(), 0);
+            ffi::sws_freeContext(sws);
+            ret
+        };
+        if ["srcw", "srch", "dstw", "dsth", "src_format", "dst_format"].contains(&opt) {
+            error!(
+                "Directly using swscale dimensions/format options is not supported, \
+                 please use the -s or -pix_fmt options"
+            );
+            return AVERROR(ffi::EINVAL as i32);
+        }
+        if ret < 0 {
+            error!("Error setting option {}.", opt);
+            return ret;
+        }
+        g.sws_dict.set(opt, arg, dict_flags(o));
+        consumed = true;
+    }
+    let o = opt_find(
+        &mut swr_class as *mut _ as *mut c_void,
+        opt_ptr,
+        ptr::null(),
+        0,
+        search_flags,
+    );
+    if !consumed && !o.is_null() {
+        let ret = unsafe {
+            let mut swr = ffi::swr_alloc();
+            let ret = ffi::av_opt_set(swr as *mut c_void, opt_ptr, arg_c.as_ptr(), 0);
+            ffi::swr_free(&mut swr);
+            ret
+        };
+        if ret < 0 {
+            error!("Error setting option {}.", opt);
+            return ret;
+        }
+        g.swr_opts.set(opt, arg, dict_flags(o));
+        consumed = true;
+    }
 
     if consumed {
         0
@@ -650,36 +1794,19 @@ fn match_group_separator(groups: &[OptionGroupDef], opt: &str) -> Option<usize>
 /// # Parameters
 /// `group_idx`     which group definition should this group belong to
 /// `arg`           argument of the group delimiting option
-fn finish_group(octx: &mut OptionParseContext, group_idx: usize, arg: &str) {
-    let mut new_group = octx.cur_group.clone();
+fn finish_group(octx: &mut OptionParseContext, group_idx: usize, arg: &OsStr) {
+    // The dictionaries filled by opt_default() move along with the group.
+    let mut new_group = mem::replace(&mut octx.cur_group, OptionGroup::new_anonymous());
     new_group.arg = arg.to_owned();
     new_group.group_def = octx.groups[group_idx].group_def;
-    unsafe {
-        new_group.sws_dict = sws_dict;
-        new_group.swr_opts = swr_opts;
-        new_group.codec_opts = codec_opts;
-        new_group.format_opts = format_opts;
-        new_group.resample_opts = resample_opts;
-    }
 
     octx.groups[group_idx].groups.push(new_group);
 
-    unsafe {
-        codec_opts = ptr::null_mut();
-        format_opts = ptr::null_mut();
-        resample_opts = ptr::null_mut();
-        sws_dict = ptr::null_mut();
-        swr_opts = ptr::null_mut();
-    }
-    init_opts();
-
-    octx.cur_group = OptionGroup::new_anonymous();
+    init_opts(&mut octx.cur_group);
 }
 
-fn init_opts() {
-    let flags = CString::new("flags").unwrap();
-    let bicubic = CString::new("bicubic").unwrap();
-    unsafe { ffi::av_dict_set(&mut sws_dict as *mut _, flags.as_ptr(), bicubic.as_ptr(), 0) };
+fn init_opts(g: &mut OptionGroup) {
+    g.sws_dict.set("flags", "bicubic", DictFlag::NONE);
 }
 
 fn find_option<'global>(
@@ -697,7 +1824,8 @@ fn add_opt<'ctxt, 'global>(
     octx: &'ctxt mut OptionParseContext<'global>,
     opt: &'global OptionDef<'global>,
     key: &str,
-    val: &str,
+    val: &OsStr,
+    index: usize,
 ) {
     let global = !opt
         .flags
@@ -712,42 +1840,165 @@ fn add_opt<'ctxt, 'global>(
         opt: opt,
         key: key.to_owned(),
         val: val.to_owned(),
+        index,
+        location: octx.locations.get(index).cloned().flatten(),
     })
 }
 
-pub fn print_error(filename: &str, err: i32) {
+/// The bytes of an argument as libav takes them, it's UTF-8 on the platforms
+/// where arguments aren't bytes.
+pub fn os_bytes(s: &OsStr) -> Cow<[u8]> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Cow::Borrowed(s.as_bytes())
+    }
+    #[cfg(not(unix))]
+    {
+        match s.to_string_lossy() {
+            Cow::Borrowed(s) => Cow::Borrowed(s.as_bytes()),
+            Cow::Owned(s) => Cow::Owned(s.into_bytes()),
+        }
+    }
+}
+
+/// The inverse of `os_bytes()`, invalid UTF-8 is replaced on the platforms
+/// where arguments aren't bytes.
+fn bytes_os_string(bytes: Vec<u8>) -> OsString {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStringExt;
+        OsString::from_vec(bytes)
+    }
+    #[cfg(not(unix))]
+    {
+        String::from_utf8_lossy(&bytes).into_owned().into()
+    }
+}
+
+/// An argument, e.g. a filename, passed as is to libav.
+pub fn os_cstring(s: &OsStr) -> Result<CString, String> {
+    CString::new(os_bytes(s).into_owned()).map_err(|e| e.to_string())
+}
+
+/// `s` without the ASCII `prefix`, the rest isn't checked to be UTF-8.
+fn strip_os_prefix<'a>(s: &'a OsStr, prefix: &str) -> Option<&'a OsStr> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        let bytes = s.as_bytes();
+        if bytes.starts_with(prefix.as_bytes()) {
+            Some(OsStr::from_bytes(&bytes[prefix.len()..]))
+        } else {
+            None
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        s.to_str()?.strip_prefix(prefix).map(OsStr::new)
+    }
+}
+
+/// Rust version of the `av_err2str()` macro.
+pub fn av_err2str(err: libc::c_int) -> String {
     let mut errbuf = [0 as libc::c_char; 128];
-    let errbuf_ptr = unsafe {
-        if ffi::av_strerror(err, &mut errbuf as *mut _ as *mut i8, 128) < 0 {
-            CString::from_raw(ffi::strerror(AVUNERROR(err)))
+    unsafe {
+        if ffi::av_strerror(err, errbuf.as_mut_ptr(), errbuf.len() as _) < 0 {
+            CStr::from_ptr(libc::strerror(AVUNERROR(err)))
+                .to_string_lossy()
+                .into_owned()
         } else {
-            CString::from_raw(&mut errbuf as *mut _ as *mut i8)
+            CStr::from_ptr(errbuf.as_ptr())
+                .to_string_lossy()
+                .into_owned()
         }
-    };
+    }
+}
+
+/// Rust version of the inline `av_q2d()`.
+pub fn av_q2d(a: ffi::AVRational) -> f64 {
+    a.num as f64 / a.den as f64
+}
+
+/// Rust version of the inline `av_inv_q()`.
+pub fn av_inv_q(q: ffi::AVRational) -> ffi::AVRational {
+    ffi::AVRational {
+        num: q.den,
+        den: q.num,
+    }
+}
+
+pub fn print_error(filename: &str, err: i32) {
+    error!("{}: {}", filename, av_err2str(err));
+}
 
-    error!("{}: {}", filename, errbuf_ptr.to_str().unwrap());
+/// Read a line from stdin, `true` if it starts with `y` or `Y`.
+pub fn read_yesno() -> bool {
+    let mut line = String::new();
+    io::stdin().read_line(&mut line).is_ok() && line.starts_with(|c| c == 'y' || c == 'Y')
+}
+
+/// Check the part after the first `:` of an `OPT_SPEC` option or an AVOption.
+/// `-metadata` and `-map_metadata` take a metadata specifier instead, whose
+/// `s` type can be followed by a stream specifier; `-program` ignores it.
+fn check_option_specifier(name: &str, opt: &str, index: usize) -> Result<(), ParseError> {
+    let spec = match opt.find(':') {
+        Some(i) => &opt[i + 1..],
+        None => return Ok(()),
+    };
+    let result = match name {
+        "program" => Ok(()),
+        "metadata" | "map_metadata" => match spec.as_bytes().first() {
+            Some(b'g') | None => Ok(()),
+            Some(b's') => match spec.get(1..2) {
+                None => Ok(()),
+                Some(":") => spec[2..].parse::<StreamSpecifier>().map(|_| ()),
+                Some(_) => Err(format!("expected ':' after 's' in '{}'", spec)),
+            },
+            Some(b'c') | Some(b'p') => match spec.get(1..2) {
+                None => Ok(()),
+                Some(":") if spec[2..].parse::<usize>().is_ok() => Ok(()),
+                _ => Err(format!("invalid metadata index in '{}'", spec)),
+            },
+            Some(_) => Err(format!("invalid metadata type in '{}'", spec)),
+        },
+        _ => spec.parse::<StreamSpecifier>().map(|_| ()),
+    };
+    result.map_err(|reason| ParseError::InvalidStreamSpecifier {
+        index,
+        opt: opt.to_owned(),
+        reason,
+    })
 }
 
+/// Whether the stream `st` of `s` matches `spec`, `None` for an invalid
+/// specifier, which is reported.
 unsafe fn check_stream_specifier(
     s: *mut ffi::AVFormatContext,
     st: *mut ffi::AVStream,
-    spec: *const libc::c_char,
-) -> libc::c_int {
-    let ret = ffi::avformat_match_stream_specifier(s, st, spec);
-    let spec = CStr::from_ptr(spec);
-    if ret < 0 {
-        error!("Invalid stream specifier: {}.", spec.to_string_lossy());
+    spec: &str,
+) -> Option<bool> {
+    match spec.parse::<StreamSpecifier>() {
+        Ok(spec) => {
+            let streams = StreamInfo::from_format_context(s);
+            Some(spec.matches(&streams, (*st).index as usize))
+        }
+        Err(e) => {
+            error!("Invalid stream specifier: {}.", e);
+            None
+        }
     }
-    ret
 }
 
-unsafe fn filter_codec_opts(
-    opts_ptr: *mut ffi::AVDictionary,
+/// Pick the entries of `opts` that apply to the stream `st` of `s`, with the
+/// stream specifier (e.g. `:v`) stripped from the key.
+pub unsafe fn filter_codec_opts(
+    opts: &Dictionary,
     codec_id: ffi::AVCodecID,
     s_ptr: *mut ffi::AVFormatContext,
     st_ptr: *mut ffi::AVStream,
     codec_ptr: *mut ffi::AVCodec,
-) -> *mut ffi::AVDictionary {
+) -> Dictionary {
     let s = s_ptr.as_ref().unwrap();
     let st = st_ptr.as_ref().unwrap();
     let mut cc = ffi::avcodec_get_class();
@@ -771,113 +2022,81 @@ unsafe fn filter_codec_opts(
     let prefix = match st.codecpar.as_ref().unwrap().codec_type {
         ffi::AVMediaType_AVMEDIA_TYPE_VIDEO => {
             flags |= ffi::AV_OPT_FLAG_VIDEO_PARAM;
-            b'v'
+            Some('v')
         }
         ffi::AVMediaType_AVMEDIA_TYPE_AUDIO => {
             flags |= ffi::AV_OPT_FLAG_AUDIO_PARAM;
-            b'a'
+            Some('a')
         }
         ffi::AVMediaType_AVMEDIA_TYPE_SUBTITLE => {
             flags |= ffi::AV_OPT_FLAG_SUBTITLE_PARAM;
-            b's'
+            Some('s')
         }
-        _ => 0 as u8,
+        _ => None,
     };
 
-    let mut ret = ptr::null_mut();
-    let mut t_ptr = ptr::null_mut();
-
-    let empty = CString::new("").unwrap();
-
-    loop {
-        t_ptr = ffi::av_dict_get(
-            opts_ptr,
-            empty.as_ptr(),
-            t_ptr,
-            ffi::AV_DICT_IGNORE_SUFFIX as i32,
-        );
-        let t = match t_ptr.as_ref() {
-            Some(t) => t,
-            None => break,
-        };
-
-        let p = libc::strchr(t.key, b':' as _);
-
-        // check stream specification in opt name
-        if !p.is_null() {
-            match check_stream_specifier(s_ptr, st_ptr, p.offset(1)) {
-                1 => {
-                    *p = 0;
-                    break;
-                }
-                0 => {
-                    continue;
-                }
-                _ => panic!(),
-            }
-        }
-
-        if !ffi::av_opt_find(
-            &mut cc as *mut _ as *mut c_void,
-            t.key,
+    let find = |class: *mut c_void, name: &str| {
+        let name = CString::new(name).unwrap();
+        !ffi::av_opt_find(
+            class,
+            name.as_ptr(),
             ptr::null(),
             flags as _,
             ffi::AV_OPT_SEARCH_FAKE_OBJ as _,
         )
         .is_null()
+    };
+
+    let mut ret = Dictionary::new();
+    for (key, value) in opts.iter() {
+        // check stream specification in opt name
+        let key = match key.find(':') {
+            Some(i) => {
+                match check_stream_specifier(s_ptr, st_ptr, &key[i + 1..]) {
+                    Some(true) => &key[..i],
+                    // Not matched, or an invalid specifier which has already
+                    // been reported.
+                    _ => continue,
+                }
+            }
+            None => key,
+        };
+
+        if find(&mut cc as *mut _ as *mut c_void, key)
             || codec_ptr.is_null()
             || (!codec_ptr.as_ref().unwrap().priv_class.is_null()
-                && !ffi::av_opt_find(
+                && find(
                     &mut codec_ptr.as_mut().unwrap().priv_class as *mut _ as *mut c_void,
-                    t.key,
-                    ptr::null(),
-                    flags as _,
-                    ffi::AV_OPT_SEARCH_FAKE_OBJ as _,
-                )
-                .is_null())
+                    key,
+                ))
         {
-            ffi::av_dict_set(&mut ret as *mut _, t.key, t.value, 0);
-        } else if *t.key == prefix as i8
-            && !ffi::av_opt_find(
-                &mut cc as *mut _ as *mut c_void,
-                t.key.offset(1),
-                ptr::null(),
-                flags as i32,
-                ffi::AV_OPT_SEARCH_FAKE_OBJ as i32,
-            )
-            .is_null()
+            ret.set(key, value, DictFlag::NONE);
+        } else if prefix.is_some()
+            && key.starts_with(prefix.unwrap())
+            && find(&mut cc as *mut _ as *mut c_void, &key[1..])
         {
-            ffi::av_dict_set(&mut ret as *mut _, t.key.offset(1), t.value, 0);
-        }
-        if !p.is_null() {
-            *p = b':' as _;
+            ret.set(&key[1..], value, DictFlag::NONE);
         }
     }
 
     ret
 }
 
+/// Codec options for each stream of `s`, converted to an `AVDictionary`
+/// array only when calling `avformat_find_stream_info()`.
 pub unsafe fn setup_find_stream_info_opts(
     s: *mut ffi::AVFormatContext,
-    codec_opts_arg: *mut ffi::AVDictionary,
-) -> *mut *mut ffi::AVDictionary {
+    codec_opts: &Dictionary,
+) -> Vec<Dictionary> {
     let s_ptr = s;
     let s = s_ptr.as_ref().unwrap();
     if s.nb_streams == 0 {
-        return ptr::null_mut();
+        return vec![];
     }
-    let opts_ptr = ffi::av_mallocz_array(
-        s.nb_streams as u64,
-        mem::size_of::<*mut ffi::AVDictionary>() as u64,
-    ) as *mut *mut ffi::AVDictionary;
-
-    if opts_ptr.is_null() {
-        error!("Could not alloc memory for stream options.");
-        ptr::null_mut()
-    } else {
-        let opts = slice::from_raw_parts_mut(opts_ptr, s.nb_streams as usize);
-        let s_streams = slice::from_raw_parts_mut(s.streams, s.nb_streams as usize);
-        for (opt, s_stream) in opts.iter_mut().zip(s_streams.iter()) {
+    let s_streams = slice::from_raw_parts_mut(s.streams, s.nb_streams as usize);
+    s_streams
+        .iter()
+        .map(|s_stream| {
             let codec_id = s_stream
                 .as_ref()
                 .unwrap()
@@ -885,28 +2104,3886 @@ pub unsafe fn setup_find_stream_info_opts(
                 .as_ref()
                 .unwrap()
                 .codec_id;
-            *opt = filter_codec_opts(codec_opts_arg, codec_id, s_ptr, *s_stream, ptr::null_mut());
+            filter_codec_opts(codec_opts, codec_id, s_ptr, *s_stream, ptr::null_mut())
+        })
+        .collect()
+}
+
+/// Find the index of `optname` in the command line, before it's parsed.
+pub fn locate_option(args: &[OsString], options: &[OptionDef], optname: &str) -> Option<usize> {
+    let mut i = 1;
+    while i < args.len() {
+        if let Some(cur_opt) = args[i].to_str().and_then(|arg| arg.strip_prefix('-')) {
+            let po = find_option(options, cur_opt).or_else(|| {
+                cur_opt
+                    .strip_prefix("no")
+                    .and_then(|name| find_option(options, name))
+            });
+            match po {
+                Some(po) if po.name == optname => return Some(i),
+                None if cur_opt == optname => return Some(i),
+                // skip the argument
+                Some(po) if po.flags.contains(OptionFlag::HAS_ARG) => i += 1,
+                None => i += 1,
+                Some(_) => {}
+            }
         }
-        opts_ptr
+        i += 1;
     }
+    None
 }
 
-#[cfg(test)]
-mod types_tests {
-    use super::*;
+/// Apply the options needed before the command line is parsed.
+pub fn parse_loglevel(session: &mut Session, args: &[OsString], options: &[OptionDef]) {
+    let idx =
+        locate_option(args, options, "loglevel").or_else(|| locate_option(args, options, "v"));
+    if let Some(arg) = idx.and_then(|idx| args.get(idx + 1)?.to_str()) {
+        if let Err(e) = set_loglevel(arg) {
+            error!("{}", e);
+        }
+    }
+    if locate_option(args, options, "hide_banner").is_some() {
+        session.hide_banner = 1;
+    }
+    // The listing options run in command line order, they must all see the
+    // format. An invalid one is reported when the option itself is parsed.
+    let idx = locate_option(args, options, "print_format");
+    if let Some(Ok(print_format)) = idx.and_then(|idx| Some(args.get(idx + 1)?.to_str()?.parse())) {
+        session.print_format = print_format;
+    }
 
-    #[test]
-    fn fmt_debug_option_operation_default() {
-        let optop: OptionOperation = Default::default();
-        assert_eq!(format!("{:?}", optop), "(Union)OptionOperation { val: 0 }");
+    let env = env::var("FFREPORT").ok();
+    if env.is_some() || locate_option(args, options, "report").is_some() {
+        match init_report(env.as_deref()) {
+            Ok(()) => {
+                if let Some(report) = &mut *REPORT.lock().unwrap_or_else(|e| e.into_inner()) {
+                    let args: Vec<_> = args.iter().map(|arg| quote_argument(arg)).collect();
+                    let _ = writeln!(report.file, "Command line:\n{}", args.join(" "));
+                }
+            }
+            Err(e) => error!("{}", e),
+        }
     }
+}
 
-    #[test]
-    fn fmt_debug_option_operation() {
-        let optop: OptionOperation = OptionOperation { off: 123_456 };
-        assert_eq!(
-            format!("{:?}", optop),
-            "(Union)OptionOperation { val: 123456 }"
-        );
+const LOG_LEVELS: &[(&str, i32)] = &[
+    ("quiet", ffi::AV_LOG_QUIET),
+    ("panic", ffi::AV_LOG_PANIC as i32),
+    ("fatal", ffi::AV_LOG_FATAL as i32),
+    ("error", ffi::AV_LOG_ERROR as i32),
+    ("warning", ffi::AV_LOG_WARNING as i32),
+    ("info", ffi::AV_LOG_INFO as i32),
+    ("verbose", ffi::AV_LOG_VERBOSE as i32),
+    ("debug", ffi::AV_LOG_DEBUG as i32),
+    ("trace", ffi::AV_LOG_TRACE as i32),
+];
+
+/// Parse a `-loglevel` argument like `repeat+level+debug` against the
+/// current av_log flags and level, returning the new ones.
+fn parse_loglevel_arg(arg: &str, mut flags: i32, level: i32) -> Result<(i32, i32), String> {
+    let skip_repeat = ffi::AV_LOG_SKIP_REPEATED as i32;
+    let print_level = ffi::AV_LOG_PRINT_LEVEL as i32;
+    let initial_flags = flags;
+    let mut arg = arg;
+    let mut i = 0;
+    while !arg.is_empty() {
+        let (cmd, token) = match arg.as_bytes()[0] {
+            cmd @ b'+' | cmd @ b'-' => (Some(cmd), &arg[1..]),
+            _ => (None, arg),
+        };
+        if i == 0 && cmd.is_none() {
+            // missing relative prefix, build absolute value
+            flags = 0;
+        }
+        if let Some(rest) = token.strip_prefix("repeat") {
+            if cmd == Some(b'-') {
+                flags |= skip_repeat;
+            } else {
+                flags &= !skip_repeat;
+            }
+            arg = rest;
+        } else if let Some(rest) = token.strip_prefix("level") {
+            if cmd == Some(b'-') {
+                flags &= !print_level;
+            } else {
+                flags |= print_level;
+            }
+            arg = rest;
+        } else {
+            break;
+        }
+        i += 1;
+    }
+    if arg.is_empty() {
+        return Ok((flags, level));
+    } else if let Some(rest) = arg.strip_prefix('+') {
+        arg = rest;
+    } else if i == 0 {
+        // level value without prefix, reset flags
+        flags = initial_flags;
+    }
+
+    if let Some(&(_, level)) = LOG_LEVELS.iter().find(|(name, _)| *name == arg) {
+        return Ok((flags, level));
+    }
+    match arg.parse() {
+        Ok(level) => Ok((flags, level)),
+        Err(_) => {
+            let names = LOG_LEVELS
+                .iter()
+                .map(|(name, _)| format!("\"{}\"", name))
+                .collect::<Vec<_>>();
+            Err(format!(
+                "Invalid loglevel \"{}\". Possible levels are numbers or:\n{}",
+                arg,
+                names.join("\n")
+            ))
+        }
+    }
+}
+
+/// The `log` level of an av_log level, `None` for `quiet`.
+fn log_level(level: i32) -> Option<log::Level> {
+    if level < ffi::AV_LOG_PANIC as i32 {
+        None
+    } else if level <= ffi::AV_LOG_ERROR as i32 {
+        Some(log::Level::Error)
+    } else if level <= ffi::AV_LOG_WARNING as i32 {
+        Some(log::Level::Warn)
+    } else if level <= ffi::AV_LOG_INFO as i32 {
+        Some(log::Level::Info)
+    } else if level <= ffi::AV_LOG_DEBUG as i32 {
+        Some(log::Level::Debug)
+    } else {
+        Some(log::Level::Trace)
+    }
+}
+
+/// Apply a `-loglevel` argument to both av_log and the `log` crate.
+pub fn set_loglevel(arg: &str) -> Result<(), String> {
+    let (flags, level) =
+        unsafe { parse_loglevel_arg(arg, ffi::av_log_get_flags(), ffi::av_log_get_level())? };
+    unsafe {
+        ffi::av_log_set_flags(flags);
+        ffi::av_log_set_level(level);
+    }
+    CONSOLE_LEVEL.store(level, Ordering::Relaxed);
+    update_max_level();
+    Ok(())
+}
+
+/// The av_log level of the console output, it's the `-loglevel`.
+static CONSOLE_LEVEL: AtomicI32 = AtomicI32::new(ffi::AV_LOG_INFO as i32);
+
+/// The `-report` file.
+struct Report {
+    file: fs::File,
+    /// The av_log level of the messages written.
+    level: i32,
+}
+
+static REPORT: Lazy<Mutex<Option<Report>>> = Lazy::new(Default::default);
+
+fn level_filter(level: i32) -> log::LevelFilter {
+    log_level(level).map_or(log::LevelFilter::Off, |level| level.to_level_filter())
+}
+
+/// Let through the messages wanted by either the console or the report.
+fn update_max_level() {
+    let mut level = CONSOLE_LEVEL.load(Ordering::Relaxed);
+    if let Some(report) = &*REPORT.lock().unwrap_or_else(|e| e.into_inner()) {
+        level = level.max(report.level);
+    }
+    log::set_max_level(level_filter(level));
+}
+
+/// Writes to the console through `env_logger`, which still filters by
+/// `RUST_LOG`, and to the `-report` file.
+struct Logger {
+    console: env_logger::Logger,
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if record.level() <= level_filter(CONSOLE_LEVEL.load(Ordering::Relaxed)) {
+            self.console.log(record);
+        }
+        if let Some(report) = &mut *REPORT.lock().unwrap_or_else(|e| e.into_inner()) {
+            if record.level() <= level_filter(report.level) {
+                let _ = writeln!(report.file, "[{}] {}", record.target(), record.args());
+            }
+        }
+    }
+
+    fn flush(&self) {
+        self.console.flush();
+    }
+}
+
+/// A line to pass to the `log` crate.
+#[derive(Debug, PartialEq)]
+struct LogLine {
+    target: String,
+    level: log::Level,
+    message: String,
+}
+
+/// The lines of av_log messages, which can come in parts, and the state of
+/// the repeated line collapsing.
+#[derive(Default)]
+struct LogLines {
+    /// The beginning of the current line.
+    current: Option<LogLine>,
+    prev: Option<LogLine>,
+    /// How many times `prev` was repeated.
+    count: usize,
+}
+
+impl LogLines {
+    /// Add a message part, returning the lines it completes.
+    fn push(
+        &mut self,
+        target: String,
+        level: log::Level,
+        part: &str,
+        skip_repeat: bool,
+    ) -> Vec<LogLine> {
+        let line = match self.current.take() {
+            Some(mut line) => {
+                line.message.push_str(part);
+                line
+            }
+            None => LogLine {
+                target,
+                level,
+                message: part.to_owned(),
+            },
+        };
+        if !line.message.ends_with('\n') && !line.message.ends_with('\r') {
+            self.current = Some(line);
+            return vec![];
+        }
+
+        if skip_repeat
+            && self.prev.as_ref() == Some(&line)
+            && line.message.trim() != ""
+            && !line.message.ends_with('\r')
+        {
+            self.count += 1;
+            return vec![];
+        }
+        let mut lines = vec![];
+        if self.count > 0 {
+            let prev = self.prev.as_ref().unwrap();
+            lines.push(LogLine {
+                target: prev.target.clone(),
+                level: prev.level,
+                message: format!("    Last message repeated {} times", self.count),
+            });
+            self.count = 0;
+        }
+        lines.push(LogLine {
+            target: line.target.clone(),
+            level: line.level,
+            message: line.message.trim_end_matches(&['\n', '\r'][..]).to_owned(),
+        });
+        self.prev = Some(line);
+        lines
+    }
+}
+
+static LOG_LINES: Lazy<Mutex<LogLines>> = Lazy::new(Default::default);
+
+/// The name of the component logging, e.g. `h264`.
+unsafe fn log_target(avcl: *mut c_void) -> String {
+    let avc = if avcl.is_null() {
+        None
+    } else {
+        (*(avcl as *const *const ffi::AVClass)).as_ref()
+    };
+    match avc {
+        Some(avc) => match avc.item_name {
+            Some(item_name) => cstr_or(item_name(avcl), "ffmpeg").into_owned(),
+            None => cstr_or(avc.class_name, "ffmpeg").into_owned(),
+        },
+        None => "ffmpeg".to_owned(),
+    }
+}
+
+/// Pass the av_log messages to the `log` crate, with the name of the
+/// component as the target.
+unsafe extern "C" fn log_callback(
+    avcl: *mut c_void,
+    level: libc::c_int,
+    fmt: *const libc::c_char,
+    vl: *mut ffi::__va_list_tag,
+) {
+    let log_level = match log_level(level) {
+        Some(log_level) if log_level <= log::max_level() => log_level,
+        _ => return,
+    };
+    let mut part = [0 as libc::c_char; 1024];
+    // the target replaces the `[name @ 0x...]` prefix
+    let mut print_prefix = 0;
+    ffi::av_log_format_line2(
+        avcl,
+        level,
+        fmt,
+        vl,
+        part.as_mut_ptr(),
+        part.len() as i32,
+        &mut print_prefix,
+    );
+    let mut part = CStr::from_ptr(part.as_ptr()).to_string_lossy().into_owned();
+    let flags = ffi::av_log_get_flags();
+    if flags & ffi::AV_LOG_PRINT_LEVEL as i32 != 0 {
+        let name = LOG_LEVELS
+            .iter()
+            .rev()
+            .find(|&&(_, l)| l <= level)
+            .map_or("", |(name, _)| name);
+        part = format!("[{}] {}", name, part);
+    }
+
+    let lines = {
+        let mut log_lines = LOG_LINES.lock().unwrap_or_else(|e| e.into_inner());
+        log_lines.push(
+            log_target(avcl),
+            log_level,
+            &part,
+            flags & ffi::AV_LOG_SKIP_REPEATED as i32 != 0,
+        )
+    };
+    for line in lines {
+        log::logger().log(
+            &log::Record::builder()
+                .args(format_args!("{}", line.message))
+                .level(line.level)
+                .target(&line.target)
+                .build(),
+        );
+    }
+}
+
+/// Install the logger and route av_log into it, collapsing repeated lines.
+pub fn init_log() {
+    let console =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("trace"))
+            .build();
+    if log::set_boxed_logger(Box::new(Logger { console })).is_ok() {
+        update_max_level();
+    }
+    unsafe {
+        ffi::av_log_set_flags(ffi::AV_LOG_SKIP_REPEATED as i32);
+        ffi::av_log_set_callback(Some(log_callback));
+    }
+}
+
+/// The settings of `FFREPORT`, e.g. `file=%p-%t.log:level=32`, as the file
+/// name template and the level.
+fn parse_ffreport(env: &str) -> Result<(Option<String>, Option<i32>), String> {
+    let (mut template, mut level) = (None, None);
+    let mut entries = vec![String::new()];
+    let mut chars = env.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => entries.last_mut().unwrap().extend(chars.next()),
+            ':' => entries.push(String::new()),
+            c => entries.last_mut().unwrap().push(c),
+        }
+    }
+    for entry in entries.iter().filter(|entry| !entry.is_empty()) {
+        let (key, value) = match entry.find('=') {
+            Some(i) => (&entry[..i], &entry[i + 1..]),
+            None => {
+                return Err(format!(
+                    "Failed to parse FFREPORT environment variable: {}",
+                    entry
+                ))
+            }
+        };
+        match key {
+            "file" => template = Some(value.to_owned()),
+            "level" => {
+                level = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("Invalid report file level: {}", value))?,
+                )
+            }
+            _ => error!("Unknown key '{}' in FFREPORT", key),
+        }
+    }
+    Ok((template, level))
+}
+
+/// Expand `%p` to the program name and `%t` to the time in the report file
+/// name template.
+fn expand_report_template(template: &str, program: &str, time: &str) -> String {
+    let mut filename = String::new();
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('%', Some('p')) => filename.push_str(program),
+            ('%', Some('t')) => filename.push_str(time),
+            ('%', Some('%')) => filename.push('%'),
+            _ => {
+                filename.push(c);
+                continue;
+            }
+        }
+        chars.next();
+    }
+    filename
+}
+
+/// Quote an argument for the command line in the report, like a shell would
+/// need it.
+fn quote_argument(arg: &OsStr) -> String {
+    let plain = |&c: &u8| match c {
+        b'+'..=b':' | b'@'..=b'Z' | b'_' | b'a'..=b'z' => true,
+        _ => false,
+    };
+    let arg = os_bytes(arg);
+    if !arg.is_empty() && arg.iter().all(plain) {
+        return String::from_utf8_lossy(&arg).into_owned();
+    }
+    let mut quoted = String::from("\"");
+    for &c in arg.iter() {
+        match c {
+            b'\\' | b'"' | b'$' | b'`' => {
+                quoted.push('\\');
+                quoted.push(c as char);
+            }
+            b' '..=b'~' => quoted.push(c as char),
+            _ => write!(quoted, "\\x{:02x}", c).unwrap(),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Open the `-report` file, `env` is the value of `FFREPORT`. Does nothing
+/// if it's already open.
+pub fn init_report(env: Option<&str>) -> Result<(), String> {
+    if REPORT.lock().unwrap_or_else(|e| e.into_inner()).is_some() {
+        return Ok(());
+    }
+    let (template, level) = parse_ffreport(env.unwrap_or(""))?;
+    let tm = unsafe {
+        let mut now = 0;
+        libc::time(&mut now);
+        let mut tm = mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        tm
+    };
+    let time = format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    );
+    let filename =
+        expand_report_template(template.as_deref().unwrap_or("%p-%t.log"), "ffcli", &time);
+    let level = level.unwrap_or_else(|| {
+        CONSOLE_LEVEL
+            .load(Ordering::Relaxed)
+            .max(ffi::AV_LOG_DEBUG as i32)
+    });
+    let file = fs::File::create(&filename)
+        .map_err(|e| format!("Failed to open report \"{}\": {}", filename, e))?;
+    *REPORT.lock().unwrap_or_else(|e| e.into_inner()) = Some(Report { file, level });
+    update_max_level();
+    info!(
+        "ffcli started on {:04}-{:02}-{:02} at {:02}:{:02}:{:02}\nReport written to \"{}\"\nLog level: {}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec,
+        filename,
+        level
+    );
+    Ok(())
+}
+
+/// A linked FFmpeg library.
+struct Lib {
+    name: &'static str,
+    /// The version of the headers ffcli was built against.
+    compiled: (u32, u32, u32),
+    version: unsafe extern "C" fn() -> libc::c_uint,
+    configuration: unsafe extern "C" fn() -> *const libc::c_char,
+}
+
+const LIBS: &[Lib] = &[
+    Lib {
+        name: "avutil",
+        compiled: (
+            ffi::LIBAVUTIL_VERSION_MAJOR,
+            ffi::LIBAVUTIL_VERSION_MINOR,
+            ffi::LIBAVUTIL_VERSION_MICRO,
+        ),
+        version: ffi::avutil_version,
+        configuration: ffi::avutil_configuration,
+    },
+    Lib {
+        name: "avcodec",
+        compiled: (
+            ffi::LIBAVCODEC_VERSION_MAJOR,
+            ffi::LIBAVCODEC_VERSION_MINOR,
+            ffi::LIBAVCODEC_VERSION_MICRO,
+        ),
+        version: ffi::avcodec_version,
+        configuration: ffi::avcodec_configuration,
+    },
+    Lib {
+        name: "avformat",
+        compiled: (
+            ffi::LIBAVFORMAT_VERSION_MAJOR,
+            ffi::LIBAVFORMAT_VERSION_MINOR,
+            ffi::LIBAVFORMAT_VERSION_MICRO,
+        ),
+        version: ffi::avformat_version,
+        configuration: ffi::avformat_configuration,
+    },
+    Lib {
+        name: "avdevice",
+        compiled: (
+            ffi::LIBAVDEVICE_VERSION_MAJOR,
+            ffi::LIBAVDEVICE_VERSION_MINOR,
+            ffi::LIBAVDEVICE_VERSION_MICRO,
+        ),
+        version: ffi::avdevice_version,
+        configuration: ffi::avdevice_configuration,
+    },
+    Lib {
+        name: "avfilter",
+        compiled: (
+            ffi::LIBAVFILTER_VERSION_MAJOR,
+            ffi::LIBAVFILTER_VERSION_MINOR,
+            ffi::LIBAVFILTER_VERSION_MICRO,
+        ),
+        version: ffi::avfilter_version,
+        configuration: ffi::avfilter_configuration,
+    },
+    Lib {
+        name: "swscale",
+        compiled: (
+            ffi::LIBSWSCALE_VERSION_MAJOR,
+            ffi::LIBSWSCALE_VERSION_MINOR,
+            ffi::LIBSWSCALE_VERSION_MICRO,
+        ),
+        version: ffi::swscale_version,
+        configuration: ffi::swscale_configuration,
+    },
+    Lib {
+        name: "swresample",
+        compiled: (
+            ffi::LIBSWRESAMPLE_VERSION_MAJOR,
+            ffi::LIBSWRESAMPLE_VERSION_MINOR,
+            ffi::LIBSWRESAMPLE_VERSION_MICRO,
+        ),
+        version: ffi::swresample_version,
+        configuration: ffi::swresample_configuration,
+    },
+];
+
+/// The configuration FFmpeg was built with, `avutil`'s one stands for the
+/// whole build.
+fn ffmpeg_configuration() -> String {
+    unsafe { cstr_or(ffi::avutil_configuration(), "").into_owned() }
+}
+
+fn program_info(indent: &str) -> String {
+    format!(
+        "ffcli version {}\n{}configuration: {}\n",
+        env!("CARGO_PKG_VERSION"),
+        indent,
+        ffmpeg_configuration()
+    )
+}
+
+/// The configuration of the libraries which differ from the one of the
+/// build.
+fn libs_configuration(indent: &str) -> String {
+    let configuration = ffmpeg_configuration();
+    let mut out = String::new();
+    for lib in LIBS {
+        let lib_configuration = unsafe { cstr_or((lib.configuration)(), "").into_owned() };
+        if lib_configuration == configuration {
+            continue;
+        }
+        if out.is_empty() {
+            let _ = writeln!(out, "{}WARNING: library configuration mismatch", indent);
+        }
+        let _ = writeln!(
+            out,
+            "{}{:<11} configuration: {}",
+            indent, lib.name, lib_configuration
+        );
+    }
+    out
+}
+
+/// The compile-time and runtime version of each library, with a warning for
+/// the ones whose major version differs.
+fn libs_version(indent: &str) -> String {
+    let mut out = String::new();
+    let mut mismatches = vec![];
+    for lib in LIBS {
+        let version = unsafe { (lib.version)() };
+        let runtime = (version >> 16, (version >> 8) & 0xff, version & 0xff);
+        let (major, minor, micro) = lib.compiled;
+        let _ = writeln!(
+            out,
+            "{}lib{:<11} {:>2}.{:>3}.{:>3} / {:>2}.{:>3}.{:>3}",
+            indent, lib.name, major, minor, micro, runtime.0, runtime.1, runtime.2
+        );
+        if runtime.0 != major {
+            mismatches.push(lib.name);
+        }
+    }
+    for name in mismatches {
+        let _ = writeln!(
+            out,
+            "{}WARNING: lib{} is not the version ffcli was built against",
+            indent, name
+        );
+    }
+    out
+}
+
+/// The banner shown at startup, unless `-hide_banner` or `-version` is given.
+pub fn show_banner(session: &Session, args: &[OsString], options: &[OptionDef]) {
+    if session.hide_banner != 0 || locate_option(args, options, "version").is_some() {
+        return;
+    }
+    let banner = program_info("  ") + &libs_configuration("  ") + &libs_version("  ");
+    for line in banner.lines() {
+        info!("{}", line);
+    }
+}
+
+/// The report of `-version`.
+pub fn version_info() -> String {
+    program_info("") + &libs_version("")
+}
+
+/// Split a configure command line into its flags, keeping the arguments of
+/// `pkg-config` with it.
+fn split_configuration(configuration: &str) -> Vec<String> {
+    let mut flags: Vec<String> = vec![];
+    for flag in configuration.split(" --") {
+        match flags.last_mut() {
+            Some(last) if last.ends_with("pkg-config") => {
+                last.push_str(" --");
+                last.push_str(flag);
+            }
+            Some(_) => flags.push(format!("--{}", flag)),
+            None if !flag.is_empty() => flags.push(flag.to_owned()),
+            None => {}
+        }
+    }
+    flags
+}
+
+/// The report of `-buildconf`, one configure flag per line.
+pub fn buildconf_info() -> String {
+    let mut out = String::from("\n  configuration:\n");
+    for flag in split_configuration(&ffmpeg_configuration()) {
+        let _ = writeln!(out, "    {}", flag);
+    }
+    out
+}
+
+/// The report of `-L`.
+pub fn license_info() -> &'static str {
+    concat!(
+        "ffcli is free software; you can redistribute it and/or\n",
+        "modify it under the terms of the GNU Lesser General Public\n",
+        "License as published by the Free Software Foundation; either\n",
+        "version 2.1 of the License, or (at your option) any later version.\n",
+        "\n",
+        "ffcli is distributed in the hope that it will be useful,\n",
+        "but WITHOUT ANY WARRANTY; without even the implied warranty of\n",
+        "MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU\n",
+        "Lesser General Public License for more details.\n",
+        "\n",
+        "You should have received a copy of the GNU Lesser General Public\n",
+        "License along with ffcli; if not, write to the Free Software\n",
+        "Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA\n",
+    )
+}
+
+/// Whether a listed name passes the substring filter given to a listing
+/// option, e.g. `-encoders 264`. An empty filter keeps everything.
+fn listing_matches(name: &str, filter: &str) -> bool {
+    name.contains(filter)
+}
+
+unsafe fn cstr_or<'a>(s: *const libc::c_char, default: &'a str) -> std::borrow::Cow<'a, str> {
+    if s.is_null() {
+        default.into()
+    } else {
+        CStr::from_ptr(s).to_string_lossy()
+    }
+}
+
+fn media_type_char(media_type: ffi::AVMediaType) -> char {
+    match media_type {
+        ffi::AVMediaType_AVMEDIA_TYPE_VIDEO => 'V',
+        ffi::AVMediaType_AVMEDIA_TYPE_AUDIO => 'A',
+        ffi::AVMediaType_AVMEDIA_TYPE_DATA => 'D',
+        ffi::AVMediaType_AVMEDIA_TYPE_SUBTITLE => 'S',
+        ffi::AVMediaType_AVMEDIA_TYPE_ATTACHMENT => 'T',
+        _ => '?',
+    }
+}
+
+unsafe fn is_device(class: *const ffi::AVClass) -> bool {
+    match class.as_ref() {
+        Some(class) => matches!(
+            class.category,
+            ffi::AVClassCategory_AV_CLASS_CATEGORY_DEVICE_VIDEO_OUTPUT
+                | ffi::AVClassCategory_AV_CLASS_CATEGORY_DEVICE_VIDEO_INPUT
+                | ffi::AVClassCategory_AV_CLASS_CATEGORY_DEVICE_AUDIO_OUTPUT
+                | ffi::AVClassCategory_AV_CLASS_CATEGORY_DEVICE_AUDIO_INPUT
+                | ffi::AVClassCategory_AV_CLASS_CATEGORY_DEVICE_OUTPUT
+                | ffi::AVClassCategory_AV_CLASS_CATEGORY_DEVICE_INPUT
+        ),
+        None => false,
+    }
+}
+
+/// Which side of the formats `-formats`, `-muxers` and `-demuxers` list.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ShowMuxDemuxers {
+    Default,
+    Demuxers,
+    Muxers,
+}
+
+/// Demuxer and muxer of each format name, sorted by name.
+type FormatMap = BTreeMap<
+    String,
+    (
+        Option<&'static ffi::AVInputFormat>,
+        Option<&'static ffi::AVOutputFormat>,
+    ),
+>;
+
+unsafe fn formats_sorted(device_only: bool, muxdemuxers: ShowMuxDemuxers) -> FormatMap {
+    let mut formats = FormatMap::new();
+    if muxdemuxers != ShowMuxDemuxers::Demuxers {
+        let mut opaque = ptr::null_mut();
+        while let Some(ofmt) = ffi::av_muxer_iterate(&mut opaque).as_ref() {
+            if device_only && !is_device(ofmt.priv_class) {
+                continue;
+            }
+            let name = CStr::from_ptr(ofmt.name).to_string_lossy().into_owned();
+            formats.entry(name).or_default().1 = Some(ofmt);
+        }
+    }
+    if muxdemuxers != ShowMuxDemuxers::Muxers {
+        let mut opaque = ptr::null_mut();
+        while let Some(ifmt) = ffi::av_demuxer_iterate(&mut opaque).as_ref() {
+            if device_only && !is_device(ifmt.priv_class) {
+                continue;
+            }
+            let name = CStr::from_ptr(ifmt.name).to_string_lossy().into_owned();
+            formats.entry(name).or_default().0 = Some(ifmt);
+        }
+    }
+    formats
+}
+
+/// The listing of `-formats`, `-muxers`, `-demuxers` and `-devices`, sorted
+/// by name.
+fn formats_listing(device_only: bool, muxdemuxers: ShowMuxDemuxers, filter: &str) -> String {
+    let mut out = format!(
+        "{}\n D. = Demuxing supported\n .E = Muxing supported\n --\n",
+        if device_only {
+            "Devices:"
+        } else {
+            "File formats:"
+        }
+    );
+    unsafe {
+        for (name, (ifmt, ofmt)) in formats_sorted(device_only, muxdemuxers) {
+            if !listing_matches(&name, filter) {
+                continue;
+            }
+            // the muxer's long name wins, like in the C listing
+            let long_name = match (ifmt, ofmt) {
+                (_, Some(ofmt)) => cstr_or(ofmt.long_name, " "),
+                (Some(ifmt), None) => cstr_or(ifmt.long_name, " "),
+                (None, None) => " ".into(),
+            };
+            let _ = writeln!(
+                out,
+                " {}{} {:<15} {}",
+                if ifmt.is_some() { "D" } else { " " },
+                if ofmt.is_some() { "E" } else { " " },
+                name,
+                long_name
+            );
+        }
+    }
+    out
+}
+
+/// Every codec descriptor, sorted by media type then name.
+unsafe fn codecs_sorted() -> Vec<&'static ffi::AVCodecDescriptor> {
+    let mut codecs = vec![];
+    let mut desc = ptr::null();
+    loop {
+        desc = ffi::avcodec_descriptor_next(desc);
+        match desc.as_ref() {
+            Some(codec) => codecs.push(codec),
+            None => break,
+        }
+    }
+    codecs.sort_by(|a, b| {
+        a.type_
+            .cmp(&b.type_)
+            .then_with(|| CStr::from_ptr(a.name).cmp(CStr::from_ptr(b.name)))
+    });
+    codecs
+}
+
+/// The decoders or encoders implementing `id`.
+unsafe fn codecs_for_id(id: ffi::AVCodecID, encoder: bool) -> Vec<&'static ffi::AVCodec> {
+    let mut codecs = vec![];
+    let mut opaque = ptr::null_mut();
+    while let Some(codec) = ffi::av_codec_iterate(&mut opaque).as_ref() {
+        let matched = if encoder {
+            ffi::av_codec_is_encoder(codec) != 0
+        } else {
+            ffi::av_codec_is_decoder(codec) != 0
+        };
+        if codec.id == id && matched {
+            codecs.push(codec);
+        }
+    }
+    codecs
+}
+
+/// The listing of `-codecs`.
+fn codecs_listing(filter: &str) -> String {
+    let mut out = String::from(concat!(
+        "Codecs:\n",
+        " D..... = Decoding supported\n",
+        " .E.... = Encoding supported\n",
+        " ..V... = Video codec\n",
+        " ..A... = Audio codec\n",
+        " ..S... = Subtitle codec\n",
+        " ...I.. = Intra frame-only codec\n",
+        " ....L. = Lossy compression\n",
+        " .....S = Lossless compression\n",
+        " -------\n",
+    ));
+    unsafe {
+        for desc in codecs_sorted() {
+            let name = CStr::from_ptr(desc.name).to_string_lossy();
+            if name.contains("_deprecated") || !listing_matches(&name, filter) {
+                continue;
+            }
+            let _ = write!(
+                out,
+                " {}{}{}{}{}{} {:<20} {}",
+                if ffi::avcodec_find_decoder(desc.id).is_null() {
+                    '.'
+                } else {
+                    'D'
+                },
+                if ffi::avcodec_find_encoder(desc.id).is_null() {
+                    '.'
+                } else {
+                    'E'
+                },
+                media_type_char(desc.type_),
+                if desc.props & ffi::AV_CODEC_PROP_INTRA_ONLY as i32 != 0 {
+                    'I'
+                } else {
+                    '.'
+                },
+                if desc.props & ffi::AV_CODEC_PROP_LOSSY as i32 != 0 {
+                    'L'
+                } else {
+                    '.'
+                },
+                if desc.props & ffi::AV_CODEC_PROP_LOSSLESS as i32 != 0 {
+                    'S'
+                } else {
+                    '.'
+                },
+                name,
+                cstr_or(desc.long_name, "")
+            );
+
+            // print decoders/encoders when there's more than one or their
+            // names are different from codec name
+            for &encoder in &[false, true] {
+                let codecs = codecs_for_id(desc.id, encoder);
+                if codecs
+                    .iter()
+                    .any(|codec| CStr::from_ptr(codec.name) != CStr::from_ptr(desc.name))
+                {
+                    let _ = write!(out, " ({}: ", if encoder { "encoders" } else { "decoders" });
+                    for codec in codecs {
+                        let _ = write!(out, "{} ", CStr::from_ptr(codec.name).to_string_lossy());
+                    }
+                    out.push(')');
+                }
+            }
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// The listing of `-decoders` or `-encoders`.
+fn codec_implementations_listing(encoder: bool, filter: &str) -> String {
+    let mut out = format!(
+        concat!(
+            "{}:\n",
+            " V..... = Video\n",
+            " A..... = Audio\n",
+            " S..... = Subtitle\n",
+            " .F.... = Frame-level multithreading\n",
+            " ..S... = Slice-level multithreading\n",
+            " ...X.. = Codec is experimental\n",
+            " ....B. = Supports draw_horiz_band\n",
+            " .....D = Supports direct rendering method 1\n",
+            " ------\n",
+        ),
+        if encoder { "Encoders" } else { "Decoders" }
+    );
+    unsafe {
+        for desc in codecs_sorted() {
+            for codec in codecs_for_id(desc.id, encoder) {
+                let name = CStr::from_ptr(codec.name).to_string_lossy();
+                if !listing_matches(&name, filter) {
+                    continue;
+                }
+                let capability = |flag: u32, c: char| {
+                    if codec.capabilities & flag as i32 != 0 {
+                        c
+                    } else {
+                        '.'
+                    }
+                };
+                let _ = write!(
+                    out,
+                    " {}{}{}{}{}{} {:<20} {}",
+                    media_type_char(desc.type_),
+                    capability(ffi::AV_CODEC_CAP_FRAME_THREADS, 'F'),
+                    capability(ffi::AV_CODEC_CAP_SLICE_THREADS, 'S'),
+                    capability(ffi::AV_CODEC_CAP_EXPERIMENTAL, 'X'),
+                    capability(ffi::AV_CODEC_CAP_DRAW_HORIZ_BAND, 'B'),
+                    capability(ffi::AV_CODEC_CAP_DR1, 'D'),
+                    name,
+                    cstr_or(codec.long_name, "")
+                );
+                if CStr::from_ptr(codec.name) != CStr::from_ptr(desc.name) {
+                    let _ = write!(
+                        out,
+                        " (codec {})",
+                        CStr::from_ptr(desc.name).to_string_lossy()
+                    );
+                }
+                out.push('\n');
+            }
+        }
+    }
+    out
+}
+
+/// The listing of `-bsfs`.
+fn bsfs_listing(filter: &str) -> String {
+    let mut out = String::from("Bitstream filters:\n");
+    let mut opaque = ptr::null_mut();
+    unsafe {
+        while let Some(bsf) = ffi::av_bsf_iterate(&mut opaque).as_ref() {
+            let name = CStr::from_ptr(bsf.name).to_string_lossy();
+            if listing_matches(&name, filter) {
+                let _ = writeln!(out, "{}", name);
+            }
+        }
+    }
+    out.push('\n');
+    out
+}
+
+/// The listing of `-protocols`.
+fn protocols_listing(filter: &str) -> String {
+    let mut out = String::from("Supported file protocols:\n");
+    for &(output, title) in &[(0, "Input:"), (1, "Output:")] {
+        let _ = writeln!(out, "{}", title);
+        let mut opaque = ptr::null_mut();
+        loop {
+            let name = unsafe { ffi::avio_enum_protocols(&mut opaque, output) };
+            if name.is_null() {
+                break;
+            }
+            let name = unsafe { CStr::from_ptr(name) }.to_string_lossy();
+            if listing_matches(&name, filter) {
+                let _ = writeln!(out, "  {}", name);
+            }
+        }
+    }
+    out
+}
+
+/// The pads of a filter like `VV->V`, `|` for sources and sinks and `N` for
+/// dynamic pads.
+unsafe fn filter_pads_description(filter: &ffi::AVFilter) -> String {
+    let mut descr = String::new();
+    for &output in &[false, true] {
+        if output {
+            descr.push_str("->");
+        }
+        let pads = if output {
+            filter.outputs
+        } else {
+            filter.inputs
+        };
+        let mut nb_pads = 0;
+        while !pads.is_null() && !ffi::avfilter_pad_get_name(pads, nb_pads).is_null() {
+            if descr.len() >= 60 {
+                break;
+            }
+            descr.push(media_type_char(ffi::avfilter_pad_get_type(pads, nb_pads)));
+            nb_pads += 1;
+        }
+        if nb_pads == 0 {
+            let dynamic = if output {
+                ffi::AVFILTER_FLAG_DYNAMIC_OUTPUTS
+            } else {
+                ffi::AVFILTER_FLAG_DYNAMIC_INPUTS
+            };
+            descr.push(if filter.flags & dynamic as i32 != 0 {
+                'N'
+            } else {
+                '|'
+            });
+        }
+    }
+    descr
+}
+
+/// The listing of `-filters`.
+fn filters_listing(filter_name: &str) -> String {
+    let mut out = String::from(concat!(
+        "Filters:\n",
+        "  T.. = Timeline support\n",
+        "  .S. = Slice threading\n",
+        "  ..C = Command support\n",
+        "  A = Audio input/output\n",
+        "  V = Video input/output\n",
+        "  N = Dynamic number and/or type of input/output\n",
+        "  | = Source or sink filter\n",
+    ));
+    let mut opaque = ptr::null_mut();
+    unsafe {
+        while let Some(filter) = ffi::av_filter_iterate(&mut opaque).as_ref() {
+            let name = CStr::from_ptr(filter.name).to_string_lossy();
+            if !listing_matches(&name, filter_name) {
+                continue;
+            }
+            let _ = writeln!(
+                out,
+                " {}{}{} {:<17} {:<10} {}",
+                if filter.flags & ffi::AVFILTER_FLAG_SUPPORT_TIMELINE as i32 != 0 {
+                    'T'
+                } else {
+                    '.'
+                },
+                if filter.flags & ffi::AVFILTER_FLAG_SLICE_THREADS as i32 != 0 {
+                    'S'
+                } else {
+                    '.'
+                },
+                if filter.process_command.is_some() {
+                    'C'
+                } else {
+                    '.'
+                },
+                name,
+                filter_pads_description(filter),
+                cstr_or(filter.description, "")
+            );
+        }
+    }
+    out
+}
+
+/// The listing of `-pix_fmts`.
+fn pix_fmts_listing(filter: &str) -> String {
+    let mut out = String::from(concat!(
+        "Pixel formats:\n",
+        "I.... = Supported Input  format for conversion\n",
+        ".O... = Supported Output format for conversion\n",
+        "..H.. = Hardware accelerated format\n",
+        "...P. = Paletted format\n",
+        "....B = Bitstream format\n",
+        "FLAGS NAME            NB_COMPONENTS BITS_PER_PIXEL\n",
+        "-----\n",
+    ));
+    let mut pix_desc = ptr::null();
+    unsafe {
+        loop {
+            pix_desc = ffi::av_pix_fmt_desc_next(pix_desc);
+            let desc = match pix_desc.as_ref() {
+                Some(desc) => desc,
+                None => break,
+            };
+            let name = CStr::from_ptr(desc.name).to_string_lossy();
+            if !listing_matches(&name, filter) {
+                continue;
+            }
+            let pix_fmt = ffi::av_pix_fmt_desc_get_id(desc);
+            let flag = |flag: u32, c: char| {
+                if desc.flags & flag as u64 != 0 {
+                    c
+                } else {
+                    '.'
+                }
+            };
+            let _ = writeln!(
+                out,
+                "{}{}{}{}{} {:<16}       {}            {:>2}",
+                if ffi::sws_isSupportedInput(pix_fmt) != 0 {
+                    'I'
+                } else {
+                    '.'
+                },
+                if ffi::sws_isSupportedOutput(pix_fmt) != 0 {
+                    'O'
+                } else {
+                    '.'
+                },
+                flag(ffi::AV_PIX_FMT_FLAG_HWACCEL, 'H'),
+                flag(ffi::AV_PIX_FMT_FLAG_PAL, 'P'),
+                flag(ffi::AV_PIX_FMT_FLAG_BITSTREAM, 'B'),
+                name,
+                desc.nb_components,
+                ffi::av_get_bits_per_pixel(desc)
+            );
+        }
+    }
+    out
+}
+
+/// The listing of `-layouts`.
+fn layouts_listing(filter: &str) -> String {
+    let mut out = String::from("Individual channels:\nNAME           DESCRIPTION\n");
+    unsafe {
+        for i in 0..63 {
+            let name = ffi::av_get_channel_name(1 << i);
+            if name.is_null() {
+                continue;
+            }
+            let name = CStr::from_ptr(name).to_string_lossy();
+            if !listing_matches(&name, filter) {
+                continue;
+            }
+            let descr = cstr_or(ffi::av_get_channel_description(1 << i), "");
+            let _ = writeln!(out, "{:<14} {}", name, descr);
+        }
+
+        out.push_str("\nStandard channel layouts:\nNAME           DECOMPOSITION\n");
+        let mut i = 0;
+        loop {
+            let mut layout = 0;
+            let mut name = ptr::null();
+            if ffi::av_get_standard_channel_layout(i, &mut layout, &mut name) != 0 {
+                break;
+            }
+            i += 1;
+            if name.is_null() {
+                continue;
+            }
+            let name = CStr::from_ptr(name).to_string_lossy();
+            if !listing_matches(&name, filter) {
+                continue;
+            }
+            let channels = (0..64)
+                .map(|bit| 1u64 << bit)
+                .filter(|channel| layout & channel != 0)
+                .map(|channel| cstr_or(ffi::av_get_channel_name(channel), "").into_owned())
+                .collect::<Vec<_>>();
+            let _ = writeln!(out, "{:<14} {}", name, channels.join("+"));
+        }
+    }
+    out
+}
+
+/// The listing of `-sample_fmts`.
+fn sample_fmts_listing(filter: &str) -> String {
+    let mut out = String::new();
+    let mut fmt_str = [0 as libc::c_char; 128];
+    for i in -1..ffi::AVSampleFormat_AV_SAMPLE_FMT_NB {
+        let line = unsafe {
+            ffi::av_get_sample_fmt_string(fmt_str.as_mut_ptr(), fmt_str.len() as i32, i);
+            CStr::from_ptr(fmt_str.as_ptr()).to_string_lossy()
+        };
+        // the first line is the header
+        let name = line.split_whitespace().next().unwrap_or("");
+        if i < 0 || listing_matches(name, filter) {
+            let _ = writeln!(out, "{}", line);
+        }
+    }
+    out
+}
+
+/// The listing of `-colors`.
+fn colors_listing(filter: &str) -> String {
+    let mut out = format!("{:<32} #RRGGBB\n", "name");
+    let mut i = 0;
+    loop {
+        let mut rgb = ptr::null();
+        let name = unsafe { ffi::av_get_known_color_name(i, &mut rgb) };
+        if name.is_null() {
+            break;
+        }
+        i += 1;
+        let name = unsafe { CStr::from_ptr(name) }.to_string_lossy();
+        if !listing_matches(&name, filter) {
+            continue;
+        }
+        let rgb = unsafe { slice::from_raw_parts(rgb, 3) };
+        let _ = writeln!(
+            out,
+            "{:<32} #{:02x}{:02x}{:02x}",
+            name, rgb[0], rgb[1], rgb[2]
+        );
+    }
+    out
+}
+
+/// The listing of `-hwaccels`.
+fn hwaccels_listing(filter: &str) -> String {
+    let mut out = String::from("Hardware acceleration methods:\n");
+    let mut device_type = ffi::AVHWDeviceType_AV_HWDEVICE_TYPE_NONE;
+    loop {
+        device_type = unsafe { ffi::av_hwdevice_iterate_types(device_type) };
+        if device_type == ffi::AVHWDeviceType_AV_HWDEVICE_TYPE_NONE {
+            break;
+        }
+        let name = unsafe { CStr::from_ptr(ffi::av_hwdevice_get_type_name(device_type)) }
+            .to_string_lossy();
+        if listing_matches(&name, filter) {
+            let _ = writeln!(out, "{}", name);
+        }
+    }
+    out.push('\n');
+    out
+}
+
+/// Output of the listing options, set by `-print_format`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PrintFormat {
+    Default,
+    Json,
+}
+
+impl FromStr for PrintFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(PrintFormat::Default),
+            "json" => Ok(PrintFormat::Json),
+            _ => Err(format!(
+                "Unknown print format '{}', expected 'default' or 'json'.",
+                s
+            )),
+        }
+    }
+}
+
+/// The listing options, e.g. `-encoders`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Listing {
+    Formats,
+    Muxers,
+    Demuxers,
+    Devices,
+    Codecs,
+    Decoders,
+    Encoders,
+    Bsfs,
+    Protocols,
+    Filters,
+    PixFmts,
+    Layouts,
+    SampleFmts,
+    Colors,
+    HWAccels,
+}
+
+/// The output of a listing option, keeping the entries whose name contains
+/// `filter`.
+pub fn listing(kind: Listing, filter: &str, print_format: PrintFormat) -> String {
+    use ShowMuxDemuxers::{Default, Demuxers, Muxers};
+    if print_format == PrintFormat::Default {
+        return match kind {
+            Listing::Formats => formats_listing(false, Default, filter),
+            Listing::Muxers => formats_listing(false, Muxers, filter),
+            Listing::Demuxers => formats_listing(false, Demuxers, filter),
+            Listing::Devices => formats_listing(true, Default, filter),
+            Listing::Codecs => codecs_listing(filter),
+            Listing::Decoders => codec_implementations_listing(false, filter),
+            Listing::Encoders => codec_implementations_listing(true, filter),
+            Listing::Bsfs => bsfs_listing(filter),
+            Listing::Protocols => protocols_listing(filter),
+            Listing::Filters => filters_listing(filter),
+            Listing::PixFmts => pix_fmts_listing(filter),
+            Listing::Layouts => layouts_listing(filter),
+            Listing::SampleFmts => sample_fmts_listing(filter),
+            Listing::Colors => colors_listing(filter),
+            Listing::HWAccels => hwaccels_listing(filter),
+        };
+    }
+    let (key, value) = listing_json(kind, filter);
+    format!("{}\n", Json::object().with(key, value))
+}
+
+/// The key and the entries of a listing option in JSON.
+fn listing_json(kind: Listing, filter: &str) -> (&'static str, Json) {
+    use ShowMuxDemuxers::{Default, Demuxers, Muxers};
+    unsafe {
+        match kind {
+            Listing::Formats => ("formats", formats_json(false, Default, filter)),
+            Listing::Muxers => ("muxers", formats_json(false, Muxers, filter)),
+            Listing::Demuxers => ("demuxers", formats_json(false, Demuxers, filter)),
+            Listing::Devices => ("devices", formats_json(true, Default, filter)),
+            Listing::Codecs => ("codecs", codecs_json(filter)),
+            Listing::Decoders => ("decoders", codec_implementations_json(false, filter)),
+            Listing::Encoders => ("encoders", codec_implementations_json(true, filter)),
+            Listing::Bsfs => ("bsfs", bsfs_json(filter)),
+            Listing::Protocols => ("protocols", protocols_json(filter)),
+            Listing::Filters => ("filters", filters_json(filter)),
+            Listing::PixFmts => ("pix_fmts", pix_fmts_json(filter)),
+            Listing::Layouts => ("layouts", layouts_json(filter)),
+            Listing::SampleFmts => ("sample_fmts", sample_fmts_json(filter)),
+            Listing::Colors => ("colors", colors_json(filter)),
+            Listing::HWAccels => ("hwaccels", hwaccels_json(filter)),
+        }
+    }
+}
+
+/// The names of the entries of a listing option, e.g. every encoder for
+/// `Listing::Encoders`, sorted.
+pub fn listing_names(kind: Listing) -> Vec<String> {
+    fn collect(json: &Json, names: &mut Vec<String>) {
+        match json {
+            Json::String(name) => names.push(name.clone()),
+            Json::Array(values) => values.iter().for_each(|value| collect(value, names)),
+            Json::Object(members) => match members.iter().find(|(key, _)| key == "name") {
+                Some((_, name)) => collect(name, names),
+                None => members.iter().for_each(|(_, value)| collect(value, names)),
+            },
+            _ => {}
+        }
+    }
+    let mut names = vec![];
+    collect(&listing_json(kind, "").1, &mut names);
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Names of the `flags` set in `value`.
+fn flag_names(value: u64, flags: &[(u64, &str)]) -> Json {
+    flags
+        .iter()
+        .filter(|(flag, _)| value & flag != 0)
+        .map(|&(_, name)| name)
+        .collect::<Vec<_>>()
+        .into()
+}
+
+/// Collect a list terminated by `end`, like `AVCodec::pix_fmts`.
+unsafe fn terminated<T: Copy + PartialEq>(list: *const T, end: T) -> Vec<T> {
+    terminated_by(list, |value| *value == end)
+}
+
+/// Collect a list up to the first value that `is_end`.
+unsafe fn terminated_by<T: Copy>(mut list: *const T, is_end: impl Fn(&T) -> bool) -> Vec<T> {
+    let mut values = vec![];
+    while !list.is_null() && !is_end(&*list) {
+        values.push(*list);
+        list = list.offset(1);
+    }
+    values
+}
+
+fn media_type_name(media_type: ffi::AVMediaType) -> String {
+    unsafe { cstr_or(ffi::av_get_media_type_string(media_type), "unknown").into_owned() }
+}
+
+pub fn option_type_name(option_type: ffi::AVOptionType) -> &'static str {
+    match option_type {
+        ffi::AVOptionType_AV_OPT_TYPE_FLAGS => "flags",
+        ffi::AVOptionType_AV_OPT_TYPE_INT => "int",
+        ffi::AVOptionType_AV_OPT_TYPE_INT64 => "int64",
+        ffi::AVOptionType_AV_OPT_TYPE_UINT64 => "uint64",
+        ffi::AVOptionType_AV_OPT_TYPE_DOUBLE => "double",
+        ffi::AVOptionType_AV_OPT_TYPE_FLOAT => "float",
+        ffi::AVOptionType_AV_OPT_TYPE_STRING => "string",
+        ffi::AVOptionType_AV_OPT_TYPE_RATIONAL => "rational",
+        ffi::AVOptionType_AV_OPT_TYPE_BINARY => "binary",
+        ffi::AVOptionType_AV_OPT_TYPE_DICT => "dictionary",
+        ffi::AVOptionType_AV_OPT_TYPE_IMAGE_SIZE => "image_size",
+        ffi::AVOptionType_AV_OPT_TYPE_PIXEL_FMT => "pix_fmt",
+        ffi::AVOptionType_AV_OPT_TYPE_SAMPLE_FMT => "sample_fmt",
+        ffi::AVOptionType_AV_OPT_TYPE_VIDEO_RATE => "video_rate",
+        ffi::AVOptionType_AV_OPT_TYPE_DURATION => "duration",
+        ffi::AVOptionType_AV_OPT_TYPE_COLOR => "color",
+        ffi::AVOptionType_AV_OPT_TYPE_CHANNEL_LAYOUT => "channel_layout",
+        ffi::AVOptionType_AV_OPT_TYPE_BOOL => "bool",
+        _ => "unknown",
+    }
+}
+
+/// Every AVOption of a class, named constants included.
+pub unsafe fn class_options(class: *const ffi::AVClass) -> Vec<&'static ffi::AVOption> {
+    let mut options = vec![];
+    if class.is_null() {
+        return options;
+    }
+    // av_opt_next() takes an object starting with its AVClass
+    let obj = &class as *const *const ffi::AVClass as *const c_void;
+    let mut opt = ptr::null();
+    loop {
+        opt = ffi::av_opt_next(obj, opt);
+        match opt.as_ref() {
+            Some(o) => options.push(o),
+            None => break,
+        }
+    }
+    options
+}
+
+unsafe fn channel_layout_name(layout: u64) -> String {
+    let mut name = [0 as libc::c_char; 128];
+    ffi::av_get_channel_layout_string(name.as_mut_ptr(), name.len() as i32, 0, layout);
+    CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned()
+}
+
+/// The private AVOptions of a component, with the named constants of each
+/// option.
+unsafe fn class_options_json(class: *const ffi::AVClass) -> Json {
+    let all = class_options(class);
+    let mut options = vec![];
+    for o in all
+        .iter()
+        .filter(|o| o.type_ != ffi::AVOptionType_AV_OPT_TYPE_CONST)
+    {
+        let mut option = Json::object()
+            .with(
+                "name",
+                CStr::from_ptr(o.name).to_string_lossy().into_owned(),
+            )
+            .with("type", option_type_name(o.type_))
+            .with("help", cstr_or(o.help, "").into_owned());
+        match o.type_ {
+            ffi::AVOptionType_AV_OPT_TYPE_FLAGS
+            | ffi::AVOptionType_AV_OPT_TYPE_INT
+            | ffi::AVOptionType_AV_OPT_TYPE_INT64
+            | ffi::AVOptionType_AV_OPT_TYPE_UINT64
+            | ffi::AVOptionType_AV_OPT_TYPE_DURATION
+            | ffi::AVOptionType_AV_OPT_TYPE_BOOL => {
+                option = option
+                    .with("min", o.min)
+                    .with("max", o.max)
+                    .with("default", o.default_val.i64);
+            }
+            ffi::AVOptionType_AV_OPT_TYPE_DOUBLE
+            | ffi::AVOptionType_AV_OPT_TYPE_FLOAT
+            | ffi::AVOptionType_AV_OPT_TYPE_RATIONAL => {
+                option = option
+                    .with("min", o.min)
+                    .with("max", o.max)
+                    .with("default", o.default_val.dbl);
+            }
+            ffi::AVOptionType_AV_OPT_TYPE_STRING
+            | ffi::AVOptionType_AV_OPT_TYPE_IMAGE_SIZE
+            | ffi::AVOptionType_AV_OPT_TYPE_VIDEO_RATE
+            | ffi::AVOptionType_AV_OPT_TYPE_COLOR => {
+                let default = o.default_val.str;
+                option = option.with(
+                    "default",
+                    (!default.is_null()).then(|| cstr_or(default, "").into_owned()),
+                );
+            }
+            _ => {}
+        }
+        if !o.unit.is_null() {
+            let unit = CStr::from_ptr(o.unit);
+            let values = all
+                .iter()
+                .filter(|c| {
+                    c.type_ == ffi::AVOptionType_AV_OPT_TYPE_CONST
+                        && !c.unit.is_null()
+                        && CStr::from_ptr(c.unit) == unit
+                })
+                .map(|c| {
+                    Json::object()
+                        .with(
+                            "name",
+                            CStr::from_ptr(c.name).to_string_lossy().into_owned(),
+                        )
+                        .with("value", c.default_val.i64)
+                        .with("help", cstr_or(c.help, "").into_owned())
+                })
+                .collect::<Vec<_>>();
+            if !values.is_empty() {
+                option = option.with("values", values);
+            }
+        }
+        options.push(option);
+    }
+    options.into()
+}
+
+unsafe fn formats_json(device_only: bool, muxdemuxers: ShowMuxDemuxers, filter: &str) -> Json {
+    let mut formats = vec![];
+    for (name, (ifmt, ofmt)) in formats_sorted(device_only, muxdemuxers) {
+        if !listing_matches(&name, filter) {
+            continue;
+        }
+        let demuxer = ifmt.map(|ifmt| {
+            Json::object()
+                .with("long_name", cstr_or(ifmt.long_name, "").into_owned())
+                .with("extensions", cstr_or(ifmt.extensions, "").into_owned())
+                .with("mime_type", cstr_or(ifmt.mime_type, "").into_owned())
+                .with("options", class_options_json(ifmt.priv_class))
+        });
+        let muxer = ofmt.map(|ofmt| {
+            let codec_name = |id| cstr_or(ffi::avcodec_get_name(id), "").into_owned();
+            Json::object()
+                .with("long_name", cstr_or(ofmt.long_name, "").into_owned())
+                .with("extensions", cstr_or(ofmt.extensions, "").into_owned())
+                .with("mime_type", cstr_or(ofmt.mime_type, "").into_owned())
+                .with("audio_codec", codec_name(ofmt.audio_codec))
+                .with("video_codec", codec_name(ofmt.video_codec))
+                .with("subtitle_codec", codec_name(ofmt.subtitle_codec))
+                .with("options", class_options_json(ofmt.priv_class))
+        });
+        formats.push(
+            Json::object()
+                .with("name", name)
+                .with("demuxer", demuxer)
+                .with("muxer", muxer),
+        );
+    }
+    formats.into()
+}
+
+unsafe fn codecs_json(filter: &str) -> Json {
+    const PROPS: &[(u64, &str)] = &[
+        (ffi::AV_CODEC_PROP_INTRA_ONLY as u64, "intra_only"),
+        (ffi::AV_CODEC_PROP_LOSSY as u64, "lossy"),
+        (ffi::AV_CODEC_PROP_LOSSLESS as u64, "lossless"),
+        (ffi::AV_CODEC_PROP_REORDER as u64, "reorder"),
+        (ffi::AV_CODEC_PROP_BITMAP_SUB as u64, "bitmap_sub"),
+        (ffi::AV_CODEC_PROP_TEXT_SUB as u64, "text_sub"),
+    ];
+    let mut codecs = vec![];
+    for desc in codecs_sorted() {
+        let name = CStr::from_ptr(desc.name).to_string_lossy().into_owned();
+        if name.contains("_deprecated") || !listing_matches(&name, filter) {
+            continue;
+        }
+        let implementations = |encoder| {
+            codecs_for_id(desc.id, encoder)
+                .iter()
+                .map(|codec| CStr::from_ptr(codec.name).to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+        };
+        codecs.push(
+            Json::object()
+                .with("name", name)
+                .with("long_name", cstr_or(desc.long_name, "").into_owned())
+                .with("type", media_type_name(desc.type_))
+                .with("props", flag_names(desc.props as u64, PROPS))
+                .with("decoders", implementations(false))
+                .with("encoders", implementations(true)),
+        );
+    }
+    codecs.into()
+}
+
+unsafe fn codec_implementations_json(encoder: bool, filter: &str) -> Json {
+    const CAPABILITIES: &[(u64, &str)] = &[
+        (ffi::AV_CODEC_CAP_DRAW_HORIZ_BAND as u64, "draw_horiz_band"),
+        (ffi::AV_CODEC_CAP_DR1 as u64, "dr1"),
+        (ffi::AV_CODEC_CAP_DELAY as u64, "delay"),
+        (
+            ffi::AV_CODEC_CAP_SMALL_LAST_FRAME as u64,
+            "small_last_frame",
+        ),
+        (ffi::AV_CODEC_CAP_SUBFRAMES as u64, "subframes"),
+        (ffi::AV_CODEC_CAP_EXPERIMENTAL as u64, "experimental"),
+        (ffi::AV_CODEC_CAP_CHANNEL_CONF as u64, "channel_conf"),
+        (ffi::AV_CODEC_CAP_FRAME_THREADS as u64, "frame_threads"),
+        (ffi::AV_CODEC_CAP_SLICE_THREADS as u64, "slice_threads"),
+        (ffi::AV_CODEC_CAP_PARAM_CHANGE as u64, "param_change"),
+        (ffi::AV_CODEC_CAP_AUTO_THREADS as u64, "auto_threads"),
+        (
+            ffi::AV_CODEC_CAP_VARIABLE_FRAME_SIZE as u64,
+            "variable_frame_size",
+        ),
+        (ffi::AV_CODEC_CAP_AVOID_PROBING as u64, "avoid_probing"),
+        (ffi::AV_CODEC_CAP_HARDWARE as u64, "hardware"),
+        (ffi::AV_CODEC_CAP_HYBRID as u64, "hybrid"),
+    ];
+    let mut codecs = vec![];
+    for desc in codecs_sorted() {
+        for codec in codecs_for_id(desc.id, encoder) {
+            let name = CStr::from_ptr(codec.name).to_string_lossy().into_owned();
+            if !listing_matches(&name, filter) {
+                continue;
+            }
+            let pix_fmts = terminated(codec.pix_fmts, ffi::AVPixelFormat_AV_PIX_FMT_NONE)
+                .into_iter()
+                .map(|pix_fmt| cstr_or(ffi::av_get_pix_fmt_name(pix_fmt), "").into_owned())
+                .collect::<Vec<_>>();
+            let sample_fmts = terminated(codec.sample_fmts, ffi::AVSampleFormat_AV_SAMPLE_FMT_NONE)
+                .into_iter()
+                .map(|fmt| cstr_or(ffi::av_get_sample_fmt_name(fmt), "").into_owned())
+                .collect::<Vec<_>>();
+            let channel_layouts = terminated(codec.channel_layouts, 0)
+                .into_iter()
+                .map(|layout| channel_layout_name(layout))
+                .collect::<Vec<_>>();
+            let framerates =
+                terminated_by(codec.supported_framerates, |q| q.num == 0 && q.den == 0)
+                    .into_iter()
+                    .map(|q| format!("{}/{}", q.num, q.den))
+                    .collect::<Vec<_>>();
+            codecs.push(
+                Json::object()
+                    .with("name", name)
+                    .with("long_name", cstr_or(codec.long_name, "").into_owned())
+                    .with("type", media_type_name(codec.type_))
+                    .with(
+                        "codec",
+                        CStr::from_ptr(desc.name).to_string_lossy().into_owned(),
+                    )
+                    .with(
+                        "capabilities",
+                        flag_names(codec.capabilities as u32 as u64, CAPABILITIES),
+                    )
+                    .with("pix_fmts", pix_fmts)
+                    .with("sample_fmts", sample_fmts)
+                    .with("sample_rates", terminated(codec.supported_samplerates, 0))
+                    .with("channel_layouts", channel_layouts)
+                    .with("framerates", framerates)
+                    .with("options", class_options_json(codec.priv_class)),
+            );
+        }
+    }
+    codecs.into()
+}
+
+unsafe fn bsfs_json(filter: &str) -> Json {
+    let mut bsfs = vec![];
+    let mut opaque = ptr::null_mut();
+    while let Some(bsf) = ffi::av_bsf_iterate(&mut opaque).as_ref() {
+        let name = CStr::from_ptr(bsf.name).to_string_lossy().into_owned();
+        if !listing_matches(&name, filter) {
+            continue;
+        }
+        let codecs = terminated(bsf.codec_ids, ffi::AVCodecID_AV_CODEC_ID_NONE)
+            .into_iter()
+            .map(|id| cstr_or(ffi::avcodec_get_name(id), "").into_owned())
+            .collect::<Vec<_>>();
+        bsfs.push(
+            Json::object()
+                .with("name", name)
+                .with("codecs", codecs)
+                .with("options", class_options_json(bsf.priv_class)),
+        );
+    }
+    bsfs.into()
+}
+
+unsafe fn protocols_json(filter: &str) -> Json {
+    let mut protocols = Json::object();
+    for &(output, key) in &[(0, "input"), (1, "output")] {
+        let mut list = vec![];
+        let mut opaque = ptr::null_mut();
+        loop {
+            let name = ffi::avio_enum_protocols(&mut opaque, output);
+            if name.is_null() {
+                break;
+            }
+            let name_s = CStr::from_ptr(name).to_string_lossy().into_owned();
+            if listing_matches(&name_s, filter) {
+                list.push(Json::object().with("name", name_s).with(
+                    "options",
+                    class_options_json(ffi::avio_protocol_get_class(name)),
+                ));
+            }
+        }
+        protocols = protocols.with(key, list);
+    }
+    protocols
+}
+
+/// The pads of a filter, or `"dynamic"`.
+unsafe fn filter_pads_json(pads: *const ffi::AVFilterPad, dynamic: bool) -> Json {
+    let mut list = vec![];
+    let mut i = 0;
+    while !pads.is_null() && !ffi::avfilter_pad_get_name(pads, i).is_null() {
+        list.push(
+            Json::object()
+                .with(
+                    "name",
+                    CStr::from_ptr(ffi::avfilter_pad_get_name(pads, i))
+                        .to_string_lossy()
+                        .into_owned(),
+                )
+                .with("type", media_type_name(ffi::avfilter_pad_get_type(pads, i))),
+        );
+        i += 1;
+    }
+    if list.is_empty() && dynamic {
+        "dynamic".into()
+    } else {
+        list.into()
+    }
+}
+
+unsafe fn filters_json(filter_name: &str) -> Json {
+    const FLAGS: &[(u64, &str)] = &[
+        (ffi::AVFILTER_FLAG_DYNAMIC_INPUTS as u64, "dynamic_inputs"),
+        (ffi::AVFILTER_FLAG_DYNAMIC_OUTPUTS as u64, "dynamic_outputs"),
+        (ffi::AVFILTER_FLAG_SLICE_THREADS as u64, "slice_threads"),
+        (
+            ffi::AVFILTER_FLAG_SUPPORT_TIMELINE_GENERIC as u64,
+            "timeline_generic",
+        ),
+        (
+            ffi::AVFILTER_FLAG_SUPPORT_TIMELINE_INTERNAL as u64,
+            "timeline_internal",
+        ),
+    ];
+    let mut filters = vec![];
+    let mut opaque = ptr::null_mut();
+    while let Some(filter) = ffi::av_filter_iterate(&mut opaque).as_ref() {
+        let name = CStr::from_ptr(filter.name).to_string_lossy().into_owned();
+        if !listing_matches(&name, filter_name) {
+            continue;
+        }
+        let flags = filter.flags as u32 as u64;
+        filters.push(
+            Json::object()
+                .with("name", name)
+                .with("description", cstr_or(filter.description, "").into_owned())
+                .with("flags", flag_names(flags, FLAGS))
+                .with("commands", filter.process_command.is_some())
+                .with(
+                    "inputs",
+                    filter_pads_json(
+                        filter.inputs,
+                        flags & ffi::AVFILTER_FLAG_DYNAMIC_INPUTS as u64 != 0,
+                    ),
+                )
+                .with(
+                    "outputs",
+                    filter_pads_json(
+                        filter.outputs,
+                        flags & ffi::AVFILTER_FLAG_DYNAMIC_OUTPUTS as u64 != 0,
+                    ),
+                )
+                .with("options", class_options_json(filter.priv_class)),
+        );
+    }
+    filters.into()
+}
+
+unsafe fn pix_fmts_json(filter: &str) -> Json {
+    const FLAGS: &[(u64, &str)] = &[
+        (ffi::AV_PIX_FMT_FLAG_BE as u64, "be"),
+        (ffi::AV_PIX_FMT_FLAG_PAL as u64, "pal"),
+        (ffi::AV_PIX_FMT_FLAG_BITSTREAM as u64, "bitstream"),
+        (ffi::AV_PIX_FMT_FLAG_HWACCEL as u64, "hwaccel"),
+        (ffi::AV_PIX_FMT_FLAG_PLANAR as u64, "planar"),
+        (ffi::AV_PIX_FMT_FLAG_RGB as u64, "rgb"),
+        (ffi::AV_PIX_FMT_FLAG_ALPHA as u64, "alpha"),
+        (ffi::AV_PIX_FMT_FLAG_BAYER as u64, "bayer"),
+        (ffi::AV_PIX_FMT_FLAG_FLOAT as u64, "float"),
+    ];
+    let mut pix_fmts = vec![];
+    let mut pix_desc = ptr::null();
+    loop {
+        pix_desc = ffi::av_pix_fmt_desc_next(pix_desc);
+        let desc = match pix_desc.as_ref() {
+            Some(desc) => desc,
+            None => break,
+        };
+        let name = CStr::from_ptr(desc.name).to_string_lossy().into_owned();
+        if !listing_matches(&name, filter) {
+            continue;
+        }
+        let pix_fmt = ffi::av_pix_fmt_desc_get_id(desc);
+        pix_fmts.push(
+            Json::object()
+                .with("name", name)
+                .with("nb_components", desc.nb_components as i32)
+                .with("bits_per_pixel", ffi::av_get_bits_per_pixel(desc))
+                .with("input", ffi::sws_isSupportedInput(pix_fmt) != 0)
+                .with("output", ffi::sws_isSupportedOutput(pix_fmt) != 0)
+                .with("flags", flag_names(desc.flags, FLAGS)),
+        );
+    }
+    pix_fmts.into()
+}
+
+unsafe fn layouts_json(filter: &str) -> Json {
+    let mut channels = vec![];
+    for i in 0..63 {
+        let name = ffi::av_get_channel_name(1 << i);
+        if name.is_null() {
+            continue;
+        }
+        let name = CStr::from_ptr(name).to_string_lossy().into_owned();
+        if listing_matches(&name, filter) {
+            channels.push(Json::object().with("name", name).with(
+                "description",
+                cstr_or(ffi::av_get_channel_description(1 << i), "").into_owned(),
+            ));
+        }
+    }
+
+    let mut layouts = vec![];
+    let mut i = 0;
+    loop {
+        let mut layout = 0;
+        let mut name = ptr::null();
+        if ffi::av_get_standard_channel_layout(i, &mut layout, &mut name) != 0 {
+            break;
+        }
+        i += 1;
+        if name.is_null() {
+            continue;
+        }
+        let name = CStr::from_ptr(name).to_string_lossy().into_owned();
+        if !listing_matches(&name, filter) {
+            continue;
+        }
+        let layout_channels = (0..64)
+            .map(|bit| 1u64 << bit)
+            .filter(|channel| layout & channel != 0)
+            .map(|channel| cstr_or(ffi::av_get_channel_name(channel), "").into_owned())
+            .collect::<Vec<_>>();
+        layouts.push(
+            Json::object()
+                .with("name", name)
+                .with("channels", layout_channels),
+        );
+    }
+    Json::object()
+        .with("channels", channels)
+        .with("layouts", layouts)
+}
+
+unsafe fn sample_fmts_json(filter: &str) -> Json {
+    let mut sample_fmts = vec![];
+    for fmt in 0..ffi::AVSampleFormat_AV_SAMPLE_FMT_NB {
+        let name = cstr_or(ffi::av_get_sample_fmt_name(fmt), "").into_owned();
+        if !listing_matches(&name, filter) {
+            continue;
+        }
+        sample_fmts.push(
+            Json::object()
+                .with("name", name)
+                .with("depth", ffi::av_get_bytes_per_sample(fmt) * 8)
+                .with("planar", ffi::av_sample_fmt_is_planar(fmt) != 0),
+        );
+    }
+    sample_fmts.into()
+}
+
+unsafe fn colors_json(filter: &str) -> Json {
+    let mut colors = vec![];
+    let mut i = 0;
+    loop {
+        let mut rgb = ptr::null();
+        let name = ffi::av_get_known_color_name(i, &mut rgb);
+        if name.is_null() {
+            break;
+        }
+        i += 1;
+        let name = CStr::from_ptr(name).to_string_lossy().into_owned();
+        if !listing_matches(&name, filter) {
+            continue;
+        }
+        let rgb = slice::from_raw_parts(rgb, 3);
+        colors.push(Json::object().with("name", name).with(
+            "rgb",
+            format!("#{:02x}{:02x}{:02x}", rgb[0], rgb[1], rgb[2]),
+        ));
+    }
+    colors.into()
+}
+
+unsafe fn hwaccels_json(filter: &str) -> Json {
+    let mut hwaccels = vec![];
+    let mut device_type = ffi::AVHWDeviceType_AV_HWDEVICE_TYPE_NONE;
+    loop {
+        device_type = ffi::av_hwdevice_iterate_types(device_type);
+        if device_type == ffi::AVHWDeviceType_AV_HWDEVICE_TYPE_NONE {
+            break;
+        }
+        let name = CStr::from_ptr(ffi::av_hwdevice_get_type_name(device_type))
+            .to_string_lossy()
+            .into_owned();
+        if listing_matches(&name, filter) {
+            hwaccels.push(name);
+        }
+    }
+    hwaccels.into()
+}
+
+/// A titled part of the option listing of `-h`, holding the options that
+/// have all of `req_flags`, none of `rej_flags` and, unless it's empty, one
+/// of `alt_flags`.
+pub struct HelpSection {
+    pub title: &'static str,
+    pub req_flags: OptionFlag,
+    pub rej_flags: OptionFlag,
+    pub alt_flags: OptionFlag,
+    /// Only listed by `-h long` and `-h full`.
+    pub advanced: bool,
+}
+
+impl HelpSection {
+    pub fn matches(&self, po: &OptionDef) -> bool {
+        po.flags.contains(self.req_flags)
+            && (self.alt_flags.is_empty() || po.flags.intersects(self.alt_flags))
+            && !po.flags.intersects(self.rej_flags)
+    }
+}
+
+pub fn help_options(options: &[OptionDef], section: &HelpSection) -> String {
+    let mut out = String::new();
+    for po in options.iter().filter(|po| section.matches(po)) {
+        if out.is_empty() {
+            let _ = writeln!(out, "{}:", section.title);
+        }
+        let name = match po.argname {
+            Some(argname) => format!("{} {}", po.name, argname),
+            None => po.name.to_owned(),
+        };
+        let _ = writeln!(out, "-{:<17}  {}", name, po.help);
+    }
+    out.push('\n');
+    out
+}
+
+/// A limit of an option range, by name when it's a well-known one.
+fn option_value(d: f64) -> String {
+    let named: [(f64, &str); 13] = [
+        (i32::MAX as f64, "INT_MAX"),
+        (i32::MIN as f64, "INT_MIN"),
+        (u32::MAX as f64, "UINT32_MAX"),
+        (i64::MAX as f64, "I64_MAX"),
+        (i64::MIN as f64, "I64_MIN"),
+        (f32::MAX as f64, "FLT_MAX"),
+        (f32::MIN_POSITIVE as f64, "FLT_MIN"),
+        (-f32::MAX as f64, "-FLT_MAX"),
+        (-f32::MIN_POSITIVE as f64, "-FLT_MIN"),
+        (f64::MAX, "DBL_MAX"),
+        (f64::MIN_POSITIVE, "DBL_MIN"),
+        (-f64::MAX, "-DBL_MAX"),
+        (-f64::MIN_POSITIVE, "-DBL_MIN"),
+    ];
+    match named.iter().find(|(value, _)| *value == d) {
+        Some((_, name)) => (*name).to_owned(),
+        None => d.to_string(),
+    }
+}
+
+fn option_int_value(i: i64) -> String {
+    match i {
+        i if i == i32::MAX as i64 => "INT_MAX".to_owned(),
+        i if i == i32::MIN as i64 => "INT_MIN".to_owned(),
+        i if i == u32::MAX as i64 => "UINT32_MAX".to_owned(),
+        i64::MAX => "I64_MAX".to_owned(),
+        i64::MIN => "I64_MIN".to_owned(),
+        i => i.to_string(),
+    }
+}
+
+/// A duration in microseconds as `[[h:]m:]s.us`, without trailing zeros.
+fn format_duration(d: i64) -> String {
+    if d == i64::MIN {
+        return "INT64_MIN".to_owned();
+    }
+    let (sign, d) = if d < 0 { ("-", -d) } else { ("", d) };
+    if d == i64::MAX {
+        return format!("{}INT64_MAX", sign);
+    }
+    let out = if d > 3600 * 1_000_000 {
+        format!(
+            "{}:{:02}:{:02}.{:06}",
+            d / 3_600_000_000,
+            (d / 60_000_000) % 60,
+            (d / 1_000_000) % 60,
+            d % 1_000_000
+        )
+    } else if d > 60 * 1_000_000 {
+        format!(
+            "{}:{:02}.{:06}",
+            d / 60_000_000,
+            (d / 1_000_000) % 60,
+            d % 1_000_000
+        )
+    } else {
+        format!("{}.{:06}", d / 1_000_000, d % 1_000_000)
+    };
+    format!(
+        "{}{}",
+        sign,
+        out.trim_end_matches('0').trim_end_matches('.')
+    )
+}
+
+/// The `(default ...)` part of an option's help, `None` for the options
+/// without a default.
+unsafe fn option_default(o: &ffi::AVOption, options: &[&ffi::AVOption]) -> Option<String> {
+    let value = o.default_val;
+    let default = match o.type_ {
+        ffi::AVOptionType_AV_OPT_TYPE_CONST | ffi::AVOptionType_AV_OPT_TYPE_BINARY => return None,
+        ffi::AVOptionType_AV_OPT_TYPE_COLOR
+        | ffi::AVOptionType_AV_OPT_TYPE_IMAGE_SIZE
+        | ffi::AVOptionType_AV_OPT_TYPE_STRING
+        | ffi::AVOptionType_AV_OPT_TYPE_DICT
+        | ffi::AVOptionType_AV_OPT_TYPE_VIDEO_RATE => {
+            if value.str.is_null() {
+                return None;
+            }
+            format!("\"{}\"", CStr::from_ptr(value.str).to_string_lossy())
+        }
+        ffi::AVOptionType_AV_OPT_TYPE_BOOL => match value.i64 {
+            0 => "false".to_owned(),
+            1 => "true".to_owned(),
+            i if i < 0 => "auto".to_owned(),
+            _ => "invalid".to_owned(),
+        },
+        ffi::AVOptionType_AV_OPT_TYPE_FLAGS => format!("{:X}", value.i64),
+        ffi::AVOptionType_AV_OPT_TYPE_DURATION => format_duration(value.i64),
+        ffi::AVOptionType_AV_OPT_TYPE_INT
+        | ffi::AVOptionType_AV_OPT_TYPE_INT64
+        | ffi::AVOptionType_AV_OPT_TYPE_UINT64 => {
+            // the named constant of the value wins
+            let unit = (!o.unit.is_null()).then(|| CStr::from_ptr(o.unit));
+            let constant = options.iter().find(|c| {
+                c.type_ == ffi::AVOptionType_AV_OPT_TYPE_CONST
+                    && !c.unit.is_null()
+                    && Some(CStr::from_ptr(c.unit)) == unit
+                    && c.default_val.i64 == value.i64
+            });
+            match constant {
+                Some(c) => CStr::from_ptr(c.name).to_string_lossy().into_owned(),
+                None => option_int_value(value.i64),
+            }
+        }
+        ffi::AVOptionType_AV_OPT_TYPE_DOUBLE | ffi::AVOptionType_AV_OPT_TYPE_FLOAT => {
+            option_value(value.dbl)
+        }
+        ffi::AVOptionType_AV_OPT_TYPE_RATIONAL => {
+            let q = ffi::av_d2q(value.dbl, i32::MAX);
+            format!("{}/{}", q.num, q.den)
+        }
+        ffi::AVOptionType_AV_OPT_TYPE_PIXEL_FMT => {
+            cstr_or(ffi::av_get_pix_fmt_name(value.i64 as _), "none").into_owned()
+        }
+        ffi::AVOptionType_AV_OPT_TYPE_SAMPLE_FMT => {
+            cstr_or(ffi::av_get_sample_fmt_name(value.i64 as _), "none").into_owned()
+        }
+        ffi::AVOptionType_AV_OPT_TYPE_CHANNEL_LAYOUT => format!("0x{:x}", value.i64),
+        _ => return None,
+    };
+    Some(default)
+}
+
+/// One line of the options of a class, like `av_opt_show2()` prints it.
+unsafe fn option_help(
+    o: &ffi::AVOption,
+    options: &[&ffi::AVOption],
+    parent_type: Option<ffi::AVOptionType>,
+) -> String {
+    const FLAGS: &[(u32, char)] = &[
+        (ffi::AV_OPT_FLAG_ENCODING_PARAM, 'E'),
+        (ffi::AV_OPT_FLAG_DECODING_PARAM, 'D'),
+        (ffi::AV_OPT_FLAG_FILTERING_PARAM, 'F'),
+        (ffi::AV_OPT_FLAG_VIDEO_PARAM, 'V'),
+        (ffi::AV_OPT_FLAG_AUDIO_PARAM, 'A'),
+        (ffi::AV_OPT_FLAG_SUBTITLE_PARAM, 'S'),
+        (ffi::AV_OPT_FLAG_EXPORT, 'X'),
+        (ffi::AV_OPT_FLAG_READONLY, 'R'),
+        (ffi::AV_OPT_FLAG_BSF_PARAM, 'B'),
+        (ffi::AV_OPT_FLAG_RUNTIME_PARAM, 'T'),
+    ];
+    let name = CStr::from_ptr(o.name).to_string_lossy();
+    let mut out = if parent_type.is_some() {
+        format!("     {:<15} ", name)
+    } else {
+        let filtering = o.flags as u32 & ffi::AV_OPT_FLAG_FILTERING_PARAM != 0;
+        format!("  {}{:<17} ", if filtering { "" } else { "-" }, name)
+    };
+    let type_name = match o.type_ {
+        ffi::AVOptionType_AV_OPT_TYPE_CONST => {
+            if parent_type == Some(ffi::AVOptionType_AV_OPT_TYPE_INT) {
+                o.default_val.i64.to_string()
+            } else {
+                String::new()
+            }
+        }
+        ffi::AVOptionType_AV_OPT_TYPE_BOOL => "<boolean>".to_owned(),
+        option_type => match option_type_name(option_type) {
+            "unknown" => String::new(),
+            type_name => format!("<{}>", type_name),
+        },
+    };
+    let _ = write!(out, "{:<12} ", type_name);
+    for &(flag, c) in FLAGS {
+        out.push(if o.flags as u32 & flag != 0 { c } else { '.' });
+    }
+    let _ = write!(out, " {}", cstr_or(o.help, ""));
+    if let ffi::AVOptionType_AV_OPT_TYPE_INT
+    | ffi::AVOptionType_AV_OPT_TYPE_INT64
+    | ffi::AVOptionType_AV_OPT_TYPE_UINT64
+    | ffi::AVOptionType_AV_OPT_TYPE_DOUBLE
+    | ffi::AVOptionType_AV_OPT_TYPE_FLOAT
+    | ffi::AVOptionType_AV_OPT_TYPE_RATIONAL = o.type_
+    {
+        let _ = write!(
+            out,
+            " (from {} to {})",
+            option_value(o.min),
+            option_value(o.max)
+        );
+    }
+    if let Some(default) = option_default(o, options) {
+        let _ = write!(out, " (default {})", default);
+    }
+    out.push('\n');
+    out
+}
+
+/// The options of a class having one of `flags` and those of its child
+/// classes, with their types, ranges, defaults and named constants.
+pub unsafe fn class_help(class: *const ffi::AVClass, flags: u32) -> String {
+    let mut out = String::new();
+    if class.is_null() {
+        return out;
+    }
+    let options = class_options(class);
+    if !options.is_empty() {
+        let _ = writeln!(out, "{} AVOptions:", cstr_or((*class).class_name, ""));
+        let wanted = options
+            .iter()
+            .filter(|o| o.flags as u32 & flags != 0)
+            .collect::<Vec<_>>();
+        for o in wanted
+            .iter()
+            .filter(|o| o.type_ != ffi::AVOptionType_AV_OPT_TYPE_CONST)
+        {
+            out.push_str(&option_help(o, &options, None));
+            if o.unit.is_null() {
+                continue;
+            }
+            let unit = CStr::from_ptr(o.unit);
+            for c in wanted.iter().filter(|c| {
+                c.type_ == ffi::AVOptionType_AV_OPT_TYPE_CONST
+                    && !c.unit.is_null()
+                    && CStr::from_ptr(c.unit) == unit
+            }) {
+                out.push_str(&option_help(c, &options, Some(o.type_)));
+            }
+        }
+        out.push('\n');
+    }
+
+    let mut child = ptr::null();
+    loop {
+        child = ffi::av_opt_child_class_next(class, child);
+        if child.is_null() {
+            break;
+        }
+        out.push_str(&class_help(child, flags));
+    }
+    out
+}
+
+/// `-h decoder=name` and `-h encoder=name`, every implementation of the codec
+/// is described when `name` is a codec rather than a decoder or encoder.
+pub fn codec_help(name: Option<&str>, encoder: bool) -> Result<String, String> {
+    let name = name.ok_or_else(|| "No codec name specified.".to_owned())?;
+    let name_c = CString::new(name).map_err(|e| e.to_string())?;
+    unsafe {
+        let codec = if encoder {
+            ffi::avcodec_find_encoder_by_name(name_c.as_ptr())
+        } else {
+            ffi::avcodec_find_decoder_by_name(name_c.as_ptr())
+        };
+        if let Some(codec) = codec.as_ref() {
+            return Ok(codec_description(codec, encoder));
+        }
+        let desc = ffi::avcodec_descriptor_get_by_name(name_c.as_ptr())
+            .as_ref()
+            .ok_or_else(|| format!("Codec '{}' is not recognized by FFmpeg.", name))?;
+        let codecs = codecs_for_id(desc.id, encoder);
+        if codecs.is_empty() {
+            return Err(format!(
+                "Codec '{}' is known to FFmpeg, but no {} for it are available. \
+                 FFmpeg might need to be recompiled with additional external libraries.",
+                name,
+                if encoder { "encoders" } else { "decoders" }
+            ));
+        }
+        Ok(codecs
+            .into_iter()
+            .map(|codec| codec_description(codec, encoder))
+            .collect())
+    }
+}
+
+/// The `Supported ...:` line of a codec or bitstream filter, nothing when
+/// the list is empty.
+fn supported_line(what: &str, values: Vec<String>) -> String {
+    if values.is_empty() {
+        String::new()
+    } else {
+        format!("    Supported {}: {}\n", what, values.join(" "))
+    }
+}
+
+unsafe fn codec_description(c: &ffi::AVCodec, encoder: bool) -> String {
+    const CAPABILITIES: &[(u32, &str)] = &[
+        (ffi::AV_CODEC_CAP_DRAW_HORIZ_BAND, "horizband"),
+        (ffi::AV_CODEC_CAP_DR1, "dr1"),
+        (ffi::AV_CODEC_CAP_TRUNCATED, "trunc"),
+        (ffi::AV_CODEC_CAP_DELAY, "delay"),
+        (ffi::AV_CODEC_CAP_SMALL_LAST_FRAME, "small"),
+        (ffi::AV_CODEC_CAP_SUBFRAMES, "subframes"),
+        (ffi::AV_CODEC_CAP_EXPERIMENTAL, "exp"),
+        (ffi::AV_CODEC_CAP_CHANNEL_CONF, "chconf"),
+        (ffi::AV_CODEC_CAP_PARAM_CHANGE, "paramchange"),
+        (ffi::AV_CODEC_CAP_VARIABLE_FRAME_SIZE, "variable"),
+        (
+            ffi::AV_CODEC_CAP_FRAME_THREADS
+                | ffi::AV_CODEC_CAP_SLICE_THREADS
+                | ffi::AV_CODEC_CAP_AUTO_THREADS,
+            "threads",
+        ),
+        (ffi::AV_CODEC_CAP_AVOID_PROBING, "avoidprobe"),
+        (ffi::AV_CODEC_CAP_INTRA_ONLY, "intraonly"),
+        (ffi::AV_CODEC_CAP_LOSSLESS, "lossless"),
+        (ffi::AV_CODEC_CAP_HARDWARE, "hardware"),
+        (ffi::AV_CODEC_CAP_HYBRID, "hybrid"),
+    ];
+    let capabilities = c.capabilities as u32;
+    let mut out = format!(
+        "{} {} [{}]:\n",
+        if encoder { "Encoder" } else { "Decoder" },
+        CStr::from_ptr(c.name).to_string_lossy(),
+        cstr_or(c.long_name, "")
+    );
+
+    out.push_str("    General capabilities: ");
+    for &(_, name) in CAPABILITIES
+        .iter()
+        .filter(|(cap, _)| capabilities & cap != 0)
+    {
+        let _ = write!(out, "{} ", name);
+    }
+    if capabilities == 0 {
+        out.push_str("none");
+    }
+    out.push('\n');
+
+    if c.type_ == ffi::AVMediaType_AVMEDIA_TYPE_VIDEO
+        || c.type_ == ffi::AVMediaType_AVMEDIA_TYPE_AUDIO
+    {
+        let threads = capabilities
+            & (ffi::AV_CODEC_CAP_FRAME_THREADS
+                | ffi::AV_CODEC_CAP_SLICE_THREADS
+                | ffi::AV_CODEC_CAP_AUTO_THREADS);
+        let threads = match threads {
+            t if t == ffi::AV_CODEC_CAP_FRAME_THREADS | ffi::AV_CODEC_CAP_SLICE_THREADS => {
+                "frame and slice"
+            }
+            ffi::AV_CODEC_CAP_FRAME_THREADS => "frame",
+            ffi::AV_CODEC_CAP_SLICE_THREADS => "slice",
+            ffi::AV_CODEC_CAP_AUTO_THREADS => "auto",
+            _ => "none",
+        };
+        let _ = writeln!(out, "    Threading capabilities: {}", threads);
+    }
+
+    let hw_devices = (0..)
+        .map(|i| ffi::avcodec_get_hw_config(c, i))
+        .take_while(|config| !config.is_null())
+        .map(|config| {
+            cstr_or(ffi::av_hwdevice_get_type_name((*config).device_type), "").into_owned()
+        })
+        .collect::<Vec<_>>();
+    if !hw_devices.is_empty() {
+        let _ = writeln!(
+            out,
+            "    Supported hardware devices: {} ",
+            hw_devices.join(" ")
+        );
+    }
+
+    let framerates = terminated_by(c.supported_framerates, |q| q.num == 0 && q.den == 0)
+        .into_iter()
+        .map(|q| format!("{}/{}", q.num, q.den))
+        .collect();
+    out.push_str(&supported_line("framerates", framerates));
+    let pix_fmts = terminated(c.pix_fmts, ffi::AVPixelFormat_AV_PIX_FMT_NONE)
+        .into_iter()
+        .map(|pix_fmt| cstr_or(ffi::av_get_pix_fmt_name(pix_fmt), "").into_owned())
+        .collect();
+    out.push_str(&supported_line("pixel formats", pix_fmts));
+    let sample_rates = terminated(c.supported_samplerates, 0)
+        .into_iter()
+        .map(|rate| rate.to_string())
+        .collect();
+    out.push_str(&supported_line("sample rates", sample_rates));
+    let sample_fmts = terminated(c.sample_fmts, ffi::AVSampleFormat_AV_SAMPLE_FMT_NONE)
+        .into_iter()
+        .map(|fmt| cstr_or(ffi::av_get_sample_fmt_name(fmt), "").into_owned())
+        .collect();
+    out.push_str(&supported_line("sample formats", sample_fmts));
+    let channel_layouts = terminated(c.channel_layouts, 0)
+        .into_iter()
+        .map(|layout| channel_layout_name(layout))
+        .collect();
+    out.push_str(&supported_line("channel layouts", channel_layouts));
+
+    out.push_str(&class_help(
+        c.priv_class,
+        ffi::AV_OPT_FLAG_ENCODING_PARAM | ffi::AV_OPT_FLAG_DECODING_PARAM,
+    ));
+    out
+}
+
+/// `-h demuxer=name`.
+pub fn demuxer_help(name: Option<&str>) -> Result<String, String> {
+    let name = name.ok_or_else(|| "No demuxer name specified.".to_owned())?;
+    let name_c = CString::new(name).map_err(|e| e.to_string())?;
+    unsafe {
+        let fmt = ffi::av_find_input_format(name_c.as_ptr())
+            .as_ref()
+            .ok_or_else(|| format!("Unknown format '{}'.", name))?;
+        let mut out = format!(
+            "Demuxer {} [{}]:\n",
+            CStr::from_ptr(fmt.name).to_string_lossy(),
+            cstr_or(fmt.long_name, "")
+        );
+        if !fmt.extensions.is_null() {
+            let _ = writeln!(
+                out,
+                "    Common extensions: {}.",
+                CStr::from_ptr(fmt.extensions).to_string_lossy()
+            );
+        }
+        out.push_str(&class_help(fmt.priv_class, ffi::AV_OPT_FLAG_DECODING_PARAM));
+        Ok(out)
+    }
+}
+
+/// `-h muxer=name`.
+pub fn muxer_help(name: Option<&str>) -> Result<String, String> {
+    let name = name.ok_or_else(|| "No muxer name specified.".to_owned())?;
+    let name_c = CString::new(name).map_err(|e| e.to_string())?;
+    unsafe {
+        let fmt = ffi::av_guess_format(name_c.as_ptr(), ptr::null(), ptr::null())
+            .as_ref()
+            .ok_or_else(|| format!("Unknown format '{}'.", name))?;
+        let mut out = format!(
+            "Muxer {} [{}]:\n",
+            CStr::from_ptr(fmt.name).to_string_lossy(),
+            cstr_or(fmt.long_name, "")
+        );
+        if !fmt.extensions.is_null() {
+            let _ = writeln!(
+                out,
+                "    Common extensions: {}.",
+                CStr::from_ptr(fmt.extensions).to_string_lossy()
+            );
+        }
+        if !fmt.mime_type.is_null() {
+            let _ = writeln!(
+                out,
+                "    Mime type: {}.",
+                CStr::from_ptr(fmt.mime_type).to_string_lossy()
+            );
+        }
+        for &(what, id) in &[
+            ("video", fmt.video_codec),
+            ("audio", fmt.audio_codec),
+            ("subtitle", fmt.subtitle_codec),
+        ] {
+            if id == ffi::AVCodecID_AV_CODEC_ID_NONE {
+                continue;
+            }
+            if let Some(desc) = ffi::avcodec_descriptor_get(id).as_ref() {
+                let _ = writeln!(
+                    out,
+                    "    Default {} codec: {}.",
+                    what,
+                    CStr::from_ptr(desc.name).to_string_lossy()
+                );
+            }
+        }
+        out.push_str(&class_help(fmt.priv_class, ffi::AV_OPT_FLAG_ENCODING_PARAM));
+        Ok(out)
+    }
+}
+
+/// `-h protocol=name`.
+pub fn protocol_help(name: Option<&str>) -> Result<String, String> {
+    let name = name.ok_or_else(|| "No protocol name specified.".to_owned())?;
+    let name_c = CString::new(name).map_err(|e| e.to_string())?;
+    unsafe {
+        let class = ffi::avio_protocol_get_class(name_c.as_ptr());
+        if class.is_null() {
+            return Err(format!("Unknown protocol '{}'.", name));
+        }
+        Ok(class_help(
+            class,
+            ffi::AV_OPT_FLAG_DECODING_PARAM | ffi::AV_OPT_FLAG_ENCODING_PARAM,
+        ))
+    }
+}
+
+/// The `Inputs:` or `Outputs:` part of `-h filter=name`.
+unsafe fn filter_pads_help(
+    out: &mut String,
+    title: &str,
+    pads: *const ffi::AVFilterPad,
+    dynamic: bool,
+    none: &str,
+) {
+    let _ = writeln!(out, "    {}:", title);
+    let mut count = 0;
+    while !pads.is_null() && !ffi::avfilter_pad_get_name(pads, count).is_null() {
+        let _ = writeln!(
+            out,
+            "       #{}: {} ({})",
+            count,
+            CStr::from_ptr(ffi::avfilter_pad_get_name(pads, count)).to_string_lossy(),
+            media_type_name(ffi::avfilter_pad_get_type(pads, count))
+        );
+        count += 1;
+    }
+    if dynamic {
+        out.push_str("        dynamic (depending on the options)\n");
+    } else if count == 0 {
+        let _ = writeln!(out, "        {}", none);
+    }
+}
+
+/// `-h filter=name`.
+pub fn filter_help(name: Option<&str>) -> Result<String, String> {
+    let name = name.ok_or_else(|| "No filter name specified.".to_owned())?;
+    let name_c = CString::new(name).map_err(|e| e.to_string())?;
+    unsafe {
+        let f = ffi::avfilter_get_by_name(name_c.as_ptr())
+            .as_ref()
+            .ok_or_else(|| format!("Unknown filter '{}'.", name))?;
+        let flags = f.flags as u32;
+        let mut out = format!("Filter {}\n", CStr::from_ptr(f.name).to_string_lossy());
+        if !f.description.is_null() {
+            let _ = writeln!(out, "  {}", CStr::from_ptr(f.description).to_string_lossy());
+        }
+        if flags & ffi::AVFILTER_FLAG_SLICE_THREADS != 0 {
+            out.push_str("    slice threading supported\n");
+        }
+        filter_pads_help(
+            &mut out,
+            "Inputs",
+            f.inputs,
+            flags & ffi::AVFILTER_FLAG_DYNAMIC_INPUTS != 0,
+            "none (source filter)",
+        );
+        filter_pads_help(
+            &mut out,
+            "Outputs",
+            f.outputs,
+            flags & ffi::AVFILTER_FLAG_DYNAMIC_OUTPUTS != 0,
+            "none (sink filter)",
+        );
+        out.push_str(&class_help(
+            f.priv_class,
+            ffi::AV_OPT_FLAG_VIDEO_PARAM
+                | ffi::AV_OPT_FLAG_FILTERING_PARAM
+                | ffi::AV_OPT_FLAG_AUDIO_PARAM,
+        ));
+        if flags
+            & (ffi::AVFILTER_FLAG_SUPPORT_TIMELINE_GENERIC
+                | ffi::AVFILTER_FLAG_SUPPORT_TIMELINE_INTERNAL)
+            != 0
+        {
+            out.push_str("This filter has support for timeline through the 'enable' option.\n");
+        }
+        Ok(out)
+    }
+}
+
+/// `-h bsf=name`.
+pub fn bsf_help(name: Option<&str>) -> Result<String, String> {
+    let name = name.ok_or_else(|| "No bitstream filter name specified.".to_owned())?;
+    let name_c = CString::new(name).map_err(|e| e.to_string())?;
+    unsafe {
+        let bsf = ffi::av_bsf_get_by_name(name_c.as_ptr())
+            .as_ref()
+            .ok_or_else(|| format!("Unknown bit stream filter '{}'.", name))?;
+        let mut out = format!(
+            "Bit stream filter {}\n",
+            CStr::from_ptr(bsf.name).to_string_lossy()
+        );
+        let codecs = terminated(bsf.codec_ids, ffi::AVCodecID_AV_CODEC_ID_NONE)
+            .into_iter()
+            .map(|id| cstr_or(ffi::avcodec_get_name(id), "").into_owned())
+            .collect();
+        out.push_str(&supported_line("codecs", codecs));
+        out.push_str(&class_help(bsf.priv_class, ffi::AV_OPT_FLAG_BSF_PARAM));
+        Ok(out)
+    }
+}
+
+/// The devices auto-detected by the input (or output) device `name`, or why
+/// they can't be listed.
+unsafe fn device_info_list(
+    sinks: bool,
+    name: *const libc::c_char,
+    list: impl FnOnce(*mut *mut ffi::AVDeviceInfoList) -> libc::c_int,
+) -> String {
+    let mut out = format!(
+        "Auto-detected {} for {}:\n",
+        if sinks { "sinks" } else { "sources" },
+        CStr::from_ptr(name).to_string_lossy()
+    );
+    let mut device_list = ptr::null_mut();
+    if list(&mut device_list) < 0 {
+        let _ = writeln!(
+            out,
+            "Cannot list {}.",
+            if sinks { "sinks" } else { "sources" }
+        );
+    } else if let Some(l) = device_list.as_ref() {
+        for i in 0..l.nb_devices {
+            let device = &**l.devices.offset(i as isize);
+            let _ = writeln!(
+                out,
+                "{} {} [{}]",
+                if l.default_device == i { "*" } else { " " },
+                cstr_or(device.device_name, ""),
+                cstr_or(device.device_description, "")
+            );
+        }
+    }
+    ffi::avdevice_free_list_devices(&mut device_list);
+    out
+}
+
+/// `-sources` and `-sinks`, `arg` is `devicename[,opt1=val1[:opt2=val2...]]`
+/// and all the devices are probed when it's empty.
+pub fn device_list(sinks: bool, arg: &str) -> Result<String, String> {
+    let (dev, opts_str) = match arg.find(',') {
+        Some(i) => (&arg[..i], &arg[i + 1..]),
+        None => (arg, ""),
+    };
+    let mut out = String::new();
+    if arg.is_empty() {
+        out.push_str(
+            "\nDevice name is not provided.\n\
+             You can pass devicename[,opt1=val1[,opt2=val2...]] as an argument.\n\n",
+        );
+    }
+    let dev_c = CString::new(dev).map_err(|e| e.to_string())?;
+    let opts_c = CString::new(opts_str).map_err(|e| e.to_string())?;
+    unsafe {
+        let mut opts = ptr::null_mut();
+        if !opts_str.is_empty() {
+            let ret = ffi::av_dict_parse_string(
+                &mut opts,
+                opts_c.as_ptr(),
+                b"=\0".as_ptr() as *const libc::c_char,
+                b":\0".as_ptr() as *const libc::c_char,
+                0,
+            );
+            if ret < 0 {
+                ffi::av_dict_free(&mut opts);
+                return Err(format!(
+                    "Invalid device options '{}': {}",
+                    opts_str,
+                    av_err2str(ret)
+                ));
+            }
+        }
+        let matches = |name| arg.is_empty() || ffi::av_match_name(dev_c.as_ptr(), name) != 0;
+
+        // Probing is noisy.
+        let level = ffi::av_log_get_level();
+        ffi::av_log_set_level(ffi::AV_LOG_WARNING as i32);
+        if sinks {
+            let mut fmt = ptr::null_mut();
+            for &next in &[
+                ffi::av_output_audio_device_next as unsafe extern "C" fn(_) -> _,
+                ffi::av_output_video_device_next,
+            ] {
+                while let Some(f) = {
+                    fmt = next(fmt);
+                    fmt.as_ref()
+                } {
+                    if !matches(f.name) || !is_device(f.priv_class) {
+                        continue;
+                    }
+                    out.push_str(&match f.get_device_list {
+                        Some(_) => device_info_list(true, f.name, |list| {
+                            ffi::avdevice_list_output_sinks(fmt, ptr::null(), opts, list)
+                        }),
+                        None => format!(
+                            "Auto-detected sinks for {}:\nCannot list sinks. Not implemented.\n",
+                            CStr::from_ptr(f.name).to_string_lossy()
+                        ),
+                    });
+                }
+            }
+        } else {
+            let mut fmt = ptr::null_mut();
+            for &next in &[
+                ffi::av_input_audio_device_next as unsafe extern "C" fn(_) -> _,
+                ffi::av_input_video_device_next,
+            ] {
+                while let Some(f) = {
+                    fmt = next(fmt);
+                    fmt.as_ref()
+                } {
+                    // it's pointless to probe lavfi
+                    if !matches(f.name)
+                        || !is_device(f.priv_class)
+                        || CStr::from_ptr(f.name).to_bytes() == b"lavfi"
+                    {
+                        continue;
+                    }
+                    out.push_str(&match f.get_device_list {
+                        Some(_) => device_info_list(false, f.name, |list| {
+                            ffi::avdevice_list_input_sources(fmt, ptr::null(), opts, list)
+                        }),
+                        None => format!(
+                            "Auto-detected sources for {}:\nCannot list sources. Not implemented.\n",
+                            CStr::from_ptr(f.name).to_string_lossy()
+                        ),
+                    });
+                }
+            }
+        }
+        ffi::av_log_set_level(level);
+        ffi::av_dict_free(&mut opts);
+    }
+    Ok(out)
+}
+
+/// Find the preset file `name` of `-fpre` (`is_path`) or of `-apre`, `-vpre`
+/// and `-spre`, which are looked up as `[codec_name-]name.ffpreset` in
+/// `$FFMPEG_DATADIR` and `$HOME/.ffmpeg`. Returns the path and the content.
+pub fn get_preset_file(
+    name: &str,
+    is_path: bool,
+    codec_name: Option<&str>,
+) -> Option<(PathBuf, String)> {
+    let read = |path: PathBuf| fs::read_to_string(&path).ok().map(|s| (path, s));
+    if is_path {
+        return read(PathBuf::from(name));
+    }
+    let bases = [
+        env::var_os("FFMPEG_DATADIR").map(PathBuf::from),
+        env::var_os("HOME").map(|home| Path::new(&home).join(".ffmpeg")),
+    ];
+    bases.iter().flatten().find_map(|base| {
+        codec_name
+            .and_then(|codec| read(base.join(format!("{}-{}.ffpreset", codec, name))))
+            .or_else(|| read(base.join(format!("{}.ffpreset", name))))
+    })
+}
+
+#[cfg(test)]
+mod types_tests {
+    use super::*;
+
+    #[test]
+    fn fmt_debug_option_operation_default() {
+        let optop: OptionOperation = Default::default();
+        assert_eq!(format!("{:?}", optop), "(Union)OptionOperation { val: 0 }");
+    }
+
+    #[test]
+    fn fmt_debug_option_operation() {
+        let optop: OptionOperation = OptionOperation { off: 123_456 };
+        assert_eq!(
+            format!("{:?}", optop),
+            "(Union)OptionOperation { val: 123456 }"
+        );
+    }
+
+    #[test]
+    fn write_option_to_session() {
+        use crate::options::OPTIONS;
+
+        let copytb = OPTIONS.iter().find(|o| o.name == "copytb").unwrap();
+        let stats = OPTIONS.iter().find(|o| o.name == "stats").unwrap();
+        let mut session = Session::default();
+        write_option(
+            &mut session,
+            &mut None,
+            copytb,
+            1,
+            "copytb",
+            OsStr::new("1"),
+        )
+        .unwrap();
+        write_option(&mut session, &mut None, stats, 3, "stats", OsStr::new("1")).unwrap();
+        assert_eq!(session.copy_tb, 1);
+        assert_eq!(session.print_stats, 1);
+
+        // Sessions don't share state.
+        let other = Session::default();
+        assert_eq!(other.copy_tb, -1);
+        assert_eq!(other.print_stats, -1);
+    }
+
+    fn split(args: &[&str]) -> Result<OptionParseContext<'static>, ParseError> {
+        split_os(&args.iter().map(OsString::from).collect::<Vec<_>>())
+    }
+
+    fn split_os(args: &[OsString]) -> Result<OptionParseContext<'static>, ParseError> {
+        use crate::options::{GROUPS, OPTIONS};
+
+        let mut octx = OptionParseContext {
+            groups: GROUPS
+                .iter()
+                .map(|group| OptionGroupList {
+                    group_def: group,
+                    groups: vec![],
+                })
+                .collect(),
+            global_opts: OptionGroup::new_global(),
+            cur_group: OptionGroup::new_anonymous(),
+            locations: vec![],
+        };
+        split_commandline(&mut octx, args, &*OPTIONS, &*GROUPS)?;
+        Ok(octx)
+    }
+
+    #[test]
+    fn bool_options_in_both_forms() {
+        use crate::options::OPTIONS;
+
+        let bools = OPTIONS
+            .iter()
+            .filter(|po| po.flags.contains(OptionFlag::OPT_BOOL));
+        for po in bools {
+            let global = !po.flags.intersects(
+                OptionFlag::OPT_PERFILE | OptionFlag::OPT_SPEC | OptionFlag::OPT_OFFSET,
+            );
+            let name = format!("-{}", po.name);
+            let negated = format!("-no{}", po.name);
+            let positive: Vec<&str> = if po.flags.contains(OptionFlag::HAS_ARG) {
+                vec![name.as_str(), "1"]
+            } else {
+                vec![name.as_str()]
+            };
+            for (opt, val) in [(positive, "1"), (vec![negated.as_str()], "0")].iter() {
+                let mut args = vec!["ffcli"];
+                args.extend(opt);
+                args.extend(&["-i", "in", "out"]);
+                let octx = split(&args).unwrap();
+                let g = if global {
+                    &octx.global_opts
+                } else {
+                    &octx.groups[OptGroup::GroupInfile as usize].groups[0]
+                };
+                assert_eq!(g.opts.len(), 1, "{:?}", args);
+                assert_eq!(g.opts[0].opt.name, po.name);
+                assert_eq!(g.opts[0].key, &opt[0][1..]);
+                assert_eq!(g.opts[0].val, *val, "{:?}", args);
+                assert_eq!(g.opts[0].index, 1);
+            }
+        }
+    }
+
+    #[test]
+    fn negated_bool_is_applied() {
+        let octx = split(&["ffcli", "-nostdin", "-noaccurate_seek", "-i", "in", "out"]).unwrap();
+        let mut session = Session::default();
+        parse_optgroup(&mut session, None, &octx.global_opts).unwrap();
+        assert_eq!(session.stdin_interaction, 0);
+
+        let mut g = octx.groups[OptGroup::GroupInfile as usize].groups[0].clone();
+        let g_ = g.clone();
+        let mut o = OptionsContext::new(&mut g);
+        parse_optgroup(&mut session, Some(&mut o), &g_).unwrap();
+        assert_eq!(o.accurate_seek, 0);
+    }
+
+    #[test]
+    fn codec_names_per_stream() {
+        let octx = split(&["ffcli", "-i", "in", "-c:v", "libx264", "-c:a", "aac", "out"]).unwrap();
+        let mut g = octx.groups[OptGroup::GroupOutfile as usize].groups[0].clone();
+        let g_ = g.clone();
+        let mut session = Session::default();
+        let mut o = OptionsContext::new(&mut g);
+        parse_optgroup(&mut session, Some(&mut o), &g_).unwrap();
+        assert_eq!(
+            o.codec_names,
+            vec![
+                SpecifierOpt {
+                    specifier: "v".to_owned(),
+                    u: SpecifierOptValue::Str("libx264".into()),
+                },
+                SpecifierOpt {
+                    specifier: "a".to_owned(),
+                    u: SpecifierOptValue::Str("aac".into()),
+                },
+            ]
+        );
+    }
+
+    fn spec(specifier: &str, u: SpecifierOptValue) -> SpecifierOpt {
+        SpecifierOpt {
+            specifier: specifier.to_owned(),
+            u,
+        }
+    }
+
+    #[test]
+    fn old_options_are_aliases() {
+        let octx = split(&[
+            "ffcli",
+            "-i",
+            "in",
+            "-vtag",
+            "avc1",
+            "-absf",
+            "aac_adtstoasc",
+            "-qscale",
+            "3",
+            "-qscale:a",
+            "4",
+            "out",
+        ])
+        .unwrap();
+        let mut g = octx.groups[OptGroup::GroupOutfile as usize].groups[0].clone();
+        let g_ = g.clone();
+        let mut session = Session::default();
+        let mut o = OptionsContext::new(&mut g);
+        parse_optgroup(&mut session, Some(&mut o), &g_).unwrap();
+        assert_eq!(
+            o.codec_tags,
+            vec![spec("v", SpecifierOptValue::Str("avc1".into()))]
+        );
+        assert_eq!(
+            o.bitstream_filters,
+            vec![spec("a", SpecifierOptValue::Str("aac_adtstoasc".into()))]
+        );
+        assert_eq!(
+            o.qscale,
+            vec![
+                spec("v", SpecifierOptValue::Double(3.)),
+                spec("a", SpecifierOptValue::Double(4.)),
+            ]
+        );
+    }
+
+    #[test]
+    fn shorthands_set_per_type_options() {
+        let octx = split(&[
+            "ffcli",
+            "-i",
+            "in",
+            "-vcodec",
+            "libx264",
+            "-acodec",
+            "aac",
+            "-vframes",
+            "10",
+            "-vf",
+            "hflip",
+            "-aq",
+            "2",
+            "-streamid",
+            "2:42",
+            "-profile",
+            "high",
+            "out",
+        ])
+        .unwrap();
+        let mut g = octx.groups[OptGroup::GroupOutfile as usize].groups[0].clone();
+        let g_ = g.clone();
+        let mut session = Session::default();
+        let mut o = OptionsContext::new(&mut g);
+        parse_optgroup(&mut session, Some(&mut o), &g_).unwrap();
+        assert_eq!(
+            o.codec_names,
+            vec![
+                spec("v", SpecifierOptValue::Str("libx264".into())),
+                spec("a", SpecifierOptValue::Str("aac".into())),
+            ]
+        );
+        assert_eq!(o.max_frames, vec![spec("v", SpecifierOptValue::Int64(10))]);
+        assert_eq!(
+            o.filters,
+            vec![spec("v", SpecifierOptValue::Str("hflip".into()))]
+        );
+        assert_eq!(o.qscale, vec![spec("a", SpecifierOptValue::Double(2.))]);
+        assert_eq!(o.streamid_map, vec![0, 0, 42]);
+        assert_eq!(o.g.codec_opts.get("profile:v"), Some("high"));
+    }
+
+    #[test]
+    fn bad_shorthand_values_fail() {
+        for args in [
+            &["ffcli", "-i", "in", "-streamid", "42", "out"][..],
+            &["ffcli", "-i", "in", "-streamid", "1024:1", "out"],
+            &["ffcli", "-i", "in", "-target", "laserdisc", "out"],
+            &["ffcli", "-i", "in", "-channel_layout", "wobbly", "out"],
+        ]
+        .iter()
+        {
+            let octx = split(args).unwrap();
+            let mut session = Session::default();
+            let mut g = octx.groups[OptGroup::GroupOutfile as usize].groups[0].clone();
+            let g_ = g.clone();
+            let mut o = OptionsContext::new(&mut g);
+            assert!(
+                parse_optgroup(&mut session, Some(&mut o), &g_).is_err(),
+                "{:?}",
+                args
+            );
+        }
+    }
+
+    #[test]
+    fn transcode_options_set_session() {
+        let octx = split(&[
+            "ffcli",
+            "-vsync",
+            "drop",
+            "-abort_on",
+            "empty_output+empty_output_stream",
+            "-i",
+            "in",
+            "out",
+        ])
+        .unwrap();
+        let mut session = Session::default();
+        parse_optgroup(&mut session, None, &octx.global_opts).unwrap();
+        assert_eq!(session.video_sync_method, crate::ffmpeg::VSYNC_DROP);
+        assert_eq!(
+            session.abort_on_flags,
+            crate::ffmpeg::ABORT_ON_FLAG_EMPTY_OUTPUT
+                | crate::ffmpeg::ABORT_ON_FLAG_EMPTY_OUTPUT_STREAM
+        );
+
+        for args in [
+            &["ffcli", "-vsync", "sometimes", "-i", "in", "out"][..],
+            &["ffcli", "-abort_on", "empty", "-i", "in", "out"],
+            &["ffcli", "-vstats", "-i", "in", "out"],
+            &["ffcli", "-sameq", "-i", "in", "out"],
+        ]
+        .iter()
+        {
+            let octx = split(args).unwrap();
+            let mut session = Session::default();
+            assert!(
+                parse_optgroup(&mut session, None, &octx.global_opts).is_err(),
+                "{:?}",
+                args
+            );
+        }
+    }
+
+    #[test]
+    fn every_spec_option_is_typed() {
+        use crate::options::OPTIONS;
+
+        let specs = OPTIONS
+            .iter()
+            .filter(|po| po.flags.contains(OptionFlag::OPT_SPEC));
+        for po in specs {
+            let (arg, expected) = if po.flags.contains(OptionFlag::OPT_STRING) {
+                ("x", SpecifierOptValue::Str("x".into()))
+            } else if po
+                .flags
+                .intersects(OptionFlag::OPT_BOOL | OptionFlag::OPT_INT)
+            {
+                ("1", SpecifierOptValue::Int(1))
+            } else if po.flags.contains(OptionFlag::OPT_INT64) {
+                ("2", SpecifierOptValue::Int64(2))
+            } else if po.flags.contains(OptionFlag::OPT_TIME) {
+                ("3", SpecifierOptValue::Time(3_000_000))
+            } else if po.flags.contains(OptionFlag::OPT_FLOAT) {
+                ("1.5", SpecifierOptValue::Float(1.5))
+            } else {
+                ("2.5", SpecifierOptValue::Double(2.5))
+            };
+            let name = format!("-{}:v", po.name);
+            let mut opt = vec![name.as_str()];
+            if po.flags.contains(OptionFlag::HAS_ARG) {
+                opt.push(arg);
+            }
+            let output = po.flags.contains(OptionFlag::OPT_OUTPUT);
+            let args = if output {
+                [&["ffcli", "-i", "in"][..], &opt[..], &["out"][..]].concat()
+            } else {
+                [&["ffcli"][..], &opt[..], &["-i", "in", "out"][..]].concat()
+            };
+            let octx = split(&args).unwrap();
+            let group_idx = if output {
+                OptGroup::GroupOutfile
+            } else {
+                OptGroup::GroupInfile
+            };
+            let mut g = octx.groups[group_idx as usize].groups[0].clone();
+            let g_ = g.clone();
+            let mut session = Session::default();
+            let mut o = OptionsContext::new(&mut g);
+            parse_optgroup(&mut session, Some(&mut o), &g_).unwrap();
+
+            let so = unsafe {
+                &*((&o as *const OptionsContext as *const u8).add(po.u.off)
+                    as *const Vec<SpecifierOpt>)
+            };
+            let expected = if po.flags.contains(OptionFlag::HAS_ARG) {
+                expected
+            } else {
+                SpecifierOptValue::Int(1)
+            };
+            assert_eq!(
+                so,
+                &vec![SpecifierOpt {
+                    specifier: "v".to_owned(),
+                    u: expected,
+                }],
+                "{}",
+                po.name
+            );
+        }
+    }
+
+    #[test]
+    fn negated_non_bool_is_unknown() {
+        match split(&["ffcli", "-nocodec", "out"]) {
+            Err(ParseError::UnknownOption { index, opt }) => {
+                assert_eq!(index, 1);
+                assert_eq!(opt, "nocodec");
+            }
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn parse_error_index_and_display() {
+        let err = ParseError::MissingArgument {
+            index: 3,
+            opt: "i".to_owned(),
+        };
+        assert_eq!(err.index(), Some(3));
+        assert_eq!(err.to_string(), "Missing argument for option 'i'.");
+
+        let err = ParseError::OpenFile {
+            filename: "in.mp4".into(),
+            error: "No such file or directory".to_owned(),
+        };
+        assert_eq!(err.index(), None);
+        assert_eq!(err.to_string(), "in.mp4: No such file or directory");
+    }
+
+    #[test]
+    fn nul_bytes_rejected() {
+        for (args, index, opt) in &[
+            (&["ffcli", "-i", "a\0b", "out"][..], 1, "i"),
+            (
+                &["ffcli", "-i", "in", "-metadata", "title=a\0b", "out"],
+                3,
+                "metadata",
+            ),
+            (&["ffcli", "-i", "in", "-b:v", "1\0", "out"], 3, "b:v"),
+            (&["ffcli", "-i", "in", "out\0.mkv"], 3, ""),
+        ] {
+            match split(args) {
+                Err(ParseError::InvalidArgument {
+                    index: i, opt: o, ..
+                }) => {
+                    assert_eq!((i, o.as_str()), (*index, *opt));
+                }
+                other => panic!("{:?}", other.map(|_| ())),
+            }
+        }
+        match split(&["ffcli", "-c\0v", "copy", "out"]) {
+            Err(ParseError::UnknownOption { index: 1, .. }) => {}
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+        assert!(os_cstring(OsStr::new("a\0b")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_arguments() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let latin1 = |bytes: &[u8]| OsStr::from_bytes(bytes).to_owned();
+        let args = vec![
+            "ffcli".into(),
+            "-i".into(),
+            latin1(b"caf\xe9.mp4"),
+            "-metadata".into(),
+            latin1(b"title=\xe9t\xe9"),
+            latin1(b"caf\xe9.mkv"),
+        ];
+        let octx = split_os(&args).unwrap();
+        let input = &octx.groups[OptGroup::GroupInfile as usize].groups[0];
+        assert_eq!(input.arg, latin1(b"caf\xe9.mp4"));
+        let output = &octx.groups[OptGroup::GroupOutfile as usize].groups[0];
+        assert_eq!(output.arg, latin1(b"caf\xe9.mkv"));
+        assert_eq!(output.opts[0].val, args[4]);
+        assert_eq!(os_cstring(&output.arg).unwrap().as_bytes(), b"caf\xe9.mkv");
+
+        let args = vec!["ffcli".into(), latin1(b"-c:\xe9"), "copy".into()];
+        match split_os(&args) {
+            Err(ParseError::UnknownOption { index: 1, .. }) => {}
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn stream_specifiers_checked_when_splitting() {
+        assert!(split(&[
+            "ffcli",
+            "-i",
+            "in.mp4",
+            "-c:p:1:a:0",
+            "aac",
+            "-metadata:s:a:0",
+            "language=eng",
+            "out.mp4"
+        ])
+        .is_ok());
+        assert!(split(&["ffcli", "-i", "in.mp4", "-b:v", "2M", "out.mp4"]).is_ok());
+
+        match split(&["ffcli", "-i", "in.mp4", "-c:x", "aac", "out.mp4"]) {
+            Err(ParseError::InvalidStreamSpecifier { index, opt, .. }) => {
+                assert_eq!(index, 3);
+                assert_eq!(opt, "c:x");
+            }
+            x => panic!("{:?}", x),
+        }
+        match split(&["ffcli", "-i", "in.mp4", "-b:v:", "2M", "out.mp4"]) {
+            Err(ParseError::InvalidStreamSpecifier { index, .. }) => assert_eq!(index, 3),
+            x => panic!("{:?}", x),
+        }
+        assert!(split(&["ffcli", "-metadata:q", "title=x", "out.mp4"]).is_err());
+    }
+}
+
+#[cfg(test)]
+mod listing_tests {
+    use super::*;
+
+    #[test]
+    fn locate_options() {
+        use crate::options::OPTIONS;
+
+        let args = [
+            "ffcli",
+            "-nohide_banner",
+            "-f",
+            "version",
+            "-i",
+            "in.mkv",
+            "-version",
+        ]
+        .iter()
+        .map(OsString::from)
+        .collect::<Vec<_>>();
+        assert_eq!(locate_option(&args, &*OPTIONS, "hide_banner"), Some(1));
+        // the argument of -f isn't an option
+        assert_eq!(locate_option(&args, &*OPTIONS, "version"), Some(6));
+        assert_eq!(locate_option(&args, &*OPTIONS, "y"), None);
+    }
+
+    #[test]
+    fn print_format_is_set_first() {
+        use crate::options::OPTIONS;
+
+        let args = ["ffcli", "-encoders", "-print_format", "json"]
+            .iter()
+            .map(OsString::from)
+            .collect::<Vec<_>>();
+        let mut session = Session::default();
+        parse_loglevel(&mut session, &args, &*OPTIONS);
+        assert_eq!(session.print_format, PrintFormat::Json);
+    }
+
+    #[test]
+    fn configuration_flags() {
+        assert_eq!(
+            split_configuration(
+                "--prefix=/usr --enable-gpl --pkg-config=pkg-config --static --cc=gcc"
+            ),
+            [
+                "--prefix=/usr",
+                "--enable-gpl",
+                "--pkg-config=pkg-config --static",
+                "--cc=gcc",
+            ]
+        );
+        assert!(split_configuration("").is_empty());
+    }
+
+    #[test]
+    fn sources_and_sinks() {
+        assert!(device_list(false, "")
+            .unwrap()
+            .starts_with("\nDevice name is not provided.\n"));
+        // lavfi is never probed, and there's no such device
+        assert_eq!(device_list(false, "lavfi").unwrap(), "");
+        assert_eq!(device_list(true, "nodevice,opt=1").unwrap(), "");
+    }
+
+    #[test]
+    fn filtered_listing() {
+        let listing = codec_implementations_listing(true, "264");
+        let mut lines = listing.lines().skip_while(|line| !line.starts_with(" ---"));
+        lines.next();
+        assert!(lines.all(|line| line.contains("264")));
+
+        let listing = sample_fmts_listing("s16");
+        assert_eq!(listing.lines().count(), 3);
+        assert!(listing.lines().next().unwrap().starts_with("name"));
+    }
+
+    #[test]
+    fn unfiltered_listing() {
+        assert!(formats_listing(false, ShowMuxDemuxers::Default, "")
+            .lines()
+            .any(|line| line.starts_with(" DE wav ")));
+        assert!(colors_listing("").contains("AliceBlue"));
+        assert!(layouts_listing("").contains("stereo         FL+FR"));
+    }
+
+    #[test]
+    fn json_listing() {
+        assert_eq!(
+            listing(Listing::SampleFmts, "s16p", PrintFormat::Json),
+            r#"{
+  "sample_fmts": [
+    {
+      "name": "s16p",
+      "depth": 16,
+      "planar": true
+    }
+  ]
+}
+"#
+        );
+        let encoders = listing(Listing::Encoders, "pcm_s16le", PrintFormat::Json);
+        assert!(encoders.contains(r#""name": "pcm_s16le""#));
+        assert!(encoders.contains(r#""options": []"#));
+        assert!("yaml".parse::<PrintFormat>().is_err());
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(format_duration(0), "0");
+        assert_eq!(format_duration(1_500_000), "1.5");
+        assert_eq!(format_duration(-90_000_000), "-1:30");
+        assert_eq!(format_duration(3_723_000_001), "1:02:03.000001");
+        assert_eq!(format_duration(i64::MAX), "INT64_MAX");
+        assert_eq!(format_duration(i64::MIN), "INT64_MIN");
+    }
+
+    #[test]
+    fn component_help() {
+        let help = codec_help(Some("pcm_s16le"), true).unwrap();
+        assert!(help.starts_with("Encoder pcm_s16le [PCM signed 16-bit little-endian]:\n"));
+        assert!(help.contains("    Supported sample formats: s16\n"));
+        assert!(codec_help(None, true).is_err());
+        assert!(codec_help(Some("no_such_codec"), false).is_err());
+
+        let help = filter_help(Some("scale")).unwrap();
+        assert!(help.contains("       #0: default (video)\n"));
+        assert!(help.contains("scale AVOptions:\n"));
+        assert!(help.contains("(default \"iw\")"));
+    }
+}
+
+#[cfg(test)]
+mod log_tests {
+    use super::*;
+
+    #[test]
+    fn loglevel_arg() {
+        let skip_repeat = ffi::AV_LOG_SKIP_REPEATED as i32;
+        let print_level = ffi::AV_LOG_PRINT_LEVEL as i32;
+        assert_eq!(
+            parse_loglevel_arg("debug", skip_repeat, 32),
+            Ok((skip_repeat, 48))
+        );
+        assert_eq!(parse_loglevel_arg("quiet", 0, 32), Ok((0, -8)));
+        assert_eq!(parse_loglevel_arg("24", 0, 32), Ok((0, 24)));
+        assert_eq!(
+            parse_loglevel_arg("repeat+level+verbose", skip_repeat, 32),
+            Ok((print_level, 40))
+        );
+        assert_eq!(
+            parse_loglevel_arg("+level", skip_repeat, 32),
+            Ok((skip_repeat | print_level, 32))
+        );
+        assert_eq!(parse_loglevel_arg("-repeat", 0, 32), Ok((skip_repeat, 32)));
+        assert_eq!(
+            parse_loglevel_arg("level", skip_repeat, 32),
+            Ok((print_level, 32))
+        );
+        assert!(parse_loglevel_arg("loud", 0, 32)
+            .unwrap_err()
+            .contains("\"trace\""));
+    }
+
+    #[test]
+    fn repeated_lines() {
+        let mut lines = LogLines::default();
+        let mut push = |part: &str| {
+            lines
+                .push("h264".to_owned(), log::Level::Warn, part, true)
+                .into_iter()
+                .map(|line| line.message)
+                .collect::<Vec<_>>()
+        };
+        assert!(push("no frame ").is_empty());
+        assert_eq!(push("!\n"), ["no frame !"]);
+        assert!(push("no frame !\n").is_empty());
+        assert!(push("no frame !\n").is_empty());
+        assert_eq!(
+            push("done\n"),
+            ["    Last message repeated 2 times", "done"]
+        );
+        assert_eq!(push("\n"), [""]);
+        assert_eq!(push("\n"), [""]);
+    }
+
+    #[test]
+    fn report_settings() {
+        assert_eq!(parse_ffreport(""), Ok((None, None)));
+        assert_eq!(
+            parse_ffreport("file=C\\:/logs/%p.log:level=40"),
+            Ok((Some("C:/logs/%p.log".to_owned()), Some(40)))
+        );
+        assert!(parse_ffreport("level=loud").is_err());
+        assert!(parse_ffreport("file").is_err());
+
+        let filename = expand_report_template("%p-%t.log", "ffcli", "20201018-093000");
+        assert_eq!(filename, "ffcli-20201018-093000.log");
+        assert_eq!(expand_report_template("100%%-%x%", "ffcli", ""), "100%-%x%");
+
+        assert_eq!(quote_argument(OsStr::new("-c:v")), "-c:v");
+        assert_eq!(quote_argument(OsStr::new("my file.mp4")), "\"my file.mp4\"");
+        assert_eq!(
+            quote_argument(OsStr::new("$HOME\u{e9}")),
+            "\"\\$HOME\\xc3\\xa9\""
+        );
+        assert_eq!(quote_argument(OsStr::new("")), "\"\"");
+    }
+}
+
+#[cfg(test)]
+mod argfile_tests {
+    use super::*;
+
+    fn split(content: &str) -> Vec<(String, usize)> {
+        split_argfile(content.as_bytes())
+            .unwrap()
+            .into_iter()
+            .map(|(arg, line)| (String::from_utf8(arg).unwrap(), line))
+            .collect()
+    }
+
+    fn args(args: &[(&str, usize)]) -> Vec<(String, usize)> {
+        args.iter()
+            .map(|&(arg, line)| (arg.to_owned(), line))
+            .collect()
+    }
+
+    #[test]
+    fn quoting_and_comments() {
+        let content = r#"# inputs
+-i 'my input.mp4' \
+    -filter_complex "[0:v]drawtext=text='\$x \"y\"'[v]"  # comment
+-metadata title=a\ b -c:v "" @nested.txt
+-c:a 'multi
+line' done"#;
+        assert_eq!(
+            split(content),
+            args(&[
+                ("-i", 2),
+                ("my input.mp4", 2),
+                ("-filter_complex", 3),
+                ("[0:v]drawtext=text='$x \"y\"'[v]", 3),
+                ("-metadata", 4),
+                ("title=a b", 4),
+                ("-c:v", 4),
+                ("", 4),
+                ("@nested.txt", 4),
+                ("-c:a", 5),
+                ("multi\nline", 5),
+                ("done", 6),
+            ])
+        );
+        assert_eq!(split(" \n# only a comment\n"), args(&[]));
+        assert_eq!(
+            split_argfile(b"-i\n'in.mp4"),
+            Err((2, "Unterminated single quote".to_owned()))
+        );
+    }
+
+    #[test]
+    fn trailing_backslash() {
+        assert_eq!(split("-y \\"), args(&[("-y", 1), ("\\", 1)]));
+        assert_eq!(split("out\\"), args(&[("out\\", 1)]));
+        assert_eq!(split("out \\\n"), args(&[("out", 1)]));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_file() {
+        use std::os::unix::ffi::OsStrExt;
+
+        assert_eq!(
+            split_argfile(b"-i caf\xe9.mp4"),
+            Ok(vec![(b"-i".to_vec(), 1), (b"caf\xe9.mp4".to_vec(), 1)])
+        );
+        let path = env::temp_dir().join(format!("ffcli-latin1-{}.txt", std::process::id()));
+        fs::write(&path, b"-i caf\xe9.mp4\n").unwrap();
+        let args: [OsString; 2] = ["ffcli".into(), format!("@{}", path.display()).into()];
+        let (args, _) = expand_argfiles(&args).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(args[2], OsStr::from_bytes(b"caf\xe9.mp4"));
+    }
+
+    #[test]
+    fn nested_files() {
+        let dir = env::temp_dir().join(format!("ffcli-argfiles-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let write = |name: &str, content: &str| fs::write(dir.join(name), content).unwrap();
+        write("args.txt", "-i in.mp4\n@sub/output.txt\n");
+        write(
+            "sub/output.txt",
+            "-c:v libx264\n-metadata title=@home @@out.mp4\n",
+        );
+        write("loop.txt", "-y\n@sub/loop.txt\n");
+        write("sub/loop.txt", "@../loop.txt\n");
+
+        let args: [OsString; 4] = [
+            "ffcli".into(),
+            format!("@{}", dir.join("args.txt").display()).into(),
+            "-y".into(),
+            "@@not-a-file".into(),
+        ];
+        let (args, locations) = expand_argfiles(&args).unwrap();
+        assert_eq!(
+            args,
+            [
+                "ffcli",
+                "-i",
+                "in.mp4",
+                "-c:v",
+                "libx264",
+                "-metadata",
+                "title=@home",
+                "@out.mp4",
+                "-y",
+                "@not-a-file",
+            ]
+        );
+        assert_eq!(locations[0], None);
+        assert_eq!(locations[9], None);
+        let location = locations[7].as_ref().unwrap();
+        assert!(location.file.ends_with("output.txt"));
+        assert_eq!(location.line, 2);
+
+        let args: [OsString; 2] = [
+            "ffcli".into(),
+            format!("@{}", dir.join("loop.txt").display()).into(),
+        ];
+        match expand_argfiles(&args) {
+            Err(ParseError::ArgFile {
+                location: Some(location),
+                error,
+            }) => {
+                assert!(location.file.ends_with("loop.txt"));
+                assert!(error.ends_with("includes itself"), "{}", error);
+            }
+            other => panic!("{:?}", other),
+        }
+
+        let args: [OsString; 2] = [
+            "ffcli".into(),
+            format!("@{}", dir.join("none.txt").display()).into(),
+        ];
+        match expand_argfiles(&args) {
+            Err(ParseError::ArgFile { location: None, .. }) => {}
+            other => panic!("{:?}", other),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod number_tests {
+    use super::*;
+
+    #[test]
+    fn suffixes() {
+        // `ff_exp10(6)` is an ulp below 10^6 and ffmpeg gets the same.
+        assert_eq!(parse_number_str("2.5M"), Ok(2_499_999.999_999_999_5));
+        assert_ne!(parse_number_str("2.5M"), Ok(2_500_000.));
+        assert_eq!(parse_number_str("2.5k"), Ok(2_500.));
+        assert_eq!(parse_number_str("1Ki"), Ok(1024.));
+        assert_eq!(parse_number_str("1GiB"), Ok(8_589_934_592.));
+        assert_eq!(parse_number_str("0x10k"), Ok(16_000.));
+        assert_eq!(parse_number_str("1.5e3"), Ok(1500.));
+        assert_eq!(parse_number_str("1E"), Ok(9.999_999_999_999_973e17));
+        assert!((parse_number_str("-6dB").unwrap() - 0.501_187).abs() < 1e-6);
+        assert_eq!(parse_number_str("12abc"), Err(NumberError::Invalid));
+        assert_eq!(parse_number_str("k"), Err(NumberError::Invalid));
+        assert_eq!(parse_number_str(""), Err(NumberError::Invalid));
+    }
+
+    #[test]
+    fn ranges() {
+        let int = |arg| {
+            parse_number(
+                1,
+                "r",
+                arg,
+                OptionFlag::OPT_INT,
+                i32::MIN as f64,
+                i32::MAX as f64,
+            )
+        };
+        assert_eq!(int("-2147483648"), Ok(i32::MIN as f64));
+        match int("2147483648") {
+            Err(ParseError::InvalidNumber { error, .. }) => {
+                assert_eq!(error, NumberError::OutOfRange)
+            }
+            other => panic!("{:?}", other),
+        }
+        match int("1.5") {
+            Err(ParseError::InvalidNumber { error, .. }) => {
+                assert_eq!(error, NumberError::NotInteger)
+            }
+            other => panic!("{:?}", other),
+        }
+
+        use crate::options::OPTIONS;
+        let threshold = OPTIONS
+            .iter()
+            .find(|o| o.name == "dts_delta_threshold")
+            .unwrap();
+        let mut session = Session::default();
+        write_option(
+            &mut session,
+            &mut None,
+            threshold,
+            1,
+            "dts_delta_threshold",
+            OsStr::new("2.5k"),
+        )
+        .unwrap();
+        assert_eq!(session.dts_delta_threshold, 2500.);
+        let err = write_option(
+            &mut session,
+            &mut None,
+            threshold,
+            1,
+            "dts_delta_threshold",
+            OsStr::new("1e39"),
+        )
+        .unwrap_err();
+        match err {
+            ParseError::InvalidNumber { error, .. } => assert_eq!(error, NumberError::OutOfRange),
+            other => panic!("{:?}", other),
+        }
+        let err = write_option(
+            &mut session,
+            &mut None,
+            threshold,
+            1,
+            "dts_delta_threshold",
+            OsStr::new("1.5x"),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Expected number for dts_delta_threshold but found: 1.5x"
+        );
+    }
+
+    /// A deterministic corpus of strings glued together from `tokens`.
+    fn corpus(seed: u64, tokens: &[&str], len: usize) -> Vec<String> {
+        let mut state = seed;
+        let mut next = move |n: usize| {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            (state >> 33) as usize % n
+        };
+        (0..len)
+            .map(|_| (0..next(6)).map(|_| tokens[next(tokens.len())]).collect())
+            .collect()
+    }
+
+    #[test]
+    fn same_as_av_strtod() {
+        let numbers = corpus(
+            3,
+            &[
+                "0",
+                "1",
+                "9",
+                "12",
+                "2.5",
+                "0x",
+                "0X",
+                "x",
+                "a",
+                "F",
+                ".",
+                "e",
+                "E",
+                "p",
+                "-",
+                "+",
+                " ",
+                "k",
+                "K",
+                "M",
+                "Gi",
+                "i",
+                "B",
+                "dB",
+                "d",
+                "m",
+                "u",
+                "Y",
+                "inf",
+                "nan",
+                "nan(",
+                "abc)",
+                "infinity",
+                "99999999999999999999",
+                "1e308",
+                "e-400",
+                "0x1p-1074",
+                "_",
+            ],
+            100_000,
+        );
+        for number in &numbers {
+            let numstr = CString::new(number.as_str()).unwrap();
+            let mut tail = ptr::null_mut();
+            let d = unsafe { ffi::av_strtod(numstr.as_ptr(), &mut tail) };
+            let len = tail as usize - numstr.as_ptr() as usize;
+            match av_strtod(number) {
+                Some((n, rest)) => {
+                    assert_eq!(number.len() - rest.len(), len, "{:?}", number);
+                    assert!(
+                        n.to_bits() == d.to_bits() || n.is_nan() && d.is_nan(),
+                        "{:?}: {} != {}",
+                        number,
+                        n,
+                        d
+                    );
+                }
+                None => assert_eq!(len, 0, "{:?}", number),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod time_tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_time_str("1:02:03.5", true), Ok(3_723_500_000));
+        assert_eq!(parse_time_str("12:34", true), Ok(754_000_000));
+        assert_eq!(parse_time_str("-90.25", true), Ok(-90_250_000));
+        assert_eq!(parse_time_str("1500ms", true), Ok(1_500_000));
+        assert_eq!(parse_time_str("2.5ms", true), Ok(2_500));
+        assert_eq!(parse_time_str("12.9us", true), Ok(12));
+        assert_eq!(parse_time_str("3.1234567s", true), Ok(3_123_456));
+        assert_eq!(parse_time_str("1:60", true), Err(TimeError::Invalid));
+        assert_eq!(parse_time_str("1s junk", true), Err(TimeError::Invalid));
+        assert_eq!(parse_time_str("", true), Err(TimeError::Invalid));
+        assert_eq!(
+            parse_time_str("9223372036854775807", true),
+            Err(TimeError::OutOfRange)
+        );
+        assert_eq!(
+            parse_time_str("99999999999999999999us", true),
+            Err(TimeError::OutOfRange)
+        );
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(
+            parse_time_str("2020-01-02T03:04:05Z", false),
+            Ok(1_577_934_245_000_000)
+        );
+        assert_eq!(
+            parse_time_str("20200102 030405.25+01:00", false),
+            Ok(1_577_930_645_250_000)
+        );
+        assert_eq!(
+            parse_time_str("2020 - 1 - 2t03:04:05-0130", false),
+            Ok(1_577_939_645_000_000)
+        );
+        assert_eq!(parse_time_str("2020-01-02", false), Err(TimeError::Invalid));
+        assert_eq!(
+            parse_time_str("2020-01-02T03:04:05+", false),
+            Err(TimeError::Invalid)
+        );
+        let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let now = parse_time_str("NOW", false).unwrap();
+        assert!(now >= before.as_micros() as i64);
+    }
+
+    /// A deterministic corpus of strings glued together from `tokens`.
+    fn corpus(seed: u64, prefixes: &[&str], tokens: &[&str], len: usize) -> Vec<String> {
+        let mut state = seed;
+        let mut next = move |n: usize| {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            (state >> 33) as usize % n
+        };
+        (0..len)
+            .map(|_| {
+                let mut s = prefixes[next(prefixes.len())].to_owned();
+                for _ in 0..next(7) {
+                    s += tokens[next(tokens.len())];
+                }
+                s
+            })
+            .collect()
+    }
+
+    fn av_parse_time(timestr: &str, is_duration: bool) -> Result<i64, TimeError> {
+        let timestr = CString::new(timestr).unwrap();
+        let mut us = 0;
+        match unsafe { ffi::av_parse_time(&mut us, timestr.as_ptr(), is_duration as i32) } {
+            0 => Ok(us),
+            ret if ret == AVERROR(ffi::ERANGE as i32) => Err(TimeError::OutOfRange),
+            ret => {
+                assert_eq!(ret, AVERROR(ffi::EINVAL as i32));
+                Err(TimeError::Invalid)
+            }
+        }
+    }
+
+    #[test]
+    fn same_as_av_parse_time() {
+        let durations = corpus(
+            1,
+            &["", "-", " ", "+", "--"],
+            &[
+                "0",
+                "1",
+                "12",
+                "59",
+                "60",
+                "123",
+                "9999",
+                "10000",
+                "922337203",
+                "6854775807",
+                "808",
+                ":",
+                ":",
+                ".",
+                ".",
+                "-",
+                "+",
+                " ",
+                "s",
+                "ms",
+                "us",
+                "m",
+                "T",
+                "a",
+            ],
+            50_000,
+        );
+        for duration in &durations {
+            assert_eq!(
+                parse_time_str(duration, true),
+                av_parse_time(duration, true),
+                "{:?}",
+                duration
+            );
+        }
+
+        // Without the day the result depends on when the test runs.
+        let dates = corpus(
+            2,
+            &[
+                "2020-01-02",
+                "19991231",
+                "1970-1-1",
+                "2038 - 01 - 19",
+                "0000-02-29",
+                "9999-12-31",
+                "2021-02-31",
+            ],
+            &[
+                "T", "t", " ", "00", "1", "12", "23", "24", "59", "60", "123456", ":", ":", ".",
+                "5", "+", "-", "Z", "z", "01:00", "0530", "x",
+            ],
+            50_000,
+        );
+        for date in &dates {
+            assert_eq!(
+                parse_time_str(date, false),
+                av_parse_time(date, false),
+                "{:?}",
+                date
+            );
+        }
     }
 }
@@ -0,0 +1,565 @@
+//! Shell completion scripts and the man page, generated from the option
+//! table, see `-generate_completions` and `-generate_manpage`.
+use rusty_ffmpeg::ffi;
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::CStr,
+    fmt::Write as _,
+    str::FromStr,
+};
+
+use crate::{
+    cmdutils::{self, Listing, OptionDef, OptionFlag},
+    ffmpeg_opt,
+    options::{GROUPS, OPTIONS},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl FromStr for Shell {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bash" => Ok(Shell::Bash),
+            "zsh" => Ok(Shell::Zsh),
+            "fish" => Ok(Shell::Fish),
+            _ => Err(format!(
+                "Unknown shell '{}', expected 'bash', 'zsh' or 'fish'.",
+                s
+            )),
+        }
+    }
+}
+
+/// What the argument of an option completes to.
+#[derive(Debug, Clone, PartialEq)]
+enum Values {
+    /// The option takes no argument.
+    None,
+    /// Any argument, there is nothing to complete.
+    Any,
+    Files,
+    Words(Vec<String>),
+}
+
+/// An option of the command line, as the completions describe it.
+#[derive(Debug)]
+struct Entry {
+    name: String,
+    argname: String,
+    help: String,
+    /// Takes a stream specifier, e.g. `-c:v`.
+    spec: bool,
+    values: Values,
+}
+
+impl Entry {
+    /// The spellings of the option, with the common stream specifiers when
+    /// it takes one.
+    fn names(&self) -> Vec<String> {
+        let mut names = vec![self.name.clone()];
+        if self.spec {
+            names.extend(
+                SPECIFIERS
+                    .iter()
+                    .map(|spec| format!("{}:{}", self.name, spec)),
+            );
+        }
+        names
+    }
+}
+
+/// The stream specifiers offered for the options taking one.
+const SPECIFIERS: &[&str] = &["v", "a", "s", "d", "t"];
+
+const HELP_TOPICS: &[&str] = &[
+    "long",
+    "full",
+    "decoder=",
+    "encoder=",
+    "demuxer=",
+    "muxer=",
+    "filter=",
+    "bsf=",
+    "protocol=",
+];
+
+fn words(list: &[&str]) -> Values {
+    Values::Words(list.iter().map(|&word| word.to_owned()).collect())
+}
+
+/// Every option of the command line: the option table, the file groups and
+/// the generic codec and format AVOptions.
+fn entries() -> Vec<Entry> {
+    let mut names = HashMap::new();
+    let mut listing = |kinds: &[Listing]| {
+        let mut words = vec![];
+        for &kind in kinds {
+            words.extend(
+                names
+                    .entry(kind)
+                    .or_insert_with(|| cmdutils::listing_names(kind))
+                    .iter()
+                    .cloned(),
+            );
+        }
+        words.sort();
+        words.dedup();
+        Values::Words(words)
+    };
+
+    let mut entries = vec![];
+    for po in OPTIONS.iter() {
+        let values = match po.name {
+            "c" | "codec" | "vcodec" | "acodec" | "scodec" | "dcodec" => {
+                match listing(&[Listing::Encoders, Listing::Decoders]) {
+                    Values::Words(mut codecs) => {
+                        codecs.insert(0, "copy".to_owned());
+                        Values::Words(codecs)
+                    }
+                    values => values,
+                }
+            }
+            "f" => listing(&[Listing::Formats]),
+            "pix_fmt" => listing(&[Listing::PixFmts]),
+            "sample_fmt" => listing(&[Listing::SampleFmts]),
+            "channel_layout" => listing(&[Listing::Layouts]),
+            "vf" | "af" | "filter" | "lavfi" | "filter_complex" => listing(&[Listing::Filters]),
+            "bsf" | "absf" | "vbsf" => listing(&[Listing::Bsfs]),
+            "hwaccel" => listing(&[Listing::HWAccels]),
+            "h" | "?" | "help" | "-help" => words(HELP_TOPICS),
+            "generate_completions" => words(&["bash", "zsh", "fish"]),
+            "print_format" => words(&["default", "json"]),
+            _ => option_values(po),
+        };
+        entries.push(Entry {
+            name: po.name.to_owned(),
+            argname: po.argname.unwrap_or("").to_owned(),
+            help: po.help.to_owned(),
+            spec: po.flags.contains(OptionFlag::OPT_SPEC),
+            values,
+        });
+    }
+
+    for group in GROUPS.iter() {
+        if let Some(sep) = group.sep {
+            entries.push(Entry {
+                name: sep.to_owned(),
+                argname: "url".to_owned(),
+                help: group.name.to_owned(),
+                spec: false,
+                values: Values::Files,
+            });
+        }
+    }
+
+    let mut known = entries
+        .iter()
+        .map(|entry| entry.name.clone())
+        .collect::<HashSet<_>>();
+    unsafe {
+        // codec options can be given per stream, e.g. -b:v
+        entries.extend(avoption_entries(ffi::avcodec_get_class(), true, &mut known));
+        entries.extend(avoption_entries(
+            ffi::avformat_get_class(),
+            false,
+            &mut known,
+        ));
+    }
+    entries
+}
+
+fn option_values(po: &OptionDef) -> Values {
+    if !po.flags.contains(OptionFlag::HAS_ARG) {
+        Values::None
+    } else if po.argname.map_or(false, |argname| argname.contains("file")) {
+        Values::Files
+    } else {
+        Values::Any
+    }
+}
+
+/// The decoding and encoding AVOptions of a class which aren't in `known`,
+/// the named constants of an option are its values.
+unsafe fn avoption_entries(
+    class: *const ffi::AVClass,
+    spec: bool,
+    known: &mut HashSet<String>,
+) -> Vec<Entry> {
+    let flags = ffi::AV_OPT_FLAG_DECODING_PARAM | ffi::AV_OPT_FLAG_ENCODING_PARAM;
+    let options = cmdutils::class_options(class);
+    let mut entries = vec![];
+    for o in options
+        .iter()
+        .filter(|o| o.type_ != ffi::AVOptionType_AV_OPT_TYPE_CONST && o.flags as u32 & flags != 0)
+    {
+        let name = CStr::from_ptr(o.name).to_string_lossy().into_owned();
+        if !known.insert(name.clone()) {
+            continue;
+        }
+        let constants = if o.unit.is_null() {
+            vec![]
+        } else {
+            let unit = CStr::from_ptr(o.unit);
+            options
+                .iter()
+                .filter(|c| {
+                    c.type_ == ffi::AVOptionType_AV_OPT_TYPE_CONST
+                        && !c.unit.is_null()
+                        && CStr::from_ptr(c.unit) == unit
+                })
+                .map(|c| CStr::from_ptr(c.name).to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+        };
+        entries.push(Entry {
+            name,
+            argname: cmdutils::option_type_name(o.type_).to_owned(),
+            help: if o.help.is_null() {
+                String::new()
+            } else {
+                CStr::from_ptr(o.help).to_string_lossy().into_owned()
+            },
+            spec,
+            values: if constants.is_empty() {
+                Values::Any
+            } else {
+                Values::Words(constants)
+            },
+        });
+    }
+    entries
+}
+
+/// The distinct word lists of the entries, so that each one is written once
+/// in a script.
+fn word_lists(entries: &[Entry]) -> Vec<&[String]> {
+    let mut lists: Vec<&[String]> = vec![];
+    for entry in entries {
+        if let Values::Words(words) = &entry.values {
+            if !lists.contains(&words.as_slice()) {
+                lists.push(words);
+            }
+        }
+    }
+    lists
+}
+
+fn word_list_index(lists: &[&[String]], words: &[String]) -> usize {
+    lists.iter().position(|list| *list == words).unwrap()
+}
+
+/// The completion script of `shell` for every option.
+pub fn completions(shell: Shell) -> String {
+    let entries = entries();
+    match shell {
+        Shell::Bash => bash_completions(&entries),
+        Shell::Zsh => zsh_completions(&entries),
+        Shell::Fish => fish_completions(&entries),
+    }
+}
+
+fn bash_completions(entries: &[Entry]) -> String {
+    let lists = word_lists(entries);
+    let mut out = String::from(concat!(
+        "# bash completion for ffcli, generated by `ffcli -generate_completions bash`\n",
+        "\n",
+        "# stream specifiers are part of the option, e.g. -c:v\n",
+        "COMP_WORDBREAKS=${COMP_WORDBREAKS//:}\n",
+        "\n",
+    ));
+    for (i, words) in lists.iter().enumerate() {
+        let _ = writeln!(out, "_ffcli_values_{}=\"{}\"", i, words.join(" "));
+    }
+    let options = entries
+        .iter()
+        .flat_map(|entry| entry.names())
+        .map(|name| format!("-{}", name))
+        .collect::<Vec<_>>();
+    let _ = writeln!(out, "_ffcli_options=\"{}\"", options.join(" "));
+
+    out.push_str(concat!(
+        "\n",
+        "_ffcli() {\n",
+        "    local cur=${COMP_WORDS[COMP_CWORD]} prev=${COMP_WORDS[COMP_CWORD-1]}\n",
+        "    case ${prev%%:*} in\n",
+    ));
+    // group the options completing the same way into one case
+    let mut cases: Vec<(String, Vec<String>)> = vec![];
+    for entry in entries {
+        let action = match &entry.values {
+            Values::None => continue,
+            Values::Any => "COMPREPLY=()".to_owned(),
+            Values::Files => "COMPREPLY=($(compgen -f -- \"$cur\"))".to_owned(),
+            Values::Words(words) => format!(
+                "COMPREPLY=($(compgen -W \"$_ffcli_values_{}\" -- \"$cur\"))",
+                word_list_index(&lists, words)
+            ),
+        };
+        let pattern = format!("'-{}'", entry.name);
+        match cases.iter_mut().find(|(a, _)| *a == action) {
+            Some((_, patterns)) => patterns.push(pattern),
+            None => cases.push((action, vec![pattern])),
+        }
+    }
+    for (action, patterns) in cases {
+        let _ = writeln!(
+            out,
+            "        {})\n            {}\n            return ;;",
+            patterns.join("|"),
+            action
+        );
+    }
+    out.push_str(concat!(
+        "    esac\n",
+        "    if [[ $cur == -* ]]; then\n",
+        "        COMPREPLY=($(compgen -W \"$_ffcli_options\" -- \"$cur\"))\n",
+        "    else\n",
+        "        COMPREPLY=($(compgen -f -- \"$cur\"))\n",
+        "    fi\n",
+        "}\n",
+        "\n",
+        "complete -o filenames -F _ffcli ffcli\n",
+    ));
+    out
+}
+
+/// Escape the `[`, `]` and `:` of an `_arguments` spec, then make it fit in
+/// a single-quoted string.
+fn zsh_escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('[', "\\[")
+        .replace(']', "\\]")
+        .replace(':', "\\:")
+        .replace('\'', "'\\''")
+}
+
+fn zsh_completions(entries: &[Entry]) -> String {
+    let lists = word_lists(entries);
+    let mut out = String::from(concat!(
+        "#compdef ffcli\n",
+        "# zsh completion for ffcli, generated by `ffcli -generate_completions zsh`\n",
+        "\n",
+    ));
+    for (i, words) in lists.iter().enumerate() {
+        let words = words
+            .iter()
+            .map(|word| format!("'{}'", word.replace('\'', "'\\''")))
+            .collect::<Vec<_>>();
+        let _ = writeln!(out, "local -a _ffcli_values_{}=({})", i, words.join(" "));
+    }
+    out.push_str("\n_arguments \\\n");
+    for entry in entries {
+        let argname = if entry.argname.is_empty() {
+            "value".to_owned()
+        } else {
+            zsh_escape(&entry.argname)
+        };
+        let action = match &entry.values {
+            Values::None => String::new(),
+            Values::Any => format!(":{}: ", argname),
+            Values::Files => format!(":{}:_files", argname),
+            Values::Words(words) => format!(
+                ":{}:{{compadd -a _ffcli_values_{}}}",
+                argname,
+                word_list_index(&lists, words)
+            ),
+        };
+        for name in entry.names() {
+            let _ = writeln!(
+                out,
+                "  '*-{}[{}]{}' \\",
+                zsh_escape(&name),
+                zsh_escape(&entry.help),
+                action
+            );
+        }
+    }
+    out.push_str("  '*:output url:_files'\n");
+    out
+}
+
+fn fish_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+fn fish_completions(entries: &[Entry]) -> String {
+    let lists = word_lists(entries);
+    let mut out = String::from(concat!(
+        "# fish completion for ffcli, generated by `ffcli -generate_completions fish`\n",
+        "\n",
+    ));
+    for (i, words) in lists.iter().enumerate() {
+        let words = words
+            .iter()
+            .map(|word| format!("'{}'", fish_escape(word)))
+            .collect::<Vec<_>>();
+        let _ = writeln!(out, "set -g __ffcli_values_{} {}", i, words.join(" "));
+    }
+    out.push('\n');
+    for entry in entries {
+        let arguments = match &entry.values {
+            Values::None => String::new(),
+            Values::Any => " -x".to_owned(),
+            Values::Files => " -r -F".to_owned(),
+            // expanded when completing, so the words aren't evaluated
+            Values::Words(words) => format!(
+                " -x -a '$__ffcli_values_{}'",
+                word_list_index(&lists, words)
+            ),
+        };
+        for name in entry.names() {
+            let _ = writeln!(
+                out,
+                "complete -c ffcli -o '{}' -d '{}'{}",
+                fish_escape(&name),
+                fish_escape(&entry.help),
+                arguments
+            );
+        }
+    }
+    out
+}
+
+/// Escape the text of a roff line.
+fn roff_escape(s: &str) -> String {
+    let s = s.replace('\\', "\\e").replace('-', "\\-");
+    // a leading dot or quote would make it a request
+    if s.starts_with('.') || s.starts_with('\'') {
+        format!("\\&{}", s)
+    } else {
+        s
+    }
+}
+
+/// A `.TP` paragraph describing an option.
+fn manpage_option(out: &mut String, entry: &Entry) {
+    let _ = write!(out, ".TP\n\\fB\\-{}\\fR", roff_escape(&entry.name));
+    if entry.spec {
+        out.push_str("[\\fB:\\fR\\fIstream_specifier\\fR]");
+    }
+    if !entry.argname.is_empty() && entry.values != Values::None {
+        let _ = write!(out, " \\fI{}\\fR", roff_escape(&entry.argname));
+    }
+    let _ = writeln!(out, "\n{}", roff_escape(&entry.help));
+    if let Values::Words(words) = &entry.values {
+        // the listing options already tell about the codecs, formats...
+        if words.len() <= 32 {
+            let _ = writeln!(
+                out,
+                ".br\nPossible values: {}.",
+                roff_escape(&words.join(", "))
+            );
+        }
+    }
+}
+
+/// The man page of ffcli, in roff.
+pub fn manpage() -> String {
+    let entries = entries();
+    let entry = |name: &str| entries.iter().find(|entry| entry.name == name).unwrap();
+    let mut out = String::from(concat!(
+        ".TH FFCLI 1 \"\" \"ffcli\" \"User Commands\"\n",
+        ".SH NAME\n",
+        "ffcli \\- Hyper fast Audio and Video encoder\n",
+        ".SH SYNOPSIS\n",
+        ".B ffcli\n",
+        "[\\fIoptions\\fR] [[\\fIinfile options\\fR] \\fB\\-i\\fR \\fIinfile\\fR]... ",
+        "{[\\fIoutfile options\\fR] \\fIoutfile\\fR}...\n",
+        ".SH DESCRIPTION\n",
+        "\\fBffcli\\fR reads any number of input files, each given with \\fB\\-i\\fR, ",
+        "and writes any number of output files, each given as a plain url.\n",
+        "The options before a file apply to it, except the global options which apply ",
+        "to the whole program.\n",
+        ".PP\n",
+        "The options marked with [\\fB:\\fR\\fIstream_specifier\\fR] apply to the matching ",
+        "streams only, e.g. \\fB\\-c:v\\fR sets the codec of the video streams.\n",
+        ".SH OPTIONS\n",
+    ));
+
+    out.push_str(".SS \"Input and output files\"\n");
+    for group in GROUPS.iter() {
+        if let Some(sep) = group.sep {
+            manpage_option(&mut out, entry(sep));
+        }
+    }
+    for section in ffmpeg_opt::help_sections() {
+        let options = OPTIONS
+            .iter()
+            .filter(|po| section.matches(po))
+            .collect::<Vec<_>>();
+        if options.is_empty() {
+            continue;
+        }
+        let _ = writeln!(out, ".SS \"{}\"", roff_escape(section.title));
+        for po in options {
+            manpage_option(&mut out, entry(po.name));
+        }
+    }
+
+    out.push_str(concat!(
+        ".SH \"CODEC AND FORMAT OPTIONS\"\n",
+        "The generic options of the codecs and of the formats, given like the options ",
+        "above. Each codec, format or filter has private options too, see ",
+        "\\fB\\-h\\fR \\fItype\\fR=\\fIname\\fR.\n",
+    ));
+    let known = OPTIONS
+        .iter()
+        .map(|po| po.name)
+        .chain(GROUPS.iter().filter_map(|group| group.sep))
+        .collect::<HashSet<_>>();
+    for entry in entries
+        .iter()
+        .filter(|entry| !known.contains(entry.name.as_str()))
+    {
+        manpage_option(&mut out, entry);
+    }
+
+    out.push_str(".SH \"SEE ALSO\"\nffmpeg(1)\n");
+    out
+}
+
+#[cfg(test)]
+mod completions_tests {
+    use super::*;
+
+    #[test]
+    fn escape() {
+        assert_eq!(roff_escape("-c:v copy"), "\\-c:v copy");
+        assert_eq!(roff_escape(".hidden \\n"), "\\&.hidden \\en");
+        assert_eq!(zsh_escape("set [a:b] 'x'"), "set \\[a\\:b\\] '\\''x'\\''");
+        assert_eq!(fish_escape("it's"), "it\\'s");
+    }
+
+    #[test]
+    fn option_entries() {
+        let entries = entries();
+        let codec = entries.iter().find(|entry| entry.name == "c").unwrap();
+        assert!(codec.spec);
+        assert_eq!(codec.names()[1], "c:v");
+        match &codec.values {
+            Values::Words(codecs) => assert!(codecs.iter().any(|codec| codec == "copy")),
+            values => panic!("unexpected values {:?}", values),
+        }
+        let y = entries.iter().find(|entry| entry.name == "y").unwrap();
+        assert_eq!(y.values, Values::None);
+        let input = entries.iter().find(|entry| entry.name == "i").unwrap();
+        assert_eq!(input.values, Values::Files);
+        // generic codec options come after the option table
+        assert!(entries.iter().any(|entry| entry.name == "threads"));
+    }
+
+    #[test]
+    fn bash_script() {
+        let script = completions(Shell::Bash);
+        assert!(script.contains(" -c:v "));
+        assert!(script.contains("'-i')\n            COMPREPLY=($(compgen -f -- \"$cur\"))"));
+        assert!(script.ends_with("complete -o filenames -F _ffcli ffcli\n"));
+    }
+}
@@ -0,0 +1,205 @@
+//! This file corresponds to libavutil/dict.\[ch\]
+//!
+//! An owned replacement of `AVDictionary`, so option groups can be cloned and
+//! compared safely. It's converted to an `AVDictionary` only when calling into
+//! libav.
+use bitflags::bitflags;
+use rusty_ffmpeg::ffi;
+
+use std::{
+    ffi::{CStr, CString},
+    iter::FromIterator,
+    ptr, slice,
+};
+
+bitflags! {
+    /// Same values as the `AV_DICT_*` flags.
+    #[derive(Default)]
+    pub struct DictFlag: u32 {
+        const NONE           = 0;
+        /// Only get an entry with exact-case key match.
+        const MATCH_CASE     = 1;
+        /// Don't overwrite existing entries.
+        const DONT_OVERWRITE = 16;
+        /// If the entry already exists, append to it.
+        const APPEND         = 32;
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Dictionary {
+    entries: Vec<(String, String)>,
+}
+
+impl Dictionary {
+    pub fn new() -> Self {
+        Self { entries: vec![] }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn position(&self, key: &str, flags: DictFlag) -> Option<usize> {
+        self.entries.iter().position(|(k, _)| {
+            if flags.contains(DictFlag::MATCH_CASE) {
+                k == key
+            } else {
+                k.eq_ignore_ascii_case(key)
+            }
+        })
+    }
+
+    /// Get the value of `key`, keys are matched case-insensitively like
+    /// `av_dict_get()` without `AV_DICT_MATCH_CASE`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.position(key, DictFlag::NONE)
+            .map(|i| self.entries[i].1.as_str())
+    }
+
+    /// Equivalent of `av_dict_set()` with a non-null value. An existing entry
+    /// keeps its position.
+    pub fn set(&mut self, key: &str, value: &str, flags: DictFlag) {
+        match self.position(key, flags) {
+            Some(_) if flags.contains(DictFlag::DONT_OVERWRITE) => {}
+            Some(i) if flags.contains(DictFlag::APPEND) => self.entries[i].1.push_str(value),
+            Some(i) => self.entries[i].1 = value.to_owned(),
+            None => self.entries.push((key.to_owned(), value.to_owned())),
+        }
+    }
+
+    /// Equivalent of `av_dict_set()` with a null value.
+    pub fn remove(&mut self, key: &str, flags: DictFlag) -> Option<String> {
+        self.position(key, flags)
+            .map(|i| self.entries.remove(i).1)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// Build an `AVDictionary`, the caller owns it and needs to free it with
+    /// `av_dict_free()`. Returns null when empty, as libav does.
+    pub fn to_av_dict(&self) -> *mut ffi::AVDictionary {
+        let mut dict = ptr::null_mut();
+        for (key, value) in self.iter() {
+            let key = CString::new(key).unwrap();
+            let value = CString::new(value).unwrap();
+            unsafe { ffi::av_dict_set(&mut dict, key.as_ptr(), value.as_ptr(), 0) };
+        }
+        dict
+    }
+
+    /// Copy all entries of an `AVDictionary` out in iteration order.
+    pub unsafe fn from_av_dict(dict: *const ffi::AVDictionary) -> Self {
+        let mut entries = vec![];
+        let mut t = ptr::null();
+        let empty = CString::new("").unwrap();
+        loop {
+            t = ffi::av_dict_get(dict, empty.as_ptr(), t, ffi::AV_DICT_IGNORE_SUFFIX as i32);
+            match t.as_ref() {
+                Some(t) => entries.push((
+                    CStr::from_ptr(t.key).to_string_lossy().into_owned(),
+                    CStr::from_ptr(t.value).to_string_lossy().into_owned(),
+                )),
+                None => break,
+            }
+        }
+        Self { entries }
+    }
+
+    /// Build an array of `AVDictionary` for `avformat_find_stream_info()`,
+    /// free it with `free_av_dict_array()`.
+    pub fn to_av_dict_array(dicts: &[Dictionary]) -> *mut *mut ffi::AVDictionary {
+        if dicts.is_empty() {
+            return ptr::null_mut();
+        }
+        let mut array: Box<[*mut ffi::AVDictionary]> =
+            dicts.iter().map(Dictionary::to_av_dict).collect();
+        let ptr = array.as_mut_ptr();
+        std::mem::forget(array);
+        ptr
+    }
+
+    /// Free an array built by `to_av_dict_array()`, `len` must be the length
+    /// of the slice it was built from.
+    pub unsafe fn free_av_dict_array(array: *mut *mut ffi::AVDictionary, len: usize) {
+        if array.is_null() {
+            return;
+        }
+        let mut array = Box::from_raw(slice::from_raw_parts_mut(array, len) as *mut [_]);
+        for dict in array.iter_mut() {
+            ffi::av_dict_free(dict);
+        }
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for Dictionary {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut dict = Dictionary::new();
+        for (key, value) in iter {
+            dict.set(&key.into(), &value.into(), DictFlag::NONE);
+        }
+        dict
+    }
+}
+
+#[cfg(test)]
+mod dict_tests {
+    use super::*;
+
+    #[test]
+    fn set_and_get() {
+        let mut dict = Dictionary::new();
+        dict.set("b:v", "2M", DictFlag::NONE);
+        dict.set("preset", "fast", DictFlag::NONE);
+        dict.set("B:V", "3M", DictFlag::NONE);
+        assert_eq!(dict.get("b:v"), Some("3M"));
+        assert_eq!(dict.len(), 2);
+        assert_eq!(
+            dict.iter().collect::<Vec<_>>(),
+            vec![("b:v", "3M"), ("preset", "fast")]
+        );
+    }
+
+    #[test]
+    fn append_and_dont_overwrite() {
+        let mut dict = Dictionary::new();
+        dict.set("flags", "+global_header", DictFlag::APPEND);
+        dict.set("flags", "-loop", DictFlag::APPEND);
+        assert_eq!(dict.get("flags"), Some("+global_header-loop"));
+
+        dict.set("flags", "bicubic", DictFlag::DONT_OVERWRITE);
+        assert_eq!(dict.get("flags"), Some("+global_header-loop"));
+    }
+
+    #[test]
+    fn remove_and_compare() {
+        let mut a: Dictionary = vec![("scan_all_pmts", "1"), ("probesize", "32")]
+            .into_iter()
+            .collect();
+        let b: Dictionary = vec![("probesize", "32")].into_iter().collect();
+        assert_ne!(a, b);
+        assert_eq!(a.remove("SCAN_ALL_PMTS", DictFlag::MATCH_CASE), None);
+        assert_eq!(
+            a.remove("scan_all_pmts", DictFlag::MATCH_CASE),
+            Some("1".to_owned())
+        );
+        assert_eq!(a, b);
+        assert_eq!(a.clone(), b);
+    }
+
+    #[test]
+    fn av_dict_round_trip() {
+        let dict: Dictionary = vec![("b:v", "2M"), ("g", "250")].into_iter().collect();
+        let mut av_dict = dict.to_av_dict();
+        let copied = unsafe { Dictionary::from_av_dict(av_dict) };
+        unsafe { ffi::av_dict_free(&mut av_dict) };
+        assert_eq!(copied, dict);
+        assert!(Dictionary::new().to_av_dict().is_null());
+    }
+}
@@ -1,70 +1,118 @@
 //! This file corresponds to ffmpeg.\[ch\]
-use log::{debug, error, info};
-use once_cell::sync::Lazy;
-use rusty_ffmpeg::{avutil::avutils::*, ffi};
+use log::{debug, error, info, warn};
+use rusty_ffmpeg::{
+    avutil::{avutils::*, error::*},
+    ffi,
+};
 
 use std::{
+    collections::VecDeque,
     env,
-    ffi::{CStr, CString},
-    ptr,
-    sync::Mutex,
+    ffi::{CStr, CString, OsString},
+    fs, ptr,
+    sync::atomic::{AtomicIsize, Ordering},
+    thread,
+    time::Duration,
 };
 
 use crate::{
-    cmdutils::{OptionGroup, SpecifierOpt},
+    cmdutils::{self, OptionGroup, ParseError, PrintFormat, SpecifierOpt},
+    dict::{DictFlag, Dictionary},
+    ffmpeg_filter::{self, FilterGraph},
     ffmpeg_opt,
+    options::OPTIONS,
+    stream_specifier::StreamInfo,
 };
 
 use ffmpeg_opt::ffmpeg_parse_options;
 
-static RECEIVED_NB_SIGNALS: Lazy<Mutex<isize>> = Lazy::new(|| Mutex::new(0));
-static TRANSCODE_INIT_DONE: Lazy<Mutex<isize>> = Lazy::new(|| Mutex::new(0));
+static RECEIVED_SIGTERM: AtomicIsize = AtomicIsize::new(0);
+static RECEIVED_NB_SIGNALS: AtomicIsize = AtomicIsize::new(0);
+static TRANSCODE_INIT_DONE: AtomicIsize = AtomicIsize::new(0);
 
-unsafe extern "C" fn decodec_interrupt_cb(_ctx: *mut libc::c_void) -> libc::c_int {
-    let received_nb_signals: &isize = &RECEIVED_NB_SIGNALS.lock().unwrap();
-    let transcode_init_done: &isize = &TRANSCODE_INIT_DONE.lock().unwrap();
-    if received_nb_signals > transcode_init_done {
-        1
-    } else {
-        0
+extern "C" fn sigterm_handler(sig: libc::c_int) {
+    RECEIVED_SIGTERM.store(sig as isize, Ordering::SeqCst);
+    let received_nb_signals = RECEIVED_NB_SIGNALS.fetch_add(1, Ordering::SeqCst) + 1;
+    if received_nb_signals > 3 {
+        let msg = b"Received > 3 system signals, hard exiting\n";
+        unsafe {
+            libc::write(libc::STDERR_FILENO, msg.as_ptr() as *const _, msg.len());
+            libc::_exit(123);
+        }
+    }
+}
+
+/// Install the signal handlers: the first signal stops the transcoding
+/// gracefully, the next ones interrupt blocking I/O.
+pub fn term_init() {
+    let handler = sigterm_handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler); // Interrupt (ANSI).
+        libc::signal(libc::SIGTERM, handler); // Termination (ANSI).
+        libc::signal(libc::SIGQUIT, handler); // Quit (POSIX).
+        libc::signal(libc::SIGXCPU, handler);
+        libc::signal(libc::SIGPIPE, libc::SIG_IGN); // Broken pipe (POSIX).
     }
 }
 
+/// The last signal received, `0` if none.
+fn received_sigterm() -> isize {
+    RECEIVED_SIGTERM.load(Ordering::SeqCst)
+}
+
+unsafe extern "C" fn decodec_interrupt_cb(_ctx: *mut libc::c_void) -> libc::c_int {
+    let received_nb_signals = RECEIVED_NB_SIGNALS.load(Ordering::SeqCst);
+    let transcode_init_done = TRANSCODE_INIT_DONE.load(Ordering::SeqCst);
+    (received_nb_signals > transcode_init_done) as libc::c_int
+}
+
 pub const INT_CB: ffi::AVIOInterruptCB = ffi::AVIOInterruptCB {
     callback: Some(decodec_interrupt_cb),
     opaque: ptr::null_mut(),
 };
 
-pub unsafe fn remove_avoptions(a: &mut *mut ffi::AVDictionary, b: *mut ffi::AVDictionary) {
-    let mut t = ptr::null();
-    let empty = CString::new("").unwrap();
-
-    loop {
-        t = ffi::av_dict_get(b, empty.as_ptr(), t, ffi::AV_DICT_IGNORE_SUFFIX as i32);
-        if let Some(t) = t.as_ref() {
-            ffi::av_dict_set(a, t.key, ptr::null(), ffi::AV_DICT_MATCH_CASE as i32);
-        } else {
-            break;
-        }
+pub fn remove_avoptions(a: &mut Dictionary, b: &Dictionary) {
+    for (key, _) in b.iter() {
+        a.remove(key, DictFlag::MATCH_CASE);
     }
 }
 
-pub unsafe fn assert_avoptions(a: *mut ffi::AVDictionary) {
-    let empty = CString::new("").unwrap();
-    if let Some(t) = ffi::av_dict_get(
-        a,
-        empty.as_ptr(),
-        ptr::null(),
-        ffi::AV_DICT_IGNORE_SUFFIX as i32,
-    )
-    .as_ref()
-    {
-        let t_key = CStr::from_ptr(t.key);
-        error!("Option {} not found.", t_key.to_string_lossy());
+pub fn assert_avoptions(a: &Dictionary) {
+    if let Some((key, _)) = a.iter().next() {
+        error!("Option {} not found.", key);
     };
 }
 
-#[derive(Debug, Default)]
+/// Set the default layout for the channel count of the decoder when it's
+/// unknown, unless there are more channels than `-guess_layout_max`.
+pub unsafe fn guess_input_channel_layout(ist: &mut InputStream) -> bool {
+    let dec = ist.dec_ctx.as_mut().unwrap();
+    if dec.channel_layout == 0 {
+        if dec.channels as isize > ist.guess_layout_max {
+            return false;
+        }
+        dec.channel_layout = ffi::av_get_default_channel_layout(dec.channels) as u64;
+        if dec.channel_layout == 0 {
+            return false;
+        }
+        let mut layout_name = [0 as libc::c_char; 256];
+        ffi::av_get_channel_layout_string(
+            layout_name.as_mut_ptr(),
+            layout_name.len() as _,
+            dec.channels,
+            dec.channel_layout,
+        );
+        error!(
+            "Guessed Channel Layout for Input Stream #{}.{} : {}",
+            ist.file_index,
+            (*ist.st).index,
+            CStr::from_ptr(layout_name.as_ptr()).to_string_lossy()
+        );
+    }
+    true
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct StreamMap {
     pub disabled: isize,
     pub file_index: isize,
@@ -74,7 +122,217 @@ pub struct StreamMap {
     pub linklabel: String,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HWAccelID {
+    None,
+    Auto,
+    /// A device type of `av_hwdevice_find_type_by_name()`.
+    Generic(ffi::AVHWDeviceType),
+}
+
+#[derive(Debug)]
+pub struct InputStream {
+    pub file_index: usize,
+    pub st: *mut ffi::AVStream,
+    /// `true` if stream data should be discarded.
+    pub discard: bool,
+    /// Discard level set by `-discard` or `-vn`/`-an`/`-sn`/`-dn`.
+    pub user_set_discard: ffi::AVDiscard,
+    pub decoding_needed: isize,
+    pub dec_ctx: *mut ffi::AVCodecContext,
+    pub dec: *mut ffi::AVCodec,
+    pub decoder_opts: Dictionary,
+
+    /// `av_gettime_relative()` when the transcoding started, for `-re`.
+    pub start: i64,
+    /// dts of the last packet read, in `AV_TIME_BASE`.
+    pub dts: i64,
+    pub min_pts: i64,
+    pub max_pts: i64,
+    pub nb_samples: i64,
+    pub filter_in_rescale_delta_last: i64,
+
+    pub ts_scale: f64,
+    /// Set by `-r`, `{0, 0}` if not given.
+    pub framerate: ffi::AVRational,
+    pub top_field_first: isize,
+    pub guess_layout_max: isize,
+    pub autorotate: isize,
+    pub fix_sub_duration: isize,
+    pub reinit_filters: isize,
+
+    pub hwaccel_id: HWAccelID,
+    pub hwaccel_device: String,
+    pub hwaccel_output_format: ffi::AVPixelFormat,
+    pub hwaccel_pix_fmt: ffi::AVPixelFormat,
+
+    /// `(filtergraph, input)` indexes of the filtergraph inputs this stream
+    /// feeds.
+    pub filters: Vec<(usize, usize)>,
+
+    /// Whether a frame was decoded.
+    pub got_output: bool,
+    /// Next pts of a video stream with a forced `-r`.
+    pub cfr_next_pts: i64,
+}
+
+/// An opened input file.
+#[derive(Debug)]
+pub struct InputFile {
+    pub filename: OsString,
+    pub ctx: *mut ffi::AVFormatContext,
+    /// Every stream of the file, in order.
+    pub streams: Vec<StreamInfo>,
+    /// Index of the first stream of this file in `Session::input_streams`.
+    pub ist_index: usize,
+    pub start_time: i64,
+    pub recording_time: i64,
+    /// Set by `-itsoffset`.
+    pub input_ts_offset: i64,
+    pub ts_offset: i64,
+    /// Set by `-stream_loop`.
+    pub loops: isize,
+    pub duration: i64,
+    pub time_base: ffi::AVRational,
+    /// Set by `-re`.
+    pub rate_emu: isize,
+    pub accurate_seek: isize,
+    pub thread_queue_size: isize,
+
+    pub eof_reached: bool,
+    /// The demuxer had no packet ready.
+    pub eagain: bool,
+}
+
+impl Default for InputFile {
+    fn default() -> Self {
+        Self {
+            filename: OsString::new(),
+            ctx: ptr::null_mut(),
+            streams: vec![],
+            ist_index: 0,
+            start_time: AV_NOPTS_VALUE,
+            recording_time: i64::MAX,
+            input_ts_offset: 0,
+            ts_offset: 0,
+            loops: 0,
+            duration: 0,
+            time_base: ffi::AVRational { num: 1, den: 1 },
+            rate_emu: 0,
+            accurate_seek: 1,
+            thread_queue_size: 8,
+            eof_reached: false,
+            eagain: false,
+        }
+    }
+}
+
+pub const DECODING_FOR_OST: isize = 1;
+pub const DECODING_FOR_FILTER: isize = 2;
+
+/// An output stream, configured before the transcoding starts.
+#[derive(Debug)]
+pub struct OutputStream {
+    pub file_index: usize,
+    /// Index in the output file.
+    pub index: usize,
+    /// Index in `Session::input_streams`, `None` for attachments and
+    /// filtergraph outputs.
+    pub source_index: Option<usize>,
+    /// Index in `Session::input_streams` of the stream used for sync.
+    pub sync_ist: Option<usize>,
+    pub st: *mut ffi::AVStream,
+    pub enc_ctx: *mut ffi::AVCodecContext,
+    /// `null` for stream copy.
+    pub enc: *mut ffi::AVCodec,
+    pub encoder_opts: Dictionary,
+    pub stream_copy: bool,
+    pub encoding_needed: bool,
+    /// Set by `-frames`.
+    pub max_frames: i64,
+    /// Set by `-r`, `{0, 0}` if not given.
+    pub frame_rate: ffi::AVRational,
+    pub force_fps: isize,
+    /// Set by `-aspect`, `{0, 0}` if not given.
+    pub frame_aspect_ratio: ffi::AVRational,
+    /// Set by `-enc_time_base`, `{0, 0}` if not given.
+    pub enc_timebase: ffi::AVRational,
+    pub top_field_first: isize,
+    pub forced_keyframes: String,
+    /// `-pix_fmt` with a leading `+`.
+    pub keep_pix_fmt: bool,
+    pub copy_initial_nonkeyframes: isize,
+    pub copy_prior_start: isize,
+    pub bitstream_filters: String,
+    /// In packets.
+    pub max_muxing_queue_size: isize,
+
+    /// `(filtergraph, output)` indexes of the filtergraph output feeding this
+    /// stream.
+    pub filter: Option<(usize, usize)>,
+    /// Description of the simple filtergraph, from `-filter` or
+    /// `-filter_script`.
+    pub avfilter: String,
+    pub apad: String,
+    /// Scaler and resampler options of the simple filtergraph.
+    pub sws_dict: Dictionary,
+    pub swr_opts: Dictionary,
+    pub resample_opts: Dictionary,
+    /// Channels picked by `-map_channel`, `-1` for a muted channel.
+    pub audio_channels_map: Vec<isize>,
+    /// Set by `-disposition`, applied when the muxer is initialized.
+    pub disposition: String,
+    /// The file attached by `-attach`.
+    pub attachment_filename: String,
+
+    /// The encoder or stream copy is set up.
+    pub initialized: bool,
+    /// No more packets will be written.
+    pub finished: bool,
+    /// The inputs of this stream have no packet ready.
+    pub unavailable: bool,
+    /// The inputs of this stream reached EOF before its filtergraph could
+    /// be configured.
+    pub inputs_done: bool,
+    /// Frames encoded, or packets written for audio and stream copy.
+    pub frame_number: i64,
+    /// Packets passed to the muxer.
+    pub packets_written: u64,
+    /// Pts of the next encoded frame, in the encoder time base.
+    pub sync_opts: i64,
+    /// In the stream time base.
+    pub last_mux_dts: i64,
+    /// Time base of the packets passed to the muxer.
+    pub mux_timebase: ffi::AVRational,
+    /// Set by `-bsf`.
+    pub bsf_ctx: *mut ffi::AVBSFContext,
+    /// Packets received before the header of the output file is written.
+    pub muxing_queue: VecDeque<*mut ffi::AVPacket>,
+}
+
+/// An output file whose streams are configured but not yet written.
+#[derive(Debug)]
+pub struct OutputFile {
+    pub filename: OsString,
+    pub ctx: *mut ffi::AVFormatContext,
+    /// Muxer options, used when writing the header.
+    pub opts: Dictionary,
+    /// Index of the first stream of this file in `Session::output_streams`.
+    pub ost_index: usize,
+    /// Set by `-t` or `-to`.
+    pub recording_time: i64,
+    /// Set by `-ss`.
+    pub start_time: i64,
+    /// Set by `-fs`.
+    pub limit_filesize: u64,
+    /// Set by `-shortest`.
+    pub shortest: isize,
+    pub header_written: bool,
+}
+
+/// One `-map_channel`, a muted channel has `-1` as input indexes, and `-1`
+/// output indexes apply it to every output audio stream.
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct AudioChannelMap {
     // input
     pub file_idx: isize,
@@ -118,10 +376,8 @@ pub struct OptionsContext<'a, 'group> {
 
     // output options
     pub stream_maps: Vec<StreamMap>,
-    // ATTENTION here does the nb_audio* is the length of the audio* array?
-    // I'm not sure. Currently I assume they are the same. If not we need to a a integer here.
-    // AudioChannelMap *audio_channel_maps; /* one info entry per -map_channel */
-    // int           nb_audio_channel_maps; /* number of (valid) -map_channel settings */
+    // nb_audio_channel_maps is the length, -map_channel with a trailing `?`
+    // and an invalid channel is not added.
     pub audio_channel_maps: Vec<AudioChannelMap>,
     pub metadata_global_manual: isize,
     pub metadata_streams_manual: isize,
@@ -277,9 +533,2530 @@ impl<'a, 'group> OptionsContext<'a, 'group> {
     }
 }
 
-pub fn ffmpeg() {
-    // TODO: May need to change to Vec<u8> for non-UTF8 args.
-    let args: Vec<String> = env::args().collect();
+pub const VSYNC_AUTO: isize = -1;
+pub const VSYNC_PASSTHROUGH: isize = 0;
+pub const VSYNC_CFR: isize = 1;
+pub const VSYNC_VFR: isize = 2;
+pub const VSYNC_VSCFR: isize = 0xfe;
+pub const VSYNC_DROP: isize = 0xff;
+
+pub const ABORT_ON_FLAG_EMPTY_OUTPUT: isize = 1 << 0;
+pub const ABORT_ON_FLAG_EMPTY_OUTPUT_STREAM: isize = 1 << 1;
+
+/// State that FFmpeg keeps in global variables (mostly in ffmpeg_opt.c).
+///
+/// One `Session` is created per command line, so several command lines can
+/// be parsed concurrently. Options without `OPT_OFFSET` or `OPT_SPEC` are
+/// written here, and it's passed to every `func_arg` callback.
+#[derive(Debug)]
+pub struct Session {
+    // In ffmpeg.h
+    pub videotoolbox_pixfmt: String,
+    pub input_streams: Vec<InputStream>,
+    pub input_files: Vec<InputFile>,
+    pub output_streams: Vec<OutputStream>,
+    pub output_files: Vec<OutputFile>,
+    pub filtergraphs: Vec<FilterGraph>,
+
+    // In cmdutils.c
+    pub hide_banner: isize,
+    pub print_format: PrintFormat,
+
+    // In ffmpeg.c
+    pub main_return_code: i32,
+
+    // In ffmpeg_qsv.c
+    pub qsv_device: String,
+
+    // In ffmpeg_opt.c
+    pub intra_only: isize,
+    pub file_overwrite: isize,
+    pub no_file_overwrite: isize,
+    pub do_psnr: isize,
+    pub input_sync: isize,
+    pub input_stream_potentially_available: isize,
+    pub ignore_unknown_streams: isize,
+    pub copy_unknown_streams: isize,
+    pub find_stream_info: isize,
+
+    pub audio_drift_threshold: f32,
+    pub dts_delta_threshold: f32,
+    pub dts_error_threshold: f32,
+
+    pub audio_volume: isize,
+    pub audio_sync_method: isize,
+    pub video_sync_method: isize,
+    pub frame_drop_threshold: f32,
+    pub do_deinterlace: isize,
+    pub do_benchmark: isize,
+    pub do_benchmark_all: isize,
+    pub do_hex_dump: isize,
+    pub do_pkt_dump: isize,
+    pub copy_ts: isize,
+    pub start_at_zero: isize,
+    pub copy_tb: isize,
+    pub debug_ts: isize,
+    pub exit_on_error: isize,
+    pub abort_on_flags: isize,
+    pub print_stats: isize,
+    pub qp_hist: isize,
+    pub stdin_interaction: isize,
+    pub frame_bits_per_raw_sample: isize,
+    pub max_error_rate: f32,
+    pub filter_nbthreads: isize,
+    pub filter_complex_nbthreads: isize,
+    pub vstats_version: isize,
+    pub sdp_filename: String,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self {
+            videotoolbox_pixfmt: String::new(),
+            input_streams: vec![],
+            input_files: vec![],
+            output_streams: vec![],
+            output_files: vec![],
+            filtergraphs: vec![],
+            hide_banner: 0,
+            print_format: PrintFormat::Default,
+            main_return_code: 0,
+            qsv_device: String::new(),
+
+            intra_only: 0,
+            file_overwrite: 0,
+            no_file_overwrite: 0,
+            do_psnr: 0,
+            input_sync: 0,
+            input_stream_potentially_available: 0,
+            ignore_unknown_streams: 0,
+            copy_unknown_streams: 0,
+            find_stream_info: 1,
+
+            audio_drift_threshold: 0.1,
+            dts_delta_threshold: 10.,
+            dts_error_threshold: 3600. * 30.,
+
+            audio_volume: 256,
+            audio_sync_method: 0,
+            video_sync_method: VSYNC_AUTO,
+            frame_drop_threshold: 0.,
+            do_deinterlace: 0,
+            do_benchmark: 0,
+            do_benchmark_all: 0,
+            do_hex_dump: 0,
+            do_pkt_dump: 0,
+            copy_ts: 0,
+            start_at_zero: 0,
+            copy_tb: -1,
+            debug_ts: 0,
+            exit_on_error: 0,
+            abort_on_flags: 0,
+            print_stats: -1,
+            qp_hist: 0,
+            stdin_interaction: 1,
+            frame_bits_per_raw_sample: 0,
+            max_error_rate: 2. / 3.,
+            filter_nbthreads: 0,
+            filter_complex_nbthreads: 0,
+            vstats_version: 2,
+            sdp_filename: String::new(),
+        }
+    }
+}
+
+pub const AV_TIME_BASE_Q: ffi::AVRational = ffi::AVRational {
+    num: 1,
+    den: ffi::AV_TIME_BASE as i32,
+};
+
+/// Open the decoder of an input stream which needs decoding.
+unsafe fn init_input_stream(session: &mut Session, ist_index: usize) -> Result<(), String> {
+    let ist = &mut session.input_streams[ist_index];
+    if ist.decoding_needed == 0 {
+        return Ok(());
+    }
+    let st = &*ist.st;
+    if ist.dec.is_null() {
+        return Err(format!(
+            "Decoder (codec {}) not found for input stream #{}:{}",
+            CStr::from_ptr(ffi::avcodec_get_name((*st.codecpar).codec_id)).to_string_lossy(),
+            ist.file_index,
+            st.index
+        ));
+    }
+    (*ist.dec_ctx).pkt_timebase = st.time_base;
+
+    if ist.decoder_opts.get("threads").is_none() {
+        ist.decoder_opts.set("threads", "auto", DictFlag::NONE);
+    }
+    // Attached pics are sparse, therefore we would not want to delay their
+    // decoding till EOF.
+    if st.disposition & ffi::AV_DISPOSITION_ATTACHED_PIC as i32 != 0 {
+        ist.decoder_opts.set("threads", "1", DictFlag::NONE);
+    }
+
+    let mut opts = ist.decoder_opts.to_av_dict();
+    let ret = ffi::avcodec_open2(ist.dec_ctx, ist.dec, &mut opts);
+    ist.decoder_opts = Dictionary::from_av_dict(opts);
+    ffi::av_dict_free(&mut opts);
+    if ret < 0 {
+        return Err(format!(
+            "Error while opening decoder for input stream #{}:{} : {}",
+            ist.file_index,
+            st.index,
+            cmdutils::av_err2str(ret)
+        ));
+    }
+    assert_avoptions(&ist.decoder_opts);
+    Ok(())
+}
+
+/// `(codec name, implementation name)` for the stream mapping, the
+/// implementation is `native` when it's named after the codec.
+unsafe fn codec_names(codec: *const ffi::AVCodec) -> (String, String) {
+    let codec = match codec.as_ref() {
+        Some(codec) => codec,
+        None => return ("?".to_owned(), "?".to_owned()),
+    };
+    let name = CStr::from_ptr(codec.name).to_string_lossy().into_owned();
+    let codec_name = match ffi::avcodec_descriptor_get(codec.id).as_ref() {
+        Some(desc) => CStr::from_ptr(desc.name).to_string_lossy().into_owned(),
+        None => "?".to_owned(),
+    };
+    if name == codec_name {
+        (codec_name, "native".to_owned())
+    } else {
+        (codec_name, name)
+    }
+}
+
+unsafe fn dump_stream_mapping(session: &Session) {
+    info!("Stream mapping:");
+    for ist in &session.input_streams {
+        for &(fg, i) in &ist.filters {
+            let fg = &session.filtergraphs[fg];
+            if fg.is_simple() {
+                continue;
+            }
+            let mut line = format!(
+                "  Stream #{}:{} ({}) -> {}",
+                ist.file_index,
+                (*ist.st).index,
+                codec_names(ist.dec).1,
+                fg.inputs[i].name
+            );
+            if session.filtergraphs.len() > 1 {
+                line += &format!(" (graph {})", fg.index);
+            }
+            info!("{}", line);
+        }
+    }
+
+    for ost in &session.output_streams {
+        if !ost.attachment_filename.is_empty() {
+            // an attached file
+            info!(
+                "  File {} -> Stream #{}:{}",
+                ost.attachment_filename, ost.file_index, ost.index
+            );
+            continue;
+        }
+
+        if let Some((fg, out)) = ost.filter {
+            let fg = &session.filtergraphs[fg];
+            if !fg.is_simple() {
+                // output from a complex graph
+                let mut line = format!("  {}", fg.outputs[out].name);
+                if session.filtergraphs.len() > 1 {
+                    line += &format!(" (graph {})", fg.index);
+                }
+                info!(
+                    "{} -> Stream #{}:{} ({})",
+                    line,
+                    ost.file_index,
+                    ost.index,
+                    codec_names(ost.enc).1
+                );
+                continue;
+            }
+        }
+
+        let ist = match ost.source_index {
+            Some(ist) => &session.input_streams[ist],
+            None => continue,
+        };
+        let mut line = format!(
+            "  Stream #{}:{} -> #{}:{}",
+            ist.file_index,
+            (*ist.st).index,
+            ost.file_index,
+            ost.index
+        );
+        if let Some(sync_ist) = ost.sync_ist.filter(|&i| Some(i) != ost.source_index) {
+            let sync_ist = &session.input_streams[sync_ist];
+            line += &format!(" [sync #{}:{}]", sync_ist.file_index, (*sync_ist.st).index);
+        }
+        if ost.stream_copy {
+            line += " (copy)";
+        } else {
+            let (in_codec_name, decoder_name) = codec_names(ist.dec);
+            let (out_codec_name, encoder_name) = codec_names(ost.enc);
+            line += &format!(
+                " ({} ({}) -> {} ({}))",
+                in_codec_name, decoder_name, out_codec_name, encoder_name
+            );
+        }
+        info!("{}", line);
+    }
+}
+
+/// Open the decoders and the stream copy outputs, and write the header of
+/// the files which can't get streams later.
+unsafe fn transcode_init(session: &mut Session) -> Result<(), String> {
+    // init framerate emulation
+    for ifile in &session.input_files {
+        if ifile.rate_emu != 0 {
+            let ist_range = ifile.ist_index..ifile.ist_index + ifile.streams.len();
+            for ist in &mut session.input_streams[ist_range] {
+                ist.start = ffi::av_gettime_relative();
+            }
+        }
+    }
+
+    for i in 0..session.input_streams.len() {
+        init_input_stream(session, i)?;
+    }
+
+    for i in 0..session.output_streams.len() {
+        let ost = &session.output_streams[i];
+        if ost.encoding_needed
+            && (*ost.enc_ctx).codec_type == ffi::AVMediaType_AVMEDIA_TYPE_SUBTITLE
+        {
+            return Err(format!(
+                "Subtitle encoding is not supported yet for output stream #{}:{}",
+                ost.file_index, ost.index
+            ));
+        }
+        // Streams fed by a filtergraph are initialized when the graph
+        // returns its first frame.
+        if ost.filter.is_some() {
+            continue;
+        }
+        init_output_stream_wrapper(session, i)?;
+    }
+
+    // write headers for files with no streams
+    for (i, of) in session.output_files.iter_mut().enumerate() {
+        let oformat = &*(*of.ctx).oformat;
+        if oformat.flags & ffi::AVFMT_NOSTREAMS as i32 != 0 && (*of.ctx).nb_streams == 0 {
+            let mut opts = of.opts.to_av_dict();
+            let ret = ffi::avformat_write_header(of.ctx, &mut opts);
+            of.opts = Dictionary::from_av_dict(opts);
+            ffi::av_dict_free(&mut opts);
+            if ret < 0 {
+                return Err(format!(
+                    "Could not write header for output file #{} (incorrect codec parameters ?): {}",
+                    i,
+                    cmdutils::av_err2str(ret)
+                ));
+            }
+            of.header_written = true;
+        }
+    }
+
+    dump_stream_mapping(session);
+
+    TRANSCODE_INIT_DONE.store(1, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Set up the encoder or the stream copy of an output stream, and write the
+/// header of its file once every stream of the file is ready.
+unsafe fn init_output_stream_wrapper(session: &mut Session, i: usize) -> Result<(), String> {
+    if session.output_streams[i].initialized {
+        return Ok(());
+    }
+    if let Err(e) = init_output_stream(session, i) {
+        let ost = &session.output_streams[i];
+        return Err(format!(
+            "Error initializing output stream {}:{} -- {}",
+            ost.file_index, ost.index, e
+        ));
+    }
+    check_init_output_file(session, session.output_streams[i].file_index)
+}
+
+unsafe fn init_output_stream(session: &mut Session, i: usize) -> Result<(), String> {
+    if session.output_streams[i].encoding_needed {
+        init_output_stream_encode(session, i)?;
+
+        let ost = &mut session.output_streams[i];
+        let enc = &*ost.enc;
+        let enc_ctx = &mut *ost.enc_ctx;
+        if ost.encoder_opts.get("threads").is_none() {
+            ost.encoder_opts.set("threads", "auto", DictFlag::NONE);
+        }
+        if enc.type_ == ffi::AVMediaType_AVMEDIA_TYPE_AUDIO
+            && enc.defaults.is_null()
+            && ost.encoder_opts.get("b").is_none()
+            && ost.encoder_opts.get("ab").is_none()
+        {
+            ost.encoder_opts.set("b", "128000", DictFlag::NONE);
+        }
+
+        let mut opts = ost.encoder_opts.to_av_dict();
+        let ret = ffi::avcodec_open2(enc_ctx, enc, &mut opts);
+        ost.encoder_opts = Dictionary::from_av_dict(opts);
+        ffi::av_dict_free(&mut opts);
+        if ret < 0 {
+            if ret != AVERROR_EXPERIMENTAL {
+                error!("{}", cmdutils::av_err2str(ret));
+            }
+            return Err(format!(
+                "Error while opening encoder for output stream #{}:{} - maybe incorrect parameters such as bit_rate, rate, width or height",
+                ost.file_index, ost.index
+            ));
+        }
+        if enc.type_ == ffi::AVMediaType_AVMEDIA_TYPE_AUDIO
+            && enc.capabilities & ffi::AV_CODEC_CAP_VARIABLE_FRAME_SIZE as i32 == 0
+        {
+            if let Some((fg, out)) = ost.filter {
+                ffi::av_buffersink_set_frame_size(
+                    session.filtergraphs[fg].outputs[out].filter,
+                    enc_ctx.frame_size as u32,
+                );
+            }
+        }
+        assert_avoptions(&ost.encoder_opts);
+        if enc_ctx.bit_rate != 0
+            && enc_ctx.bit_rate < 1000
+            && enc_ctx.codec_id != ffi::AVCodecID_AV_CODEC_ID_CODEC2
+        {
+            error!(
+                "The bitrate parameter is set too low. It takes bits/s as argument, not kbits/s"
+            );
+        }
+
+        let st = &mut *ost.st;
+        if ffi::avcodec_parameters_from_context(st.codecpar, enc_ctx) < 0 {
+            return Err("Error initializing the output stream codec context.".to_owned());
+        }
+        // copy timebase while removing common factors
+        if st.time_base.num <= 0 || st.time_base.den <= 0 {
+            st.time_base = ffi::av_add_q(enc_ctx.time_base, ffi::AVRational { num: 0, den: 1 });
+        }
+        // copy estimated duration as a hint to the muxer
+        if st.duration <= 0 {
+            if let Some(ist) = ost.source_index {
+                let ist_st = &*session.input_streams[ist].st;
+                if ist_st.duration > 0 {
+                    st.duration =
+                        ffi::av_rescale_q(ist_st.duration, ist_st.time_base, st.time_base);
+                }
+            }
+        }
+        ost.mux_timebase = enc_ctx.time_base;
+    } else if session.output_streams[i].stream_copy
+        && session.output_streams[i].source_index.is_some()
+    {
+        init_output_stream_streamcopy(session, i)?;
+    }
+
+    let ost = &mut session.output_streams[i];
+    // an attachment is written with the header
+    if !ost.attachment_filename.is_empty() {
+        ost.finished = true;
+    }
+    if !ost.disposition.is_empty() {
+        let st = &mut *ost.st;
+        st.disposition = parse_disposition(&ost.disposition, st.disposition)?;
+    }
+    init_output_bsfs(ost)?;
+    ost.initialized = true;
+    Ok(())
+}
+
+/// Tag the stream with the encoder name, like `Lavc58.54.100 libx264`.
+unsafe fn set_encoder_id(of: &OutputFile, ost: &OutputStream) {
+    let st = &mut *ost.st;
+    let key = CString::new("encoder").unwrap();
+    if !ffi::av_dict_get(st.metadata, key.as_ptr(), ptr::null(), 0).is_null() {
+        return;
+    }
+    let has_bitexact = |value: Option<&str>| {
+        value.map_or(false, |value| {
+            value.split(&['+', '-'][..]).any(|flag| flag == "bitexact")
+        })
+    };
+    let bitexact = (*of.ctx).flags & ffi::AVFMT_FLAG_BITEXACT as i32 != 0
+        || (*ost.enc_ctx).flags & ffi::AV_CODEC_FLAG_BITEXACT as i32 != 0
+        || has_bitexact(of.opts.get("fflags"))
+        || has_bitexact(ost.encoder_opts.get("flags"));
+    let ident = if bitexact {
+        "Lavc ".to_owned()
+    } else {
+        let version = ffi::avcodec_version();
+        format!(
+            "Lavc{}.{}.{} ",
+            version >> 16,
+            (version >> 8) & 0xff,
+            version & 0xff
+        )
+    };
+    let encoder = CString::new(ident + &CStr::from_ptr((*ost.enc).name).to_string_lossy()).unwrap();
+    ffi::av_dict_set(
+        &mut st.metadata,
+        key.as_ptr(),
+        encoder.as_ptr(),
+        ffi::AV_DICT_DONT_OVERWRITE as i32,
+    );
+}
+
+/// The encoder time base set by `-enc_time_base`, a negative value picks
+/// the input stream time base.
+unsafe fn encoder_time_base(
+    ost: &OutputStream,
+    ist: Option<&InputStream>,
+    default_time_base: ffi::AVRational,
+) -> ffi::AVRational {
+    if ost.enc_timebase.num > 0 {
+        return ost.enc_timebase;
+    }
+    if ost.enc_timebase.num < 0 {
+        if let Some(ist) = ist {
+            return (*ist.st).time_base;
+        }
+        error!(
+            "Input stream data for output stream #{}:{} not available, using default time base",
+            ost.file_index, ost.index
+        );
+    }
+    default_time_base
+}
+
+/// The `-vsync` method of an output video stream, resolving `auto` from the
+/// muxer.
+unsafe fn format_video_sync(session: &Session, ost: &OutputStream) -> isize {
+    let mut vsync = session.video_sync_method;
+    if vsync == VSYNC_AUTO {
+        let of = &session.output_files[ost.file_index];
+        let oformat = &*(*of.ctx).oformat;
+        vsync = if CStr::from_ptr(oformat.name).to_bytes() == b"avi" {
+            VSYNC_VFR
+        } else if oformat.flags & ffi::AVFMT_VARIABLE_FPS as i32 != 0 {
+            if oformat.flags & ffi::AVFMT_NOTIMESTAMPS as i32 != 0 {
+                VSYNC_PASSTHROUGH
+            } else {
+                VSYNC_VFR
+            }
+        } else {
+            VSYNC_CFR
+        };
+        if let Some(ist) = ost.source_index {
+            let ifile = &session.input_files[session.input_streams[ist].file_index];
+            if vsync == VSYNC_CFR && (*ifile.ctx).nb_streams == 1 && ifile.input_ts_offset == 0 {
+                vsync = VSYNC_VSCFR;
+            }
+        }
+        if vsync == VSYNC_CFR && session.copy_ts != 0 {
+            vsync = VSYNC_VSCFR;
+        }
+    }
+    vsync
+}
+
+/// Set the encoder parameters from the filtergraph output feeding it.
+unsafe fn init_output_stream_encode(session: &mut Session, i: usize) -> Result<(), String> {
+    let ost = &session.output_streams[i];
+    set_encoder_id(&session.output_files[ost.file_index], ost);
+    let vsync = format_video_sync(session, ost);
+
+    let input_streams = &session.input_streams;
+    let ost = &mut session.output_streams[i];
+    let ist = ost.source_index.map(|ist| &input_streams[ist]);
+    let dec_ctx = ist.map(|ist| &*ist.dec_ctx);
+    let enc_ctx = &mut *ost.enc_ctx;
+    let (fg, out) = ost.filter.unwrap();
+    let sink = session.filtergraphs[fg].outputs[out].filter;
+
+    if let Some(dec_ctx) = dec_ctx {
+        enc_ctx.chroma_sample_location = dec_ctx.chroma_sample_location;
+    }
+
+    if enc_ctx.codec_type == ffi::AVMediaType_AVMEDIA_TYPE_VIDEO {
+        if ost.frame_rate.num == 0 {
+            ost.frame_rate = ffi::av_buffersink_get_frame_rate(sink);
+        }
+        if let Some(ist) = ist {
+            if ost.frame_rate.num == 0 {
+                ost.frame_rate = ist.framerate;
+            }
+            if ost.frame_rate.num == 0 {
+                ost.frame_rate = (*ist.st).r_frame_rate;
+            }
+        }
+        if ost.frame_rate.num == 0 {
+            ost.frame_rate = ffi::AVRational { num: 25, den: 1 };
+            error!(
+                "No information about the input framerate is available. Falling back to a default value of 25fps for output stream #{}:{}. Use the -r option if you want a different framerate.",
+                ost.file_index, ost.index
+            );
+        }
+        let enc = &*ost.enc;
+        if !enc.supported_framerates.is_null() && ost.force_fps == 0 {
+            let idx = ffi::av_find_nearest_q_idx(ost.frame_rate, enc.supported_framerates);
+            ost.frame_rate = *enc.supported_framerates.offset(idx as isize);
+        }
+        // reduce frame rate for mpeg4 to be within the spec limits
+        if enc_ctx.codec_id == ffi::AVCodecID_AV_CODEC_ID_MPEG4 {
+            let frame_rate = ost.frame_rate;
+            ffi::av_reduce(
+                &mut ost.frame_rate.num,
+                &mut ost.frame_rate.den,
+                frame_rate.num as i64,
+                frame_rate.den as i64,
+                65535,
+            );
+        }
+    }
+
+    match enc_ctx.codec_type {
+        ffi::AVMediaType_AVMEDIA_TYPE_AUDIO => {
+            enc_ctx.sample_fmt = ffi::av_buffersink_get_format(sink);
+            if let Some(dec_ctx) = dec_ctx {
+                enc_ctx.bits_per_raw_sample = dec_ctx
+                    .bits_per_raw_sample
+                    .min(ffi::av_get_bytes_per_sample(enc_ctx.sample_fmt) << 3);
+            }
+            enc_ctx.sample_rate = ffi::av_buffersink_get_sample_rate(sink);
+            enc_ctx.channel_layout = ffi::av_buffersink_get_channel_layout(sink);
+            enc_ctx.channels = ffi::av_buffersink_get_channels(sink);
+            enc_ctx.time_base = encoder_time_base(
+                ost,
+                ist,
+                ffi::AVRational {
+                    num: 1,
+                    den: enc_ctx.sample_rate,
+                },
+            );
+        }
+        ffi::AVMediaType_AVMEDIA_TYPE_VIDEO => {
+            enc_ctx.time_base = encoder_time_base(ost, ist, cmdutils::av_inv_q(ost.frame_rate));
+            if enc_ctx.time_base.num == 0 || enc_ctx.time_base.den == 0 {
+                enc_ctx.time_base = ffi::av_buffersink_get_time_base(sink);
+            }
+            let oformat = &*(*session.output_files[ost.file_index].ctx).oformat;
+            if cmdutils::av_q2d(enc_ctx.time_base) < 0.001
+                && vsync != VSYNC_PASSTHROUGH
+                && (vsync == VSYNC_CFR
+                    || vsync == VSYNC_VSCFR
+                    || (vsync == VSYNC_AUTO && oformat.flags & ffi::AVFMT_VARIABLE_FPS as i32 == 0))
+            {
+                error!("Frame rate very high for a muxer not efficiently supporting it.\nPlease consider specifying a lower framerate, a different muxer or -vsync 2");
+            }
+
+            enc_ctx.width = ffi::av_buffersink_get_w(sink);
+            enc_ctx.height = ffi::av_buffersink_get_h(sink);
+            // overridden by the -aspect cli option
+            let sample_aspect_ratio = if ost.frame_aspect_ratio.num != 0 {
+                ffi::av_mul_q(
+                    ost.frame_aspect_ratio,
+                    ffi::AVRational {
+                        num: enc_ctx.height,
+                        den: enc_ctx.width,
+                    },
+                )
+            } else {
+                ffi::av_buffersink_get_sample_aspect_ratio(sink)
+            };
+            enc_ctx.sample_aspect_ratio = sample_aspect_ratio;
+            (*ost.st).sample_aspect_ratio = sample_aspect_ratio;
+
+            enc_ctx.pix_fmt = ffi::av_buffersink_get_format(sink);
+            if let Some(dec_ctx) = dec_ctx {
+                enc_ctx.bits_per_raw_sample = dec_ctx
+                    .bits_per_raw_sample
+                    .min((*ffi::av_pix_fmt_desc_get(enc_ctx.pix_fmt)).comp[0].depth);
+            }
+
+            enc_ctx.framerate = ost.frame_rate;
+            (*ost.st).avg_frame_rate = ost.frame_rate;
+
+            if dec_ctx.map_or(true, |dec_ctx| {
+                enc_ctx.width != dec_ctx.width
+                    || enc_ctx.height != dec_ctx.height
+                    || enc_ctx.pix_fmt != dec_ctx.pix_fmt
+            }) {
+                enc_ctx.bits_per_raw_sample = session.frame_bits_per_raw_sample as i32;
+            }
+
+            if ost.top_field_first == 0 {
+                enc_ctx.field_order = ffi::AVFieldOrder_AV_FIELD_BB;
+            } else if ost.top_field_first == 1 {
+                enc_ctx.field_order = ffi::AVFieldOrder_AV_FIELD_TT;
+            }
+
+            if !ost.forced_keyframes.is_empty() {
+                error!(
+                    "-force_key_frames is not supported yet, ignored for output stream #{}:{}.",
+                    ost.file_index, ost.index
+                );
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Copy the codec parameters of the input stream, the packets are passed to
+/// the muxer untouched.
+unsafe fn init_output_stream_streamcopy(session: &mut Session, i: usize) -> Result<(), String> {
+    let ost = &mut session.output_streams[i];
+    let of = &session.output_files[ost.file_index];
+    let ist = &session.input_streams[ost.source_index.unwrap()];
+    let ist_st = &*ist.st;
+    let st = &mut *ost.st;
+    let par_dst = &mut *st.codecpar;
+    let mut codec_tag = par_dst.codec_tag;
+
+    let mut ret = ffi::avcodec_parameters_to_context(ost.enc_ctx, ist_st.codecpar);
+    if ret >= 0 {
+        let mut opts = ost.encoder_opts.to_av_dict();
+        ret = ffi::av_opt_set_dict(ost.enc_ctx as *mut libc::c_void, &mut opts);
+        ost.encoder_opts = Dictionary::from_av_dict(opts);
+        ffi::av_dict_free(&mut opts);
+    }
+    if ret < 0 {
+        return Err("Error setting up codec context options.".to_owned());
+    }
+
+    let mut par_src = ffi::avcodec_parameters_alloc();
+    if par_src.is_null() {
+        return Err(cmdutils::av_err2str(AVERROR(ffi::ENOMEM as i32)));
+    }
+    let ret = ffi::avcodec_parameters_from_context(par_src, ost.enc_ctx);
+    if ret < 0 {
+        ffi::avcodec_parameters_free(&mut par_src);
+        return Err("Error getting reference codec parameters.".to_owned());
+    }
+
+    if codec_tag == 0 {
+        let oformat = &*(*of.ctx).oformat;
+        let mut codec_tag_tmp = 0;
+        if oformat.codec_tag.is_null()
+            || ffi::av_codec_get_id(oformat.codec_tag, (*par_src).codec_tag) == (*par_src).codec_id
+            || ffi::av_codec_get_tag2(oformat.codec_tag, (*par_src).codec_id, &mut codec_tag_tmp)
+                == 0
+        {
+            codec_tag = (*par_src).codec_tag;
+        }
+    }
+
+    let ret = ffi::avcodec_parameters_copy(par_dst, par_src);
+    ffi::avcodec_parameters_free(&mut par_src);
+    if ret < 0 {
+        return Err(cmdutils::av_err2str(ret));
+    }
+    par_dst.codec_tag = codec_tag;
+
+    if ost.frame_rate.num == 0 {
+        ost.frame_rate = ist.framerate;
+    }
+    st.avg_frame_rate = ost.frame_rate;
+
+    let ret = ffi::avformat_transfer_internal_stream_timing_info(
+        (*of.ctx).oformat,
+        st,
+        ist_st,
+        session.copy_tb as _,
+    );
+    if ret < 0 {
+        return Err(cmdutils::av_err2str(ret));
+    }
+
+    // copy timebase while removing common factors
+    if st.time_base.num <= 0 || st.time_base.den <= 0 {
+        st.time_base = ffi::av_add_q(
+            ffi::av_stream_get_codec_timebase(st),
+            ffi::AVRational { num: 0, den: 1 },
+        );
+    }
+    // copy estimated duration as a hint to the muxer
+    if st.duration <= 0 && ist_st.duration > 0 {
+        st.duration = ffi::av_rescale_q(ist_st.duration, ist_st.time_base, st.time_base);
+    }
+    // copy disposition
+    st.disposition = ist_st.disposition;
+
+    for j in 0..ist_st.nb_side_data as isize {
+        let sd_src = &*ist_st.side_data.offset(j);
+        let dst_data = ffi::av_stream_new_side_data(st, sd_src.type_, sd_src.size as _);
+        if dst_data.is_null() {
+            return Err(cmdutils::av_err2str(AVERROR(ffi::ENOMEM as i32)));
+        }
+        ptr::copy_nonoverlapping(sd_src.data, dst_data, sd_src.size as usize);
+    }
+
+    match par_dst.codec_type {
+        ffi::AVMediaType_AVMEDIA_TYPE_AUDIO => {
+            if session.audio_volume != 256 {
+                return Err(
+                    "-acodec copy and -vol are incompatible (frames are not decoded)".to_owned(),
+                );
+            }
+            if (par_dst.block_align == 1
+                || par_dst.block_align == 1152
+                || par_dst.block_align == 576)
+                && par_dst.codec_id == ffi::AVCodecID_AV_CODEC_ID_MP3
+            {
+                par_dst.block_align = 0;
+            }
+            if par_dst.codec_id == ffi::AVCodecID_AV_CODEC_ID_AC3 {
+                par_dst.block_align = 0;
+            }
+        }
+        ffi::AVMediaType_AVMEDIA_TYPE_VIDEO => {
+            let sample_aspect_ratio = if ost.frame_aspect_ratio.num != 0 {
+                // overridden by the -aspect cli option
+                error!("Overriding aspect ratio with stream copy may produce invalid files");
+                ffi::av_mul_q(
+                    ost.frame_aspect_ratio,
+                    ffi::AVRational {
+                        num: par_dst.height,
+                        den: par_dst.width,
+                    },
+                )
+            } else if ist_st.sample_aspect_ratio.num != 0 {
+                ist_st.sample_aspect_ratio
+            } else {
+                par_dst.sample_aspect_ratio
+            };
+            st.sample_aspect_ratio = sample_aspect_ratio;
+            par_dst.sample_aspect_ratio = sample_aspect_ratio;
+            st.avg_frame_rate = ist_st.avg_frame_rate;
+            st.r_frame_rate = ist_st.r_frame_rate;
+        }
+        _ => {}
+    }
+
+    ost.mux_timebase = ist_st.time_base;
+    Ok(())
+}
+
+/// Flags accepted by `-disposition`.
+const DISPOSITIONS: &[(&str, u32)] = &[
+    ("default", ffi::AV_DISPOSITION_DEFAULT),
+    ("dub", ffi::AV_DISPOSITION_DUB),
+    ("original", ffi::AV_DISPOSITION_ORIGINAL),
+    ("comment", ffi::AV_DISPOSITION_COMMENT),
+    ("lyrics", ffi::AV_DISPOSITION_LYRICS),
+    ("karaoke", ffi::AV_DISPOSITION_KARAOKE),
+    ("forced", ffi::AV_DISPOSITION_FORCED),
+    ("hearing_impaired", ffi::AV_DISPOSITION_HEARING_IMPAIRED),
+    ("visual_impaired", ffi::AV_DISPOSITION_VISUAL_IMPAIRED),
+    ("clean_effects", ffi::AV_DISPOSITION_CLEAN_EFFECTS),
+    ("attached_pic", ffi::AV_DISPOSITION_ATTACHED_PIC),
+    ("captions", ffi::AV_DISPOSITION_CAPTIONS),
+    ("descriptions", ffi::AV_DISPOSITION_DESCRIPTIONS),
+    ("dependent", ffi::AV_DISPOSITION_DEPENDENT),
+    ("metadata", ffi::AV_DISPOSITION_METADATA),
+];
+
+/// Evaluate a `-disposition` value like `default+forced-dub` against the
+/// `current` disposition.
+pub fn parse_disposition(s: &str, current: i32) -> Result<i32, String> {
+    eval_flags(s, current as i64, |name| {
+        DISPOSITIONS
+            .iter()
+            .find(|(flag, _)| *flag == name)
+            .map(|&(_, value)| value as i64)
+    })
+    .map(|disposition| disposition as i32)
+    .ok_or_else(|| format!("Error parsing disposition {}.", s))
+}
+
+/// Evaluate named flags like `a+b-c` against `current` the same way as
+/// `av_opt_eval_flags()`: a flag without a sign replaces the value, `+` and
+/// `-` set and clear flags. A flag is either a name known to `lookup` or a
+/// number, `None` otherwise.
+pub fn eval_flags(s: &str, current: i64, lookup: impl Fn(&str) -> Option<i64>) -> Option<i64> {
+    let mut flags = current;
+    let mut rest = s;
+    loop {
+        let sign = match rest.chars().next() {
+            Some(c @ '+') | Some(c @ '-') => {
+                rest = &rest[1..];
+                Some(c)
+            }
+            _ => None,
+        };
+        let len = rest.find(&['+', '-'][..]).unwrap_or(rest.len());
+        let name = &rest[..len];
+        let flag = match lookup(name) {
+            Some(value) => value,
+            None => name.parse().ok()?,
+        };
+        flags = match sign {
+            Some('+') => flags | flag,
+            Some('-') => flags & !flag,
+            _ => flag,
+        };
+        rest = &rest[len..];
+        if len == 0 || rest.is_empty() {
+            return Some(flags);
+        }
+    }
+}
+
+/// Create the `-bsf` bitstream filters of an output stream.
+unsafe fn init_output_bsfs(ost: &mut OutputStream) -> Result<(), String> {
+    if ost.bitstream_filters.is_empty() {
+        return Ok(());
+    }
+    let bsfs = CString::new(ost.bitstream_filters.as_str()).unwrap();
+    let ret = ffi::av_bsf_list_parse_str(bsfs.as_ptr(), &mut ost.bsf_ctx);
+    if ret < 0 {
+        return Err(format!(
+            "Error parsing bitstream filter sequence '{}': {}",
+            ost.bitstream_filters,
+            cmdutils::av_err2str(ret)
+        ));
+    }
+    let ctx = &mut *ost.bsf_ctx;
+    let ret = ffi::avcodec_parameters_copy(ctx.par_in, (*ost.st).codecpar);
+    if ret < 0 {
+        return Err(cmdutils::av_err2str(ret));
+    }
+    ctx.time_base_in = (*ost.st).time_base;
+    let ret = ffi::av_bsf_init(ctx);
+    if ret < 0 {
+        return Err(format!(
+            "Error initializing bitstream filter: {}",
+            CStr::from_ptr((*ctx.filter).name).to_string_lossy()
+        ));
+    }
+    let ret = ffi::avcodec_parameters_copy((*ost.st).codecpar, ctx.par_out);
+    if ret < 0 {
+        return Err(cmdutils::av_err2str(ret));
+    }
+    (*ost.st).time_base = ctx.time_base_out;
+    Ok(())
+}
+
+/// Write the header of an output file once all its streams are
+/// initialized, then the packets queued meanwhile.
+unsafe fn check_init_output_file(session: &mut Session, file_index: usize) -> Result<(), String> {
+    let of = &mut session.output_files[file_index];
+    let ost_range = of.ost_index..of.ost_index + (*of.ctx).nb_streams as usize;
+    if session.output_streams[ost_range.clone()]
+        .iter()
+        .any(|ost| !ost.initialized)
+    {
+        return Ok(());
+    }
+
+    let mut opts = of.opts.to_av_dict();
+    let ret = ffi::avformat_write_header(of.ctx, &mut opts);
+    of.opts = Dictionary::from_av_dict(opts);
+    ffi::av_dict_free(&mut opts);
+    if ret < 0 {
+        return Err(format!(
+            "Could not write header for output file #{} (incorrect codec parameters ?): {}",
+            file_index,
+            cmdutils::av_err2str(ret)
+        ));
+    }
+    of.header_written = true;
+    let filename = cmdutils::os_cstring(&of.filename)?;
+    ffi::av_dump_format(of.ctx, file_index as i32, filename.as_ptr(), 1);
+    print_sdp(session);
+
+    // flush the muxing queues
+    for i in ost_range {
+        let ost = &mut session.output_streams[i];
+        // try to improve muxing time_base (only possible if nothing has been written yet)
+        if ost.muxing_queue.is_empty() {
+            ost.mux_timebase = (*ost.st).time_base;
+        }
+        while let Some(mut pkt) = session.output_streams[i].muxing_queue.pop_front() {
+            let result = write_packet(session, i, pkt, true);
+            ffi::av_packet_free(&mut pkt);
+            result?;
+        }
+    }
+    Ok(())
+}
+
+/// Print the SDP of the RTP outputs, to `-sdp_file` or stdout, once the
+/// headers of all the output files are written.
+unsafe fn print_sdp(session: &Session) {
+    if session.output_files.iter().any(|of| !of.header_written) {
+        return;
+    }
+    let mut avc: Vec<_> = session
+        .output_files
+        .iter()
+        .map(|of| of.ctx)
+        .filter(|&oc| CStr::from_ptr((*(*oc).oformat).name).to_bytes() == b"rtp")
+        .collect();
+    if avc.is_empty() {
+        return;
+    }
+    let mut sdp = [0 as libc::c_char; 16384];
+    ffi::av_sdp_create(
+        avc.as_mut_ptr(),
+        avc.len() as i32,
+        sdp.as_mut_ptr(),
+        sdp.len() as i32,
+    );
+    let sdp = CStr::from_ptr(sdp.as_ptr()).to_string_lossy();
+    if session.sdp_filename.is_empty() {
+        println!("SDP:\n{}", sdp);
+    } else if let Err(e) = fs::write(&session.sdp_filename, sdp.as_bytes()) {
+        error!("Failed to open sdp file '{}': {}", session.sdp_filename, e);
+    }
+}
+
+/// Mark an output stream as finished, with `-shortest` this ends the other
+/// streams of the file at the same time.
+unsafe fn close_output_stream(session: &mut Session, i: usize) {
+    let ost = &mut session.output_streams[i];
+    ost.finished = true;
+    let of = &mut session.output_files[ost.file_index];
+    if of.shortest != 0 {
+        let end = ffi::av_rescale_q(ost.sync_opts, (*ost.enc_ctx).time_base, AV_TIME_BASE_Q);
+        of.recording_time = of.recording_time.min(end);
+    }
+}
 
-    ffmpeg_parse_options(&args);
+/// Mark an output stream as finished, with `-shortest` the whole file is.
+unsafe fn finish_output_stream(session: &mut Session, i: usize) {
+    let of = &session.output_files[session.output_streams[i].file_index];
+    session.output_streams[i].finished = true;
+    if of.shortest != 0 {
+        let ost_range = of.ost_index..of.ost_index + (*of.ctx).nb_streams as usize;
+        for ost in &mut session.output_streams[ost_range] {
+            ost.finished = true;
+        }
+    }
+}
+
+/// Whether the frame at `ost.sync_opts` is still within `-t`, otherwise
+/// the stream is closed.
+unsafe fn check_recording_time(session: &mut Session, i: usize) -> bool {
+    let ost = &session.output_streams[i];
+    let of = &session.output_files[ost.file_index];
+    if of.recording_time != i64::MAX
+        && ffi::av_compare_ts(
+            ost.sync_opts,
+            (*ost.enc_ctx).time_base,
+            of.recording_time,
+            AV_TIME_BASE_Q,
+        ) >= 0
+    {
+        close_output_stream(session, i);
+        return false;
+    }
+    true
+}
+
+/// Whether any output stream still wants data. Streams over `-fs` or
+/// `-frames` close their file.
+unsafe fn need_output(session: &mut Session) -> bool {
+    for i in 0..session.output_streams.len() {
+        let ost = &session.output_streams[i];
+        let of = &session.output_files[ost.file_index];
+        let pb = (*of.ctx).pb;
+        if ost.finished
+            || (!pb.is_null() && ffi::avio_seek(pb, 0, libc::SEEK_CUR) as u64 >= of.limit_filesize)
+        {
+            continue;
+        }
+        if ost.frame_number >= ost.max_frames {
+            let ost_range = of.ost_index..of.ost_index + (*of.ctx).nb_streams as usize;
+            for j in ost_range {
+                close_output_stream(session, j);
+            }
+            continue;
+        }
+        return true;
+    }
+    false
+}
+
+/// Select the output stream to process, the one with the smallest
+/// timestamp so the outputs stay interleaved.
+///
+/// Returns `None` when the selected stream is unavailable.
+unsafe fn choose_output(session: &Session) -> Option<usize> {
+    let mut opts_min = i64::MAX;
+    let mut ost_min = None;
+    for (i, ost) in session.output_streams.iter().enumerate() {
+        let opts = if ost.last_mux_dts == AV_NOPTS_VALUE {
+            i64::MIN
+        } else {
+            ffi::av_rescale_q(ost.last_mux_dts, (*ost.st).time_base, AV_TIME_BASE_Q)
+        };
+        if !ost.initialized && !ost.inputs_done {
+            return if ost.unavailable { None } else { Some(i) };
+        }
+        if !ost.finished && opts < opts_min {
+            opts_min = opts;
+            ost_min = if ost.unavailable { None } else { Some(i) };
+        }
+    }
+    ost_min
+}
+
+fn got_eagain(session: &Session) -> bool {
+    session.output_streams.iter().any(|ost| ost.unavailable)
+}
+
+fn reset_eagain(session: &mut Session) {
+    for ifile in &mut session.input_files {
+        ifile.eagain = false;
+    }
+    for ost in &mut session.output_streams {
+        ost.unavailable = false;
+    }
+}
+
+/// Encode a filtered video frame, dropping or duplicating it to follow the
+/// `-vsync` method. The pts of `frame` is in the encoder time base.
+unsafe fn do_video_out(
+    session: &mut Session,
+    i: usize,
+    frame: *mut ffi::AVFrame,
+) -> Result<(), String> {
+    let vsync = format_video_sync(session, &session.output_streams[i]);
+    let ost = &mut session.output_streams[i];
+    let enc_ctx = &*ost.enc_ctx;
+    let frame = &mut *frame;
+
+    let mut sync_ipts = if frame.pts == AV_NOPTS_VALUE {
+        ost.sync_opts as f64
+    } else {
+        frame.pts as f64
+    };
+    let mut duration =
+        1. / (cmdutils::av_q2d(ost.frame_rate) * cmdutils::av_q2d(enc_ctx.time_base));
+    if !duration.is_finite() {
+        duration = 1.;
+    }
+    // the drift between the frame and where it would fall in the output
+    let delta0 = sync_ipts - ost.sync_opts as f64;
+    let mut delta = delta0 + duration;
+
+    // by default, we output a single frame
+    let mut nb_frames: i64 = 1;
+    if delta0 < 0. && delta > 0. && vsync != VSYNC_PASSTHROUGH && vsync != VSYNC_DROP {
+        if delta0 < -0.6 {
+            debug!("Past duration {:.6} too large", -delta0);
+        } else {
+            debug!("Clipping frame in rate conversion by {:.6}", -delta0);
+        }
+        sync_ipts = ost.sync_opts as f64;
+        duration += delta0;
+        delta = duration;
+    }
+
+    match vsync {
+        VSYNC_VSCFR | VSYNC_CFR => {
+            if vsync == VSYNC_VSCFR && ost.frame_number == 0 && delta0 >= 0.5 {
+                debug!("Not duplicating {} initial frames", delta0.round() as i64);
+                delta = duration;
+                ost.sync_opts = sync_ipts.round() as i64;
+            }
+            let frame_drop_threshold = session.frame_drop_threshold as f64;
+            if frame_drop_threshold != 0. && delta < frame_drop_threshold && ost.frame_number != 0 {
+                nb_frames = 0;
+            } else if delta < -1.1 {
+                nb_frames = 0;
+            } else if delta > 1.1 {
+                nb_frames = delta.round() as i64;
+            }
+        }
+        VSYNC_VFR => {
+            if delta <= -0.6 {
+                nb_frames = 0;
+            } else if delta > 0.6 {
+                ost.sync_opts = sync_ipts.round() as i64;
+            }
+        }
+        _ => ost.sync_opts = sync_ipts.round() as i64,
+    }
+
+    nb_frames = nb_frames.min(ost.max_frames - ost.frame_number);
+    if nb_frames == 0 {
+        debug!(
+            "*** dropping frame {} from stream {} at ts {}",
+            ost.frame_number,
+            (*ost.st).index,
+            frame.pts
+        );
+        return Ok(());
+    }
+    if nb_frames > 1 {
+        if nb_frames as f64 > session.dts_error_threshold as f64 * 30. {
+            error!("{} frame duplication too large, skipping", nb_frames - 1);
+            return Ok(());
+        }
+        debug!("*** {} dup!", nb_frames - 1);
+    }
+
+    // duplicates frame if needed
+    for _ in 0..nb_frames {
+        frame.pts = session.output_streams[i].sync_opts;
+        if !check_recording_time(session, i) {
+            return Ok(());
+        }
+        frame.quality = (*session.output_streams[i].enc_ctx).global_quality;
+        frame.pict_type = ffi::AVPictureType_AV_PICTURE_TYPE_NONE;
+        encode_frame(session, i, frame)?;
+
+        let ost = &mut session.output_streams[i];
+        ost.sync_opts += 1;
+        ost.frame_number += 1;
+    }
+    Ok(())
+}
+
+unsafe fn do_audio_out(
+    session: &mut Session,
+    i: usize,
+    frame: *mut ffi::AVFrame,
+) -> Result<(), String> {
+    if !check_recording_time(session, i) {
+        return Ok(());
+    }
+    let ost = &mut session.output_streams[i];
+    let frame = &mut *frame;
+    if frame.pts == AV_NOPTS_VALUE || session.audio_sync_method < 0 {
+        frame.pts = ost.sync_opts;
+    }
+    ost.sync_opts = frame.pts + frame.nb_samples as i64;
+    encode_frame(session, i, frame)
+}
+
+/// Send a frame to the encoder, or flush it when `frame` is null, and mux
+/// the packets it returns.
+unsafe fn encode_frame(
+    session: &mut Session,
+    i: usize,
+    frame: *const ffi::AVFrame,
+) -> Result<(), String> {
+    let enc_ctx = session.output_streams[i].enc_ctx;
+    let kind = if (*enc_ctx).codec_type == ffi::AVMediaType_AVMEDIA_TYPE_VIDEO {
+        "Video"
+    } else {
+        "Audio"
+    };
+    let ret = ffi::avcodec_send_frame(enc_ctx, frame);
+    if ret < 0 && !(frame.is_null() && ret == AVERROR_EOF) {
+        return Err(format!(
+            "{} encoding failed: {}",
+            kind,
+            cmdutils::av_err2str(ret)
+        ));
+    }
+
+    let mut pkt = ffi::av_packet_alloc();
+    if pkt.is_null() {
+        return Err(cmdutils::av_err2str(AVERROR(ffi::ENOMEM as i32)));
+    }
+    let result = loop {
+        let ret = ffi::avcodec_receive_packet(enc_ctx, pkt);
+        if ret == AVERROR(ffi::EAGAIN as i32) || ret == AVERROR_EOF {
+            break Ok(());
+        }
+        if ret < 0 {
+            break Err(format!(
+                "{} encoding failed: {}",
+                kind,
+                cmdutils::av_err2str(ret)
+            ));
+        }
+        if let Err(e) = output_packet(session, i, pkt, false) {
+            break Err(e);
+        }
+    };
+    ffi::av_packet_free(&mut pkt);
+    result
+}
+
+/// Pass an encoded or copied packet through the `-bsf` bitstream filters to
+/// the muxer. With `eof` the filters are drained into `pkt`.
+unsafe fn output_packet(
+    session: &mut Session,
+    i: usize,
+    pkt: *mut ffi::AVPacket,
+    eof: bool,
+) -> Result<(), String> {
+    let bsf_ctx = session.output_streams[i].bsf_ctx;
+    if bsf_ctx.is_null() {
+        if !eof {
+            write_packet(session, i, pkt, false)?;
+        }
+        return Ok(());
+    }
+
+    let mut ret = ffi::av_bsf_send_packet(bsf_ctx, if eof { ptr::null_mut() } else { pkt });
+    while ret >= 0 {
+        ret = ffi::av_bsf_receive_packet(bsf_ctx, pkt);
+        if ret >= 0 {
+            write_packet(session, i, pkt, false)?;
+        }
+    }
+    if ret != AVERROR(ffi::EAGAIN as i32) && ret != AVERROR_EOF {
+        let ost = &session.output_streams[i];
+        let msg = format!(
+            "Error applying bitstream filters to an output packet for stream #{}:{}.",
+            ost.file_index, ost.index
+        );
+        if session.exit_on_error != 0 {
+            return Err(msg);
+        }
+        error!("{}", msg);
+    }
+    Ok(())
+}
+
+/// Mux a packet whose timestamps are in `ost.mux_timebase`, it's queued
+/// until the header of the file is written.
+unsafe fn write_packet(
+    session: &mut Session,
+    i: usize,
+    pkt: *mut ffi::AVPacket,
+    unqueue: bool,
+) -> Result<(), String> {
+    let ost = &mut session.output_streams[i];
+    let of = &session.output_files[ost.file_index];
+    let st = &*ost.st;
+    let pkt_ref = &mut *pkt;
+    let codec_type = (*st.codecpar).codec_type;
+
+    if (codec_type == ffi::AVMediaType_AVMEDIA_TYPE_VIDEO
+        && session.video_sync_method == VSYNC_DROP)
+        || (codec_type == ffi::AVMediaType_AVMEDIA_TYPE_AUDIO && session.audio_sync_method < 0)
+    {
+        pkt_ref.pts = AV_NOPTS_VALUE;
+        pkt_ref.dts = AV_NOPTS_VALUE;
+    }
+
+    // Audio encoders may split the packets -- #frames in != #packets out.
+    // But there is no reordering, so we can limit the number of output
+    // packets by simply dropping them here. Counting encoded video frames
+    // needs to be done separately because of reordering, see do_video_out().
+    // Do not count the packet when unqueued because it has been counted
+    // when queued.
+    if !(codec_type == ffi::AVMediaType_AVMEDIA_TYPE_VIDEO && ost.encoding_needed) && !unqueue {
+        if ost.frame_number >= ost.max_frames {
+            ffi::av_packet_unref(pkt);
+            return Ok(());
+        }
+        ost.frame_number += 1;
+    }
+
+    if !of.header_written {
+        // the muxer is not initialized yet, buffer the packet
+        if ost.muxing_queue.len() >= ost.max_muxing_queue_size as usize {
+            return Err(format!(
+                "Too many packets buffered for output stream {}:{}.",
+                ost.file_index, st.index
+            ));
+        }
+        let queued = ffi::av_packet_alloc();
+        if queued.is_null() {
+            return Err(cmdutils::av_err2str(AVERROR(ffi::ENOMEM as i32)));
+        }
+        ffi::av_packet_move_ref(queued, pkt);
+        ost.muxing_queue.push_back(queued);
+        return Ok(());
+    }
+
+    ffi::av_packet_rescale_ts(pkt, ost.mux_timebase, st.time_base);
+
+    let oformat = &*(*of.ctx).oformat;
+    if oformat.flags & ffi::AVFMT_NOTIMESTAMPS as i32 == 0 {
+        if pkt_ref.dts != AV_NOPTS_VALUE
+            && pkt_ref.pts != AV_NOPTS_VALUE
+            && pkt_ref.dts > pkt_ref.pts
+        {
+            error!(
+                "Invalid DTS: {} PTS: {} in output stream {}:{}, replacing by guess",
+                pkt_ref.dts, pkt_ref.pts, ost.file_index, st.index
+            );
+            // the median of pts, dts and last_mux_dts + 1
+            let mut candidates = [pkt_ref.pts, pkt_ref.dts, ost.last_mux_dts.wrapping_add(1)];
+            candidates.sort_unstable();
+            pkt_ref.pts = candidates[1];
+            pkt_ref.dts = candidates[1];
+        }
+        if (codec_type == ffi::AVMediaType_AVMEDIA_TYPE_AUDIO
+            || codec_type == ffi::AVMediaType_AVMEDIA_TYPE_VIDEO
+            || codec_type == ffi::AVMediaType_AVMEDIA_TYPE_SUBTITLE)
+            && pkt_ref.dts != AV_NOPTS_VALUE
+            && ost.last_mux_dts != AV_NOPTS_VALUE
+        {
+            let max = ost.last_mux_dts
+                + if oformat.flags & ffi::AVFMT_TS_NONSTRICT as i32 != 0 {
+                    0
+                } else {
+                    1
+                };
+            if pkt_ref.dts < max {
+                let msg = format!(
+                    "Non-monotonous DTS in output stream {}:{}; previous: {}, current: {}; ",
+                    ost.file_index, st.index, ost.last_mux_dts, pkt_ref.dts
+                );
+                if session.exit_on_error != 0 {
+                    return Err(msg + "aborting.");
+                }
+                let msg = format!(
+                    "{}changing to {}. This may result in incorrect timestamps in the output file.",
+                    msg, max
+                );
+                if max - pkt_ref.dts > 2 || codec_type == ffi::AVMediaType_AVMEDIA_TYPE_VIDEO {
+                    error!("{}", msg);
+                } else {
+                    debug!("{}", msg);
+                }
+                if pkt_ref.pts >= pkt_ref.dts {
+                    pkt_ref.pts = pkt_ref.pts.max(max);
+                }
+                pkt_ref.dts = max;
+            }
+        }
+    }
+    ost.last_mux_dts = pkt_ref.dts;
+    ost.packets_written += 1;
+    pkt_ref.stream_index = ost.index as i32;
+
+    let ret = ffi::av_interleaved_write_frame(of.ctx, pkt);
+    if ret < 0 {
+        cmdutils::print_error("av_interleaved_write_frame()", ret);
+        session.main_return_code = 1;
+        for ost in &mut session.output_streams {
+            ost.finished = true;
+        }
+    }
+    Ok(())
+}
+
+/// Get the frames available at the filtergraph outputs and encode them.
+unsafe fn reap_filters(session: &mut Session) -> Result<(), String> {
+    let mut frame = ffi::av_frame_alloc();
+    if frame.is_null() {
+        return Err(cmdutils::av_err2str(AVERROR(ffi::ENOMEM as i32)));
+    }
+    let mut result = Ok(());
+    for i in 0..session.output_streams.len() {
+        result = reap_filter(session, i, frame);
+        if result.is_err() {
+            break;
+        }
+    }
+    ffi::av_frame_free(&mut frame);
+    result
+}
+
+unsafe fn reap_filter(
+    session: &mut Session,
+    i: usize,
+    frame: *mut ffi::AVFrame,
+) -> Result<(), String> {
+    let (fg, out) = match session.output_streams[i].filter {
+        Some(filter) => filter,
+        None => return Ok(()),
+    };
+    let filter = session.filtergraphs[fg].outputs[out].filter;
+    if filter.is_null() || session.filtergraphs[fg].graph.is_null() {
+        return Ok(());
+    }
+    init_output_stream_wrapper(session, i)?;
+
+    loop {
+        let ret = ffi::av_buffersink_get_frame_flags(
+            filter,
+            frame,
+            ffi::AV_BUFFERSINK_FLAG_NO_REQUEST as i32,
+        );
+        if ret < 0 {
+            if ret != AVERROR(ffi::EAGAIN as i32) && ret != AVERROR_EOF {
+                error!(
+                    "Error in av_buffersink_get_frame_flags(): {}",
+                    cmdutils::av_err2str(ret)
+                );
+            }
+            return Ok(());
+        }
+        let ost = &session.output_streams[i];
+        if ost.finished {
+            ffi::av_frame_unref(frame);
+            continue;
+        }
+
+        let frame_ref = &mut *frame;
+        if frame_ref.pts != AV_NOPTS_VALUE {
+            let of = &session.output_files[ost.file_index];
+            let start_time = if of.start_time == AV_NOPTS_VALUE {
+                0
+            } else {
+                of.start_time
+            };
+            let enc_time_base = (*ost.enc_ctx).time_base;
+            frame_ref.pts = ffi::av_rescale_q(
+                frame_ref.pts,
+                ffi::av_buffersink_get_time_base(filter),
+                enc_time_base,
+            ) - ffi::av_rescale_q(start_time, AV_TIME_BASE_Q, enc_time_base);
+        }
+
+        let enc_ctx = &*ost.enc_ctx;
+        let result = match ffi::av_buffersink_get_type(filter) {
+            ffi::AVMediaType_AVMEDIA_TYPE_VIDEO => do_video_out(session, i, frame),
+            ffi::AVMediaType_AVMEDIA_TYPE_AUDIO => {
+                if (*enc_ctx.codec).capabilities & ffi::AV_CODEC_CAP_PARAM_CHANGE as i32 == 0
+                    && enc_ctx.channels != frame_ref.channels
+                {
+                    error!("Audio filter graph output is not normalized and encoder does not support parameter changes");
+                    Ok(())
+                } else {
+                    do_audio_out(session, i, frame)
+                }
+            }
+            _ => Ok(()),
+        };
+        ffi::av_frame_unref(frame);
+        result?;
+    }
+}
+
+/// Log a decoding error, which is fatal with `-xerror`.
+unsafe fn decode_error(session: &Session, ist_index: usize, ret: i32) -> Result<(), String> {
+    let ist = &session.input_streams[ist_index];
+    let msg = format!(
+        "Error while decoding stream #{}:{}: {}",
+        ist.file_index,
+        (*ist.st).index,
+        cmdutils::av_err2str(ret)
+    );
+    if session.exit_on_error != 0 {
+        return Err(msg);
+    }
+    error!("{}", msg);
+    Ok(())
+}
+
+/// Set the pts of a decoded frame in the time base of the filtergraph
+/// inputs of the stream.
+unsafe fn set_decoded_frame_pts(ist: &mut InputStream, frame: &mut ffi::AVFrame) {
+    if (*ist.dec_ctx).codec_type == ffi::AVMediaType_AVMEDIA_TYPE_AUDIO {
+        let sample_rate_tb = ffi::AVRational {
+            num: 1,
+            den: frame.sample_rate,
+        };
+        ist.nb_samples = frame.nb_samples as i64;
+        if frame.pts == AV_NOPTS_VALUE {
+            frame.pts = frame.best_effort_timestamp;
+        }
+        if frame.pts != AV_NOPTS_VALUE {
+            frame.pts = ffi::av_rescale_delta(
+                (*ist.st).time_base,
+                frame.pts,
+                sample_rate_tb,
+                frame.nb_samples,
+                &mut ist.filter_in_rescale_delta_last,
+                sample_rate_tb,
+            );
+        }
+    } else if ist.framerate.num != 0 {
+        frame.pts = ist.cfr_next_pts;
+        ist.cfr_next_pts += 1;
+    } else {
+        frame.pts = frame.best_effort_timestamp;
+    }
+}
+
+/// Decode a packet, or flush the decoder when `pkt` is null, and send the
+/// frames to the filtergraphs. The EOF isn't sent to the filtergraphs with
+/// `no_eof`, when the file is looped.
+unsafe fn decode_packet(
+    session: &mut Session,
+    ist_index: usize,
+    pkt: *const ffi::AVPacket,
+    no_eof: bool,
+) -> Result<(), String> {
+    let dec_ctx = session.input_streams[ist_index].dec_ctx;
+    let codec_type = (*dec_ctx).codec_type;
+    if codec_type != ffi::AVMediaType_AVMEDIA_TYPE_VIDEO
+        && codec_type != ffi::AVMediaType_AVMEDIA_TYPE_AUDIO
+    {
+        return Ok(());
+    }
+
+    let ret = ffi::avcodec_send_packet(dec_ctx, pkt);
+    if ret < 0 && !(pkt.is_null() && ret == AVERROR_EOF) {
+        decode_error(session, ist_index, ret)?;
+        if !pkt.is_null() {
+            return Ok(());
+        }
+    }
+
+    let mut frame = ffi::av_frame_alloc();
+    if frame.is_null() {
+        return Err(cmdutils::av_err2str(AVERROR(ffi::ENOMEM as i32)));
+    }
+    let result = loop {
+        let ret = ffi::avcodec_receive_frame(dec_ctx, frame);
+        if ret == AVERROR(ffi::EAGAIN as i32) || ret == AVERROR_EOF {
+            break Ok(());
+        }
+        if ret < 0 {
+            break decode_error(session, ist_index, ret);
+        }
+        let ist = &mut session.input_streams[ist_index];
+        ist.got_output = true;
+        set_decoded_frame_pts(ist, &mut *frame);
+
+        let mut result = Ok(());
+        for (fg, input) in ist.filters.clone() {
+            result = ifilter_send_frame(session, fg, input, frame);
+            if result.is_err() {
+                break;
+            }
+        }
+        ffi::av_frame_unref(frame);
+        if result.is_err() {
+            break result;
+        }
+    };
+    ffi::av_frame_free(&mut frame);
+    result?;
+
+    if pkt.is_null() && !no_eof {
+        for (fg, input) in session.input_streams[ist_index].filters.clone() {
+            ifilter_send_eof(session, fg, input)?;
+        }
+    }
+    Ok(())
+}
+
+/// Send a decoded frame to a filtergraph input, (re)configuring the graph
+/// when the frame parameters change.
+unsafe fn ifilter_send_frame(
+    session: &mut Session,
+    fg: usize,
+    input: usize,
+    frame: *mut ffi::AVFrame,
+) -> Result<(), String> {
+    let frame_ref = &*frame;
+    let graph_configured = !session.filtergraphs[fg].graph.is_null();
+    let ifilter = &mut session.filtergraphs[fg].inputs[input];
+    let ist = &session.input_streams[ifilter.ist];
+
+    // determine if the parameters for this input changed
+    let mut need_reinit = ifilter.format != frame_ref.format;
+    match ifilter.media_type {
+        ffi::AVMediaType_AVMEDIA_TYPE_AUDIO => {
+            need_reinit |= ifilter.sample_rate != frame_ref.sample_rate
+                || ifilter.channels != frame_ref.channels
+                || ifilter.channel_layout != frame_ref.channel_layout;
+        }
+        ffi::AVMediaType_AVMEDIA_TYPE_VIDEO => {
+            need_reinit |= ifilter.width != frame_ref.width || ifilter.height != frame_ref.height;
+        }
+        _ => {}
+    }
+    // don't reconfigure a running graph with -reinit_filter 0
+    if ist.reinit_filters == 0 && graph_configured {
+        need_reinit = false;
+    }
+    if need_reinit {
+        ffmpeg_filter::ifilter_parameters_from_frame(ifilter, frame_ref);
+    }
+
+    // (re)init the graph if possible, otherwise buffer the frame and return
+    if need_reinit || !graph_configured {
+        if !session.filtergraphs[fg].has_all_input_formats() {
+            let queued = ffi::av_frame_clone(frame);
+            if queued.is_null() {
+                return Err(cmdutils::av_err2str(AVERROR(ffi::ENOMEM as i32)));
+            }
+            session.filtergraphs[fg].inputs[input]
+                .frame_queue
+                .push_back(queued);
+            return Ok(());
+        }
+
+        reap_filters(session)?;
+        if let Err(e) = ffmpeg_filter::configure_filtergraph(session, fg) {
+            error!("{}", e);
+            return Err("Error reinitializing filters!".to_owned());
+        }
+    }
+
+    let ret = ffi::av_buffersrc_add_frame_flags(
+        session.filtergraphs[fg].inputs[input].filter,
+        frame,
+        (ffi::AV_BUFFERSRC_FLAG_PUSH | ffi::AV_BUFFERSRC_FLAG_KEEP_REF) as i32,
+    );
+    if ret < 0 && ret != AVERROR_EOF {
+        return Err(format!(
+            "Error while filtering: {}",
+            cmdutils::av_err2str(ret)
+        ));
+    }
+    Ok(())
+}
+
+/// Signal EOF to a filtergraph input. An unconfigured graph takes the
+/// input parameters from the demuxer instead.
+unsafe fn ifilter_send_eof(session: &mut Session, fg: usize, input: usize) -> Result<(), String> {
+    let fg = &mut session.filtergraphs[fg];
+    let graph_configured = !fg.graph.is_null();
+    let ifilter = &mut fg.inputs[input];
+    ifilter.eof = true;
+
+    if graph_configured {
+        let ret = ffi::av_buffersrc_add_frame_flags(
+            ifilter.filter,
+            ptr::null_mut(),
+            ffi::AV_BUFFERSRC_FLAG_PUSH as i32,
+        );
+        if ret < 0 && ret != AVERROR_EOF {
+            return Err(format!(
+                "Error while filtering: {}",
+                cmdutils::av_err2str(ret)
+            ));
+        }
+    } else {
+        // the filtergraph was never configured
+        let ist = &session.input_streams[ifilter.ist];
+        if ifilter.format < 0 {
+            ffmpeg_filter::ifilter_parameters_from_codecpar(ifilter, &*(*ist.st).codecpar);
+        }
+        if ifilter.format < 0
+            && (ifilter.media_type == ffi::AVMediaType_AVMEDIA_TYPE_AUDIO
+                || ifilter.media_type == ffi::AVMediaType_AVMEDIA_TYPE_VIDEO)
+        {
+            return Err(format!(
+                "Cannot determine format of input stream {}:{} after EOF",
+                ist.file_index,
+                (*ist.st).index
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Rescale the timestamps of a demuxed packet for a stream copy output, and
+/// mux it.
+unsafe fn do_streamcopy(
+    session: &mut Session,
+    ist_index: usize,
+    i: usize,
+    pkt: &ffi::AVPacket,
+) -> Result<(), String> {
+    let ist = &session.input_streams[ist_index];
+    let ifile = &session.input_files[ist.file_index];
+    let ost = &session.output_streams[i];
+    let of = &session.output_files[ost.file_index];
+    let ist_st = &*ist.st;
+    if ost.finished {
+        return Ok(());
+    }
+
+    let start_time = if of.start_time == AV_NOPTS_VALUE {
+        0
+    } else {
+        of.start_time
+    };
+    let ost_tb_start_time = ffi::av_rescale_q(start_time, AV_TIME_BASE_Q, ost.mux_timebase);
+    // position of the packet in AV_TIME_BASE
+    let ts = if pkt.dts != AV_NOPTS_VALUE {
+        pkt.dts
+    } else {
+        pkt.pts
+    };
+    let ist_pts = if ts == AV_NOPTS_VALUE {
+        AV_NOPTS_VALUE
+    } else {
+        ffi::av_rescale_q(ts, ist_st.time_base, AV_TIME_BASE_Q)
+    };
+
+    if of.start_time != AV_NOPTS_VALUE && ist_pts != AV_NOPTS_VALUE && ist_pts < of.start_time {
+        return Ok(());
+    }
+    if ost.frame_number == 0
+        && pkt.flags & ffi::AV_PKT_FLAG_KEY as i32 == 0
+        && ost.copy_initial_nonkeyframes == 0
+    {
+        return Ok(());
+    }
+    if ost.frame_number == 0 && ost.copy_prior_start == 0 {
+        let mut comp_start = start_time;
+        if session.copy_ts != 0 && ifile.start_time != AV_NOPTS_VALUE {
+            comp_start = comp_start.max(ifile.start_time + ifile.ts_offset);
+        }
+        if pkt.pts != AV_NOPTS_VALUE
+            && pkt.pts < ffi::av_rescale_q(comp_start, AV_TIME_BASE_Q, ist_st.time_base)
+        {
+            return Ok(());
+        }
+    }
+
+    if ist_pts != AV_NOPTS_VALUE {
+        if of.recording_time != i64::MAX && ist_pts >= of.recording_time + start_time {
+            close_output_stream(session, i);
+            return Ok(());
+        }
+        if ifile.recording_time != i64::MAX {
+            let mut start_time = (*ifile.ctx).start_time;
+            if start_time == AV_NOPTS_VALUE {
+                start_time = 0;
+            }
+            if ifile.start_time != AV_NOPTS_VALUE && session.copy_ts != 0 {
+                start_time += ifile.start_time;
+            }
+            if ist_pts >= ifile.recording_time + start_time {
+                close_output_stream(session, i);
+                return Ok(());
+            }
+        }
+    }
+
+    let mux_timebase = ost.mux_timebase;
+    let mut opkt = ffi::av_packet_clone(pkt);
+    if opkt.is_null() {
+        return Err(cmdutils::av_err2str(AVERROR(ffi::ENOMEM as i32)));
+    }
+    let opkt_ref = &mut *opkt;
+    if pkt.pts != AV_NOPTS_VALUE {
+        opkt_ref.pts =
+            ffi::av_rescale_q(pkt.pts, ist_st.time_base, mux_timebase) - ost_tb_start_time;
+    }
+    if pkt.dts != AV_NOPTS_VALUE {
+        opkt_ref.dts =
+            ffi::av_rescale_q(pkt.dts, ist_st.time_base, mux_timebase) - ost_tb_start_time;
+    }
+    opkt_ref.duration = ffi::av_rescale_q(pkt.duration, ist_st.time_base, mux_timebase);
+    if (*ist_st.codecpar).codec_type == ffi::AVMediaType_AVMEDIA_TYPE_VIDEO {
+        session.output_streams[i].sync_opts += 1;
+    }
+
+    let result = output_packet(session, i, opkt, false);
+    ffi::av_packet_free(&mut opkt);
+    result
+}
+
+/// Decode a demuxed packet, or flush the decoder when `pkt` is null, and
+/// copy it to the stream copy outputs.
+unsafe fn process_input_packet(
+    session: &mut Session,
+    ist_index: usize,
+    pkt: *const ffi::AVPacket,
+) -> Result<(), String> {
+    if session.input_streams[ist_index].decoding_needed != 0 {
+        decode_packet(session, ist_index, pkt, false)?;
+    }
+    let pkt = match pkt.as_ref() {
+        Some(pkt) => pkt,
+        None => return Ok(()),
+    };
+    for i in 0..session.output_streams.len() {
+        let ost = &session.output_streams[i];
+        if ost.source_index == Some(ist_index) && ost.stream_copy {
+            do_streamcopy(session, ist_index, i, pkt)?;
+        }
+    }
+    Ok(())
+}
+
+/// Read a packet of an input file, `EAGAIN` if `-re` has to wait for it.
+unsafe fn get_input_packet(session: &Session, file_index: usize, pkt: *mut ffi::AVPacket) -> i32 {
+    let ifile = &session.input_files[file_index];
+    if ifile.rate_emu != 0 {
+        let ist_range = ifile.ist_index..ifile.ist_index + ifile.streams.len();
+        for ist in &session.input_streams[ist_range] {
+            let pts = ffi::av_rescale(ist.dts, 1000000, ffi::AV_TIME_BASE as i64);
+            let now = ffi::av_gettime_relative() - ist.start;
+            if pts > now {
+                return AVERROR(ffi::EAGAIN as i32);
+            }
+        }
+    }
+    ffi::av_read_frame(ifile.ctx, pkt)
+}
+
+/// Pick the longer of `duration` in `time_base` and `tmp` in
+/// `tmp_time_base`, returns the time base of the result.
+unsafe fn duration_max(
+    tmp: i64,
+    duration: &mut i64,
+    tmp_time_base: ffi::AVRational,
+    time_base: ffi::AVRational,
+) -> ffi::AVRational {
+    if *duration == 0 || ffi::av_compare_ts(*duration, time_base, tmp, tmp_time_base) < 0 {
+        *duration = tmp;
+        return tmp_time_base;
+    }
+    time_base
+}
+
+/// Seek an input file back to its start for `-stream_loop`, and add its
+/// duration to `InputFile::duration`, which offsets the timestamps of the
+/// next loop.
+unsafe fn seek_to_start(session: &mut Session, file_index: usize) -> i32 {
+    let ifile = &mut session.input_files[file_index];
+    let is = ifile.ctx;
+    let ret = ffi::avformat_seek_file(is, -1, i64::MIN, (*is).start_time, (*is).start_time, 0);
+    if ret < 0 {
+        return ret;
+    }
+
+    let ist_range = ifile.ist_index..ifile.ist_index + ifile.streams.len();
+    let ists = &session.input_streams[ist_range];
+    let has_samples = |ist: &InputStream| {
+        (*ist.dec_ctx).codec_type == ffi::AVMediaType_AVMEDIA_TYPE_AUDIO && ist.nb_samples != 0
+    };
+    // duration is the length of the last frame in a stream
+    // when audio stream is present we don't care about
+    // last video frame length because it's not defined exactly
+    let has_audio = ists.iter().any(has_samples);
+
+    for ist in ists {
+        let st = &*ist.st;
+        let mut duration = if has_audio {
+            if !has_samples(ist) {
+                continue;
+            }
+            let sample_rate = ffi::AVRational {
+                num: 1,
+                den: (*ist.dec_ctx).sample_rate,
+            };
+            ffi::av_rescale_q(ist.nb_samples, sample_rate, st.time_base)
+        } else {
+            let framerate = if ist.framerate.num != 0 {
+                ist.framerate
+            } else {
+                st.avg_frame_rate
+            };
+            if framerate.num != 0 {
+                let inv = ffi::AVRational {
+                    num: framerate.den,
+                    den: framerate.num,
+                };
+                ffi::av_rescale_q(1, inv, st.time_base)
+            } else {
+                1
+            }
+        };
+        if ifile.duration == 0 {
+            ifile.time_base = st.time_base;
+        }
+        // the total duration of the stream, max_pts - min_pts is
+        // the duration of the stream without the last frame
+        if ist.max_pts > ist.min_pts
+            && (ist.max_pts as u64).wrapping_sub(ist.min_pts as u64) < (i64::MAX - duration) as u64
+        {
+            duration += ist.max_pts - ist.min_pts;
+        }
+        ifile.time_base =
+            duration_max(duration, &mut ifile.duration, st.time_base, ifile.time_base);
+    }
+
+    if ifile.loops > 0 {
+        ifile.loops -= 1;
+    }
+    ret
+}
+
+/// Read one packet of an input file and process it, or flush the streams
+/// of the file at EOF. A looped file is flushed and read again from its
+/// start instead.
+unsafe fn process_input(session: &mut Session, file_index: usize) -> Result<(), String> {
+    let mut pkt = ffi::av_packet_alloc();
+    if pkt.is_null() {
+        return Err(cmdutils::av_err2str(AVERROR(ffi::ENOMEM as i32)));
+    }
+    let mut ret = get_input_packet(session, file_index, pkt);
+    if ret < 0 && ret != AVERROR(ffi::EAGAIN as i32) && session.input_files[file_index].loops != 0 {
+        let ifile = &session.input_files[file_index];
+        let ist_range = ifile.ist_index..ifile.ist_index + ifile.streams.len();
+        for ist_index in ist_range {
+            let ist = &session.input_streams[ist_index];
+            if ist.decoding_needed != 0 {
+                let dec_ctx = ist.dec_ctx;
+                if let Err(e) = decode_packet(session, ist_index, ptr::null(), true) {
+                    ffi::av_packet_free(&mut pkt);
+                    return Err(e);
+                }
+                ffi::avcodec_flush_buffers(dec_ctx);
+            }
+        }
+        ret = seek_to_start(session, file_index);
+        if ret < 0 {
+            warn!("Seek to start failed.");
+        } else {
+            ret = get_input_packet(session, file_index, pkt);
+        }
+    }
+    let ifile = &mut session.input_files[file_index];
+    if ret == AVERROR(ffi::EAGAIN as i32) {
+        ifile.eagain = true;
+        ffi::av_packet_free(&mut pkt);
+        return Ok(());
+    }
+    if ret < 0 {
+        ffi::av_packet_free(&mut pkt);
+        if ret != AVERROR_EOF {
+            cmdutils::print_error(&ifile.filename.to_string_lossy(), ret);
+            if session.exit_on_error != 0 {
+                return Err(cmdutils::av_err2str(ret));
+            }
+        }
+
+        let ist_range = ifile.ist_index..ifile.ist_index + ifile.streams.len();
+        for ist_index in ist_range {
+            if session.input_streams[ist_index].decoding_needed != 0 {
+                process_input_packet(session, ist_index, ptr::null())?;
+            }
+            // mark all outputs that don't go through lavfi as finished
+            for i in 0..session.output_streams.len() {
+                let ost = &session.output_streams[i];
+                if ost.source_index == Some(ist_index) && ost.stream_copy {
+                    finish_output_stream(session, i);
+                }
+            }
+        }
+        session.input_files[file_index].eof_reached = true;
+        return Ok(());
+    }
+
+    reset_eagain(session);
+    let result = process_demuxed_packet(session, file_index, &mut *pkt);
+    ffi::av_packet_free(&mut pkt);
+    result
+}
+
+unsafe fn process_demuxed_packet(
+    session: &mut Session,
+    file_index: usize,
+    pkt: &mut ffi::AVPacket,
+) -> Result<(), String> {
+    let ifile = &session.input_files[file_index];
+    // the demuxer added a stream after the file was opened
+    if pkt.stream_index as usize >= ifile.streams.len() {
+        return Ok(());
+    }
+    let ist_index = ifile.ist_index + pkt.stream_index as usize;
+    let ist = &session.input_streams[ist_index];
+    if ist.discard {
+        return Ok(());
+    }
+
+    let time_base = (*ist.st).time_base;
+    if pkt.dts != AV_NOPTS_VALUE {
+        pkt.dts += ffi::av_rescale_q(ifile.ts_offset, AV_TIME_BASE_Q, time_base);
+    }
+    if pkt.pts != AV_NOPTS_VALUE {
+        pkt.pts += ffi::av_rescale_q(ifile.ts_offset, AV_TIME_BASE_Q, time_base);
+    }
+    if pkt.pts != AV_NOPTS_VALUE {
+        pkt.pts = (pkt.pts as f64 * ist.ts_scale) as i64;
+    }
+    if pkt.dts != AV_NOPTS_VALUE {
+        pkt.dts = (pkt.dts as f64 * ist.ts_scale) as i64;
+    }
+
+    // offset the timestamps of a looped file by the duration of the
+    // previous loops
+    let duration = ffi::av_rescale_q(ifile.duration, ifile.time_base, time_base);
+    let ist = &mut session.input_streams[ist_index];
+    if pkt.pts != AV_NOPTS_VALUE {
+        pkt.pts += duration;
+        ist.max_pts = ist.max_pts.max(pkt.pts);
+        ist.min_pts = ist.min_pts.min(pkt.pts);
+    }
+    if pkt.dts != AV_NOPTS_VALUE {
+        pkt.dts += duration;
+        ist.dts = ffi::av_rescale_q(pkt.dts, time_base, AV_TIME_BASE_Q);
+    }
+    process_input_packet(session, ist_index, pkt)
+}
+
+/// Run the filtergraph feeding an output stream.
+///
+/// Returns the input stream whose file must be read next, if any.
+unsafe fn transcode_from_filter(session: &mut Session, fg: usize) -> Result<Option<usize>, String> {
+    let ret = ffi::avfilter_graph_request_oldest(session.filtergraphs[fg].graph);
+    if ret >= 0 {
+        return Ok(None);
+    }
+    if ret == AVERROR_EOF {
+        reap_filters(session)?;
+        for out in 0..session.filtergraphs[fg].outputs.len() {
+            if let Some(i) = session.filtergraphs[fg].outputs[out].ost {
+                close_output_stream(session, i);
+            }
+        }
+        return Ok(None);
+    }
+    if ret != AVERROR(ffi::EAGAIN as i32) {
+        return Err(cmdutils::av_err2str(ret));
+    }
+
+    // feed the input which had the most failed requests
+    let mut nb_requests_max = 0;
+    let mut ist_min = None;
+    for ifilter in &session.filtergraphs[fg].inputs {
+        let ist = &session.input_streams[ifilter.ist];
+        let ifile = &session.input_files[ist.file_index];
+        if ifile.eagain || ifile.eof_reached {
+            continue;
+        }
+        let nb_requests = ffi::av_buffersrc_get_nb_failed_requests(ifilter.filter);
+        if nb_requests > nb_requests_max {
+            nb_requests_max = nb_requests;
+            ist_min = Some(ifilter.ist);
+        }
+    }
+    if ist_min.is_none() {
+        for ofilter in &session.filtergraphs[fg].outputs {
+            if let Some(i) = ofilter.ost {
+                session.output_streams[i].unavailable = true;
+            }
+        }
+    }
+    Ok(ist_min)
+}
+
+/// Run a single step of transcoding.
+///
+/// Returns `false` when there is nothing more to transcode.
+unsafe fn transcode_step(session: &mut Session) -> Result<bool, String> {
+    let i = match choose_output(session) {
+        Some(i) => i,
+        None => {
+            if got_eagain(session) {
+                reset_eagain(session);
+                thread::sleep(Duration::from_millis(10));
+                return Ok(true);
+            }
+            debug!("No more inputs to read from, finishing.");
+            return Ok(false);
+        }
+    };
+
+    let ost = &session.output_streams[i];
+    let ist = if let Some((fg, _)) = ost.filter {
+        if session.filtergraphs[fg].graph.is_null()
+            && session.filtergraphs[fg].has_all_input_formats()
+        {
+            if let Err(e) = ffmpeg_filter::configure_filtergraph(session, fg) {
+                error!("{}", e);
+                return Err("Error reinitializing filters!".to_owned());
+            }
+        }
+        if !session.filtergraphs[fg].graph.is_null() {
+            init_output_stream_wrapper(session, i)?;
+            match transcode_from_filter(session, fg)? {
+                Some(ist) => Some(ist),
+                None => return Ok(true),
+            }
+        } else {
+            // feed an input which hasn't produced a frame yet
+            let ist = session.filtergraphs[fg]
+                .inputs
+                .iter()
+                .map(|ifilter| ifilter.ist)
+                .find(|&ist| {
+                    let ist = &session.input_streams[ist];
+                    !ist.got_output && !session.input_files[ist.file_index].eof_reached
+                });
+            if ist.is_none() {
+                session.output_streams[i].inputs_done = true;
+                return Ok(true);
+            }
+            ist
+        }
+    } else {
+        ost.source_index
+    };
+    let ist = match ist {
+        Some(ist) => ist,
+        None => {
+            session.output_streams[i].finished = true;
+            return Ok(true);
+        }
+    };
+
+    let file_index = session.input_streams[ist].file_index;
+    process_input(session, file_index)?;
+    if session.input_files[file_index].eagain {
+        session.output_streams[i].unavailable = true;
+        return Ok(true);
+    }
+
+    reap_filters(session)?;
+    Ok(true)
+}
+
+/// Drain the encoders and the bitstream filters at the end of transcoding.
+unsafe fn flush_encoders(session: &mut Session) -> Result<(), String> {
+    for i in 0..session.output_streams.len() {
+        let ost = &session.output_streams[i];
+        if !ost.encoding_needed {
+            if ost.initialized {
+                flush_output_bsfs(session, i)?;
+            }
+            continue;
+        }
+
+        // Try to enable encoding with no input frames.
+        // Maybe we should just let encoding fail instead.
+        if !ost.initialized {
+            error!(
+                "Finishing stream {}:{} without any data written to it.",
+                ost.file_index, ost.index
+            );
+            if let Some((fg, _)) = ost.filter {
+                if session.filtergraphs[fg].graph.is_null() {
+                    for input in 0..session.filtergraphs[fg].inputs.len() {
+                        let ifilter = &mut session.filtergraphs[fg].inputs[input];
+                        if ifilter.format < 0 {
+                            let par = &*(*session.input_streams[ifilter.ist].st).codecpar;
+                            ffmpeg_filter::ifilter_parameters_from_codecpar(ifilter, par);
+                        }
+                    }
+                    if !session.filtergraphs[fg].has_all_input_formats() {
+                        continue;
+                    }
+                    if let Err(e) = ffmpeg_filter::configure_filtergraph(session, fg) {
+                        error!("{}", e);
+                        return Err("Error configuring filter graph".to_owned());
+                    }
+                    finish_output_stream(session, i);
+                }
+            }
+            init_output_stream_wrapper(session, i)?;
+        }
+
+        let codec_type = (*session.output_streams[i].enc_ctx).codec_type;
+        if codec_type != ffi::AVMediaType_AVMEDIA_TYPE_VIDEO
+            && codec_type != ffi::AVMediaType_AVMEDIA_TYPE_AUDIO
+        {
+            continue;
+        }
+        encode_frame(session, i, ptr::null())?;
+        flush_output_bsfs(session, i)?;
+    }
+    Ok(())
+}
+
+unsafe fn flush_output_bsfs(session: &mut Session, i: usize) -> Result<(), String> {
+    let mut pkt = ffi::av_packet_alloc();
+    if pkt.is_null() {
+        return Err(cmdutils::av_err2str(AVERROR(ffi::ENOMEM as i32)));
+    }
+    let result = output_packet(session, i, pkt, true);
+    ffi::av_packet_free(&mut pkt);
+    result
+}
+
+/// Transcode the opened input files into the output files.
+pub unsafe fn transcode(session: &mut Session) -> Result<(), String> {
+    transcode_init(session)?;
+
+    while received_sigterm() == 0 {
+        // check if there's any stream where output is still needed
+        if !need_output(session) {
+            debug!("No more output streams to write to, finishing.");
+            break;
+        }
+        if !transcode_step(session)? {
+            break;
+        }
+    }
+
+    // at the end of stream, we must flush the decoder buffers
+    for i in 0..session.input_streams.len() {
+        let ist = &session.input_streams[i];
+        if !session.input_files[ist.file_index].eof_reached {
+            process_input_packet(session, i, ptr::null())?;
+        }
+    }
+    flush_encoders(session)?;
+
+    // write the trailer if needed
+    for (i, of) in session.output_files.iter().enumerate() {
+        if !of.header_written {
+            error!(
+                "Nothing was written into output file {} ({}), because at least one of its streams received no packets.",
+                i,
+                of.filename.to_string_lossy()
+            );
+            continue;
+        }
+        let ret = ffi::av_write_trailer(of.ctx);
+        if ret < 0 {
+            let msg = format!(
+                "Error writing trailer of {}: {}",
+                of.filename.to_string_lossy(),
+                cmdutils::av_err2str(ret)
+            );
+            if session.exit_on_error != 0 {
+                return Err(msg);
+            }
+            error!("{}", msg);
+        }
+    }
+
+    let mut total_packets_written = 0;
+    for (i, ost) in session.output_streams.iter().enumerate() {
+        total_packets_written += ost.packets_written;
+        if ost.packets_written == 0
+            && session.abort_on_flags & ABORT_ON_FLAG_EMPTY_OUTPUT_STREAM != 0
+        {
+            return Err(format!("Empty output on stream {}.", i));
+        }
+    }
+    if total_packets_written == 0 && session.abort_on_flags & ABORT_ON_FLAG_EMPTY_OUTPUT != 0 {
+        return Err("Empty output".to_owned());
+    }
+    Ok(())
+}
+
+/// Free everything the session holds in libav and close the files.
+pub unsafe fn ffmpeg_cleanup(session: &mut Session) {
+    for fg in &mut session.filtergraphs {
+        ffmpeg_filter::cleanup_filtergraph(fg);
+        for ifilter in &mut fg.inputs {
+            while let Some(mut frame) = ifilter.frame_queue.pop_front() {
+                ffi::av_frame_free(&mut frame);
+            }
+        }
+    }
+
+    for of in &mut session.output_files {
+        let s = of.ctx;
+        if s.is_null() {
+            continue;
+        }
+        if (*(*s).oformat).flags & ffi::AVFMT_NOFILE as i32 == 0 {
+            ffi::avio_closep(&mut (*s).pb);
+        }
+        ffi::avformat_free_context(s);
+        of.ctx = ptr::null_mut();
+    }
+    for ost in &mut session.output_streams {
+        ffi::av_bsf_free(&mut ost.bsf_ctx);
+        ffi::avcodec_free_context(&mut ost.enc_ctx);
+        while let Some(mut pkt) = ost.muxing_queue.pop_front() {
+            ffi::av_packet_free(&mut pkt);
+        }
+    }
+
+    for ifile in &mut session.input_files {
+        ffi::avformat_close_input(&mut ifile.ctx);
+    }
+    for ist in &mut session.input_streams {
+        ffi::avcodec_free_context(&mut ist.dec_ctx);
+    }
+
+    ffi::avformat_network_deinit();
+}
+
+/// Returns the exit code of the program.
+pub fn ffmpeg() -> i32 {
+    let args: Vec<OsString> = env::args_os().collect();
+
+    let mut session = Session::default();
+    cmdutils::init_log();
+    // The `@file`s are expanded again, and their errors reported, by the
+    // option parsing, this is only for the options needed early.
+    let early_args =
+        cmdutils::expand_argfiles(&args).map_or_else(|_| args.clone(), |(args, _)| args);
+    cmdutils::parse_loglevel(&mut session, &early_args, &*OPTIONS);
+
+    unsafe {
+        ffi::avdevice_register_all();
+        ffi::avformat_network_init();
+    }
+
+    cmdutils::show_banner(&session, &early_args, &*OPTIONS);
+
+    let ret = match ffmpeg_parse_options(&mut session, &args) {
+        Ok(_) => run(&mut session),
+        Err(ParseError::ExitRequested { .. }) => 0,
+        Err(_) => 1,
+    };
+    unsafe { ffmpeg_cleanup(&mut session) };
+
+    let sig = received_sigterm();
+    if sig != 0 {
+        info!("Exiting normally, received signal {}.", sig);
+    } else if ret != 0 && TRANSCODE_INIT_DONE.load(Ordering::SeqCst) != 0 {
+        info!("Conversion failed!");
+    }
+    if RECEIVED_NB_SIGNALS.load(Ordering::SeqCst) != 0 {
+        255
+    } else {
+        ret
+    }
+}
+
+fn run(session: &mut Session) -> i32 {
+    if session.output_files.is_empty() && session.input_files.is_empty() {
+        ffmpeg_opt::show_usage();
+        error!("Use -h to get full help or, even better, run 'man ffcli'");
+        return 1;
+    }
+    // file converter / grab
+    if session.output_files.is_empty() {
+        error!("At least one output file must be specified");
+        return 1;
+    }
+    if let Err(e) = unsafe { transcode(session) } {
+        error!("{}", e);
+        return 1;
+    }
+    session.main_return_code
+}
+
+#[cfg(test)]
+mod ffmpeg_tests {
+    use super::*;
+
+    #[test]
+    fn disposition_flags() {
+        let default = ffi::AV_DISPOSITION_DEFAULT as i32;
+        let forced = ffi::AV_DISPOSITION_FORCED as i32;
+        let dub = ffi::AV_DISPOSITION_DUB as i32;
+        assert_eq!(parse_disposition("default", dub), Ok(default));
+        assert_eq!(parse_disposition("default+forced", 0), Ok(default | forced));
+        assert_eq!(parse_disposition("+forced", dub), Ok(dub | forced));
+        assert_eq!(parse_disposition("-dub", dub | forced), Ok(forced));
+        assert_eq!(parse_disposition("0", dub), Ok(0));
+        assert_eq!(parse_disposition("+3", 0), Ok(3));
+        assert!(parse_disposition("loud", 0).is_err());
+    }
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn transcode_lavfi_to_null() {
+        unsafe { ffi::avdevice_register_all() };
+        let mut session = Session::default();
+        let args = args(&[
+            "ffcli",
+            "-f",
+            "lavfi",
+            "-i",
+            "testsrc=duration=1:size=64x48:rate=10",
+            "-f",
+            "lavfi",
+            "-i",
+            "sine=duration=1",
+            "-vcodec",
+            "rawvideo",
+            "-vf",
+            "hflip",
+            "-vframes",
+            "5",
+            "-acodec",
+            "pcm_s16le",
+            "-streamid",
+            "0:42",
+            "-abort_on",
+            "empty_output_stream",
+            "-f",
+            "null",
+            "-",
+        ]);
+        ffmpeg_opt::ffmpeg_parse_options(&mut session, &args).unwrap();
+        let ret = unsafe { transcode(&mut session) };
+        assert_eq!(ret, Ok(()));
+
+        assert_eq!(session.output_streams.len(), 2);
+        let video = &session.output_streams[0];
+        assert_eq!(video.packets_written, 5);
+        assert_eq!(unsafe { (*video.st).id }, 42);
+        assert!(session.output_streams[1].packets_written > 0);
+        unsafe { ffmpeg_cleanup(&mut session) };
+    }
+
+    #[test]
+    fn abort_on_empty_output() {
+        unsafe { ffi::avdevice_register_all() };
+        let mut session = Session::default();
+        let args = args(&[
+            "ffcli",
+            "-abort_on",
+            "empty_output",
+            "-f",
+            "lavfi",
+            "-i",
+            "testsrc=duration=1:size=64x48:rate=10",
+            "-vframes",
+            "0",
+            "-f",
+            "null",
+            "-",
+        ]);
+        ffmpeg_opt::ffmpeg_parse_options(&mut session, &args).unwrap();
+        let ret = unsafe { transcode(&mut session) };
+        assert_eq!(ret, Err("Empty output".to_owned()));
+        unsafe { ffmpeg_cleanup(&mut session) };
+    }
+
+    #[test]
+    fn stream_loop_with_rate_emulation() {
+        unsafe { ffi::avdevice_register_all() };
+        let path = std::env::temp_dir().join(format!("ffcli-loop-{}.nut", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut session = Session::default();
+        let encode = args(&[
+            "ffcli",
+            "-f",
+            "lavfi",
+            "-i",
+            "testsrc=duration=1:size=64x48:rate=10",
+            "-c:v",
+            "rawvideo",
+            "-y",
+            path,
+        ]);
+        ffmpeg_opt::ffmpeg_parse_options(&mut session, &encode).unwrap();
+        assert_eq!(unsafe { transcode(&mut session) }, Ok(()));
+        unsafe { ffmpeg_cleanup(&mut session) };
+
+        let mut session = Session::default();
+        let play = args(&[
+            "ffcli",
+            "-stream_loop",
+            "1",
+            "-re",
+            "-i",
+            path,
+            "-c:v",
+            "rawvideo",
+            "-f",
+            "null",
+            "-",
+        ]);
+        ffmpeg_opt::ffmpeg_parse_options(&mut session, &play).unwrap();
+        let start = std::time::Instant::now();
+        assert_eq!(unsafe { transcode(&mut session) }, Ok(()));
+        // 2 loops of 1s read at the native frame rate
+        assert!(start.elapsed() >= Duration::from_millis(1800));
+        assert_eq!(session.input_files[0].loops, 0);
+        assert_eq!(session.output_streams[0].packets_written, 20);
+        unsafe { ffmpeg_cleanup(&mut session) };
+        std::fs::remove_file(path).unwrap();
+    }
 }
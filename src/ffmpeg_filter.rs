@@ -0,0 +1,1125 @@
+//! This file corresponds to ffmpeg_filter.c
+use libc::c_void;
+use log::error;
+use rusty_ffmpeg::{
+    avutil::{avutils::*, error::*},
+    ffi,
+};
+
+use std::{
+    collections::VecDeque,
+    ffi::{CStr, CString},
+    ptr,
+};
+
+use crate::{
+    cmdutils,
+    dict::Dictionary,
+    ffmpeg::{InputFile, OutputStream, Session, DECODING_FOR_FILTER},
+    stream_specifier::{strtol, MediaType, StreamSpecifier},
+};
+
+/// An input pad of a filtergraph, fed by an input stream.
+#[derive(Debug)]
+pub struct InputFilter {
+    /// The `buffer` or `abuffer` source, null while the graph is not
+    /// configured.
+    pub filter: *mut ffi::AVFilterContext,
+    /// Index in `Session::input_streams`.
+    pub ist: usize,
+    pub media_type: ffi::AVMediaType,
+    /// The filter and, if it has several inputs, the pad, e.g. `overlay:main`.
+    pub name: String,
+    /// Decoded frames received before the graph could be configured.
+    pub frame_queue: VecDeque<*mut ffi::AVFrame>,
+
+    // parameters configured for this input, -1 format until the first frame
+    pub format: i32,
+    pub width: i32,
+    pub height: i32,
+    pub sample_aspect_ratio: ffi::AVRational,
+    pub sample_rate: i32,
+    pub channels: i32,
+    pub channel_layout: u64,
+
+    pub eof: bool,
+}
+
+impl InputFilter {
+    fn new(ist: usize, media_type: ffi::AVMediaType, name: String) -> Self {
+        Self {
+            filter: ptr::null_mut(),
+            ist,
+            media_type,
+            name,
+            frame_queue: VecDeque::new(),
+            format: -1,
+            width: 0,
+            height: 0,
+            sample_aspect_ratio: ffi::AVRational { num: 0, den: 1 },
+            sample_rate: 0,
+            channels: 0,
+            channel_layout: 0,
+            eof: false,
+        }
+    }
+}
+
+/// An output pad of a filtergraph, feeding an output stream.
+#[derive(Debug)]
+pub struct OutputFilter {
+    /// The `buffersink` or `abuffersink`, null while the graph is not
+    /// configured.
+    pub filter: *mut ffi::AVFilterContext,
+    /// Index in `Session::output_streams`, `None` until the pad is bound by
+    /// `-map "[label]"` or as an unlabeled output.
+    pub ost: Option<usize>,
+    pub media_type: ffi::AVMediaType,
+    /// The filter and, if it has several outputs, the pad.
+    pub name: String,
+    /// Label of the pad in the graph description, empty if unlabeled.
+    pub label: String,
+
+    // desired output stream properties
+    pub width: i32,
+    pub height: i32,
+    pub frame_rate: ffi::AVRational,
+    pub format: i32,
+    pub sample_rate: i32,
+    pub channel_layout: u64,
+
+    // those are only set if no format is specified and the encoder gives us
+    // multiple options
+    pub formats: Vec<i32>,
+    pub channel_layouts: Vec<u64>,
+    pub sample_rates: Vec<i32>,
+}
+
+impl OutputFilter {
+    pub(crate) fn new(media_type: ffi::AVMediaType, name: String, label: String) -> Self {
+        Self {
+            filter: ptr::null_mut(),
+            ost: None,
+            media_type,
+            name,
+            label,
+            width: 0,
+            height: 0,
+            frame_rate: ffi::AVRational { num: 0, den: 0 },
+            format: -1,
+            sample_rate: 0,
+            channel_layout: 0,
+            formats: vec![],
+            channel_layouts: vec![],
+            sample_rates: vec![],
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FilterGraph {
+    /// Index in `Session::filtergraphs`.
+    pub index: usize,
+    /// Null until the parameters of every input are known.
+    pub graph: *mut ffi::AVFilterGraph,
+    /// Set by `-filter_complex`, empty for simple filtergraphs whose
+    /// description is the `avfilter` of their output stream.
+    pub graph_desc: String,
+    pub inputs: Vec<InputFilter>,
+    pub outputs: Vec<OutputFilter>,
+}
+
+impl FilterGraph {
+    pub fn new(index: usize, graph_desc: &str) -> Self {
+        Self {
+            index,
+            graph: ptr::null_mut(),
+            graph_desc: graph_desc.to_owned(),
+            inputs: vec![],
+            outputs: vec![],
+        }
+    }
+
+    pub fn is_simple(&self) -> bool {
+        self.graph_desc.is_empty()
+    }
+
+    /// Whether the graph can be configured, i.e. every audio and video input
+    /// got its parameters.
+    pub fn has_all_input_formats(&self) -> bool {
+        self.inputs.iter().all(|ifilter| {
+            ifilter.format >= 0
+                || (ifilter.media_type != ffi::AVMediaType_AVMEDIA_TYPE_AUDIO
+                    && ifilter.media_type != ffi::AVMediaType_AVMEDIA_TYPE_VIDEO)
+        })
+    }
+}
+
+/// Connect the input stream `ist` to the output stream `ost` through a new
+/// one-input, one-output filtergraph.
+pub unsafe fn init_simple_filtergraph(session: &mut Session, ist: usize, ost: usize) {
+    let index = session.filtergraphs.len();
+    let mut fg = FilterGraph::new(index, "");
+    let media_type = (*session.output_streams[ost].enc_ctx).codec_type;
+
+    let mut ofilter = OutputFilter::new(media_type, String::new(), String::new());
+    ofilter.ost = Some(ost);
+    fg.outputs.push(ofilter);
+    session.output_streams[ost].filter = Some((index, 0));
+
+    fg.inputs
+        .push(InputFilter::new(ist, media_type, String::new()));
+    session.input_streams[ist].filters.push((index, 0));
+
+    session.filtergraphs.push(fg);
+}
+
+/// The filter name of a pad, with the pad name if the filter has several
+/// pads in that direction.
+unsafe fn describe_filter_link(inout: &ffi::AVFilterInOut, input: bool) -> String {
+    let ctx = inout.filter_ctx.as_ref().unwrap();
+    let (pads, nb_pads) = if input {
+        (ctx.input_pads, ctx.nb_inputs)
+    } else {
+        (ctx.output_pads, ctx.nb_outputs)
+    };
+    let name = CStr::from_ptr((*ctx.filter).name).to_string_lossy();
+    if nb_pads > 1 {
+        let pad_name = CStr::from_ptr(ffi::avfilter_pad_get_name(pads, inout.pad_idx));
+        format!("{}:{}", name, pad_name.to_string_lossy())
+    } else {
+        name.into_owned()
+    }
+}
+
+/// Split the label of a filtergraph input, `file_index[:stream_specifier]`.
+fn split_input_label(label: &str) -> (i64, &str) {
+    strtol(label).unwrap_or((0, label))
+}
+
+/// Find the stream a labeled filtergraph input refers to: the first stream
+/// of the file which matches the specifier and has the pad's type (a
+/// subtitle stream can feed a video pad). Returns the file and stream index.
+pub fn find_labeled_input(
+    input_files: &[InputFile],
+    label: &str,
+    media_type: MediaType,
+    graph_desc: &str,
+) -> Result<(usize, usize), String> {
+    let (file_index, spec) = split_input_label(label);
+    if file_index < 0 || file_index as usize >= input_files.len() {
+        return Err(format!(
+            "Invalid file index {} in filtergraph description {}.",
+            file_index, graph_desc
+        ));
+    }
+    let file_index = file_index as usize;
+    let streams = &input_files[file_index].streams;
+    let found = spec
+        .strip_prefix(':')
+        .unwrap_or(spec)
+        .parse::<StreamSpecifier>()
+        .ok()
+        .and_then(|specifier| {
+            (0..streams.len()).find(|&i| {
+                let stream_type = streams[i].media_type;
+                // sub2video hack
+                (stream_type == Some(media_type)
+                    || (stream_type == Some(MediaType::Subtitle) && media_type == MediaType::Video))
+                    && specifier.matches(streams, i)
+            })
+        });
+    match found {
+        Some(stream_index) => Ok((file_index, stream_index)),
+        None => Err(format!(
+            "Stream specifier '{}' in filtergraph description {} matches no streams.",
+            spec, graph_desc
+        )),
+    }
+}
+
+/// Bind an input pad of the filtergraph `index` to its labeled input stream,
+/// or to the first unused input stream of its type.
+unsafe fn init_input_filter(
+    session: &mut Session,
+    index: usize,
+    inout: &ffi::AVFilterInOut,
+) -> Result<(), String> {
+    let ctx = inout.filter_ctx.as_ref().unwrap();
+    let ty = ffi::avfilter_pad_get_type(ctx.input_pads, inout.pad_idx);
+    let media_type = match MediaType::from_av(ty) {
+        Some(t @ MediaType::Video) | Some(t @ MediaType::Audio) => t,
+        // TODO: support other filter types
+        _ => return Err("Only video and audio filters supported currently.".to_owned()),
+    };
+    let graph_desc = session.filtergraphs[index].graph_desc.clone();
+
+    let ist_index = if !inout.name.is_null() {
+        let label = CStr::from_ptr(inout.name).to_string_lossy();
+        let (file_index, stream_index) =
+            find_labeled_input(&session.input_files, &label, media_type, &graph_desc)?;
+        let ist_index = session.input_files[file_index].ist_index + stream_index;
+        if session.input_streams[ist_index].user_set_discard == ffi::AVDiscard_AVDISCARD_ALL {
+            return Err(format!(
+                "Stream specifier '{}' in filtergraph description {} matches a disabled input \
+                 stream.",
+                split_input_label(&label).1,
+                graph_desc
+            ));
+        }
+        ist_index
+    } else {
+        // find the first unused stream of corresponding type
+        let found = session.input_streams.iter().position(|ist| {
+            ist.user_set_discard != ffi::AVDiscard_AVDISCARD_ALL
+                && (*ist.dec_ctx).codec_type == ty
+                && ist.discard
+        });
+        match found {
+            Some(ist_index) => ist_index,
+            None => {
+                return Err(format!(
+                    "Cannot find a matching stream for unlabeled input pad {} on filter {}",
+                    inout.pad_idx,
+                    CStr::from_ptr(ctx.name).to_string_lossy()
+                ))
+            }
+        }
+    };
+
+    let ist = &mut session.input_streams[ist_index];
+    ist.discard = false;
+    ist.decoding_needed |= DECODING_FOR_FILTER;
+    (*ist.st).discard = ffi::AVDiscard_AVDISCARD_NONE;
+
+    let fg = &mut session.filtergraphs[index];
+    fg.inputs.push(InputFilter::new(
+        ist_index,
+        (*(*ist.st).codecpar).codec_type,
+        describe_filter_link(inout, true),
+    ));
+    ist.filters.push((index, fg.inputs.len() - 1));
+    Ok(())
+}
+
+/// Record the inputs and outputs of the `-filter_complex` graph `index`.
+/// The inputs are bound to input streams now, the outputs are bound when the
+/// output files are opened.
+pub unsafe fn init_complex_filtergraph(session: &mut Session, index: usize) -> Result<(), String> {
+    // this graph is only used for determining the kinds of inputs and
+    // outputs we have, and is discarded on exit from this function
+    let mut graph = ffi::avfilter_graph_alloc();
+    if graph.is_null() {
+        return Err(cmdutils::av_err2str(AVERROR(ffi::ENOMEM as i32)));
+    }
+    (*graph).nb_threads = 1;
+
+    let graph_desc = &session.filtergraphs[index].graph_desc;
+    let graph_desc_s = CString::new(graph_desc.as_str())
+        .map_err(|_| format!("Invalid filtergraph description {}.", graph_desc))?;
+    let mut inputs = ptr::null_mut();
+    let mut outputs = ptr::null_mut();
+    let ret = ffi::avfilter_graph_parse2(graph, graph_desc_s.as_ptr(), &mut inputs, &mut outputs);
+    let result = if ret < 0 {
+        Err(format!(
+            "Error parsing filtergraph description {}: {}",
+            graph_desc,
+            cmdutils::av_err2str(ret)
+        ))
+    } else {
+        add_filtergraph_pads(session, index, inputs, outputs)
+    };
+
+    ffi::avfilter_inout_free(&mut inputs);
+    ffi::avfilter_inout_free(&mut outputs);
+    ffi::avfilter_graph_free(&mut graph);
+    result
+}
+
+unsafe fn add_filtergraph_pads(
+    session: &mut Session,
+    index: usize,
+    inputs: *mut ffi::AVFilterInOut,
+    outputs: *mut ffi::AVFilterInOut,
+) -> Result<(), String> {
+    let mut cur = inputs;
+    while let Some(inout) = cur.as_ref() {
+        init_input_filter(session, index, inout)?;
+        cur = inout.next;
+    }
+
+    let mut cur = outputs;
+    while let Some(inout) = cur.as_ref() {
+        let ctx = inout.filter_ctx.as_ref().unwrap();
+        let label = if inout.name.is_null() {
+            String::new()
+        } else {
+            CStr::from_ptr(inout.name).to_string_lossy().into_owned()
+        };
+        session.filtergraphs[index].outputs.push(OutputFilter::new(
+            ffi::avfilter_pad_get_type(ctx.output_pads, inout.pad_idx),
+            describe_filter_link(inout, false),
+            label,
+        ));
+        cur = inout.next;
+    }
+    Ok(())
+}
+
+/// Free the libavfilter graph, the graph can be configured again later.
+pub unsafe fn cleanup_filtergraph(fg: &mut FilterGraph) {
+    for ofilter in &mut fg.outputs {
+        ofilter.filter = ptr::null_mut();
+    }
+    for ifilter in &mut fg.inputs {
+        ifilter.filter = ptr::null_mut();
+    }
+    ffi::avfilter_graph_free(&mut fg.graph);
+}
+
+/// Take the parameters of a filtergraph input from a decoded frame.
+pub fn ifilter_parameters_from_frame(ifilter: &mut InputFilter, frame: &ffi::AVFrame) {
+    ifilter.format = frame.format;
+    ifilter.width = frame.width;
+    ifilter.height = frame.height;
+    ifilter.sample_aspect_ratio = frame.sample_aspect_ratio;
+    ifilter.sample_rate = frame.sample_rate;
+    ifilter.channels = frame.channels;
+    ifilter.channel_layout = frame.channel_layout;
+}
+
+/// Take the parameters of a filtergraph input from the demuxer, for streams
+/// which reached EOF without decoding any frame.
+pub fn ifilter_parameters_from_codecpar(ifilter: &mut InputFilter, par: &ffi::AVCodecParameters) {
+    ifilter.format = par.format;
+    ifilter.width = par.width;
+    ifilter.height = par.height;
+    ifilter.sample_aspect_ratio = par.sample_aspect_ratio;
+    ifilter.sample_rate = par.sample_rate;
+    ifilter.channels = par.channels;
+    ifilter.channel_layout = par.channel_layout;
+}
+
+/// `key=value` pairs joined by `sep`, the argument syntax of filters.
+fn dict_to_args(dict: &Dictionary, sep: &str) -> String {
+    dict.iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(sep)
+}
+
+unsafe fn pix_fmt_name(format: i32) -> String {
+    let name = ffi::av_get_pix_fmt_name(format);
+    if name.is_null() {
+        format.to_string()
+    } else {
+        CStr::from_ptr(name).to_string_lossy().into_owned()
+    }
+}
+
+unsafe fn sample_fmt_name(format: i32) -> String {
+    let name = ffi::av_get_sample_fmt_name(format);
+    if name.is_null() {
+        format.to_string()
+    } else {
+        CStr::from_ptr(name).to_string_lossy().into_owned()
+    }
+}
+
+/// Create an instance `name` of the filter `filter_name` in `graph`.
+unsafe fn create_filter(
+    filter_name: &str,
+    name: &str,
+    args: Option<&str>,
+    graph: *mut ffi::AVFilterGraph,
+) -> Result<*mut ffi::AVFilterContext, String> {
+    let filter_name_s = CString::new(filter_name).unwrap();
+    let filter = ffi::avfilter_get_by_name(filter_name_s.as_ptr());
+    if filter.is_null() {
+        return Err(format!("{} filter not present.", filter_name));
+    }
+    let name_s = CString::new(name).unwrap();
+    let args_s = args.map(|args| CString::new(args).unwrap());
+    let mut ctx = ptr::null_mut();
+    let ret = ffi::avfilter_graph_create_filter(
+        &mut ctx,
+        filter,
+        name_s.as_ptr(),
+        args_s.as_ref().map_or(ptr::null(), |args| args.as_ptr()),
+        ptr::null_mut(),
+        graph,
+    );
+    if ret < 0 {
+        return Err(format!(
+            "Error creating filter {} with args '{}': {}",
+            name,
+            args.unwrap_or(""),
+            cmdutils::av_err2str(ret)
+        ));
+    }
+    Ok(ctx)
+}
+
+unsafe fn link_filters(
+    src: *mut ffi::AVFilterContext,
+    srcpad: u32,
+    dst: *mut ffi::AVFilterContext,
+    dstpad: u32,
+) -> Result<(), String> {
+    let ret = ffi::avfilter_link(src, srcpad, dst, dstpad);
+    if ret < 0 {
+        Err(cmdutils::av_err2str(ret))
+    } else {
+        Ok(())
+    }
+}
+
+/// Append a filter after the output pad `pad_idx` of `last_filter`, which
+/// are updated to the new filter.
+unsafe fn insert_filter(
+    last_filter: &mut *mut ffi::AVFilterContext,
+    pad_idx: &mut u32,
+    filter_name: &str,
+    name: &str,
+    args: &str,
+) -> Result<(), String> {
+    let ctx = create_filter(filter_name, name, Some(args), (**last_filter).graph)?;
+    link_filters(*last_filter, *pad_idx, ctx, 0)?;
+    *last_filter = ctx;
+    *pad_idx = 0;
+    Ok(())
+}
+
+/// Append a `trim` or `atrim` filter implementing `-ss` and `-t`, if they
+/// are set.
+unsafe fn insert_trim(
+    start_time: i64,
+    duration: i64,
+    last_filter: &mut *mut ffi::AVFilterContext,
+    pad_idx: &mut u32,
+    filter_name: &str,
+) -> Result<(), String> {
+    if duration == i64::MAX && start_time == AV_NOPTS_VALUE {
+        return Ok(());
+    }
+    let graph = (**last_filter).graph;
+    let ty = ffi::avfilter_pad_get_type((**last_filter).output_pads, *pad_idx as i32);
+    let name = if ty == ffi::AVMediaType_AVMEDIA_TYPE_VIDEO {
+        "trim"
+    } else {
+        "atrim"
+    };
+
+    let name_s = CString::new(name).unwrap();
+    let trim = ffi::avfilter_get_by_name(name_s.as_ptr());
+    if trim.is_null() {
+        return Err(format!(
+            "{} filter not present, cannot limit recording time.",
+            name
+        ));
+    }
+    let filter_name_s = CString::new(filter_name).unwrap();
+    let ctx = ffi::avfilter_graph_alloc_filter(graph, trim, filter_name_s.as_ptr());
+    if ctx.is_null() {
+        return Err(cmdutils::av_err2str(AVERROR(ffi::ENOMEM as i32)));
+    }
+
+    let mut ret = 0;
+    if duration != i64::MAX {
+        let key = CString::new("durationi").unwrap();
+        ret = ffi::av_opt_set_int(
+            ctx as *mut c_void,
+            key.as_ptr(),
+            duration,
+            ffi::AV_OPT_SEARCH_CHILDREN as i32,
+        );
+    }
+    if ret >= 0 && start_time != AV_NOPTS_VALUE {
+        let key = CString::new("starti").unwrap();
+        ret = ffi::av_opt_set_int(
+            ctx as *mut c_void,
+            key.as_ptr(),
+            start_time,
+            ffi::AV_OPT_SEARCH_CHILDREN as i32,
+        );
+    }
+    if ret < 0 {
+        return Err(format!("Error configuring the {} filter", name));
+    }
+    let ret = ffi::avfilter_init_str(ctx, ptr::null());
+    if ret < 0 {
+        return Err(cmdutils::av_err2str(ret));
+    }
+
+    link_filters(*last_filter, *pad_idx, ctx, 0)?;
+    *last_filter = ctx;
+    *pad_idx = 0;
+    Ok(())
+}
+
+unsafe fn configure_input_video_filter(
+    session: &mut Session,
+    index: usize,
+    i: usize,
+    inout: &ffi::AVFilterInOut,
+) -> Result<(), String> {
+    let fg = &session.filtergraphs[index];
+    let ifilter = &fg.inputs[i];
+    let ist = &session.input_streams[ifilter.ist];
+    let f = &session.input_files[ist.file_index];
+    let st = ist.st.as_ref().unwrap();
+
+    if (*st.codecpar).codec_type == ffi::AVMediaType_AVMEDIA_TYPE_SUBTITLE {
+        return Err(format!(
+            "Subtitle stream {}:{} cannot feed a filtergraph yet.",
+            ist.file_index, st.index
+        ));
+    }
+
+    let tb = if ist.framerate.num != 0 {
+        cmdutils::av_inv_q(ist.framerate)
+    } else {
+        st.time_base
+    };
+    let fr = if ist.framerate.num != 0 {
+        ist.framerate
+    } else {
+        ffi::av_guess_frame_rate(f.ctx, ist.st, ptr::null_mut())
+    };
+    let sar = if ifilter.sample_aspect_ratio.den == 0 {
+        ffi::AVRational { num: 0, den: 1 }
+    } else {
+        ifilter.sample_aspect_ratio
+    };
+    let mut args = format!(
+        "video_size={}x{}:pix_fmt={}:time_base={}/{}:pixel_aspect={}/{}",
+        ifilter.width, ifilter.height, ifilter.format, tb.num, tb.den, sar.num, sar.den
+    );
+    if fr.num != 0 && fr.den != 0 {
+        args += &format!(":frame_rate={}/{}", fr.num, fr.den);
+    }
+    let name = format!(
+        "graph {} input from stream {}:{}",
+        index, ist.file_index, st.index
+    );
+    let filter = create_filter("buffer", &name, Some(&args), fg.graph)?;
+    let mut last_filter = filter;
+    let mut pad_idx = 0;
+
+    let mut tsoffset = 0;
+    if session.copy_ts != 0 {
+        tsoffset = if f.start_time == AV_NOPTS_VALUE {
+            0
+        } else {
+            f.start_time
+        };
+        if session.start_at_zero == 0 && (*f.ctx).start_time != AV_NOPTS_VALUE {
+            tsoffset += (*f.ctx).start_time;
+        }
+    }
+    let start_time = if f.start_time == AV_NOPTS_VALUE || f.accurate_seek == 0 {
+        AV_NOPTS_VALUE
+    } else {
+        tsoffset
+    };
+    let name = format!("trim_in_{}_{}", ist.file_index, st.index);
+    insert_trim(
+        start_time,
+        f.recording_time,
+        &mut last_filter,
+        &mut pad_idx,
+        &name,
+    )?;
+    link_filters(last_filter, pad_idx, inout.filter_ctx, inout.pad_idx as u32)?;
+
+    session.filtergraphs[index].inputs[i].filter = filter;
+    Ok(())
+}
+
+unsafe fn configure_input_audio_filter(
+    session: &mut Session,
+    index: usize,
+    i: usize,
+    inout: &ffi::AVFilterInOut,
+) -> Result<(), String> {
+    let fg = &session.filtergraphs[index];
+    let ifilter = &fg.inputs[i];
+    let ist = &session.input_streams[ifilter.ist];
+    let f = &session.input_files[ist.file_index];
+    let st = ist.st.as_ref().unwrap();
+
+    let mut args = format!(
+        "time_base={}/{}:sample_rate={}:sample_fmt={}",
+        1,
+        ifilter.sample_rate,
+        ifilter.sample_rate,
+        sample_fmt_name(ifilter.format)
+    );
+    if ifilter.channel_layout != 0 {
+        args += &format!(":channel_layout=0x{:x}", ifilter.channel_layout);
+    } else {
+        args += &format!(":channels={}", ifilter.channels);
+    }
+    let name = format!("graph_{}_in_{}_{}", index, ist.file_index, st.index);
+    let filter = create_filter("abuffer", &name, Some(&args), fg.graph)?;
+    let mut last_filter = filter;
+    let mut pad_idx = 0;
+
+    if session.audio_volume != 256 {
+        error!("-vol has been deprecated. Use the volume audio filter instead.");
+        let args = format!("{:.6}", session.audio_volume as f64 / 256.);
+        insert_filter(&mut last_filter, &mut pad_idx, "volume", "volume", &args)
+            .map_err(|e| format!("Error inserting filter for -vol: {}", e))?;
+    }
+
+    let mut tsoffset = 0;
+    if session.copy_ts != 0 {
+        tsoffset = if f.start_time == AV_NOPTS_VALUE {
+            0
+        } else {
+            f.start_time
+        };
+        if session.start_at_zero == 0 && (*f.ctx).start_time != AV_NOPTS_VALUE {
+            tsoffset += (*f.ctx).start_time;
+        }
+    }
+    let start_time = if f.start_time == AV_NOPTS_VALUE || f.accurate_seek == 0 {
+        AV_NOPTS_VALUE
+    } else {
+        tsoffset
+    };
+    let name = format!("trim for input stream {}:{}", ist.file_index, st.index);
+    insert_trim(
+        start_time,
+        f.recording_time,
+        &mut last_filter,
+        &mut pad_idx,
+        &name,
+    )?;
+    link_filters(last_filter, pad_idx, inout.filter_ctx, inout.pad_idx as u32)?;
+
+    session.filtergraphs[index].inputs[i].filter = filter;
+    Ok(())
+}
+
+unsafe fn configure_input_filter(
+    session: &mut Session,
+    index: usize,
+    i: usize,
+    inout: &ffi::AVFilterInOut,
+) -> Result<(), String> {
+    let ctx = inout.filter_ctx.as_ref().unwrap();
+    match ffi::avfilter_pad_get_type(ctx.input_pads, inout.pad_idx) {
+        ffi::AVMediaType_AVMEDIA_TYPE_VIDEO => {
+            configure_input_video_filter(session, index, i, inout)
+        }
+        ffi::AVMediaType_AVMEDIA_TYPE_AUDIO => {
+            configure_input_audio_filter(session, index, i, inout)
+        }
+        _ => Err("Only video and audio filters supported currently.".to_owned()),
+    }
+}
+
+/// The `-pix_fmt` of the stream, or the formats its encoder supports.
+unsafe fn choose_pix_fmts(
+    ofilter: &OutputFilter,
+    ost: &OutputStream,
+    graph: *mut ffi::AVFilterGraph,
+) -> Option<String> {
+    if ost.keep_pix_fmt {
+        ffi::avfilter_graph_set_auto_convert(graph, ffi::AVFILTER_AUTO_CONVERT_NONE as u32);
+        let pix_fmt = (*ost.enc_ctx).pix_fmt;
+        return if pix_fmt == ffi::AVPixelFormat_AV_PIX_FMT_NONE {
+            None
+        } else {
+            Some(pix_fmt_name(pix_fmt))
+        };
+    }
+    if ofilter.format != -1 {
+        Some(pix_fmt_name(ofilter.format))
+    } else if !ofilter.formats.is_empty() {
+        let names: Vec<_> = ofilter.formats.iter().map(|&f| pix_fmt_name(f)).collect();
+        Some(names.join("|"))
+    } else {
+        None
+    }
+}
+
+unsafe fn configure_output_video_filter(
+    session: &mut Session,
+    index: usize,
+    i: usize,
+    inout: &ffi::AVFilterInOut,
+) -> Result<(), String> {
+    let fg = &session.filtergraphs[index];
+    let ofilter = &fg.outputs[i];
+    let ost = &session.output_streams[ofilter.ost.unwrap()];
+    let of = &session.output_files[ost.file_index];
+
+    let name = format!("out_{}_{}", ost.file_index, ost.index);
+    let sink = create_filter("buffersink", &name, None, fg.graph)?;
+    let mut last_filter = inout.filter_ctx;
+    let mut pad_idx = inout.pad_idx as u32;
+
+    if ofilter.width != 0 || ofilter.height != 0 {
+        let mut args = format!("{}:{}", ofilter.width, ofilter.height);
+        for (key, value) in ost.sws_dict.iter() {
+            args += &format!(":{}={}", key, value);
+        }
+        let name = format!("scaler_out_{}_{}", ost.file_index, ost.index);
+        insert_filter(&mut last_filter, &mut pad_idx, "scale", &name, &args)?;
+    }
+
+    if let Some(pix_fmts) = choose_pix_fmts(ofilter, ost, fg.graph) {
+        let name = format!("format_out_{}_{}", ost.file_index, ost.index);
+        insert_filter(&mut last_filter, &mut pad_idx, "format", &name, &pix_fmts)?;
+    }
+
+    let name = format!("trim_out_{}_{}", ost.file_index, ost.index);
+    insert_trim(
+        of.start_time,
+        of.recording_time,
+        &mut last_filter,
+        &mut pad_idx,
+        &name,
+    )?;
+    link_filters(last_filter, pad_idx, sink, 0)?;
+
+    session.filtergraphs[index].outputs[i].filter = sink;
+    Ok(())
+}
+
+unsafe fn configure_output_audio_filter(
+    session: &mut Session,
+    index: usize,
+    i: usize,
+    inout: &ffi::AVFilterInOut,
+) -> Result<(), String> {
+    let fg = &session.filtergraphs[index];
+    let ofilter = &fg.outputs[i];
+    let ost = &session.output_streams[ofilter.ost.unwrap()];
+    let of = &session.output_files[ost.file_index];
+
+    let name = format!("out_{}_{}", ost.file_index, ost.index);
+    let sink = create_filter("abuffersink", &name, None, fg.graph)?;
+    let key = CString::new("all_channel_counts").unwrap();
+    let ret = ffi::av_opt_set_int(
+        sink as *mut c_void,
+        key.as_ptr(),
+        1,
+        ffi::AV_OPT_SEARCH_CHILDREN as i32,
+    );
+    if ret < 0 {
+        return Err(cmdutils::av_err2str(ret));
+    }
+    let mut last_filter = inout.filter_ctx;
+    let mut pad_idx = inout.pad_idx as u32;
+
+    if !ost.audio_channels_map.is_empty() {
+        let layout = ffi::av_get_default_channel_layout(ost.audio_channels_map.len() as i32);
+        let mut args = format!("0x{:x}", layout);
+        for (c, &channel) in ost.audio_channels_map.iter().enumerate() {
+            if channel != -1 {
+                args += &format!("|c{}=c{}", c, channel);
+            }
+        }
+        insert_filter(&mut last_filter, &mut pad_idx, "pan", "pan", &args)
+            .map_err(|e| format!("Error inserting filter for -map_channel: {}", e))?;
+    }
+
+    let mut args = vec![];
+    if ofilter.format != -1 {
+        args.push(format!("sample_fmts={}", sample_fmt_name(ofilter.format)));
+    } else if !ofilter.formats.is_empty() {
+        let names: Vec<_> = ofilter
+            .formats
+            .iter()
+            .map(|&f| sample_fmt_name(f))
+            .collect();
+        args.push(format!("sample_fmts={}", names.join("|")));
+    }
+    if ofilter.sample_rate != 0 {
+        args.push(format!("sample_rates={}", ofilter.sample_rate));
+    } else if !ofilter.sample_rates.is_empty() {
+        let rates: Vec<_> = ofilter.sample_rates.iter().map(|r| r.to_string()).collect();
+        args.push(format!("sample_rates={}", rates.join("|")));
+    }
+    if ofilter.channel_layout != 0 {
+        args.push(format!("channel_layouts=0x{:x}", ofilter.channel_layout));
+    } else if !ofilter.channel_layouts.is_empty() {
+        let layouts: Vec<_> = ofilter
+            .channel_layouts
+            .iter()
+            .map(|l| format!("0x{:x}", l))
+            .collect();
+        args.push(format!("channel_layouts={}", layouts.join("|")));
+    }
+    if !args.is_empty() {
+        let name = format!("format_out_{}_{}", ost.file_index, ost.index);
+        insert_filter(
+            &mut last_filter,
+            &mut pad_idx,
+            "aformat",
+            &name,
+            &args.join(":"),
+        )?;
+    }
+
+    if !ost.apad.is_empty() && of.shortest != 0 {
+        let has_video = session.output_streams[of.ost_index..]
+            .iter()
+            .take_while(|other| other.file_index == ost.file_index)
+            .any(|other| (*other.enc_ctx).codec_type == ffi::AVMediaType_AVMEDIA_TYPE_VIDEO);
+        if has_video {
+            insert_filter(&mut last_filter, &mut pad_idx, "apad", "apad", &ost.apad)
+                .map_err(|e| format!("Error inserting filter for -apad: {}", e))?;
+        }
+    }
+
+    let name = format!("trim for output stream {}:{}", ost.file_index, ost.index);
+    insert_trim(
+        of.start_time,
+        of.recording_time,
+        &mut last_filter,
+        &mut pad_idx,
+        &name,
+    )?;
+    link_filters(last_filter, pad_idx, sink, 0)?;
+
+    session.filtergraphs[index].outputs[i].filter = sink;
+    Ok(())
+}
+
+unsafe fn configure_output_filter(
+    session: &mut Session,
+    index: usize,
+    i: usize,
+    inout: &ffi::AVFilterInOut,
+) -> Result<(), String> {
+    if session.filtergraphs[index].outputs[i].ost.is_none() {
+        return Err(format!(
+            "Filter {} has an unconnected output",
+            session.filtergraphs[index].outputs[i].name
+        ));
+    }
+    let ctx = inout.filter_ctx.as_ref().unwrap();
+    match ffi::avfilter_pad_get_type(ctx.output_pads, inout.pad_idx) {
+        ffi::AVMediaType_AVMEDIA_TYPE_VIDEO => {
+            configure_output_video_filter(session, index, i, inout)
+        }
+        ffi::AVMediaType_AVMEDIA_TYPE_AUDIO => {
+            configure_output_audio_filter(session, index, i, inout)
+        }
+        _ => Err("Only video and audio filters supported currently.".to_owned()),
+    }
+}
+
+unsafe fn configure_filtergraph_pads(
+    session: &mut Session,
+    index: usize,
+    inputs: *mut ffi::AVFilterInOut,
+    outputs: *mut ffi::AVFilterInOut,
+) -> Result<(), String> {
+    let mut cur = inputs;
+    let mut i = 0;
+    while let Some(inout) = cur.as_ref() {
+        configure_input_filter(session, index, i, inout)?;
+        cur = inout.next;
+        i += 1;
+    }
+
+    let mut cur = outputs;
+    let mut i = 0;
+    while let Some(inout) = cur.as_ref() {
+        configure_output_filter(session, index, i, inout)?;
+        cur = inout.next;
+        i += 1;
+    }
+    Ok(())
+}
+
+fn count_inouts(mut cur: *const ffi::AVFilterInOut) -> usize {
+    let mut count = 0;
+    while let Some(inout) = unsafe { cur.as_ref() } {
+        count += 1;
+        cur = inout.next;
+    }
+    count
+}
+
+/// (Re)create the libavfilter graph of the filtergraph `index`, using the
+/// parameters of its inputs, then push the frames queued meanwhile.
+pub unsafe fn configure_filtergraph(session: &mut Session, index: usize) -> Result<(), String> {
+    let result = configure_filtergraph_graph(session, index);
+    if result.is_err() {
+        cleanup_filtergraph(&mut session.filtergraphs[index]);
+    }
+    result
+}
+
+unsafe fn configure_filtergraph_graph(session: &mut Session, index: usize) -> Result<(), String> {
+    cleanup_filtergraph(&mut session.filtergraphs[index]);
+    let fg = &mut session.filtergraphs[index];
+    fg.graph = ffi::avfilter_graph_alloc();
+    let graph = match fg.graph.as_mut() {
+        Some(graph) => graph,
+        None => return Err(cmdutils::av_err2str(AVERROR(ffi::ENOMEM as i32))),
+    };
+    let simple = fg.is_simple();
+
+    let graph_desc = if simple {
+        let ost = &session.output_streams[fg.outputs[0].ost.unwrap()];
+        graph.nb_threads = session.filter_nbthreads as i32;
+
+        let args = CString::new(dict_to_args(&ost.sws_dict, ":")).unwrap();
+        graph.scale_sws_opts = ffi::av_strdup(args.as_ptr());
+
+        let key = CString::new("aresample_swr_opts").unwrap();
+        let args = CString::new(dict_to_args(&ost.swr_opts, ":")).unwrap();
+        ffi::av_opt_set(
+            graph as *mut _ as *mut c_void,
+            key.as_ptr(),
+            args.as_ptr(),
+            0,
+        );
+
+        if let Some(threads) = ost.encoder_opts.get("threads") {
+            let key = CString::new("threads").unwrap();
+            let threads = CString::new(threads).unwrap();
+            ffi::av_opt_set(
+                graph as *mut _ as *mut c_void,
+                key.as_ptr(),
+                threads.as_ptr(),
+                0,
+            );
+        }
+        ost.avfilter.clone()
+    } else {
+        graph.nb_threads = session.filter_complex_nbthreads as i32;
+        fg.graph_desc.clone()
+    };
+
+    let graph_desc_s = CString::new(graph_desc.as_str())
+        .map_err(|_| format!("Invalid filtergraph description {}.", graph_desc))?;
+    let mut inputs = ptr::null_mut();
+    let mut outputs = ptr::null_mut();
+    let ret = ffi::avfilter_graph_parse2(graph, graph_desc_s.as_ptr(), &mut inputs, &mut outputs);
+    if ret < 0 {
+        return Err(format!(
+            "Error parsing filtergraph description {}: {}",
+            graph_desc,
+            cmdutils::av_err2str(ret)
+        ));
+    }
+
+    let result = if simple && (count_inouts(inputs) != 1 || count_inouts(outputs) != 1) {
+        Err(format!(
+            "Simple filtergraph '{}' was expected to have exactly 1 input and 1 output. However, \
+             it had {} input(s) and {} output(s). Please adjust, or use a complex filtergraph \
+             (-filter_complex) instead.",
+            graph_desc,
+            count_inouts(inputs),
+            count_inouts(outputs)
+        ))
+    } else {
+        configure_filtergraph_pads(session, index, inputs, outputs)
+    };
+    ffi::avfilter_inout_free(&mut inputs);
+    ffi::avfilter_inout_free(&mut outputs);
+    result?;
+
+    let fg = &mut session.filtergraphs[index];
+    let ret = ffi::avfilter_graph_config(fg.graph, ptr::null_mut());
+    if ret < 0 {
+        return Err(format!(
+            "Error configuring filtergraph: {}",
+            cmdutils::av_err2str(ret)
+        ));
+    }
+
+    // limit the lists of allowed formats to the ones selected, to make sure
+    // they stay the same if the filtergraph is reconfigured later
+    for ofilter in &mut fg.outputs {
+        let sink = ofilter.filter;
+        ofilter.format = ffi::av_buffersink_get_format(sink);
+        ofilter.width = ffi::av_buffersink_get_w(sink);
+        ofilter.height = ffi::av_buffersink_get_h(sink);
+        ofilter.sample_rate = ffi::av_buffersink_get_sample_rate(sink);
+        ofilter.channel_layout = ffi::av_buffersink_get_channel_layout(sink);
+    }
+
+    for ifilter in &mut fg.inputs {
+        while let Some(mut frame) = ifilter.frame_queue.pop_front() {
+            let ret = ffi::av_buffersrc_add_frame(ifilter.filter, frame);
+            ffi::av_frame_free(&mut frame);
+            if ret < 0 {
+                return Err(format!(
+                    "Error while filtering: {}",
+                    cmdutils::av_err2str(ret)
+                ));
+            }
+        }
+    }
+
+    // send the EOFs for the finished inputs
+    for ifilter in &fg.inputs {
+        if ifilter.eof {
+            let ret = ffi::av_buffersrc_add_frame(ifilter.filter, ptr::null_mut());
+            if ret < 0 {
+                return Err(format!(
+                    "Error while filtering: {}",
+                    cmdutils::av_err2str(ret)
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod ffmpeg_filter_tests {
+    use super::*;
+    use crate::stream_specifier::StreamInfo;
+
+    fn input(types: &[MediaType]) -> InputFile {
+        InputFile {
+            streams: types
+                .iter()
+                .enumerate()
+                .map(|(index, &media_type)| StreamInfo {
+                    index,
+                    media_type: Some(media_type),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn labeled_inputs() {
+        use MediaType::*;
+        let inputs = vec![
+            input(&[Video, Audio, Audio, Subtitle]),
+            input(&[Audio, Video]),
+        ];
+        let find = |label, media_type| find_labeled_input(&inputs, label, media_type, "overlay");
+        assert_eq!(find("0:a", Audio), Ok((0, 1)));
+        assert_eq!(find("0:a:1", Audio), Ok((0, 2)));
+        assert_eq!(find("0", Audio), Ok((0, 1)));
+        assert_eq!(find("1", Video), Ok((1, 1)));
+        assert_eq!(find("0:s", Video), Ok((0, 3)));
+        assert_eq!(find("0:1", Audio), Ok((0, 1)));
+        assert_eq!(
+            find("2:v", Video),
+            Err("Invalid file index 2 in filtergraph description overlay.".to_owned())
+        );
+        assert_eq!(
+            find("1:v", Audio),
+            Err(
+                "Stream specifier ':v' in filtergraph description overlay matches no streams."
+                    .to_owned()
+            )
+        );
+    }
+}
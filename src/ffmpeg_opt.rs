@@ -5,17 +5,19 @@ use rusty_ffmpeg::{
     ffi,
 };
 use std::{
-    ffi::{CStr, CString},
-    ptr, slice,
+    collections::VecDeque,
+    ffi::{CStr, CString, OsStr, OsString},
+    fs, ptr, slice,
 };
 
 use crate::{
     cmdutils::{
         // need to remove the directly imported functions
         self,
+        expand_argfiles,
         parse_optgroup,
-        print_error,
         split_commandline,
+        HelpSection,
         OptionDef,
         OptionFlag,
         OptionGroup,
@@ -24,46 +26,65 @@ use crate::{
         OptionKV,
         OptionOperation,
         OptionParseContext,
+        ParseError,
         SpecifierOpt,
         SpecifierOptValue,
     },
-    ffmpeg::{self, OptionsContext, INT_CB},
+    dict::{DictFlag, Dictionary},
+    ffmpeg::{
+        self, AudioChannelMap, HWAccelID, InputFile, InputStream, OptionsContext, OutputFile,
+        OutputStream, Session, StreamMap, INT_CB,
+    },
+    ffmpeg_filter::{self, OutputFilter},
     options::*,
+    stream_specifier::{
+        strtol, MediaType, StreamInfo, StreamSelector, StreamSpecifier, DISPOSITION_ATTACHED_PIC,
+        DISPOSITION_DEFAULT,
+    },
 };
 
-enum OptGroup {
+pub enum OptGroup {
     GroupOutFile = 0,
     GroupInFile = 1,
 }
 
 fn open_files(
+    session: &mut Session,
     l: &mut OptionGroupList,
     inout: &str,
-    open_file: fn(&mut OptionsContext, &str) -> isize,
-) -> Result<(), ()> {
+    open_file: fn(&mut Session, &mut OptionsContext, &OsStr) -> Result<(), String>,
+) -> Result<(), ParseError> {
     for g in l.groups.iter_mut() {
         let g_arg = g.arg.clone();
+        let name = g_arg.to_string_lossy();
         // This is a workaround for g's immutable borrow while g is mutably
         // borrowed, consider that g's properties use by this function are not
         // modified in the parse_optgroup.
         let g_ = g.clone();
         let mut o = OptionsContext::new(g);
-        if let Err(ret) = parse_optgroup(Some(&mut o), &g_) {
-            error!("Error parsing options for {} file {}.", inout, g.arg);
+        if let Err(ret) = parse_optgroup(session, Some(&mut o), &g_) {
+            error!("Error parsing options for {} file {}.", inout, name);
             return Err(ret);
         }
-        debug!("Opening an {} file: {}.", inout, g_arg);
-        let ret = open_file(&mut o, &g_arg);
-        if ret < 0 {
-            error!("Error opening {} file {}.\n", inout, g.arg);
-            return Err(());
+        debug!("Opening an {} file: {}.", inout, name);
+        if let Err(e) = open_file(session, &mut o, &g_arg) {
+            error!("{}: {}", name, e);
+            error!("Error opening {} file {}.", inout, name);
+            return Err(ParseError::OpenFile {
+                filename: name.into_owned(),
+                error: e,
+            });
         }
         debug!("Successfully opened the file.");
     }
     Ok(())
 }
 
-fn open_input_file(o: &mut OptionsContext, filename: &str) -> isize {
+fn open_input_file(
+    session: &mut Session,
+    o: &mut OptionsContext,
+    os_filename: &OsStr,
+) -> Result<(), String> {
     if o.stop_time != i64::MAX && o.recording_time != i64::MAX {
         o.stop_time = i64::MAX;
         error!("-t and -to cannot be used together; using -t.");
@@ -75,17 +96,17 @@ fn open_input_file(o: &mut OptionsContext, filename: &str) -> isize {
             o.start_time
         };
         if o.stop_time <= start_time {
-            panic!("-to value smaller than -ss; aborting.");
+            return Err("-to value smaller than -ss; aborting.".to_owned());
         } else {
             o.recording_time = o.stop_time - start_time;
         }
     }
 
     let file_iformat_ptr = if !o.format.is_empty() {
-        let o_format = CString::new(&o.format as &str).unwrap();
+        let o_format = CString::new(&o.format as &str).map_err(|e| e.to_string())?;
         let file_iformat = unsafe { ffi::av_find_input_format(o_format.as_ptr()) };
         if file_iformat.is_null() {
-            panic!("Unknown input format: '{}'", o.format);
+            return Err(format!("Unknown input format: '{}'", o.format));
         }
         file_iformat
     } else {
@@ -93,51 +114,32 @@ fn open_input_file(o: &mut OptionsContext, filename: &str) -> isize {
     };
     let mut file_iformat = unsafe { file_iformat_ptr.as_mut() };
 
-    let filename = match filename {
-        "-" => "pipe:",
-        _ => filename,
+    let os_filename = match os_filename.to_str() {
+        Some("-") => OsStr::new("pipe:"),
+        _ => os_filename,
     };
+    // libav gets the filename as is, this one is for the messages.
+    let filename = os_filename.to_string_lossy();
 
-    let file_stdin = if filename.starts_with("pipe:") && filename != "/dev/stdin" {
-        1
-    } else {
-        0
-    };
-    unsafe {
-        stdin_interaction = stdin_interaction & file_stdin;
+    if filename.starts_with("pipe:") || filename == "fd:" || filename == "/dev/stdin" {
+        session.stdin_interaction = 0;
     }
 
-    // get default parameters from command line
-    let ic = match unsafe { ffi::avformat_alloc_context().as_mut() } {
-        Some(x) => x,
-        None => {
-            print_error(filename, AVERROR(ffi::ENOMEM as i32));
-            panic!();
-        }
-    };
-
-    if !o.audio_sample_rate.is_empty() {
-        let tmp = CString::new("sameple_rate").unwrap();
-        unsafe {
-            ffi::av_dict_set_int(
-                &mut o.g.format_opts as *mut _,
-                tmp.as_ptr(),
-                o.audio_sample_rate[o.audio_sample_rate.len() - 1].u.i as i64,
-                0,
-            );
-        }
+    if let Some(sample_rate) = o.audio_sample_rate.last().and_then(|so| so.u.as_int()) {
+        o.g.format_opts
+            .set("sample_rate", &sample_rate.to_string(), DictFlag::NONE);
     }
 
-    if !o.audio_channels.is_empty() {
+    if let Some(channels) = o.audio_channels.last().and_then(|so| so.u.as_int()) {
         // Because we set audio_channels based on both the "ac" and
         // "channel_layout" options, we need to check that the specified
         // demuxer actually has the "channels" option before setting it */
         if let Some(file_iformat) = &mut file_iformat {
-            let channels = CString::new("channels").unwrap();
+            let channels_s = CString::new("channels").unwrap();
             if !unsafe {
                 ffi::av_opt_find(
                     &mut file_iformat.priv_class as *mut _ as *mut c_void,
-                    channels.as_ptr(),
+                    channels_s.as_ptr(),
                     ptr::null(),
                     0,
                     ffi::AV_OPT_SEARCH_FAKE_OBJ as i32,
@@ -145,27 +147,21 @@ fn open_input_file(o: &mut OptionsContext, filename: &str) -> isize {
             }
             .is_null()
             {
-                unsafe {
-                    ffi::av_dict_set_int(
-                        &mut o.g.format_opts as *mut _,
-                        channels.as_ptr(),
-                        o.audio_channels[o.audio_channels.len() - 1].u.i as i64,
-                        0,
-                    );
-                }
+                o.g.format_opts
+                    .set("channels", &channels.to_string(), DictFlag::NONE);
             }
         }
     }
 
-    if !o.frame_rates.is_empty() {
+    if let Some(framerate) = o.frame_rates.last().and_then(|so| so.u.as_str()) {
         // set the format-level framerate option;
         // this is important for video grabbers, e.g. x11
         if let Some(file_iformat) = &mut file_iformat {
-            let framerate = CString::new("framerate").unwrap();
+            let framerate_s = CString::new("framerate").unwrap();
             if !unsafe {
                 ffi::av_opt_find(
                     &mut file_iformat.priv_class as *mut _ as *mut c_void,
-                    framerate.as_ptr(),
+                    framerate_s.as_ptr(),
                     ptr::null(),
                     0,
                     ffi::AV_OPT_SEARCH_FAKE_OBJ as i32,
@@ -173,104 +169,65 @@ fn open_input_file(o: &mut OptionsContext, filename: &str) -> isize {
             }
             .is_null()
             {
-                unsafe {
-                    ffi::av_dict_set(
-                        &mut o.g.format_opts as *mut _,
-                        framerate.as_ptr(),
-                        o.frame_rates[o.frame_rates.len() - 1].u.str as *const _ as *const i8,
-                        0,
-                    );
-                }
+                o.g.format_opts.set("framerate", framerate, DictFlag::NONE);
             }
         }
     }
-    if !o.frame_sizes.is_empty() {
-        let video_size = CString::new("video_size").unwrap();
-        unsafe {
-            ffi::av_dict_set(
-                &mut o.g.format_opts as *mut _,
-                video_size.as_ptr(),
-                o.frame_sizes[o.frame_sizes.len() - 1].u.str as *const _ as *const i8,
-                0,
-            );
-        }
+    if let Some(video_size) = o.frame_sizes.last().and_then(|so| so.u.as_str()) {
+        o.g.format_opts.set("video_size", video_size, DictFlag::NONE);
     }
 
-    if !o.frame_pix_fmts.is_empty() {
-        let pixel_format = CString::new("pixel_format").unwrap();
-        unsafe {
-            ffi::av_dict_set(
-                &mut o.g.format_opts as *mut _,
-                pixel_format.as_ptr(),
-                o.frame_pix_fmts[o.frame_pix_fmts.len() - 1].u.str as *const _ as *const i8,
-                0,
-            );
-        }
+    if let Some(pixel_format) = o.frame_pix_fmts.last().and_then(|so| so.u.as_str()) {
+        o.g.format_opts
+            .set("pixel_format", pixel_format, DictFlag::NONE);
     }
 
-    let mut video_codec_name = ptr::null_mut();
-    let mut audio_codec_name = ptr::null_mut();
-    let mut subtitle_codec_name = ptr::null_mut();
-    let mut data_codec_name = ptr::null_mut();
+    let video_codec_name = match_per_type_opt(&o.codec_names, "v").and_then(|u| u.as_str());
+    let audio_codec_name = match_per_type_opt(&o.codec_names, "a").and_then(|u| u.as_str());
+    let subtitle_codec_name = match_per_type_opt(&o.codec_names, "s").and_then(|u| u.as_str());
+    let data_codec_name = match_per_type_opt(&o.codec_names, "d").and_then(|u| u.as_str());
 
-    for codec_name in o.codec_names.iter() {
-        let tmp = unsafe { codec_name.u.str } as *mut libc::c_char;
-        if codec_name.specifier == "v" {
-            video_codec_name = tmp;
-        }
-        if codec_name.specifier == "a" {
-            audio_codec_name = tmp;
-        }
-        if codec_name.specifier == "s" {
-            subtitle_codec_name = tmp;
-        }
-        if codec_name.specifier == "d" {
-            data_codec_name = tmp;
-        }
-    }
+    let find_decoder = |name: Option<&str>, ty| match name {
+        Some(name) => unsafe { find_codec_or_die(name, ty, false) },
+        None => Ok(ptr::null_mut()),
+    };
+    let video_codec = find_decoder(video_codec_name, ffi::AVMediaType_AVMEDIA_TYPE_VIDEO)?;
+    let audio_codec = find_decoder(audio_codec_name, ffi::AVMediaType_AVMEDIA_TYPE_AUDIO)?;
+    let subtitle_codec = find_decoder(subtitle_codec_name, ffi::AVMediaType_AVMEDIA_TYPE_SUBTITLE)?;
+    let data_codec = find_decoder(data_codec_name, ffi::AVMediaType_AVMEDIA_TYPE_DATA)?;
+    let filename_s = cmdutils::os_cstring(os_filename)?;
 
-    unsafe {
-        if video_codec_name.is_null() {
-            ic.video_codec =
-                find_codec_or_die(video_codec_name, ffi::AVMediaType_AVMEDIA_TYPE_VIDEO, false);
-        }
-        if audio_codec_name.is_null() {
-            ic.audio_codec =
-                find_codec_or_die(audio_codec_name, ffi::AVMediaType_AVMEDIA_TYPE_AUDIO, false);
-        }
-        if subtitle_codec_name.is_null() {
-            ic.subtitle_codec = find_codec_or_die(
-                subtitle_codec_name,
-                ffi::AVMediaType_AVMEDIA_TYPE_SUBTITLE,
-                false,
-            );
-        }
-        if data_codec_name.is_null() {
-            ic.data_codec =
-                find_codec_or_die(data_codec_name, ffi::AVMediaType_AVMEDIA_TYPE_DATA, false);
-        }
-    }
+    // get default parameters from command line, nothing can fail from here
+    // until avformat_open_input() which frees the context on failure
+    let ic = match unsafe { ffi::avformat_alloc_context().as_mut() } {
+        Some(x) => x,
+        None => return Err(cmdutils::av_err2str(AVERROR(ffi::ENOMEM as i32))),
+    };
+    ic.video_codec = video_codec;
+    ic.audio_codec = audio_codec;
+    ic.subtitle_codec = subtitle_codec;
+    ic.data_codec = data_codec;
 
     unsafe {
-        ic.video_codec_id = if video_codec_name.is_null() {
+        ic.video_codec_id = if video_codec_name.is_none() {
             ffi::AVCodecID_AV_CODEC_ID_NONE
         } else {
             ic.video_codec.as_ref().unwrap().id
         };
 
-        ic.audio_codec_id = if audio_codec_name.is_null() {
+        ic.audio_codec_id = if audio_codec_name.is_none() {
             ffi::AVCodecID_AV_CODEC_ID_NONE
         } else {
             ic.audio_codec.as_ref().unwrap().id
         };
 
-        ic.subtitle_codec_id = if subtitle_codec_name.is_null() {
+        ic.subtitle_codec_id = if subtitle_codec_name.is_none() {
             ffi::AVCodecID_AV_CODEC_ID_NONE
         } else {
             ic.subtitle_codec.as_ref().unwrap().id
         };
 
-        ic.data_codec_id = if data_codec_name.is_null() {
+        ic.data_codec_id = if data_codec_name.is_none() {
             ffi::AVCodecID_AV_CODEC_ID_NONE
         } else {
             ic.data_codec.as_ref().unwrap().id
@@ -282,86 +239,65 @@ fn open_input_file(o: &mut OptionsContext, filename: &str) -> isize {
     }
     ic.interrupt_callback = INT_CB;
 
-    let scan_all_pmts_s = CString::new("scan_all_pmts").unwrap();
-    let scan_all_pmts_set = if !unsafe {
-        ffi::av_dict_get(
-            o.g.format_opts,
-            scan_all_pmts_s.as_ptr(),
-            ptr::null(),
-            ffi::AV_DICT_MATCH_CASE as i32,
-        )
-    }
-    .is_null()
+    let scan_all_pmts_set = if o
+        .g
+        .format_opts
+        .iter()
+        .all(|(key, _)| key != "scan_all_pmts")
     {
-        let one = CString::new("1").unwrap();
-        unsafe {
-            ffi::av_dict_set(
-                &mut o.g.format_opts as *mut _,
-                scan_all_pmts_s.as_ptr(),
-                one.as_ptr(),
-                ffi::AV_DICT_DONT_OVERWRITE as i32,
-            );
-        };
+        o.g.format_opts
+            .set("scan_all_pmts", "1", DictFlag::DONT_OVERWRITE);
         true
     } else {
         false
     };
     // open the input file with generic avformat function
-    let filename_s = CString::new(filename).unwrap();
+    let mut format_opts = o.g.format_opts.to_av_dict();
     let err = unsafe {
         ffi::avformat_open_input(
             &mut (ic as *mut _) as *mut _,
             filename_s.as_ptr(),
             file_iformat_ptr,
-            &mut o.g.format_opts as *mut _,
+            &mut format_opts,
         )
     };
+    // Keep the options which are not consumed by the demuxer.
+    o.g.format_opts = unsafe { Dictionary::from_av_dict(format_opts) };
+    unsafe { ffi::av_dict_free(&mut format_opts) };
     if err < 0 {
-        print_error(filename, err);
+        let mut e = cmdutils::av_err2str(err);
         if err == AVERROR_PROTOCOL_NOT_FOUND {
-            error!("Did you mean file:{}?", filename);
+            e += &format!("\nDid you mean file:{}?", filename);
         }
-        panic!()
+        return Err(e);
     }
     if scan_all_pmts_set {
-        unsafe {
-            ffi::av_dict_set(
-                &mut o.g.format_opts as *mut _,
-                scan_all_pmts_s.as_ptr(),
-                ptr::null(),
-                ffi::AV_DICT_MATCH_CASE as i32,
-            );
-        }
-    }
-    unsafe {
-        ffmpeg::remove_avoptions(&mut o.g.format_opts, o.g.codec_opts);
-        ffmpeg::assert_avoptions(o.g.format_opts);
+        o.g.format_opts
+            .remove("scan_all_pmts", DictFlag::MATCH_CASE);
     }
+    ffmpeg::remove_avoptions(&mut o.g.format_opts, &o.g.codec_opts);
+    ffmpeg::assert_avoptions(&o.g.format_opts);
     let ic_streams = unsafe { slice::from_raw_parts(ic.streams, ic.nb_streams as usize) };
-    for stream in ic_streams {
-        unsafe {
-            choose_decoder(o, ic, *stream);
+    for &stream in ic_streams {
+        if let Err(e) = unsafe { choose_decoder(o, ic, stream) } {
+            unsafe { ffi::avformat_close_input(&mut (ic as *mut _) as *mut _) };
+            return Err(e);
         }
     }
 
-    if unsafe { find_stream_info } != 0 {
-        let opts_ptr = unsafe { cmdutils::setup_find_stream_info_opts(ic, o.g.codec_opts) };
-        let orig_nb_streams = ic.nb_streams;
+    if session.find_stream_info != 0 {
+        let opts = unsafe { cmdutils::setup_find_stream_info_opts(ic, &o.g.codec_opts) };
+        let opts_ptr = Dictionary::to_av_dict_array(&opts);
         // If not enough info to get the stream parameters, we decode the
         // first frames to get it. (used in mpeg case for example)
         let ret = unsafe { ffi::avformat_find_stream_info(ic, opts_ptr) };
-
-        let opts = unsafe { slice::from_raw_parts_mut(opts_ptr, orig_nb_streams as _) };
-        for opt in opts.iter_mut() {
-            unsafe { ffi::av_dict_free(opt) };
-        }
-        unsafe { ffi::av_freep(opts_ptr as *mut c_void) };
+        unsafe { Dictionary::free_av_dict_array(opts_ptr, opts.len()) };
 
         if ret < 0 {
             error!("{}: could not find codec parameters", filename);
             if ic.nb_streams == 0 {
                 unsafe { ffi::avformat_close_input(&mut (ic as *mut _) as *mut _) };
-                panic!();
+                return Err("could not find codec parameters".to_owned());
             }
         }
     }
@@ -373,8 +309,8 @@ fn open_input_file(o: &mut OptionsContext, filename: &str) -> isize {
 
     if o.start_time_eof != AV_NOPTS_VALUE {
         if o.start_time_eof >= 0 {
-            error!("-sseof value must be negative; aborting");
-            panic!()
+            unsafe { ffi::avformat_close_input(&mut (ic as *mut _) as *mut _) };
+            return Err("-sseof value must be negative; aborting".to_owned());
         }
         if ic.duration > 0 {
             o.start_time = o.start_time_eof + ic.duration;
@@ -423,23 +359,582 @@ fn open_input_file(o: &mut OptionsContext, filename: &str) -> isize {
     }
 
     // update the current parameters so that they match the one of the input stream
-    unimplemented!()
+    let file_index = session.input_files.len();
+    let ist_index = session.input_streams.len();
+    if let Err(e) = unsafe { add_input_streams(session, o, ic) } {
+        unsafe { close_input_file(session, ist_index, ic) };
+        return Err(e);
+    }
+
+    // dump the file content
+    unsafe { ffi::av_dump_format(ic, file_index as i32, filename_s.as_ptr(), 0) };
+
+    let f = InputFile {
+        filename: os_filename.to_owned(),
+        ctx: ic,
+        streams: unsafe { StreamInfo::from_format_context(ic) },
+        ist_index,
+        start_time: o.start_time,
+        recording_time: o.recording_time,
+        input_ts_offset: o.input_ts_offset,
+        ts_offset: o.input_ts_offset
+            - if session.copy_ts != 0 {
+                if session.start_at_zero != 0 && ic.start_time != AV_NOPTS_VALUE {
+                    ic.start_time
+                } else {
+                    0
+                }
+            } else {
+                timestamp
+            },
+        loops: o.loops,
+        duration: 0,
+        time_base: ffi::AVRational { num: 1, den: 1 },
+        rate_emu: o.rate_emu,
+        accurate_seek: o.accurate_seek,
+        thread_queue_size: if o.thread_queue_size > 0 {
+            o.thread_queue_size
+        } else {
+            8
+        },
+        eof_reached: false,
+        eagain: false,
+    };
+
+    // check if all codec options have been used
+    let used = session.input_streams[ist_index..]
+        .iter()
+        .map(|ist| &ist.decoder_opts);
+    if let Err(e) = check_unused_codec_opts(&o.g.codec_opts, used, false, file_index, &filename) {
+        unsafe { close_input_file(session, ist_index, ic) };
+        return Err(e);
+    }
+
+    for so in &o.dump_attachment {
+        // Specifiers have been checked when splitting the command line.
+        let spec: StreamSpecifier = match so.specifier.parse() {
+            Ok(spec) => spec,
+            Err(_) => continue,
+        };
+        let ic_streams = unsafe { slice::from_raw_parts(ic.streams, ic.nb_streams as usize) };
+        for (j, &st) in ic_streams.iter().enumerate() {
+            if spec.matches(&f.streams, j) {
+                let out = so.u.as_os_str().unwrap_or_default();
+                if let Err(e) = unsafe { dump_attachment(session, file_index, st, out) } {
+                    unsafe { close_input_file(session, ist_index, ic) };
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    session.input_files.push(f);
+    session.input_stream_potentially_available = 1;
+    Ok(())
+}
+
+/// Create the `InputStream` of every stream of `ic`.
+unsafe fn add_input_streams(
+    session: &mut Session,
+    o: &OptionsContext,
+    ic: *mut ffi::AVFormatContext,
+) -> Result<(), String> {
+    let ic_ref = ic.as_ref().unwrap();
+    let streams = StreamInfo::from_format_context(ic);
+    let file_index = session.input_files.len();
+    let mut cc = ffi::avcodec_get_class();
+    let skip_frame = CString::new("skip_frame").unwrap();
+    let discard_opt = ffi::av_opt_find(
+        &mut cc as *mut _ as *mut c_void,
+        skip_frame.as_ptr(),
+        ptr::null(),
+        0,
+        0,
+    );
+
+    let ic_streams = slice::from_raw_parts(ic_ref.streams, ic_ref.nb_streams as usize);
+    for (i, &st_ptr) in ic_streams.iter().enumerate() {
+        let st = st_ptr.as_mut().unwrap();
+        let par = st.codecpar.as_mut().unwrap();
+        let opt = |opts| match_per_stream_opt(opts, &streams, i);
+
+        st.discard = ffi::AVDiscard_AVDISCARD_ALL;
+        let mut ist = InputStream {
+            file_index,
+            st: st_ptr,
+            discard: true,
+            user_set_discard: ffi::AVDiscard_AVDISCARD_NONE,
+            decoding_needed: 0,
+            dec_ctx: ptr::null_mut(),
+            dec: ptr::null_mut(),
+            decoder_opts: Dictionary::new(),
+            start: 0,
+            dts: 0,
+            min_pts: i64::MAX,
+            max_pts: i64::MIN,
+            nb_samples: 0,
+            filter_in_rescale_delta_last: AV_NOPTS_VALUE,
+            ts_scale: opt(&o.ts_scale).and_then(|u| u.as_f64()).unwrap_or(1.),
+            framerate: ffi::AVRational { num: 0, den: 0 },
+            top_field_first: -1,
+            guess_layout_max: i32::MAX as isize,
+            autorotate: opt(&o.autorotate).and_then(|u| u.as_int()).unwrap_or(1),
+            fix_sub_duration: 0,
+            reinit_filters: opt(&o.reinit_filters)
+                .and_then(|u| u.as_int())
+                .unwrap_or(-1),
+            hwaccel_id: HWAccelID::None,
+            hwaccel_device: String::new(),
+            hwaccel_output_format: ffi::AVPixelFormat_AV_PIX_FMT_NONE,
+            hwaccel_pix_fmt: ffi::AVPixelFormat_AV_PIX_FMT_NONE,
+            filters: vec![],
+            got_output: false,
+            cfr_next_pts: 0,
+        };
+
+        if let Some(codec_tag) = opt(&o.codec_tags).and_then(|u| u.as_str()) {
+            par.codec_tag = parse_codec_tag(codec_tag);
+        }
+
+        ist.dec = choose_decoder(o, ic, st_ptr)?;
+        ist.decoder_opts =
+            cmdutils::filter_codec_opts(&o.g.codec_opts, par.codec_id, ic, st_ptr, ist.dec);
+
+        if (o.video_disable != 0 && par.codec_type == ffi::AVMediaType_AVMEDIA_TYPE_VIDEO)
+            || (o.audio_disable != 0 && par.codec_type == ffi::AVMediaType_AVMEDIA_TYPE_AUDIO)
+            || (o.subtitle_disable != 0 && par.codec_type == ffi::AVMediaType_AVMEDIA_TYPE_SUBTITLE)
+            || (o.data_disable != 0 && par.codec_type == ffi::AVMediaType_AVMEDIA_TYPE_DATA)
+        {
+            ist.user_set_discard = ffi::AVDiscard_AVDISCARD_ALL;
+        }
+
+        if let Some(discard_str) = opt(&o.discard).and_then(|u| u.as_str()) {
+            let discard_s = CString::new(discard_str).map_err(|e| e.to_string())?;
+            let mut discard: libc::c_int = 0;
+            if ffi::av_opt_eval_int(
+                &mut cc as *mut _ as *mut c_void,
+                discard_opt,
+                discard_s.as_ptr(),
+                &mut discard,
+            ) < 0
+            {
+                return Err(format!("Error parsing discard {}.", discard_str));
+            }
+            ist.user_set_discard = discard as _;
+        }
+
+        ist.dec_ctx = ffi::avcodec_alloc_context3(ist.dec);
+        if ist.dec_ctx.is_null() {
+            return Err("Error allocating the decoder context.".to_owned());
+        }
+        // The session owns the decoder context from here, it's freed with
+        // the stream if the file can't be opened.
+        session.input_streams.push(ist);
+        let ist = session.input_streams.last_mut().unwrap();
+        let ret = ffi::avcodec_parameters_to_context(ist.dec_ctx, par);
+        if ret < 0 {
+            return Err(format!(
+                "Error initializing the decoder context: {}",
+                cmdutils::av_err2str(ret)
+            ));
+        }
+        let dec_ctx = ist.dec_ctx.as_mut().unwrap();
+        if o.bitexact != 0 {
+            dec_ctx.flags |= ffi::AV_CODEC_FLAG_BITEXACT as i32;
+        }
+
+        match par.codec_type {
+            ffi::AVMediaType_AVMEDIA_TYPE_VIDEO => {
+                if ist.dec.is_null() {
+                    ist.dec = ffi::avcodec_find_decoder(par.codec_id);
+                }
+                // avformat_find_stream_info() doesn't set this for us anymore.
+                dec_ctx.framerate = st.avg_frame_rate;
+
+                if let Some(framerate) = opt(&o.frame_rates).and_then(|u| u.as_str()) {
+                    let framerate_s = CString::new(framerate).map_err(|e| e.to_string())?;
+                    if ffi::av_parse_video_rate(&mut ist.framerate, framerate_s.as_ptr()) < 0 {
+                        return Err(format!("Error parsing framerate {}.", framerate));
+                    }
+                }
+
+                if let Some(top_field_first) = opt(&o.top_field_first).and_then(|u| u.as_int()) {
+                    ist.top_field_first = top_field_first;
+                }
+
+                let hwaccel = opt(&o.hwaccels).and_then(|u| u.as_str());
+                let hwaccel_output_format = opt(&o.hwaccel_output_formats).and_then(|u| u.as_str());
+                ist.hwaccel_output_format = match (hwaccel_output_format, hwaccel) {
+                    (None, Some("cuvid")) => {
+                        error!(
+                            "WARNING: defaulting hwaccel_output_format to cuda for compatibility \
+                             with old commandlines. This behaviour is DEPRECATED and will be \
+                             removed in the future. Please explicitly set \
+                             \"-hwaccel_output_format cuda\"."
+                        );
+                        ffi::AVPixelFormat_AV_PIX_FMT_CUDA
+                    }
+                    (None, Some("qsv")) => {
+                        error!(
+                            "WARNING: defaulting hwaccel_output_format to qsv for compatibility \
+                             with old commandlines. This behaviour is DEPRECATED and will be \
+                             removed in the future. Please explicitly set \
+                             \"-hwaccel_output_format qsv\"."
+                        );
+                        ffi::AVPixelFormat_AV_PIX_FMT_QSV
+                    }
+                    (Some(format), _) => {
+                        let format_s = CString::new(format).map_err(|e| e.to_string())?;
+                        let pix_fmt = ffi::av_get_pix_fmt(format_s.as_ptr());
+                        if pix_fmt == ffi::AVPixelFormat_AV_PIX_FMT_NONE {
+                            return Err(format!("Unrecognised hwaccel output format: {}", format));
+                        }
+                        pix_fmt
+                    }
+                    (None, _) => ffi::AVPixelFormat_AV_PIX_FMT_NONE,
+                };
+
+                if let Some(hwaccel) = hwaccel {
+                    // The NVDEC hwaccels use a CUDA device, so remap the name here.
+                    let hwaccel = match hwaccel {
+                        "nvdec" | "cuvid" => "cuda",
+                        _ => hwaccel,
+                    };
+                    ist.hwaccel_id = match hwaccel {
+                        "none" => HWAccelID::None,
+                        "auto" => HWAccelID::Auto,
+                        _ => {
+                            let hwaccel_s = CString::new(hwaccel).map_err(|e| e.to_string())?;
+                            let ty = ffi::av_hwdevice_find_type_by_name(hwaccel_s.as_ptr());
+                            if ty == ffi::AVHWDeviceType_AV_HWDEVICE_TYPE_NONE {
+                                let mut supported = vec![];
+                                let mut ty = ffi::AVHWDeviceType_AV_HWDEVICE_TYPE_NONE;
+                                loop {
+                                    ty = ffi::av_hwdevice_iterate_types(ty);
+                                    if ty == ffi::AVHWDeviceType_AV_HWDEVICE_TYPE_NONE {
+                                        break;
+                                    }
+                                    let name = CStr::from_ptr(ffi::av_hwdevice_get_type_name(ty));
+                                    supported.push(name.to_string_lossy().into_owned());
+                                }
+                                return Err(format!(
+                                    "Unrecognized hwaccel: {}.\nSupported hwaccels: {}",
+                                    hwaccel,
+                                    supported.join(" ")
+                                ));
+                            }
+                            HWAccelID::Generic(ty)
+                        }
+                    };
+                }
+
+                if let Some(hwaccel_device) = opt(&o.hwaccel_devices).and_then(|u| u.as_str()) {
+                    ist.hwaccel_device = hwaccel_device.to_owned();
+                }
+            }
+            ffi::AVMediaType_AVMEDIA_TYPE_AUDIO => {
+                if let Some(guess_layout_max) = opt(&o.guess_layout_max).and_then(|u| u.as_int()) {
+                    ist.guess_layout_max = guess_layout_max;
+                }
+                ffmpeg::guess_input_channel_layout(ist);
+            }
+            ffi::AVMediaType_AVMEDIA_TYPE_DATA | ffi::AVMediaType_AVMEDIA_TYPE_SUBTITLE => {
+                if ist.dec.is_null() {
+                    ist.dec = ffi::avcodec_find_decoder(par.codec_id);
+                }
+                if let Some(fix_sub_duration) = opt(&o.fix_sub_duration).and_then(|u| u.as_int()) {
+                    ist.fix_sub_duration = fix_sub_duration;
+                }
+                if let Some(canvas_size) = opt(&o.canvas_sizes).and_then(|u| u.as_str()) {
+                    let canvas_size_s = CString::new(canvas_size).map_err(|e| e.to_string())?;
+                    if ffi::av_parse_video_size(
+                        &mut dec_ctx.width,
+                        &mut dec_ctx.height,
+                        canvas_size_s.as_ptr(),
+                    ) < 0
+                    {
+                        return Err(format!("Invalid canvas size: {}.", canvas_size));
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        let ret = ffi::avcodec_parameters_from_context(par, ist.dec_ctx);
+        if ret < 0 {
+            return Err(format!(
+                "Error initializing the decoder context: {}",
+                cmdutils::av_err2str(ret)
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Close the format context of an input file which can't be opened, and
+/// free the streams added for it from `ist_index`.
+unsafe fn close_input_file(
+    session: &mut Session,
+    ist_index: usize,
+    mut ic: *mut ffi::AVFormatContext,
+) {
+    for mut ist in session.input_streams.drain(ist_index..) {
+        ffi::avcodec_free_context(&mut ist.dec_ctx);
+    }
+    ffi::avformat_close_input(&mut ic);
+}
+
+/// Codec options with the stream specifiers removed from the keys.
+fn strip_specifiers(dict: &Dictionary) -> Dictionary {
+    dict.iter()
+        .map(|(key, value)| (key.split(':').next().unwrap(), value))
+        .collect()
+}
+
+/// `-tag` value: a number, or a fourcc read like `AV_RL32()`.
+fn parse_codec_tag(codec_tag: &str) -> u32 {
+    match strtol(codec_tag) {
+        Some((tag, "")) => tag as u32,
+        _ => {
+            let mut tag = [0u8; 4];
+            let len = codec_tag.len().min(4);
+            tag[..len].copy_from_slice(&codec_tag.as_bytes()[..len]);
+            u32::from_le_bytes(tag)
+        }
+    }
 }
 
+/// Complain about the codec options of a file which no stream has used. An
+/// option for the wrong direction (e.g. a decoding option on an output file)
+/// is an error.
+fn check_unused_codec_opts<'a>(
+    codec_opts: &Dictionary,
+    used: impl Iterator<Item = &'a Dictionary>,
+    encoding: bool,
+    file_index: usize,
+    filename: &str,
+) -> Result<(), String> {
+    let (inout, direction, codec, flag) = if encoding {
+        (
+            "output",
+            "encoding",
+            "encoder",
+            ffi::AV_OPT_FLAG_ENCODING_PARAM,
+        )
+    } else {
+        (
+            "input",
+            "decoding",
+            "decoder",
+            ffi::AV_OPT_FLAG_DECODING_PARAM,
+        )
+    };
+    let mut unused_opts = strip_specifiers(codec_opts);
+    for opts in used {
+        for (key, _) in opts.iter() {
+            unused_opts.remove(key, DictFlag::NONE);
+        }
+    }
+    for (key, _) in unused_opts.iter() {
+        let key_s = CString::new(key).map_err(|e| e.to_string())?;
+        let find = |mut class: *const ffi::AVClass| unsafe {
+            ffi::av_opt_find(
+                &mut class as *mut _ as *mut c_void,
+                key_s.as_ptr(),
+                ptr::null(),
+                0,
+                (ffi::AV_OPT_SEARCH_CHILDREN | ffi::AV_OPT_SEARCH_FAKE_OBJ) as i32,
+            )
+            .as_ref()
+        };
+        let option = match find(unsafe { ffi::avcodec_get_class() }) {
+            Some(option) => option,
+            None => continue,
+        };
+        if find(unsafe { ffi::avformat_get_class() }).is_some() {
+            continue;
+        }
+        let help = if option.help.is_null() {
+            "".into()
+        } else {
+            unsafe { CStr::from_ptr(option.help) }.to_string_lossy()
+        };
+        if option.flags & flag as i32 == 0 {
+            return Err(format!(
+                "Codec AVOption {} ({}) specified for {} file #{} ({}) is not a {} option.",
+                key, help, inout, file_index, filename, direction
+            ));
+        }
+        // gop_timecode is injected by generic code but not always used
+        if encoding && key == "gop_timecode" {
+            continue;
+        }
+        error!(
+            "Codec AVOption {} ({}) specified for {} file #{} ({}) has not been used for any \
+             stream. The most likely reason is either wrong type (e.g. a video option with no \
+             video streams) or that it is a private option of some {} which was not \
+             actually used for any stream.",
+            key, help, inout, file_index, filename, codec
+        );
+    }
+    Ok(())
+}
+
+/// Write the extradata of an attachment stream to `filename`, or to the
+/// name in its `filename` tag.
+unsafe fn dump_attachment(
+    session: &Session,
+    file_index: usize,
+    st: *mut ffi::AVStream,
+    filename: &OsStr,
+) -> Result<(), String> {
+    let st = st.as_ref().unwrap();
+    let par = st.codecpar.as_ref().unwrap();
+    if par.extradata_size == 0 {
+        error!(
+            "No extradata to dump in stream #{}:{}.",
+            file_index, st.index
+        );
+        return Ok(());
+    }
+    let metadata = Dictionary::from_av_dict(st.metadata);
+    let filename = match (filename.is_empty(), metadata.get("filename")) {
+        (true, Some(tag)) => OsStr::new(tag),
+        (true, None) => {
+            return Err(format!(
+                "No filename specified and no 'filename' tag in stream #{}:{}.",
+                file_index, st.index
+            ))
+        }
+        (false, _) => filename,
+    };
+
+    assert_file_overwrite(session, filename)?;
+
+    let filename_s = cmdutils::os_cstring(filename)?;
+    let mut out = ptr::null_mut();
+    let ret = ffi::avio_open2(
+        &mut out,
+        filename_s.as_ptr(),
+        ffi::AVIO_FLAG_WRITE as i32,
+        &INT_CB,
+        ptr::null_mut(),
+    );
+    if ret < 0 {
+        return Err(format!(
+            "Could not open file {} for writing.",
+            filename.to_string_lossy()
+        ));
+    }
+    ffi::avio_write(out, par.extradata, par.extradata_size);
+    ffi::avio_flush(out);
+    ffi::avio_close(out);
+    Ok(())
+}
+
+/// Make sure an output file can be written: ask before overwriting unless
+/// `-y` or `-n` is given, and refuse to overwrite an input file.
+pub fn assert_file_overwrite(session: &Session, os_filename: &OsStr) -> Result<(), String> {
+    let filename_s = cmdutils::os_cstring(os_filename)?;
+    let filename = os_filename.to_string_lossy();
+    let proto_name = unsafe { ffi::avio_find_protocol_name(filename_s.as_ptr()) };
+    let is_file =
+        !proto_name.is_null() && unsafe { CStr::from_ptr(proto_name) }.to_bytes() == b"file";
+
+    if session.file_overwrite != 0 && session.no_file_overwrite != 0 {
+        return Err("Error, both -y and -n supplied. Exiting.".to_owned());
+    }
+
+    if session.file_overwrite == 0
+        && is_file
+        && unsafe { ffi::avio_check(filename_s.as_ptr(), 0) } == 0
+    {
+        if session.stdin_interaction != 0 && session.no_file_overwrite == 0 {
+            eprint!("File '{}' already exists. Overwrite? [y/N] ", filename);
+            if !cmdutils::read_yesno() {
+                return Err("Not overwriting - exiting".to_owned());
+            }
+        } else {
+            return Err(format!("File '{}' already exists. Exiting.", filename));
+        }
+    }
+
+    if is_file {
+        for (i, file) in session.input_files.iter().enumerate() {
+            let nofile = unsafe { file.ctx.as_ref().and_then(|ctx| ctx.iformat.as_ref()) }
+                .map_or(false, |iformat| {
+                    iformat.flags & ffi::AVFMT_NOFILE as i32 != 0
+                });
+            if !nofile && file.filename.as_os_str() == os_filename {
+                return Err(format!(
+                    "Output {} same as Input #{} - exiting\n\
+                     FFmpeg cannot edit existing files in-place.",
+                    filename, i
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Rust version of the `MATCH_PER_TYPE_OPT` macro, the last value whose
+/// specifier is exactly `mediatype` wins.
+pub fn match_per_type_opt<'a>(
+    opts: &'a [SpecifierOpt],
+    mediatype: &str,
+) -> Option<&'a SpecifierOptValue> {
+    opts.iter()
+        .rev()
+        .find(|so| so.specifier == mediatype)
+        .map(|so| &so.u)
+}
+
+/// Rust version of the `MATCH_PER_STREAM_OPT` macro, the last value whose
+/// specifier matches the stream `index` of `streams` wins.
+fn match_per_stream_opt<'a>(
+    opts: &'a [SpecifierOpt],
+    streams: &[StreamInfo],
+    index: usize,
+) -> Option<&'a SpecifierOptValue> {
+    opts.iter()
+        .rev()
+        .find(|so| {
+            so.specifier
+                .parse::<StreamSpecifier>()
+                .map_or(false, |spec| spec.matches(streams, index))
+        })
+        .map(|so| &so.u)
+}
+
+/// The decoder given by `-c` for the stream, or the default one.
 unsafe fn choose_decoder(
-    o: *mut OptionsContext,
+    o: &OptionsContext,
     s: *mut ffi::AVFormatContext,
     st: *mut ffi::AVStream,
-) -> *mut ffi::AVCodec {
-    unimplemented!()
+) -> Result<*mut ffi::AVCodec, String> {
+    let streams = StreamInfo::from_format_context(s);
+    let st = st.as_mut().unwrap();
+    let par = st.codecpar.as_mut().unwrap();
+    match match_per_stream_opt(&o.codec_names, &streams, st.index as usize).and_then(|u| u.as_str())
+    {
+        Some(codec_name) => {
+            let codec = find_codec_or_die(codec_name, par.codec_type, false)?;
+            par.codec_id = (*codec).id;
+            Ok(codec)
+        }
+        None => Ok(ffi::avcodec_find_decoder(par.codec_id)),
+    }
 }
 
+/// Find a codec by name, the error is fatal for the caller.
 unsafe fn find_codec_or_die(
-    name: *const libc::c_char,
+    name: &str,
     ty: ffi::AVMediaType,
     encoder: bool,
-) -> *mut ffi::AVCodec {
+) -> Result<*mut ffi::AVCodec, String> {
     let codec_string = if encoder { "encoder" } else { "decoder" };
+    let name_s = CString::new(name).map_err(|e| e.to_string())?;
+    let name = name_s.as_ptr();
     let mut codec = if encoder {
         ffi::avcodec_find_encoder_by_name(name)
     } else {
@@ -454,7 +949,7 @@ unsafe fn find_codec_or_die(
             };
             if let Some(codec) = { codec.as_ref() } {
                 let codec_name = { CStr::from_ptr(codec.name) };
-                let desc_name = { CStr::from_ptr(codec.name) };
+                let desc_name = { CStr::from_ptr(desc.name) };
                 debug!(
                     "Matched {} '{}' for codec '{}'.",
                     codec_string,
@@ -464,82 +959,2470 @@ unsafe fn find_codec_or_die(
             }
         }
     }
-    let name = CStr::from_ptr(name);
+    let name = name_s.to_string_lossy();
     match codec.as_ref() {
-        Some(codec) => {
-            if codec.type_ != ty {
-                error!("Invalid {} type '{}'", codec_string, name.to_string_lossy());
-                panic!()
-            }
-        }
-        None => {
-            error!("Unknown {} '{}'", codec_string, name.to_string_lossy());
-            panic!();
-        }
+        Some(c) if c.type_ != ty => Err(format!("Invalid {} type '{}'", codec_string, name)),
+        Some(_) => Ok(codec),
+        None => Err(format!("Unknown {} '{}'", codec_string, name)),
     }
-    codec
 }
 
-fn open_output_file(o: &mut OptionsContext, filename: &str) -> isize {
-    unimplemented!()
-}
+/// Parse the argument of `-map`:
+/// `[-]input_file_id[:stream_specifier][?][,sync_file_id[:stream_specifier]]`
+/// or `[linklabel]`, and add the selected streams to `maps`. A negative map
+/// disables the already added maps it matches.
+pub fn parse_stream_map(
+    input_files: &[InputFile],
+    maps: &mut Vec<StreamMap>,
+    arg: &str,
+) -> Result<(), String> {
+    let (negative, map) = match arg.strip_prefix('-') {
+        Some(map) => (true, map),
+        None => (false, arg),
+    };
 
-fn init_complex_filters() {
-    unimplemented!()
-}
+    let (map, sync) = match map.find(',') {
+        Some(i) => (&map[..i], Some(&map[i + 1..])),
+        None => (map, None),
+    };
 
-fn check_filter_outputs() {
-    unimplemented!()
-    /*
-        int i;
-        for (i = 0; i < nb_filtergraphs; i++) {
-            int n;
-            for (n = 0; n < filtergraphs[i]->nb_outputs; n++) {
-                OutputFilter *output = filtergraphs[i]->outputs[n];
-                if (!output->ost) {
-                    av_log(NULL, AV_LOG_FATAL, "Filter %s has an unconnected output\n", output->name);
-                    exit_program(1);
+    // Parse sync stream first, just pick first matching stream.
+    let sync = match sync {
+        Some(sync) => {
+            let (sync_file_index, spec) = match strtol(sync) {
+                Some((i, spec)) if i >= 0 && (i as usize) < input_files.len() => (i as usize, spec),
+                Some((i, _)) => return Err(format!("Invalid sync file index: {}.", i)),
+                None => return Err(format!("Invalid sync file index in map {}.", arg)),
+            };
+            let spec: StreamSpecifier = spec.strip_prefix(':').unwrap_or(spec).parse()?;
+            let streams = &input_files[sync_file_index].streams;
+            match (0..streams.len()).find(|&i| spec.matches(streams, i)) {
+                Some(sync_stream_index) => Some((sync_file_index, sync_stream_index)),
+                None => {
+                    return Err(format!(
+                        "Sync stream specification in map {} does not match any streams.",
+                        arg
+                    ))
                 }
             }
         }
-    */
+        None => None,
+    };
+
+    if let Some(label) = map.strip_prefix('[') {
+        // This mapping refers to lavfi output.
+        return match label.strip_suffix(']') {
+            Some(label) if !label.is_empty() && !label.contains(']') => {
+                maps.push(StreamMap {
+                    linklabel: label.to_owned(),
+                    ..Default::default()
+                });
+                Ok(())
+            }
+            _ => Err(format!("Invalid output link label: {}.", map)),
+        };
+    }
+
+    let (map, allow_unused) = match map.find('?') {
+        Some(i) => (&map[..i], true),
+        None => (map, false),
+    };
+    let (file_index, spec) = match strtol(map) {
+        Some((i, spec)) if i >= 0 && (i as usize) < input_files.len() => (i as usize, spec),
+        Some((i, _)) => return Err(format!("Invalid input file index: {}.", i)),
+        None => return Err(format!("Invalid input file index in map {}.", arg)),
+    };
+    let spec: StreamSpecifier = match spec.strip_prefix(':') {
+        Some(spec) => spec.parse()?,
+        None if spec.is_empty() => spec.parse()?,
+        None => return Err(format!("Invalid stream specifier in map {}.", arg)),
+    };
+    let streams = &input_files[file_index].streams;
+
+    let mut matched = false;
+    if negative {
+        // Disable some already defined maps, like FFmpeg it's only an error
+        // when there is no map at all.
+        for m in maps.iter_mut() {
+            if m.linklabel.is_empty()
+                && m.file_index == file_index as isize
+                && spec.matches(streams, m.stream_index as usize)
+            {
+                m.disabled = 1;
+            }
+            matched = true;
+        }
+    } else {
+        for i in (0..streams.len()).filter(|&i| spec.matches(streams, i)) {
+            let (sync_file_index, sync_stream_index) = sync.unwrap_or((file_index, i));
+            maps.push(StreamMap {
+                disabled: 0,
+                file_index: file_index as isize,
+                stream_index: i as isize,
+                sync_file_index: sync_file_index as isize,
+                sync_stream_index: sync_stream_index as isize,
+                linklabel: String::new(),
+            });
+            matched = true;
+        }
+    }
+
+    if matched || allow_unused {
+        if !matched {
+            debug!("Stream map '{}' matches no streams; ignoring.", arg);
+        }
+        return Ok(());
+    }
+    match spec.selector {
+        StreamSelector::Index(i) if !negative => {
+            // Say how many streams the index could have picked from.
+            let candidates = StreamSpecifier {
+                selector: StreamSelector::All,
+                ..spec.clone()
+            };
+            let count = (0..streams.len())
+                .filter(|&i| candidates.matches(streams, i))
+                .count();
+            Err(format!(
+                "Stream map '{}' matches no streams: stream index {} is out of range, input \
+                 file #{} has {} such streams.\nTo ignore this, add a trailing '?' to the map.",
+                arg, i, file_index, count
+            ))
+        }
+        _ => Err(format!(
+            "Stream map '{}' matches no streams.\nTo ignore this, add a trailing '?' to the map.",
+            arg
+        )),
+    }
 }
 
-pub fn ffmpeg_parse_options(args: &[String]) {
-    // IMPROVEMENT move `init_parse_context(octx, groups)` out of split_commandline() and inline it.
-    let mut octx = OptionParseContext {
-        groups: (&*GROUPS)
-            .iter()
-            .map(|group| OptionGroupList {
-                group_def: group,
-                groups: vec![],
-            })
-            .collect(),
-        global_opts: OptionGroup::new_global(),
-        cur_group: OptionGroup::new_anonymous(),
+/// Parse the argument of `-map_channel`:
+/// `[input_file_id.stream_id.channel_id|-1][?][:output_file_id.stream_id]`,
+/// and add it to `maps`. `-1` mutes the channel.
+pub fn parse_audio_channel_map(
+    input_files: &[InputFile],
+    maps: &mut Vec<AudioChannelMap>,
+    arg: &str,
+) -> Result<(), String> {
+    let usage = || {
+        format!(
+            "Syntax error, mapchan usage: \
+             [file.stream.channel|-1][:ofile.ostream], found: {}",
+            arg
+        )
+    };
+    let indexes = |s: &str, n: usize| -> Result<Vec<isize>, String> {
+        let v = s
+            .split('.')
+            .map(|x| x.parse::<isize>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| usage())?;
+        if v.len() == n {
+            Ok(v)
+        } else {
+            Err(usage())
+        }
     };
 
-    split_commandline(&mut octx, &args, &*OPTIONS, &*GROUPS).unwrap();
-    println!("{:#?}", octx);
-    parse_optgroup(None, &octx.global_opts).unwrap();
+    let (input, output) = match arg.find(':') {
+        Some(i) => (&arg[..i], Some(&arg[i + 1..])),
+        None => (arg, None),
+    };
+    let (input, allow_unused) = match input.find('?') {
+        Some(i) => (&input[..i], true),
+        None => (input, false),
+    };
+    let (ofile_idx, ostream_idx) = match output {
+        Some(output) => {
+            let v = indexes(output, 2)?;
+            (v[0], v[1])
+        }
+        None => (-1, -1),
+    };
 
-    /*
-    open_files(
-        &mut octx.groups[OptGroup::GroupInFile as usize],
-        "input",
-        open_input_file,
-    )
-    .unwrap();
+    // Muted channel syntax
+    if input == "-1" {
+        maps.push(AudioChannelMap {
+            file_idx: -1,
+            stream_idx: -1,
+            channel_idx: -1,
+            ofile_idx,
+            ostream_idx,
+        });
+        return Ok(());
+    }
 
-    init_complex_filters();
+    let v = indexes(input, 3)?;
+    let m = AudioChannelMap {
+        file_idx: v[0],
+        stream_idx: v[1],
+        channel_idx: v[2],
+        ofile_idx,
+        ostream_idx,
+    };
 
-    open_files(
-        &mut octx.groups[OptGroup::GroupOutFile as usize],
-        "output",
-        open_output_file,
-    )
-    .unwrap();
+    // Check input
+    let file = match input_files.get(m.file_idx as usize) {
+        Some(file) if m.file_idx >= 0 => file,
+        _ => return Err(format!("mapchan: invalid input file index: {}", m.file_idx)),
+    };
+    let st = match file.streams.get(m.stream_idx as usize) {
+        Some(st) if m.stream_idx >= 0 => st,
+        _ => {
+            return Err(format!(
+                "mapchan: invalid input file stream index #{}.{}",
+                m.file_idx, m.stream_idx
+            ))
+        }
+    };
+    if st.media_type != Some(MediaType::Audio) {
+        return Err(format!(
+            "mapchan: stream #{}.{} is not an audio stream.",
+            m.file_idx, m.stream_idx
+        ));
+    }
+    if m.channel_idx < 0 || m.channel_idx as usize >= st.channels {
+        if allow_unused {
+            debug!(
+                "mapchan: invalid audio channel #{}.{}.{}",
+                m.file_idx, m.stream_idx, m.channel_idx
+            );
+            return Ok(());
+        }
+        return Err(format!(
+            "mapchan: invalid audio channel #{}.{}.{}, the stream has {} channels.\n\
+             To ignore this, add a trailing '?' to the map_channel.",
+            m.file_idx, m.stream_idx, m.channel_idx, st.channels
+        ));
+    }
+    maps.push(m);
+    Ok(())
+}
+
+/// `-map_channel` is applied by a pan filter in front of the simple audio
+/// filtergraph, so it cannot work on streams which are not encoded from that
+/// graph.
+fn check_audio_channel_maps(o: &OptionsContext) -> Result<(), String> {
+    if o.audio_channel_maps.is_empty() {
+        return Ok(());
+    }
+    let audio_copy = o.codec_names.iter().any(|so| {
+        (so.specifier.is_empty() || so.specifier.starts_with('a')) && so.u.as_str() == Some("copy")
+    });
+    if audio_copy {
+        return Err(
+            "-map_channel cannot be used with audio stream copy, the channels can only be \
+             remapped when decoding and encoding."
+                .to_owned(),
+        );
+    }
+    if let Some(m) = o.stream_maps.iter().find(|m| !m.linklabel.is_empty()) {
+        return Err(format!(
+            "-map_channel cannot be used with the filtergraph output [{}], use the pan filter \
+             in -filter_complex instead.",
+            m.linklabel
+        ));
+    }
+    Ok(())
+}
+
+fn open_output_file(
+    session: &mut Session,
+    o: &mut OptionsContext,
+    filename: &OsStr,
+) -> Result<(), String> {
+    check_audio_channel_maps(o)?;
 
-    check_filter_outputs();
-    */
+    if o.stop_time != i64::MAX && o.recording_time != i64::MAX {
+        o.stop_time = i64::MAX;
+        error!("-t and -to cannot be used together; using -t.");
+    }
+    if o.stop_time != i64::MAX && o.recording_time == i64::MAX {
+        let start_time = if o.start_time == AV_NOPTS_VALUE {
+            0
+        } else {
+            o.start_time
+        };
+        if o.stop_time <= start_time {
+            return Err("-to value smaller than -ss; aborting.".to_owned());
+        } else {
+            o.recording_time = o.stop_time - start_time;
+        }
+    }
+
+    let filename = match filename.to_str() {
+        Some("-") => OsStr::new("pipe:"),
+        _ => filename,
+    };
+
+    let filename_s = cmdutils::os_cstring(filename)?;
+    let format_s = CString::new(&o.format as &str).map_err(|e| e.to_string())?;
+    let mut oc = ptr::null_mut();
+    let err = unsafe {
+        ffi::avformat_alloc_output_context2(
+            &mut oc,
+            ptr::null_mut(),
+            if o.format.is_empty() {
+                ptr::null()
+            } else {
+                format_s.as_ptr()
+            },
+            filename_s.as_ptr(),
+        )
+    };
+    if oc.is_null() {
+        return Err(cmdutils::av_err2str(err));
+    }
+
+    let mut of = OutputFile {
+        filename: filename.to_owned(),
+        ctx: oc,
+        opts: o.g.format_opts.clone(),
+        ost_index: session.output_streams.len(),
+        recording_time: o.recording_time,
+        start_time: o.start_time,
+        limit_filesize: o.limit_filesize,
+        shortest: o.shortest,
+        header_written: false,
+    };
+
+    if let Err(e) = unsafe { add_output_streams(session, o, &mut of) } {
+        for mut ost in session.output_streams.drain(of.ost_index..) {
+            unsafe { ffi::avcodec_free_context(&mut ost.enc_ctx) };
+        }
+        unsafe {
+            ffi::avio_closep(&mut (*oc).pb);
+            ffi::avformat_free_context(oc);
+        }
+        return Err(e);
+    }
+    session.output_files.push(of);
+    Ok(())
+}
+
+/// Create the streams of the output file `of` from `-map` or the automatic
+/// stream selection, then open the file and set its metadata. `of` is not
+/// in `Session::output_files` yet.
+unsafe fn add_output_streams(
+    session: &mut Session,
+    o: &mut OptionsContext,
+    of: &mut OutputFile,
+) -> Result<(), String> {
+    let oc_ptr = of.ctx;
+    let oc = oc_ptr.as_mut().unwrap();
+    let oformat = oc.oformat.as_ref().unwrap();
+    let file_index = session.output_files.len();
+    let filename_s = cmdutils::os_cstring(&of.filename)?;
+
+    if of.recording_time != i64::MAX {
+        oc.duration = of.recording_time;
+    }
+    oc.interrupt_callback = INT_CB;
+    if o.bitexact != 0 {
+        oc.flags |= ffi::AVFMT_FLAG_BITEXACT as i32;
+    }
+
+    let mut video_disable = o.video_disable != 0;
+    let mut audio_disable = o.audio_disable != 0;
+    let mut subtitle_disable = o.subtitle_disable != 0;
+    let data_disable = o.data_disable != 0;
+
+    // create streams for all unlabeled output pads
+    for i in 0..session.filtergraphs.len() {
+        for j in 0..session.filtergraphs[i].outputs.len() {
+            let ofilter = &session.filtergraphs[i].outputs[j];
+            if ofilter.ost.is_some() || !ofilter.label.is_empty() {
+                continue;
+            }
+            match ofilter.media_type {
+                ffi::AVMediaType_AVMEDIA_TYPE_VIDEO => video_disable = true,
+                ffi::AVMediaType_AVMEDIA_TYPE_AUDIO => audio_disable = true,
+                ffi::AVMediaType_AVMEDIA_TYPE_SUBTITLE => subtitle_disable = true,
+                _ => {}
+            }
+            init_output_filter(session, o, oc_ptr, (i, j))?;
+        }
+    }
+
+    if o.stream_maps.is_empty() {
+        let selected: Vec<_> = {
+            let streams: Vec<&StreamInfo> = session
+                .input_files
+                .iter()
+                .flat_map(|f| f.streams.iter())
+                .collect();
+            let input_streams = &session.input_streams;
+            let kept = |i: usize| input_streams[i].user_set_discard != ffi::AVDiscard_AVDISCARD_ALL;
+            let guess_codec = |ty| {
+                ffi::av_guess_codec(
+                    oc.oformat,
+                    ptr::null(),
+                    filename_s.as_ptr(),
+                    ptr::null(),
+                    ty,
+                )
+            };
+            let mut selected = vec![];
+
+            // video: highest resolution
+            if !video_disable
+                && guess_codec(ffi::AVMediaType_AVMEDIA_TYPE_VIDEO)
+                    != ffi::AVCodecID_AV_CODEC_ID_NONE
+            {
+                let qcr = ffi::avformat_query_codec(oc.oformat, oformat.video_codec, 0);
+                let apic = qcr == i32::from_le_bytes(*b"APIC");
+                selected.extend(select_best_stream(&streams, MediaType::Video, apic, kept));
+            }
+
+            // audio: most channels
+            if !audio_disable
+                && guess_codec(ffi::AVMediaType_AVMEDIA_TYPE_AUDIO)
+                    != ffi::AVCodecID_AV_CODEC_ID_NONE
+            {
+                selected.extend(select_best_stream(&streams, MediaType::Audio, false, kept));
+            }
+
+            // subtitles: pick first
+            let subtitle_codec_name = match_per_type_opt(&o.codec_names, "s");
+            let output_codec = ffi::avcodec_find_encoder(oformat.subtitle_codec);
+            if !subtitle_disable && (!output_codec.is_null() || subtitle_codec_name.is_some()) {
+                let props = (ffi::AV_CODEC_PROP_TEXT_SUB | ffi::AV_CODEC_PROP_BITMAP_SUB) as i32;
+                let output_descriptor = output_codec
+                    .as_ref()
+                    .and_then(|codec| ffi::avcodec_descriptor_get(codec.id).as_ref());
+                let accept = |i: usize| {
+                    let par = (*input_streams[i].st).codecpar.as_ref().unwrap();
+                    let input_descriptor = ffi::avcodec_descriptor_get(par.codec_id).as_ref();
+                    let input_props = input_descriptor.map_or(0, |d| d.props & props);
+                    let output_props = output_descriptor.map_or(0, |d| d.props & props);
+                    kept(i)
+                        && (subtitle_codec_name.is_some()
+                        || input_props & output_props != 0
+                        // Map dvb teletext which has neither property to any
+                        // output subtitle encoder
+                        || match (input_descriptor, output_descriptor) {
+                            (Some(input), Some(output)) => input.props == 0 || output.props == 0,
+                            _ => false,
+                        })
+                };
+                selected.extend(select_best_stream(
+                    &streams,
+                    MediaType::Subtitle,
+                    false,
+                    accept,
+                ));
+            }
+
+            // Data only if codec id match
+            if !data_disable {
+                let codec_id = guess_codec(ffi::AVMediaType_AVMEDIA_TYPE_DATA);
+                if codec_id != ffi::AVCodecID_AV_CODEC_ID_NONE {
+                    selected.extend((0..streams.len()).filter(|&i| {
+                        streams[i].media_type == Some(MediaType::Data)
+                            && kept(i)
+                            && (*(*input_streams[i].st).codecpar).codec_id == codec_id
+                    }));
+                }
+            }
+
+            selected
+                .into_iter()
+                .map(|i| (i, streams[i].media_type))
+                .collect()
+        };
+        for (i, media_type) in selected {
+            new_stream(session, o, oc_ptr, media_type, Some(i))?;
+        }
+    } else {
+        for map in &o.stream_maps {
+            if map.disabled != 0 {
+                continue;
+            }
+            if !map.linklabel.is_empty() {
+                let ofilter = session.filtergraphs.iter().enumerate().find_map(|(i, fg)| {
+                    fg.outputs
+                        .iter()
+                        .position(|out| out.ost.is_none() && out.label == map.linklabel)
+                        .map(|j| (i, j))
+                });
+                match ofilter {
+                    Some(ofilter) => init_output_filter(session, o, oc_ptr, ofilter)?,
+                    None => {
+                        return Err(format!(
+                            "Output with label '{}' does not exist in any defined filter graph, \
+                             or was already used elsewhere.",
+                            map.linklabel
+                        ))
+                    }
+                }
+                continue;
+            }
+
+            let input_file = &session.input_files[map.file_index as usize];
+            let src_idx = input_file.ist_index + map.stream_index as usize;
+            let media_type = input_file.streams[map.stream_index as usize].media_type;
+            let disabled = match media_type {
+                Some(MediaType::Video) => video_disable,
+                Some(MediaType::Audio) => audio_disable,
+                Some(MediaType::Subtitle) => subtitle_disable,
+                Some(MediaType::Data) => data_disable,
+                _ => false,
+            };
+            if disabled {
+                continue;
+            }
+            if session.input_streams[src_idx].user_set_discard == ffi::AVDiscard_AVDISCARD_ALL {
+                return Err(format!(
+                    "Stream #{}:{} is disabled and cannot be mapped.",
+                    map.file_index, map.stream_index
+                ));
+            }
+            if media_type.is_none() && session.copy_unknown_streams == 0 {
+                let e = format!(
+                    "Cannot map stream #{}:{} - unsupported type.",
+                    map.file_index, map.stream_index
+                );
+                if session.ignore_unknown_streams != 0 {
+                    error!("{}", e);
+                    continue;
+                }
+                return Err(e
+                    + "\nIf you want unsupported types ignored instead of failing, please use \
+                       the -ignore_unknown option\nIf you want them copied, please use \
+                       -copy_unknown");
+            }
+
+            let ost = new_stream(session, o, oc_ptr, media_type, Some(src_idx))?;
+            let sync_file = &session.input_files[map.sync_file_index as usize];
+            session.output_streams[ost].sync_ist =
+                Some(sync_file.ist_index + map.sync_stream_index as usize);
+        }
+    }
+
+    // handle attached files
+    for attachment in &o.attachments {
+        let data = fs::read(attachment)
+            .map_err(|e| format!("Could not open attachment file {}: {}.", attachment, e))?;
+        if data.len() > i32::MAX as usize - ffi::AV_INPUT_BUFFER_PADDING_SIZE as usize {
+            return Err(format!("Attachment {} too large.", attachment));
+        }
+        let extradata =
+            ffi::av_mallocz((data.len() + ffi::AV_INPUT_BUFFER_PADDING_SIZE as usize) as u64)
+                as *mut u8;
+        if extradata.is_null() {
+            return Err(format!(
+                "Attachment {} too large, out of memory.",
+                attachment
+            ));
+        }
+        ptr::copy_nonoverlapping(data.as_ptr(), extradata, data.len());
+
+        let idx = new_stream(session, o, oc_ptr, Some(MediaType::Attachment), None)?;
+        let ost = &mut session.output_streams[idx];
+        ost.stream_copy = false;
+        ost.attachment_filename = attachment.to_owned();
+        let st = ost.st.as_mut().unwrap();
+        let par = st.codecpar.as_mut().unwrap();
+        par.extradata = extradata;
+        par.extradata_size = data.len() as i32;
+        let name = attachment.rsplit('/').next().unwrap();
+        let name = if name.is_empty() { attachment } else { name };
+        set_metadata(&mut st.metadata, "filename", name, DictFlag::DONT_OVERWRITE)?;
+    }
+
+    if oc.nb_streams == 0 && oformat.flags & ffi::AVFMT_NOSTREAMS as i32 == 0 {
+        ffi::av_dump_format(oc, file_index as i32, filename_s.as_ptr(), 1);
+        return Err(format!(
+            "Output file #{} does not contain any stream",
+            file_index
+        ));
+    }
+
+    // check if all codec options have been used
+    let used = session.output_streams[of.ost_index..]
+        .iter()
+        .map(|ost| &ost.encoder_opts);
+    check_unused_codec_opts(
+        &o.g.codec_opts,
+        used,
+        true,
+        file_index,
+        &of.filename.to_string_lossy(),
+    )?;
+
+    // set the decoding_needed flags and create simple filtergraphs
+    for i in of.ost_index..session.output_streams.len() {
+        let ost = &session.output_streams[i];
+        if let (true, Some(ist)) = (ost.encoding_needed, ost.source_index) {
+            session.input_streams[ist].decoding_needed |= ffmpeg::DECODING_FOR_OST;
+            let ty = (*ost.enc_ctx).codec_type;
+            if ty == ffi::AVMediaType_AVMEDIA_TYPE_VIDEO
+                || ty == ffi::AVMediaType_AVMEDIA_TYPE_AUDIO
+            {
+                ffmpeg_filter::init_simple_filtergraph(session, ist, i);
+            }
+        }
+
+        // set the filter output constraints
+        let ost = &session.output_streams[i];
+        if let Some((fg, out)) = ost.filter {
+            set_output_filter_constraints(&mut session.filtergraphs[fg].outputs[out], ost);
+        }
+    }
+
+    // check filename in case of an image number is expected
+    if oformat.flags & ffi::AVFMT_NEEDNUMBER as i32 != 0
+        && ffi::av_filename_number_test(filename_s.as_ptr()) == 0
+    {
+        return Err(cmdutils::av_err2str(AVERROR(ffi::EINVAL as i32)));
+    }
+
+    if oformat.flags & ffi::AVFMT_NOSTREAMS as i32 == 0
+        && session.input_stream_potentially_available == 0
+    {
+        return Err("No input streams but output needs an input stream".to_owned());
+    }
+
+    if oformat.flags & ffi::AVFMT_NOFILE as i32 == 0 {
+        // test if it already exists to avoid losing precious files
+        assert_file_overwrite(session, &of.filename)?;
+
+        // open the file
+        let mut opts = of.opts.to_av_dict();
+        let err = ffi::avio_open2(
+            &mut oc.pb,
+            filename_s.as_ptr(),
+            ffi::AVIO_FLAG_WRITE as i32,
+            &oc.interrupt_callback,
+            &mut opts,
+        );
+        of.opts = Dictionary::from_av_dict(opts);
+        ffi::av_dict_free(&mut opts);
+        if err < 0 {
+            return Err(cmdutils::av_err2str(err));
+        }
+    } else if CStr::from_ptr(oformat.name).to_bytes() == b"image2"
+        && ffi::av_filename_number_test(filename_s.as_ptr()) == 0
+    {
+        assert_file_overwrite(session, &of.filename)?;
+    }
+
+    if o.mux_preload != 0. {
+        let preload = (o.mux_preload * ffi::AV_TIME_BASE as f32) as i64;
+        of.opts.set("preload", &preload.to_string(), DictFlag::NONE);
+    }
+    oc.max_delay = (o.mux_max_delay * ffi::AV_TIME_BASE as f32) as i32;
+
+    // copy metadata
+    for so in o.metadata_map.clone() {
+        let map = so.u.as_str().unwrap_or_default();
+        let (in_file_index, inspec) = strtol(map).unwrap_or((0, map));
+        if in_file_index >= session.input_files.len() as i64 {
+            return Err(format!(
+                "Invalid input file index {} while processing metadata maps",
+                in_file_index
+            ));
+        }
+        let ifile = if in_file_index >= 0 {
+            Some(&session.input_files[in_file_index as usize])
+        } else {
+            None
+        };
+        let inspec = inspec.get(1..).unwrap_or("");
+        copy_metadata(&so.specifier, inspec, oc, ifile, o)?;
+    }
+
+    // copy chapters
+    if o.chapters_input_file >= session.input_files.len() as isize {
+        if o.chapters_input_file == isize::MAX {
+            // copy chapters from the first input file that has them
+            o.chapters_input_file = session
+                .input_files
+                .iter()
+                .position(|f| (*f.ctx).nb_chapters != 0)
+                .map_or(-1, |i| i as isize);
+        } else {
+            return Err(format!(
+                "Invalid input file index {} in chapter mapping.",
+                o.chapters_input_file
+            ));
+        }
+    }
+    if o.chapters_input_file >= 0 {
+        copy_chapters(
+            &session.input_files[o.chapters_input_file as usize],
+            of,
+            o.metadata_chapters_manual == 0,
+        )?;
+    }
+
+    // copy global metadata by default
+    if o.metadata_global_manual == 0 {
+        if let Some(ic) = session.input_files.first().and_then(|f| f.ctx.as_ref()) {
+            ffi::av_dict_copy(
+                &mut oc.metadata,
+                ic.metadata,
+                ffi::AV_DICT_DONT_OVERWRITE as i32,
+            );
+            if of.recording_time != i64::MAX {
+                set_metadata(&mut oc.metadata, "duration", "", DictFlag::NONE)?;
+            }
+            for key in &[
+                "creation_time",
+                "company_name",
+                "product_name",
+                "product_version",
+            ] {
+                set_metadata(&mut oc.metadata, key, "", DictFlag::NONE)?;
+            }
+        }
+    }
+    if o.metadata_streams_manual == 0 {
+        for ost in &session.output_streams[of.ost_index..] {
+            if let Some(i) = ost.source_index {
+                let st = ost.st.as_mut().unwrap();
+                ffi::av_dict_copy(
+                    &mut st.metadata,
+                    (*session.input_streams[i].st).metadata,
+                    ffi::AV_DICT_DONT_OVERWRITE as i32,
+                );
+                if !ost.stream_copy {
+                    set_metadata(&mut st.metadata, "encoder", "", DictFlag::NONE)?;
+                }
+            }
+        }
+    }
+
+    // process manually set programs
+    for (i, so) in o.program.iter().enumerate() {
+        let arg = cmdutils::os_bytes(so.u.as_os_str().unwrap_or_default());
+        let mut progid = i as i32 + 1;
+        for (key, value) in program_keys(&arg) {
+            let value = match value {
+                Some(value) => value,
+                None => break,
+            };
+            if key == b"program_num" {
+                progid = parse_int(&value) as i32;
+            }
+        }
+
+        let program = ffi::av_new_program(oc, progid);
+        if program.is_null() {
+            return Err(cmdutils::av_err2str(AVERROR(ffi::ENOMEM as i32)));
+        }
+        for (key, value) in program_keys(&arg) {
+            let value = match value {
+                Some(value) => value,
+                None => {
+                    return Err(format!(
+                        "No '=' character in program string {}.",
+                        String::from_utf8_lossy(&key)
+                    ))
+                }
+            };
+            match &key[..] {
+                b"title" => {
+                    set_metadata(&mut (*program).metadata, "title", &value, DictFlag::NONE)?
+                }
+                b"program_num" => {}
+                b"st" => ffi::av_program_add_stream_index(oc, progid, parse_int(&value) as u32),
+                _ => {
+                    return Err(format!(
+                        "Unknown program key {}.",
+                        String::from_utf8_lossy(&key)
+                    ))
+                }
+            }
+        }
+    }
+
+    // process manually set metadata
+    let streams = StreamInfo::from_format_context(oc);
+    let oc_streams = slice::from_raw_parts(oc.streams, oc.nb_streams as usize);
+    for so in &o.metadata {
+        // The value is passed as is, it's not necessarily UTF-8.
+        let arg = cmdutils::os_bytes(so.u.as_os_str().unwrap_or_default());
+        let (key, value) = match arg.iter().position(|&c| c == b'=') {
+            Some(i) => (&arg[..i], &arg[i + 1..]),
+            None => {
+                return Err(format!(
+                    "No '=' character in metadata string {}.",
+                    String::from_utf8_lossy(&arg)
+                ))
+            }
+        };
+        let m = match parse_meta_type(&so.specifier)? {
+            MetaType::Global => &mut oc.metadata,
+            MetaType::Stream(spec) => {
+                // Specifiers have been checked when splitting the command line.
+                let spec: StreamSpecifier = spec.parse()?;
+                for (j, &st) in oc_streams.iter().enumerate() {
+                    if spec.matches(&streams, j) {
+                        set_metadata(&mut (*st).metadata, key, value, DictFlag::NONE)?;
+                    }
+                }
+                continue;
+            }
+            MetaType::Chapter(index) => {
+                if index < 0 || index >= oc.nb_chapters as i64 {
+                    return Err(format!(
+                        "Invalid chapter index {} in metadata specifier.",
+                        index
+                    ));
+                }
+                &mut (**oc.chapters.offset(index as isize)).metadata
+            }
+            MetaType::Program(index) => {
+                if index < 0 || index >= oc.nb_programs as i64 {
+                    return Err(format!(
+                        "Invalid program index {} in metadata specifier.",
+                        index
+                    ));
+                }
+                &mut (**oc.programs.offset(index as isize)).metadata
+            }
+        };
+        set_metadata(m, key, value, DictFlag::NONE)?;
+    }
+    Ok(())
+}
+
+/// Split the `key=value:key=value` argument of `-program` like the
+/// `av_get_token()` loop of ffmpeg, the value is `None` without a `=`.
+fn program_keys(mut p: &[u8]) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+    let mut keys = vec![];
+    while !p.is_empty() {
+        let (token, rest) = get_token(p, b":");
+        p = rest.get(1..).unwrap_or(rest);
+        let (key, value) = get_token(&token, b"=");
+        keys.push((key, value.get(1..).map(<[u8]>::to_vec)));
+    }
+    keys
+}
+
+/// `av_get_token()`: skip the leading whitespace, read up to a `term` byte
+/// with `\` escapes and `'` quotes, and trim the trailing whitespace.
+/// Returns the token and the rest, starting at the `term` byte.
+fn get_token<'a>(p: &'a [u8], term: &[u8]) -> (Vec<u8>, &'a [u8]) {
+    const WHITESPACES: &[u8] = b" \n\t\r";
+    let mut i = p.iter().take_while(|c| WHITESPACES.contains(c)).count();
+    let mut out = vec![];
+    // Length of the token which can't be trimmed.
+    let mut end = 0;
+    while i < p.len() && !term.contains(&p[i]) {
+        let c = p[i];
+        i += 1;
+        if c == b'\\' && i < p.len() {
+            out.push(p[i]);
+            i += 1;
+            end = out.len();
+        } else if c == b'\'' {
+            while i < p.len() && p[i] != b'\'' {
+                out.push(p[i]);
+                i += 1;
+            }
+            if i < p.len() {
+                i += 1;
+                end = out.len();
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    while out.len() > end && WHITESPACES.contains(out.last().unwrap()) {
+        out.pop();
+    }
+    (out, &p[i..])
+}
+
+/// `strtol(s, NULL, 0)` on bytes, 0 if there's no number.
+fn parse_int(s: &[u8]) -> i64 {
+    std::str::from_utf8(s)
+        .ok()
+        .and_then(strtol)
+        .map_or(0, |(n, _)| n)
+}
+
+/// The metadata dictionary of `s` for a global, chapter or program type.
+unsafe fn meta_dict(
+    s: *mut ffi::AVFormatContext,
+    meta_type: &MetaType,
+) -> Result<*mut *mut ffi::AVDictionary, String> {
+    let s = &mut *s;
+    let check_index = |index: i64, nb: u32, desc: &str| {
+        if index < 0 || index >= nb as i64 {
+            Err(format!(
+                "Invalid {} index {} while processing metadata maps.",
+                desc, index
+            ))
+        } else {
+            Ok(index as isize)
+        }
+    };
+    Ok(match *meta_type {
+        MetaType::Global => &mut s.metadata,
+        MetaType::Chapter(index) => {
+            let index = check_index(index, s.nb_chapters, "chapter")?;
+            &mut (**s.chapters.offset(index)).metadata
+        }
+        MetaType::Program(index) => {
+            let index = check_index(index, s.nb_programs, "program")?;
+            &mut (**s.programs.offset(index)).metadata
+        }
+        MetaType::Stream(_) => unreachable!(),
+    })
+}
+
+/// `-map_metadata`: copy the metadata selected by `inspec` in `ifile` to
+/// the one selected by `outspec` in `oc`, which disables the automatic copy
+/// of that type. `ifile` is `None` for a negative file index, which only
+/// disables the automatic copy.
+unsafe fn copy_metadata(
+    outspec: &str,
+    inspec: &str,
+    oc: *mut ffi::AVFormatContext,
+    ifile: Option<&InputFile>,
+    o: &mut OptionsContext,
+) -> Result<(), String> {
+    let type_in = parse_meta_type(inspec)?;
+    let type_out = parse_meta_type(outspec)?;
+
+    let mut types = vec![&type_out];
+    if ifile.is_some() {
+        types.push(&type_in);
+    } else if outspec.is_empty() {
+        types.extend(&[&MetaType::Stream(""), &MetaType::Chapter(0)]);
+    }
+    for meta_type in types {
+        match meta_type {
+            MetaType::Global => o.metadata_global_manual = 1,
+            MetaType::Stream(_) => o.metadata_streams_manual = 1,
+            MetaType::Chapter(_) => o.metadata_chapters_manual = 1,
+            MetaType::Program(_) => {}
+        }
+    }
+    let ifile = match ifile {
+        Some(ifile) => ifile,
+        None => return Ok(()),
+    };
+
+    let meta_out = match type_out {
+        MetaType::Stream(_) => None,
+        ref meta_type => Some(meta_dict(oc, meta_type)?),
+    };
+    let meta_in = match type_in {
+        // for input streams choose first matching stream
+        MetaType::Stream(spec) => {
+            let istream_spec: StreamSpecifier = spec.parse()?;
+            match (0..ifile.streams.len()).find(|&i| istream_spec.matches(&ifile.streams, i)) {
+                Some(i) => (**(*ifile.ctx).streams.add(i)).metadata,
+                None => {
+                    return Err(format!(
+                        "Stream specifier {} does not match  any streams.",
+                        spec
+                    ))
+                }
+            }
+        }
+        ref meta_type => *meta_dict(ifile.ctx, meta_type)?,
+    };
+
+    match (meta_out, type_out) {
+        (Some(meta_out), _) => {
+            ffi::av_dict_copy(meta_out, meta_in, ffi::AV_DICT_DONT_OVERWRITE as i32);
+        }
+        (None, MetaType::Stream(spec)) => {
+            let ostream_spec: StreamSpecifier = spec.parse()?;
+            let streams = StreamInfo::from_format_context(oc);
+            for i in (0..streams.len()).filter(|&i| ostream_spec.matches(&streams, i)) {
+                ffi::av_dict_copy(
+                    &mut (**(*oc).streams.add(i)).metadata,
+                    meta_in,
+                    ffi::AV_DICT_DONT_OVERWRITE as i32,
+                );
+            }
+        }
+        (None, _) => unreachable!(),
+    }
+    Ok(())
+}
+
+/// Copy the chapters of `ifile` which are within the `-ss`/`-t` range of
+/// `of`, with their metadata if `copy_metadata`.
+unsafe fn copy_chapters(
+    ifile: &InputFile,
+    of: &OutputFile,
+    copy_metadata: bool,
+) -> Result<(), String> {
+    let is = &*ifile.ctx;
+    let os = &mut *of.ctx;
+    let enomem = || cmdutils::av_err2str(AVERROR(ffi::ENOMEM as i32));
+
+    let chapters = ffi::av_realloc_f(
+        os.chapters as *mut c_void,
+        (is.nb_chapters + os.nb_chapters) as _,
+        std::mem::size_of::<*mut ffi::AVChapter>() as _,
+    ) as *mut *mut ffi::AVChapter;
+    if chapters.is_null() {
+        // av_realloc_f() has freed the chapters.
+        os.chapters = ptr::null_mut();
+        os.nb_chapters = 0;
+        return Err(enomem());
+    }
+    os.chapters = chapters;
+
+    for i in 0..is.nb_chapters as isize {
+        let in_ch = &**is.chapters.offset(i);
+        let start_time = if of.start_time == AV_NOPTS_VALUE {
+            0
+        } else {
+            of.start_time
+        };
+        let ts_off = ffi::av_rescale_q(
+            start_time - ifile.ts_offset,
+            ffmpeg::AV_TIME_BASE_Q,
+            in_ch.time_base,
+        );
+        let rt = if of.recording_time == i64::MAX {
+            i64::MAX
+        } else {
+            ffi::av_rescale_q(of.recording_time, ffmpeg::AV_TIME_BASE_Q, in_ch.time_base)
+        };
+
+        if in_ch.end < ts_off {
+            continue;
+        }
+        if rt != i64::MAX && in_ch.start > rt + ts_off {
+            break;
+        }
+
+        let out_ch =
+            ffi::av_mallocz(std::mem::size_of::<ffi::AVChapter>() as _) as *mut ffi::AVChapter;
+        let out_ch = match out_ch.as_mut() {
+            Some(out_ch) => out_ch,
+            None => return Err(enomem()),
+        };
+        out_ch.id = in_ch.id;
+        out_ch.time_base = in_ch.time_base;
+        out_ch.start = 0.max(in_ch.start - ts_off);
+        out_ch.end = rt.min(in_ch.end - ts_off);
+        if copy_metadata {
+            ffi::av_dict_copy(&mut out_ch.metadata, in_ch.metadata, 0);
+        }
+
+        *os.chapters.offset(os.nb_chapters as isize) = out_ch;
+        os.nb_chapters += 1;
+    }
+    Ok(())
+}
+
+/// Create the output stream fed by the filtergraph output `ofilter`, given
+/// as `(filtergraph, output)` indexes.
+unsafe fn init_output_filter(
+    session: &mut Session,
+    o: &OptionsContext,
+    oc: *mut ffi::AVFormatContext,
+    ofilter: (usize, usize),
+) -> Result<(), String> {
+    let (fg, out) = ofilter;
+    let idx = match session.filtergraphs[fg].outputs[out].media_type {
+        ffi::AVMediaType_AVMEDIA_TYPE_VIDEO => new_video_stream(session, o, oc, None)?,
+        ffi::AVMediaType_AVMEDIA_TYPE_AUDIO => new_audio_stream(session, o, oc, None)?,
+        _ => return Err("Only video and audio filters are supported currently.".to_owned()),
+    };
+    session.filtergraphs[fg].outputs[out].ost = Some(idx);
+    let ost = &mut session.output_streams[idx];
+    ost.filter = Some(ofilter);
+
+    if ost.stream_copy {
+        return Err(format!(
+            "Streamcopy requested for output stream {}:{}, which is fed from a complex \
+             filtergraph. Filtering and streamcopy cannot be used together.",
+            ost.file_index, ost.index
+        ));
+    }
+
+    let streams = StreamInfo::from_format_context(oc);
+    let opt = |opts| match_per_stream_opt(opts, &streams, ost.index);
+    let (kind, option, filtergraph) = match (
+        opt(&o.filters).and_then(|u| u.as_str()),
+        opt(&o.filter_scripts).and_then(|u| u.as_str()),
+    ) {
+        (Some(filters), _) => ("Filtergraph", "-vf/-af/-filter", filters),
+        (None, Some(script)) => ("Filtergraph script", "-filter_script", script),
+        (None, None) => return Ok(()),
+    };
+    Err(format!(
+        "{} '{}' was specified through the {} option for output stream {}:{}, which is fed \
+         from a complex filtergraph.\n{} and -filter_complex cannot be used together for the \
+         same stream.",
+        kind, filtergraph, option, ost.file_index, ost.index, option
+    ))
+}
+
+/// Copy a list terminated by `end`, like the formats supported by an
+/// `AVCodec`.
+unsafe fn terminated_list<T: Copy + PartialEq>(mut p: *const T, end: T) -> Vec<T> {
+    let mut list = vec![];
+    if !p.is_null() {
+        while *p != end {
+            list.push(*p);
+            p = p.add(1);
+        }
+    }
+    list
+}
+
+/// Make the filtergraph output produce what the encoder of `ost` accepts.
+unsafe fn set_output_filter_constraints(f: &mut OutputFilter, ost: &OutputStream) {
+    let enc_ctx = ost.enc_ctx.as_ref().unwrap();
+    let enc = ost.enc.as_ref();
+    match enc_ctx.codec_type {
+        ffi::AVMediaType_AVMEDIA_TYPE_VIDEO => {
+            f.frame_rate = ost.frame_rate;
+            f.width = enc_ctx.width;
+            f.height = enc_ctx.height;
+            if enc_ctx.pix_fmt != ffi::AVPixelFormat_AV_PIX_FMT_NONE {
+                f.format = enc_ctx.pix_fmt as i32;
+            } else if let Some(enc) = enc {
+                f.formats = terminated_list(enc.pix_fmts, ffi::AVPixelFormat_AV_PIX_FMT_NONE)
+                    .into_iter()
+                    .map(|x| x as i32)
+                    .collect();
+            }
+        }
+        ffi::AVMediaType_AVMEDIA_TYPE_AUDIO => {
+            if enc_ctx.sample_fmt != ffi::AVSampleFormat_AV_SAMPLE_FMT_NONE {
+                f.format = enc_ctx.sample_fmt as i32;
+            } else if let Some(enc) = enc {
+                f.formats =
+                    terminated_list(enc.sample_fmts, ffi::AVSampleFormat_AV_SAMPLE_FMT_NONE)
+                        .into_iter()
+                        .map(|x| x as i32)
+                        .collect();
+            }
+            if enc_ctx.sample_rate != 0 {
+                f.sample_rate = enc_ctx.sample_rate;
+            } else if let Some(enc) = enc {
+                f.sample_rates = terminated_list(enc.supported_samplerates, 0);
+            }
+            if enc_ctx.channels != 0 {
+                f.channel_layout = ffi::av_get_default_channel_layout(enc_ctx.channels) as u64;
+            } else if let Some(enc) = enc {
+                f.channel_layouts = terminated_list(enc.channel_layouts, 0);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Index of the input stream an output file without `-map` gets for
+/// `media_type`: the video stream with the highest resolution, the audio
+/// stream with the most channels, or the first stream of other types.
+/// Streams with decoded frames and default streams come first. `streams` are
+/// the streams of every input file in the order of `Session::input_streams`,
+/// and the ones `accept` rejects are skipped. `apic` is set when the muxer
+/// wants attached pictures as its video streams.
+fn select_best_stream(
+    streams: &[&StreamInfo],
+    media_type: MediaType,
+    apic: bool,
+    accept: impl Fn(usize) -> bool,
+) -> Option<usize> {
+    let mut best = None;
+    let mut best_score = 0;
+    for (i, st) in streams.iter().enumerate() {
+        if st.media_type != Some(media_type) || !accept(i) {
+            continue;
+        }
+        let attached_pic = st.disposition & DISPOSITION_ATTACHED_PIC != 0;
+        let bonus = if st.codec_info_nb_frames > 0 {
+            100_000_000
+        } else {
+            0
+        } + if st.disposition & DISPOSITION_DEFAULT != 0 {
+            5_000_000
+        } else {
+            0
+        };
+        let score = match media_type {
+            MediaType::Video if apic && !attached_pic => continue,
+            MediaType::Video if !apic && attached_pic => 1,
+            MediaType::Video => st.width * st.height + bonus,
+            MediaType::Audio => st.channels + bonus,
+            _ => return Some(i),
+        };
+        if score > best_score {
+            best_score = score;
+            best = Some(i);
+        }
+    }
+    best
+}
+
+/// Where `-metadata` writes, from its specifier.
+#[derive(Debug, PartialEq)]
+enum MetaType<'a> {
+    Global,
+    /// The streams matching a stream specifier.
+    Stream(&'a str),
+    Chapter(i64),
+    Program(i64),
+}
+
+/// Parse the `g`, `s[:stream_spec]`, `c[:index]` or `p[:index]` specifier of
+/// the metadata options, an empty specifier is global.
+fn parse_meta_type(spec: &str) -> Result<MetaType, String> {
+    let rest = spec.get(1..).unwrap_or("");
+    let index = || {
+        rest.strip_prefix(':')
+            .and_then(strtol)
+            .map_or(0, |(index, _)| index)
+    };
+    match spec.chars().next() {
+        None | Some('g') => Ok(MetaType::Global),
+        Some('s') => match rest.strip_prefix(':') {
+            Some(stream_spec) => Ok(MetaType::Stream(stream_spec)),
+            None if rest.is_empty() => Ok(MetaType::Stream("")),
+            None => Err(format!("Invalid metadata specifier {}.", spec)),
+        },
+        Some('c') => Ok(MetaType::Chapter(index())),
+        Some('p') => Ok(MetaType::Program(index())),
+        Some(c) => Err(format!("Invalid metadata type {}.", c)),
+    }
+}
+
+/// `av_dict_set()` on a dictionary owned by libav, an empty value removes
+/// the entry.
+unsafe fn set_metadata(
+    m: &mut *mut ffi::AVDictionary,
+    key: impl AsRef<[u8]>,
+    value: impl AsRef<[u8]>,
+    flags: DictFlag,
+) -> Result<(), String> {
+    let (key, value) = (key.as_ref(), value.as_ref());
+    let key_s = CString::new(key).map_err(|e| e.to_string())?;
+    let value_s = CString::new(value).map_err(|e| e.to_string())?;
+    ffi::av_dict_set(
+        m,
+        key_s.as_ptr(),
+        if value.is_empty() {
+            ptr::null()
+        } else {
+            value_s.as_ptr()
+        },
+        flags.bits() as i32,
+    );
+    Ok(())
+}
+
+/// Create an output stream of `media_type` (`None` for unknown types) in
+/// `oc`, returns its index in `Session::output_streams`.
+unsafe fn new_stream(
+    session: &mut Session,
+    o: &OptionsContext,
+    oc: *mut ffi::AVFormatContext,
+    media_type: Option<MediaType>,
+    source_index: Option<usize>,
+) -> Result<usize, String> {
+    let (ty, copy_only) = match media_type {
+        Some(MediaType::Video) => return new_video_stream(session, o, oc, source_index),
+        Some(MediaType::Audio) => return new_audio_stream(session, o, oc, source_index),
+        Some(MediaType::Subtitle) => return new_subtitle_stream(session, o, oc, source_index),
+        Some(MediaType::Data) => (ffi::AVMediaType_AVMEDIA_TYPE_DATA, "Data"),
+        Some(MediaType::Attachment) => (ffi::AVMediaType_AVMEDIA_TYPE_ATTACHMENT, ""),
+        None => (ffi::AVMediaType_AVMEDIA_TYPE_UNKNOWN, "Unknown"),
+    };
+    let idx = new_output_stream(session, o, oc, ty, source_index)?;
+    let ost = &mut session.output_streams[idx];
+    if !ost.stream_copy && !copy_only.is_empty() {
+        return Err(format!(
+            "{} stream encoding not supported yet (only streamcopy)",
+            copy_only
+        ));
+    }
+    ost.stream_copy = true;
+    Ok(idx)
+}
+
+/// Create the output stream and its encoder context, with the options which
+/// are shared by every media type.
+unsafe fn new_output_stream(
+    session: &mut Session,
+    o: &OptionsContext,
+    oc: *mut ffi::AVFormatContext,
+    ty: ffi::AVMediaType,
+    source_index: Option<usize>,
+) -> Result<usize, String> {
+    let st_ptr = ffi::avformat_new_stream(oc, ptr::null());
+    let st = match st_ptr.as_mut() {
+        Some(st) => st,
+        None => return Err("Could not alloc stream.".to_owned()),
+    };
+    let oc_ref = oc.as_ref().unwrap();
+    let idx = oc_ref.nb_streams as usize - 1;
+    if let Some(&id) = o.streamid_map.get(idx) {
+        st.id = id as i32;
+    }
+    let par = st.codecpar.as_mut().unwrap();
+    par.codec_type = ty;
+
+    let streams = StreamInfo::from_format_context(oc);
+    let opt = |opts| match_per_stream_opt(opts, &streams, idx);
+
+    let mut ost = OutputStream {
+        file_index: session.output_files.len(),
+        index: idx,
+        source_index,
+        sync_ist: source_index,
+        st: st_ptr,
+        enc_ctx: ptr::null_mut(),
+        enc: ptr::null_mut(),
+        encoder_opts: Dictionary::new(),
+        stream_copy: false,
+        encoding_needed: false,
+        max_frames: opt(&o.max_frames)
+            .and_then(|u| u.as_i64())
+            .unwrap_or(i64::MAX),
+        frame_rate: ffi::AVRational { num: 0, den: 0 },
+        force_fps: 0,
+        frame_aspect_ratio: ffi::AVRational { num: 0, den: 0 },
+        enc_timebase: ffi::AVRational { num: 0, den: 0 },
+        top_field_first: -1,
+        forced_keyframes: String::new(),
+        keep_pix_fmt: false,
+        copy_initial_nonkeyframes: 0,
+        copy_prior_start: opt(&o.copy_prior_start)
+            .and_then(|u| u.as_int())
+            .unwrap_or(-1),
+        bitstream_filters: opt(&o.bitstream_filters)
+            .and_then(|u| u.as_str())
+            .unwrap_or("")
+            .to_owned(),
+        max_muxing_queue_size: opt(&o.max_muxing_queue_size)
+            .and_then(|u| u.as_int())
+            .unwrap_or(128),
+        filter: None,
+        avfilter: String::new(),
+        apad: String::new(),
+        sws_dict: o.g.sws_dict.clone(),
+        swr_opts: o.g.swr_opts.clone(),
+        resample_opts: o.g.resample_opts.clone(),
+        audio_channels_map: vec![],
+        disposition: opt(&o.disposition)
+            .and_then(|u| u.as_str())
+            .unwrap_or("")
+            .to_owned(),
+        attachment_filename: String::new(),
+        initialized: false,
+        finished: false,
+        unavailable: false,
+        inputs_done: false,
+        frame_number: 0,
+        packets_written: 0,
+        sync_opts: 0,
+        last_mux_dts: AV_NOPTS_VALUE,
+        mux_timebase: ffi::AVRational { num: 0, den: 1 },
+        bsf_ctx: ptr::null_mut(),
+        muxing_queue: VecDeque::new(),
+    };
+
+    choose_encoder(o, oc, &mut ost, &streams)?;
+
+    ost.enc_ctx = ffi::avcodec_alloc_context3(ost.enc);
+    let enc_ctx = match ost.enc_ctx.as_mut() {
+        Some(enc_ctx) => enc_ctx,
+        None => return Err("Error allocating the encoding context.".to_owned()),
+    };
+    enc_ctx.codec_type = ty;
+
+    let codec_id = match ost.enc.as_ref() {
+        Some(enc) => enc.id,
+        None => ffi::AVCodecID_AV_CODEC_ID_NONE,
+    };
+    ost.encoder_opts = cmdutils::filter_codec_opts(&o.g.codec_opts, codec_id, oc, st_ptr, ost.enc);
+
+    if o.bitexact != 0 {
+        enc_ctx.flags |= ffi::AV_CODEC_FLAG_BITEXACT as i32;
+    }
+
+    if let Some(time_base) = opt(&o.time_bases).and_then(|u| u.as_str()) {
+        match parse_ratio(time_base, i32::MAX) {
+            Some(q) if q.num > 0 && q.den > 0 => st.time_base = q,
+            _ => return Err(format!("Invalid time base: {}", time_base)),
+        }
+    }
+
+    if let Some(time_base) = opt(&o.enc_time_bases).and_then(|u| u.as_str()) {
+        match parse_ratio(time_base, i32::MAX) {
+            Some(q) if q.den > 0 => ost.enc_timebase = q,
+            _ => return Err(format!("Invalid time base: {}", time_base)),
+        }
+    }
+
+    if ty != ffi::AVMediaType_AVMEDIA_TYPE_VIDEO
+        && o.max_frames.iter().any(|so| so.specifier.is_empty())
+    {
+        error!("Applying unspecific -frames to non video streams, maybe you meant -vframes ?");
+    }
+
+    if let Some(codec_tag) = opt(&o.codec_tags).and_then(|u| u.as_str()) {
+        let tag = parse_codec_tag(codec_tag);
+        par.codec_tag = tag;
+        enc_ctx.codec_tag = tag;
+    }
+
+    if let Some(qscale) = opt(&o.qscale).and_then(|u| u.as_f64()) {
+        if qscale >= 0. {
+            enc_ctx.flags |= ffi::AV_CODEC_FLAG_QSCALE as i32;
+            enc_ctx.global_quality = (ffi::FF_QP2LAMBDA as f64 * qscale) as i32;
+        }
+    }
+
+    if (*oc_ref.oformat).flags & ffi::AVFMT_GLOBALHEADER as i32 != 0 {
+        enc_ctx.flags |= ffi::AV_CODEC_FLAG_GLOBAL_HEADER as i32;
+    }
+
+    if codec_id != ffi::AVCodecID_AV_CODEC_ID_NONE
+        && ffi::av_get_exact_bits_per_sample(codec_id) == 24
+    {
+        ost.swr_opts.set("output_sample_bits", "24", DictFlag::NONE);
+    }
+
+    if let Some(i) = source_index {
+        let ist = &mut session.input_streams[i];
+        ist.discard = false;
+        (*ist.st).discard = ist.user_set_discard;
+    }
+
+    session.output_streams.push(ost);
+    Ok(session.output_streams.len() - 1)
+}
+
+/// The encoder given by `-c` for the stream, or the default one of the
+/// muxer. `copy` and streams which are not video, audio or subtitles are
+/// stream copied.
+unsafe fn choose_encoder(
+    o: &OptionsContext,
+    s: *mut ffi::AVFormatContext,
+    ost: &mut OutputStream,
+    streams: &[StreamInfo],
+) -> Result<(), String> {
+    let s = s.as_ref().unwrap();
+    let par = (*ost.st).codecpar.as_mut().unwrap();
+    match par.codec_type {
+        ffi::AVMediaType_AVMEDIA_TYPE_VIDEO
+        | ffi::AVMediaType_AVMEDIA_TYPE_AUDIO
+        | ffi::AVMediaType_AVMEDIA_TYPE_SUBTITLE => {
+            match match_per_stream_opt(&o.codec_names, streams, ost.index).and_then(|u| u.as_str())
+            {
+                None => {
+                    par.codec_id = ffi::av_guess_codec(
+                        s.oformat,
+                        ptr::null(),
+                        s.url,
+                        ptr::null(),
+                        par.codec_type,
+                    );
+                    ost.enc = ffi::avcodec_find_encoder(par.codec_id);
+                    if ost.enc.is_null() {
+                        return Err(format!(
+                            "Automatic encoder selection failed for output stream #{}:{}. \
+                             Default encoder for format {} (codec {}) is probably disabled. \
+                             Please choose an encoder manually.",
+                            ost.file_index,
+                            ost.index,
+                            CStr::from_ptr((*s.oformat).name).to_string_lossy(),
+                            CStr::from_ptr(ffi::avcodec_get_name(par.codec_id)).to_string_lossy()
+                        ));
+                    }
+                }
+                Some("copy") => ost.stream_copy = true,
+                Some(codec_name) => {
+                    ost.enc = find_codec_or_die(codec_name, par.codec_type, true)?;
+                    par.codec_id = (*ost.enc).id;
+                }
+            }
+            ost.encoding_needed = !ost.stream_copy;
+        }
+        _ => {
+            // no encoding supported for other media types
+            ost.stream_copy = true;
+            ost.encoding_needed = false;
+        }
+    }
+    Ok(())
+}
+
+/// `av_parse_ratio()`, `None` if `s` is not a ratio.
+unsafe fn parse_ratio(s: &str, max: i32) -> Option<ffi::AVRational> {
+    let s_ = CString::new(s).ok()?;
+    let mut q = ffi::AVRational { num: 0, den: 0 };
+    if ffi::av_parse_ratio(&mut q, s_.as_ptr(), max, 0, ptr::null_mut()) < 0 {
+        None
+    } else {
+        Some(q)
+    }
+}
+
+/// The simple filtergraph of an encoded stream, from `-filter` or
+/// `-filter_script`, or the `default` pass-through filter.
+fn get_ost_filters(
+    ost: &OutputStream,
+    default: &str,
+    filters: Option<&str>,
+    filters_script: Option<&str>,
+) -> Result<String, String> {
+    match (filters, filters_script) {
+        (Some(_), Some(_)) => Err(format!(
+            "Both -filter and -filter_script set for output stream #{}:{}.",
+            ost.file_index, ost.index
+        )),
+        (None, Some(script)) => {
+            fs::read_to_string(script).map_err(|e| format!("Error opening file {}: {}.", script, e))
+        }
+        (Some(filters), None) => Ok(filters.to_owned()),
+        (None, None) => Ok(default.to_owned()),
+    }
+}
+
+/// Filters cannot be applied to a stream copy.
+fn check_streamcopy_filters(
+    ost: &OutputStream,
+    media_type: &str,
+    filters: Option<&str>,
+    filters_script: Option<&str>,
+) -> Result<(), String> {
+    let (kind, filtergraph) = match (filters, filters_script) {
+        (Some(filters), _) => ("Filtergraph", filters),
+        (None, Some(script)) => ("Filtergraph script", script),
+        (None, None) => return Ok(()),
+    };
+    Err(format!(
+        "{} '{}' was defined for {} output stream {}:{} but codec copy was selected.\n\
+         Filtering and streamcopy cannot be used together.",
+        kind, filtergraph, media_type, ost.file_index, ost.index
+    ))
+}
+
+unsafe fn new_video_stream(
+    session: &mut Session,
+    o: &OptionsContext,
+    oc: *mut ffi::AVFormatContext,
+    source_index: Option<usize>,
+) -> Result<usize, String> {
+    let idx = new_output_stream(
+        session,
+        o,
+        oc,
+        ffi::AVMediaType_AVMEDIA_TYPE_VIDEO,
+        source_index,
+    )?;
+    let ost = &mut session.output_streams[idx];
+    let st = ost.st.as_mut().unwrap();
+    let video_enc = ost.enc_ctx.as_mut().unwrap();
+    let streams = StreamInfo::from_format_context(oc);
+    let index = ost.index;
+    let opt = |opts| match_per_stream_opt(opts, &streams, index);
+
+    if let Some(frame_rate) = opt(&o.frame_rates).and_then(|u| u.as_str()) {
+        let frame_rate_s = CString::new(frame_rate).map_err(|e| e.to_string())?;
+        if ffi::av_parse_video_rate(&mut ost.frame_rate, frame_rate_s.as_ptr()) < 0 {
+            return Err(format!("Invalid framerate value: {}", frame_rate));
+        }
+        if session.video_sync_method == ffmpeg::VSYNC_PASSTHROUGH {
+            error!("Using -vsync 0 and -r can produce invalid output files");
+        }
+    }
+
+    if let Some(aspect) = opt(&o.frame_aspect_ratios).and_then(|u| u.as_str()) {
+        match parse_ratio(aspect, 255) {
+            Some(q) if q.num > 0 && q.den > 0 => ost.frame_aspect_ratio = q,
+            _ => return Err(format!("Invalid aspect ratio: {}", aspect)),
+        }
+    }
+
+    let filters_script = opt(&o.filter_scripts).and_then(|u| u.as_str());
+    let filters = opt(&o.filters).and_then(|u| u.as_str());
+    if let (Some(filters), true) = (filters, o.filters.len() > 1) {
+        error!(
+            "Only '-vf {}' read, ignoring remaining -vf options: Use ',' to separate filters",
+            filters
+        );
+    }
+
+    if !ost.stream_copy {
+        if let Some(frame_size) = opt(&o.frame_sizes).and_then(|u| u.as_str()) {
+            let frame_size_s = CString::new(frame_size).map_err(|e| e.to_string())?;
+            if ffi::av_parse_video_size(
+                &mut video_enc.width,
+                &mut video_enc.height,
+                frame_size_s.as_ptr(),
+            ) < 0
+            {
+                return Err(format!("Invalid frame size: {}.", frame_size));
+            }
+        }
+
+        video_enc.bits_per_raw_sample = session.frame_bits_per_raw_sample as i32;
+        if let Some(frame_pix_fmt) = opt(&o.frame_pix_fmts).and_then(|u| u.as_str()) {
+            let frame_pix_fmt = match frame_pix_fmt.strip_prefix('+') {
+                Some(frame_pix_fmt) => {
+                    ost.keep_pix_fmt = true;
+                    frame_pix_fmt
+                }
+                None => frame_pix_fmt,
+            };
+            if !frame_pix_fmt.is_empty() {
+                let frame_pix_fmt_s = CString::new(frame_pix_fmt).map_err(|e| e.to_string())?;
+                video_enc.pix_fmt = ffi::av_get_pix_fmt(frame_pix_fmt_s.as_ptr());
+                if video_enc.pix_fmt == ffi::AVPixelFormat_AV_PIX_FMT_NONE {
+                    return Err(format!(
+                        "Unknown pixel format requested: {}.",
+                        frame_pix_fmt
+                    ));
+                }
+            }
+        }
+        st.sample_aspect_ratio = video_enc.sample_aspect_ratio;
+
+        if session.intra_only != 0 {
+            video_enc.gop_size = 0;
+        }
+
+        if let Some(forced_key_frames) = opt(&o.forced_key_frames).and_then(|u| u.as_str()) {
+            ost.forced_keyframes = forced_key_frames.to_owned();
+        }
+        if let Some(force_fps) = opt(&o.force_fps).and_then(|u| u.as_int()) {
+            ost.force_fps = force_fps;
+        }
+        if let Some(top_field_first) = opt(&o.top_field_first).and_then(|u| u.as_int()) {
+            ost.top_field_first = top_field_first;
+        }
+
+        ost.avfilter = get_ost_filters(ost, "null", filters, filters_script)?;
+    } else {
+        if let Some(copyinkf) = opt(&o.copy_initial_nonkeyframes).and_then(|u| u.as_int()) {
+            ost.copy_initial_nonkeyframes = copyinkf;
+        }
+        check_streamcopy_filters(ost, "video", filters, filters_script)?;
+    }
+    Ok(idx)
+}
+
+unsafe fn new_audio_stream(
+    session: &mut Session,
+    o: &OptionsContext,
+    oc: *mut ffi::AVFormatContext,
+    source_index: Option<usize>,
+) -> Result<usize, String> {
+    let idx = new_output_stream(
+        session,
+        o,
+        oc,
+        ffi::AVMediaType_AVMEDIA_TYPE_AUDIO,
+        source_index,
+    )?;
+    let ost = &mut session.output_streams[idx];
+    let audio_enc = ost.enc_ctx.as_mut().unwrap();
+    let streams = StreamInfo::from_format_context(oc);
+    let index = ost.index;
+    let opt = |opts| match_per_stream_opt(opts, &streams, index);
+
+    let filters_script = opt(&o.filter_scripts).and_then(|u| u.as_str());
+    let filters = opt(&o.filters).and_then(|u| u.as_str());
+
+    if !ost.stream_copy {
+        if let Some(channels) = opt(&o.audio_channels).and_then(|u| u.as_int()) {
+            audio_enc.channels = channels as i32;
+        }
+
+        if let Some(sample_fmt) = opt(&o.sample_fmts).and_then(|u| u.as_str()) {
+            let sample_fmt_s = CString::new(sample_fmt).map_err(|e| e.to_string())?;
+            audio_enc.sample_fmt = ffi::av_get_sample_fmt(sample_fmt_s.as_ptr());
+            if audio_enc.sample_fmt == ffi::AVSampleFormat_AV_SAMPLE_FMT_NONE {
+                return Err(format!("Invalid sample format '{}'", sample_fmt));
+            }
+        }
+
+        if let Some(sample_rate) = opt(&o.audio_sample_rate).and_then(|u| u.as_int()) {
+            audio_enc.sample_rate = sample_rate as i32;
+        }
+
+        if let Some(apad) = opt(&o.apad).and_then(|u| u.as_str()) {
+            ost.apad = apad.to_owned();
+        }
+
+        ost.avfilter = get_ost_filters(ost, "anull", filters, filters_script)?;
+
+        // check for channel mapping for this audio stream
+        for map in &o.audio_channel_maps {
+            if (map.ofile_idx != -1 && map.ofile_idx != ost.file_index as isize)
+                || (map.ostream_idx != -1 && map.ostream_idx != ost.index as isize)
+            {
+                continue;
+            }
+            let ist = match (map.channel_idx, ost.source_index) {
+                (-1, _) => None,
+                (_, Some(i)) => Some(&session.input_streams[i]),
+                (_, None) => {
+                    error!(
+                        "Cannot determine input stream for channel mapping {}.{}",
+                        ost.file_index, ost.index
+                    );
+                    continue;
+                }
+            };
+            let mapped = ist.map_or(true, |ist| {
+                ist.file_index as isize == map.file_idx
+                    && (*ist.st).index as isize == map.stream_idx
+            });
+            if mapped {
+                ost.audio_channels_map.push(map.channel_idx);
+            }
+        }
+    } else {
+        check_streamcopy_filters(ost, "audio", filters, filters_script)?;
+    }
+    Ok(idx)
+}
+
+unsafe fn new_subtitle_stream(
+    session: &mut Session,
+    o: &OptionsContext,
+    oc: *mut ffi::AVFormatContext,
+    source_index: Option<usize>,
+) -> Result<usize, String> {
+    let idx = new_output_stream(
+        session,
+        o,
+        oc,
+        ffi::AVMediaType_AVMEDIA_TYPE_SUBTITLE,
+        source_index,
+    )?;
+    let ost = &mut session.output_streams[idx];
+    let subtitle_enc = ost.enc_ctx.as_mut().unwrap();
+    let streams = StreamInfo::from_format_context(oc);
+
+    if !ost.stream_copy {
+        if let Some(frame_size) =
+            match_per_stream_opt(&o.frame_sizes, &streams, ost.index).and_then(|u| u.as_str())
+        {
+            let frame_size_s = CString::new(frame_size).map_err(|e| e.to_string())?;
+            if ffi::av_parse_video_size(
+                &mut subtitle_enc.width,
+                &mut subtitle_enc.height,
+                frame_size_s.as_ptr(),
+            ) < 0
+            {
+                return Err(format!("Invalid frame size: {}.", frame_size));
+            }
+        }
+    }
+    Ok(idx)
+}
+
+fn init_complex_filters(session: &mut Session) -> Result<(), String> {
+    for i in 0..session.filtergraphs.len() {
+        unsafe { ffmpeg_filter::init_complex_filtergraph(session, i) }?;
+    }
+    Ok(())
+}
+
+/// Every output of the `-filter_complex` graphs must feed an output stream.
+fn check_filter_outputs(session: &Session) -> Result<(), String> {
+    for fg in &session.filtergraphs {
+        if let Some(output) = fg.outputs.iter().find(|output| output.ost.is_none()) {
+            return Err(format!("Filter {} has an unconnected output", output.name));
+        }
+    }
+    Ok(())
+}
+
+pub fn show_usage() {
+    info!("Hyper fast Audio and Video encoder");
+    info!(
+        "usage: ffcli [options] [[infile options] -i infile]... {{[outfile options] outfile}}..."
+    );
+    info!("");
+}
+
+/// The sections of the option listing of `-h`, in order.
+pub fn help_sections() -> Vec<HelpSection> {
+    // per-file options have at least one of those set
+    let per_file = OptionFlag::OPT_SPEC | OptionFlag::OPT_OFFSET | OptionFlag::OPT_PERFILE;
+    let none = OptionFlag::NONE;
+    let avs = OptionFlag::OPT_AUDIO | OptionFlag::OPT_VIDEO | OptionFlag::OPT_SUBTITLE;
+    let section = |title, req_flags, rej_flags, alt_flags, advanced| HelpSection {
+        title,
+        req_flags,
+        rej_flags,
+        alt_flags,
+        advanced,
+    };
+    vec![
+        section(
+            "Print help / information / capabilities",
+            OptionFlag::OPT_EXIT,
+            none,
+            none,
+            false,
+        ),
+        section(
+            "Global options (affect whole program instead of just one file)",
+            none,
+            per_file | OptionFlag::OPT_EXIT | OptionFlag::OPT_EXPERT,
+            none,
+            false,
+        ),
+        section(
+            "Advanced global options",
+            OptionFlag::OPT_EXPERT,
+            per_file | OptionFlag::OPT_EXIT,
+            none,
+            true,
+        ),
+        section(
+            "Per-file main options",
+            none,
+            OptionFlag::OPT_EXPERT | avs | OptionFlag::OPT_EXIT,
+            per_file,
+            false,
+        ),
+        section(
+            "Advanced per-file options",
+            OptionFlag::OPT_EXPERT,
+            avs,
+            per_file,
+            true,
+        ),
+        section(
+            "Video options",
+            OptionFlag::OPT_VIDEO,
+            OptionFlag::OPT_EXPERT | OptionFlag::OPT_AUDIO,
+            none,
+            false,
+        ),
+        section(
+            "Advanced Video options",
+            OptionFlag::OPT_EXPERT | OptionFlag::OPT_VIDEO,
+            OptionFlag::OPT_AUDIO,
+            none,
+            true,
+        ),
+        section(
+            "Audio options",
+            OptionFlag::OPT_AUDIO,
+            OptionFlag::OPT_EXPERT | OptionFlag::OPT_VIDEO,
+            none,
+            false,
+        ),
+        section(
+            "Advanced Audio options",
+            OptionFlag::OPT_EXPERT | OptionFlag::OPT_AUDIO,
+            OptionFlag::OPT_VIDEO,
+            none,
+            true,
+        ),
+        section(
+            "Subtitle options",
+            OptionFlag::OPT_SUBTITLE,
+            none,
+            none,
+            false,
+        ),
+    ]
+}
+
+/// The option listing of `-h`, with the advanced options for `-h long` and
+/// every AVOption too for `-h full`.
+fn help_default(show_advanced: bool, show_avoptions: bool) -> String {
+    let mut out = String::from(concat!(
+        "Getting help:\n",
+        "    -h      -- print basic options\n",
+        "    -h long -- print more options\n",
+        "    -h full -- print all options (including all format and codec specific options, very long)\n",
+        "    -h type=name -- print all options for the named decoder/encoder/demuxer/muxer/filter/bsf/protocol\n",
+        "    See man ffcli for detailed description of the options.\n",
+        "\n",
+    ));
+    for section in help_sections()
+        .iter()
+        .filter(|section| show_advanced || !section.advanced)
+    {
+        out += &cmdutils::help_options(&*OPTIONS, section);
+    }
+    out.push('\n');
+
+    if show_avoptions {
+        let codec_flags = ffi::AV_OPT_FLAG_DECODING_PARAM | ffi::AV_OPT_FLAG_ENCODING_PARAM;
+        let filter_flags = ffi::AV_OPT_FLAG_VIDEO_PARAM | ffi::AV_OPT_FLAG_AUDIO_PARAM;
+        unsafe {
+            out += &cmdutils::class_help(ffi::avcodec_get_class(), codec_flags);
+            out += &cmdutils::class_help(ffi::avformat_get_class(), codec_flags);
+            out += &cmdutils::class_help(ffi::sws_get_class(), codec_flags);
+            out += &cmdutils::class_help(ffi::swr_get_class(), ffi::AV_OPT_FLAG_AUDIO_PARAM);
+            out += &cmdutils::class_help(
+                ffi::avfilter_get_class(),
+                filter_flags | ffi::AV_OPT_FLAG_FILTERING_PARAM,
+            );
+            out += &cmdutils::class_help(
+                ffi::av_bsf_get_class(),
+                filter_flags | ffi::AV_OPT_FLAG_BSF_PARAM,
+            );
+        }
+    }
+    out
+}
+
+/// `-h`, `-h long`, `-h full` and `-h` with an unknown topic.
+pub fn show_help_default(topic: &str) {
+    let (show_advanced, show_avoptions) = match topic {
+        "" => (false, false),
+        "long" => (true, false),
+        "full" => (true, true),
+        _ => {
+            error!("Unknown help option '{}'.", topic);
+            (false, false)
+        }
+    };
+    show_usage();
+    print!("{}", help_default(show_advanced, show_avoptions));
+}
+
+/// Expand the `@file` arguments and split the command line into the global
+/// options and the per-file option groups, nothing is applied yet.
+pub fn split_options(args: &[OsString]) -> Result<OptionParseContext<'static>, ParseError> {
+    let (args, locations) = match expand_argfiles(args) {
+        Ok(expanded) => expanded,
+        Err(e) => {
+            error!("Error expanding the argument files: {}", e);
+            return Err(e);
+        }
+    };
+
+    // IMPROVEMENT move `init_parse_context(octx, groups)` out of split_commandline() and inline it.
+    let mut octx = OptionParseContext {
+        groups: (&*GROUPS)
+            .iter()
+            .map(|group| OptionGroupList {
+                group_def: group,
+                groups: vec![],
+            })
+            .collect(),
+        global_opts: OptionGroup::new_global(),
+        cur_group: OptionGroup::new_anonymous(),
+        locations,
+    };
+
+    if let Err(e) = split_commandline(&mut octx, &args, &*OPTIONS, &*GROUPS) {
+        match e
+            .index()
+            .and_then(|i| octx.locations.get(i).cloned().flatten())
+        {
+            Some(location) => error!("Error splitting the argument list: {}: {}", location, e),
+            None => error!("Error splitting the argument list: {}", e),
+        }
+        return Err(e);
+    }
+    Ok(octx)
+}
+
+/// Split the command line and apply the global options, the returned context
+/// contains the per-file option groups.
+pub fn parse_global_options(
+    session: &mut Session,
+    args: &[OsString],
+) -> Result<OptionParseContext<'static>, ParseError> {
+    let octx = split_options(args)?;
+    if let Err(e) = parse_optgroup(session, None, &octx.global_opts) {
+        error!("Error parsing global options: {}", e);
+        return Err(e);
+    }
+    Ok(octx)
+}
+
+/// Parse the command line, open the input files and configure the output
+/// files into `session`.
+pub fn ffmpeg_parse_options(
+    session: &mut Session,
+    args: &[OsString],
+) -> Result<OptionParseContext<'static>, ParseError> {
+    let mut octx = parse_global_options(session, args)?;
+
+    // setup signal handlers
+    ffmpeg::term_init();
+
+    open_files(
+        session,
+        &mut octx.groups[OptGroup::GroupInFile as usize],
+        "input",
+        open_input_file,
+    )?;
+
+    if let Err(e) = init_complex_filters(session) {
+        error!("{}", e);
+        error!("Error initializing complex filters.");
+        return Err(ParseError::FilterGraph { error: e });
+    }
+
+    open_files(
+        session,
+        &mut octx.groups[OptGroup::GroupOutFile as usize],
+        "output",
+        open_output_file,
+    )?;
+
+    if let Err(e) = check_filter_outputs(session) {
+        error!("{}", e);
+        return Err(ParseError::FilterGraph { error: e });
+    }
+    Ok(octx)
+}
+
+#[cfg(test)]
+mod ffmpeg_opt_tests {
+    use super::*;
+    use crate::ffmpeg_filter::FilterGraph;
+
+    fn input(types: &[MediaType]) -> InputFile {
+        InputFile {
+            filename: "in.mkv".into(),
+            streams: types
+                .iter()
+                .enumerate()
+                .map(|(index, &media_type)| StreamInfo {
+                    index,
+                    media_type: Some(media_type),
+                    usable: true,
+                    channels: if media_type == MediaType::Audio { 2 } else { 0 },
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn inputs() -> Vec<InputFile> {
+        use MediaType::*;
+        vec![
+            input(&[Video, Audio, Audio, Subtitle]),
+            input(&[Audio, Video]),
+        ]
+    }
+
+    fn map(file_index: isize, stream_index: isize) -> StreamMap {
+        StreamMap {
+            file_index,
+            stream_index,
+            sync_file_index: file_index,
+            sync_stream_index: stream_index,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn help_tiers() {
+        let basic = help_default(false, false);
+        assert!(
+            basic.contains("\nGlobal options (affect whole program instead of just one file):\n")
+        );
+        assert!(basic.contains("\n-y                  overwrite output files\n"));
+        assert!(!basic.contains("Advanced global options:"));
+        assert!(!basic.contains("AVOptions:"));
+
+        let long = help_default(true, false);
+        assert!(long.contains("Advanced global options:"));
+        assert!(!long.contains("AVOptions:"));
+        assert!(help_default(true, true).contains("AVCodecContext AVOptions:"));
+    }
+
+    #[test]
+    fn map_streams() {
+        let inputs = inputs();
+        let mut maps = vec![];
+        parse_stream_map(&inputs, &mut maps, "0:v:0").unwrap();
+        parse_stream_map(&inputs, &mut maps, "0:a").unwrap();
+        parse_stream_map(&inputs, &mut maps, "1:s?").unwrap();
+        parse_stream_map(&inputs, &mut maps, "0").unwrap();
+        parse_stream_map(&inputs, &mut maps, "-0:s").unwrap();
+        assert_eq!(
+            maps,
+            vec![
+                map(0, 0),
+                map(0, 1),
+                map(0, 2),
+                map(0, 0),
+                map(0, 1),
+                map(0, 2),
+                StreamMap {
+                    disabled: 1,
+                    ..map(0, 3)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn map_sync_and_label() {
+        let inputs = inputs();
+        let mut maps = vec![];
+        parse_stream_map(&inputs, &mut maps, "0:1,1:v").unwrap();
+        parse_stream_map(&inputs, &mut maps, "[out]").unwrap();
+        assert_eq!(
+            maps,
+            vec![
+                StreamMap {
+                    sync_file_index: 1,
+                    sync_stream_index: 1,
+                    ..map(0, 1)
+                },
+                StreamMap {
+                    linklabel: "out".to_owned(),
+                    ..Default::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn map_errors() {
+        let inputs = inputs();
+        let mut maps = vec![];
+        let err = |arg| parse_stream_map(&inputs, &mut vec![], arg).unwrap_err();
+        assert_eq!(err("2:v"), "Invalid input file index: 2.");
+        assert_eq!(err("0:1,3"), "Invalid sync file index: 3.");
+        assert!(err("0:v:1")
+            .contains("stream index 1 is out of range, input file #0 has 1 such streams"));
+        assert!(
+            err("1:9").contains("stream index 9 is out of range, input file #1 has 2 such streams")
+        );
+        assert!(err("0:1,1:s").starts_with("Sync stream specification"));
+        assert!(err("[out").starts_with("Invalid output link label"));
+        assert!(err("0:x").contains("unexpected"));
+        assert!(err("-0:v").contains("matches no streams"));
+        assert!(parse_stream_map(&inputs, &mut maps, "1:s?").is_ok());
+        assert!(maps.is_empty());
+    }
+
+    #[test]
+    fn map_channels() {
+        let inputs = inputs();
+        let mut maps = vec![];
+        parse_audio_channel_map(&inputs, &mut maps, "0.1.1").unwrap();
+        parse_audio_channel_map(&inputs, &mut maps, "-1:0.1").unwrap();
+        parse_audio_channel_map(&inputs, &mut maps, "1.0.0:0.2").unwrap();
+        parse_audio_channel_map(&inputs, &mut maps, "0.2.5?").unwrap();
+        let map = |file_idx, stream_idx, channel_idx, ofile_idx, ostream_idx| AudioChannelMap {
+            file_idx,
+            stream_idx,
+            channel_idx,
+            ofile_idx,
+            ostream_idx,
+        };
+        assert_eq!(
+            maps,
+            vec![
+                map(0, 1, 1, -1, -1),
+                map(-1, -1, -1, 0, 1),
+                map(1, 0, 0, 0, 2)
+            ]
+        );
+
+        let err = |arg| parse_audio_channel_map(&inputs, &mut vec![], arg).unwrap_err();
+        assert!(err("0.1").starts_with("Syntax error"));
+        assert!(err("0.1.0:1").starts_with("Syntax error"));
+        assert_eq!(err("2.0.0"), "mapchan: invalid input file index: 2");
+        assert_eq!(
+            err("0.4.0"),
+            "mapchan: invalid input file stream index #0.4"
+        );
+        assert_eq!(err("0.0.0"), "mapchan: stream #0.0 is not an audio stream.");
+        assert!(err("0.1.2").starts_with("mapchan: invalid audio channel #0.1.2, the stream has 2"));
+    }
+
+    #[test]
+    fn select_default_streams() {
+        let stream = |media_type, width, height, channels| StreamInfo {
+            media_type: Some(media_type),
+            width,
+            height,
+            channels,
+            codec_info_nb_frames: 1,
+            ..Default::default()
+        };
+        use MediaType::*;
+        let mut streams = vec![
+            stream(Audio, 0, 0, 2),
+            stream(Video, 640, 480, 0),
+            stream(Subtitle, 0, 0, 0),
+            stream(Video, 1920, 1080, 0),
+            stream(Audio, 0, 0, 6),
+            stream(Subtitle, 0, 0, 0),
+        ];
+        let select = |streams: &[StreamInfo], media_type, apic, skip: usize| {
+            let streams: Vec<_> = streams.iter().collect();
+            select_best_stream(&streams, media_type, apic, |i| i != skip)
+        };
+        assert_eq!(select(&streams, Video, false, 9), Some(3));
+        assert_eq!(select(&streams, Audio, false, 9), Some(4));
+        assert_eq!(select(&streams, Subtitle, false, 9), Some(2));
+        assert_eq!(select(&streams, Subtitle, false, 2), Some(5));
+        assert_eq!(select(&streams, Data, false, 9), None);
+
+        // Discarded streams are never picked.
+        assert_eq!(select(&streams, Video, false, 3), Some(1));
+
+        // Streams with decoded frames and default streams are preferred.
+        streams[4].codec_info_nb_frames = 0;
+        assert_eq!(select(&streams, Audio, false, 9), Some(0));
+        streams[4].codec_info_nb_frames = 1;
+        streams[0].disposition = DISPOSITION_DEFAULT;
+        assert_eq!(select(&streams, Audio, false, 9), Some(0));
+
+        // Attached pictures only win when the muxer wants them.
+        streams[3].disposition = DISPOSITION_ATTACHED_PIC;
+        assert_eq!(select(&streams, Video, false, 9), Some(1));
+        assert_eq!(select(&streams, Video, true, 9), Some(3));
+    }
+
+    #[test]
+    fn metadata_specifiers() {
+        assert_eq!(parse_meta_type(""), Ok(MetaType::Global));
+        assert_eq!(parse_meta_type("g"), Ok(MetaType::Global));
+        assert_eq!(parse_meta_type("s"), Ok(MetaType::Stream("")));
+        assert_eq!(parse_meta_type("s:a:1"), Ok(MetaType::Stream("a:1")));
+        assert_eq!(parse_meta_type("c:2"), Ok(MetaType::Chapter(2)));
+        assert_eq!(parse_meta_type("p"), Ok(MetaType::Program(0)));
+        assert_eq!(parse_meta_type("p:0x10"), Ok(MetaType::Program(16)));
+        assert!(parse_meta_type("sa").is_err());
+        assert!(parse_meta_type("x").is_err());
+    }
+
+    #[test]
+    fn program_strings() {
+        assert_eq!(
+            get_token(b"  a\\:b :c", b":"),
+            (b"a:b".to_vec(), &b":c"[..])
+        );
+        assert_eq!(get_token(b"'a:b ' ", b":"), (b"a:b ".to_vec(), &b""[..]));
+        let keys = program_keys(b"title=My title:program_num=0x10:st=1:st");
+        assert_eq!(
+            keys,
+            [
+                (b"title".to_vec(), Some(b"My title".to_vec())),
+                (b"program_num".to_vec(), Some(b"0x10".to_vec())),
+                (b"st".to_vec(), Some(b"1".to_vec())),
+                (b"st".to_vec(), None),
+            ]
+        );
+        assert_eq!(parse_int(b"0x10"), 16);
+        assert_eq!(parse_int(b"x"), 0);
+    }
+
+    #[test]
+    fn codec_tags() {
+        assert_eq!(parse_codec_tag("0x31637661"), 0x31637661);
+        assert_eq!(parse_codec_tag("avc1"), u32::from_le_bytes(*b"avc1"));
+        assert_eq!(parse_codec_tag("mp"), u32::from_le_bytes(*b"mp\0\0"));
+    }
+
+    #[test]
+    fn unconnected_filter_outputs() {
+        let mut session = Session::default();
+        let mut fg = FilterGraph::new(0, "split[out]");
+        fg.outputs.push(OutputFilter::new(
+            ffi::AVMediaType_AVMEDIA_TYPE_VIDEO,
+            "split:output1".to_owned(),
+            "out".to_owned(),
+        ));
+        session.filtergraphs.push(fg);
+        assert_eq!(
+            check_filter_outputs(&session),
+            Err("Filter split:output1 has an unconnected output".to_owned())
+        );
+        session.filtergraphs[0].outputs[0].ost = Some(0);
+        assert_eq!(check_filter_outputs(&session), Ok(()));
+    }
+
+    #[test]
+    fn open_lavfi_input_and_output() {
+        unsafe { ffi::avdevice_register_all() };
+        let args: Vec<OsString> = [
+            "ffcli",
+            "-itsoffset",
+            "2",
+            "-itsscale",
+            "0.5",
+            "-stream_loop",
+            "1",
+            "-re",
+            "-thread_queue_size",
+            "16",
+            "-discard:v",
+            "nokey",
+            "-c:v",
+            "rawvideo",
+            "-f",
+            "lavfi",
+            "-i",
+            "testsrc=duration=1:size=64x48:rate=10",
+            "-f",
+            "null",
+            "-",
+        ]
+        .iter()
+        .map(OsString::from)
+        .collect();
+        let mut session = Session::default();
+        ffmpeg_parse_options(&mut session, &args).unwrap();
+
+        assert_eq!(session.input_files.len(), 1);
+        let ifile = &session.input_files[0];
+        assert_eq!(ifile.input_ts_offset, 2 * ffi::AV_TIME_BASE as i64);
+        assert_eq!(ifile.loops, 1);
+        assert_eq!(ifile.rate_emu, 1);
+        assert_eq!(ifile.thread_queue_size, 16);
+        assert_eq!(ifile.streams.len(), 1);
+
+        assert_eq!(session.input_streams.len(), 1);
+        let ist = &session.input_streams[0];
+        assert_eq!(ist.ts_scale, 0.5);
+        assert_eq!(ist.user_set_discard, ffi::AVDiscard_AVDISCARD_NONKEY);
+        assert_eq!(
+            unsafe { CStr::from_ptr((*ist.dec).name) }.to_bytes(),
+            b"rawvideo"
+        );
+
+        assert_eq!(session.output_files.len(), 1);
+        assert_eq!(session.output_streams.len(), 1);
+        assert_eq!(session.output_streams[0].source_index, Some(0));
+        unsafe { ffmpeg::ffmpeg_cleanup(&mut session) };
+    }
+
+    #[test]
+    fn input_file_errors() {
+        unsafe { ffi::avdevice_register_all() };
+        let parse = |input_opts: &[&str]| {
+            let mut args = vec!["ffcli"];
+            args.extend(input_opts);
+            args.extend(&["-f", "lavfi", "-i", "testsrc=duration=1", "-f", "null", "-"]);
+            let args: Vec<OsString> = args.iter().map(OsString::from).collect();
+            let mut session = Session::default();
+            let result = ffmpeg_parse_options(&mut session, &args).map(|_| ());
+            // The streams of the file are dropped with it.
+            assert!(session.input_files.is_empty());
+            assert!(session.input_streams.is_empty());
+            match result {
+                Err(ParseError::OpenFile { error, .. }) => error,
+                other => panic!("{:?}", other),
+            }
+        };
+        assert_eq!(
+            parse(&["-c:v", "nonexistent"]),
+            "Unknown decoder 'nonexistent'"
+        );
+        assert_eq!(parse(&["-r", "fast"]), "Error parsing framerate fast.");
+    }
+
+    #[test]
+    fn map_discarded_stream() {
+        unsafe { ffi::avdevice_register_all() };
+        let args: Vec<OsString> = [
+            "ffcli",
+            "-discard:v",
+            "all",
+            "-f",
+            "lavfi",
+            "-i",
+            "testsrc=duration=1:size=64x48:rate=10",
+            "-map",
+            "0:v",
+            "-f",
+            "null",
+            "-",
+        ]
+        .iter()
+        .map(OsString::from)
+        .collect();
+        let mut session = Session::default();
+        match ffmpeg_parse_options(&mut session, &args) {
+            Err(ParseError::OpenFile { error, .. }) => {
+                assert_eq!(error, "Stream #0:0 is disabled and cannot be mapped.")
+            }
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+        unsafe { ffmpeg::ffmpeg_cleanup(&mut session) };
+    }
+
+    #[test]
+    fn programs_and_metadata_maps() {
+        unsafe { ffi::avdevice_register_all() };
+        let parse = |output_opts: &[&str]| {
+            let mut args = vec!["ffcli", "-f", "lavfi", "-i", "testsrc=duration=1"];
+            args.extend(output_opts);
+            args.extend(&["-f", "null", "-"]);
+            let args: Vec<OsString> = args.iter().map(OsString::from).collect();
+            let mut session = Session::default();
+            let result = ffmpeg_parse_options(&mut session, &args).map(|_| ());
+            (session, result)
+        };
+
+        let (mut session, result) = parse(&[
+            "-program",
+            "title=first:st=0",
+            "-program",
+            "program_num=7:title=second",
+            "-metadata:p:1",
+            "service_name=test",
+        ]);
+        result.unwrap();
+        unsafe {
+            let oc = &*session.output_files[0].ctx;
+            let programs = slice::from_raw_parts(oc.programs, oc.nb_programs as usize);
+            assert_eq!(programs.len(), 2);
+            let get = |program: *mut ffi::AVProgram, key: &[u8]| {
+                let key = CString::new(key).unwrap();
+                let e = ffi::av_dict_get((*program).metadata, key.as_ptr(), ptr::null(), 0);
+                CStr::from_ptr(e.as_ref().unwrap().value)
+                    .to_bytes()
+                    .to_vec()
+            };
+            assert_eq!((*programs[0]).id, 1);
+            assert_eq!((*programs[0]).nb_stream_indexes, 1);
+            assert_eq!(get(programs[0], b"title"), b"first");
+            assert_eq!((*programs[1]).id, 7);
+            assert_eq!((*programs[1]).nb_stream_indexes, 0);
+            assert_eq!(get(programs[1], b"title"), b"second");
+            assert_eq!(get(programs[1], b"service_name"), b"test");
+            ffmpeg::ffmpeg_cleanup(&mut session);
+        }
+
+        let (mut session, result) = parse(&["-program", "title=x:sd=0"]);
+        match result {
+            Err(ParseError::OpenFile { error, .. }) => assert_eq!(error, "Unknown program key sd."),
+            other => panic!("{:?}", other),
+        }
+        unsafe { ffmpeg::ffmpeg_cleanup(&mut session) };
+
+        let (mut session, result) = parse(&["-map_metadata", "0:p:0"]);
+        match result {
+            Err(ParseError::OpenFile { error, .. }) => assert_eq!(
+                error,
+                "Invalid program index 0 while processing metadata maps."
+            ),
+            other => panic!("{:?}", other),
+        }
+        unsafe { ffmpeg::ffmpeg_cleanup(&mut session) };
+
+        let (mut session, result) = parse(&["-map_chapters", "1"]);
+        match result {
+            Err(ParseError::OpenFile { error, .. }) => {
+                assert_eq!(error, "Invalid input file index 1 in chapter mapping.")
+            }
+            other => panic!("{:?}", other),
+        }
+        unsafe { ffmpeg::ffmpeg_cleanup(&mut session) };
+    }
 }
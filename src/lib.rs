@@ -0,0 +1,197 @@
+//! FFmpeg command line arguments parser.
+//!
+//! [`parse`] splits an ffmpeg-style command line into the global options and
+//! the options of each input and output file, e.g.
+//!
+//! ```no_run
+//! let args = ["ffcli", "-i", "input.mp4", "-c:v", "libx264", "output.mp4"];
+//! let command = ffcli::parse(&args).unwrap();
+//! assert_eq!(command.inputs[0].arg, "input.mp4");
+//! assert_eq!(command.outputs[0].opts[0].key, "c:v");
+//! ```
+
+// For the half open range in match in `split_commandline()`'s AVOption part
+#![feature(exclusive_range_pattern)]
+#![feature(half_open_range_patterns)]
+mod cmdutils;
+mod completions;
+mod dict;
+mod ffmpeg;
+mod ffmpeg_filter;
+mod ffmpeg_opt;
+mod json;
+mod options;
+mod stream_specifier;
+
+pub use crate::{
+    cmdutils::{ArgLocation, NumberError, ParseError, TimeError},
+    dict::{DictFlag, Dictionary},
+    ffmpeg::ffmpeg,
+    stream_specifier::{MediaType, StreamInfo, StreamSelector, StreamSpecifier},
+};
+
+use crate::cmdutils::{OptionGroup, OptionKV};
+
+use std::ffi::{OsStr, OsString};
+
+/// An option recognized by ffcli itself.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedOption {
+    /// Name of the option in the option table, e.g. `c` for `-c:v` and
+    /// `stdin` for `-nostdin`.
+    pub name: String,
+    /// The option as given without the leading `-`, e.g. `c:v` or `nostdin`.
+    pub key: String,
+    /// The argument as given, `"1"` or `"0"` for a boolean flag without one.
+    pub value: OsString,
+    /// Index of the option in the arguments, after the `@file` expansion.
+    pub index: usize,
+    /// Where the option is, if it's from an `@file`.
+    pub location: Option<ArgLocation>,
+}
+
+impl From<OptionKV<'static>> for ParsedOption {
+    fn from(o: OptionKV<'static>) -> Self {
+        ParsedOption {
+            name: o.opt.name.to_owned(),
+            key: o.key,
+            value: o.val,
+            index: o.index,
+            location: o.location,
+        }
+    }
+}
+
+/// The options of one input file, one output file or the global options.
+#[derive(Debug, Clone)]
+pub struct ParsedGroup {
+    /// Argument of the group delimiting option, e.g. the filename, as given.
+    /// Empty for the global options.
+    pub arg: OsString,
+    /// Options recognized by ffcli itself, in command line order.
+    pub opts: Vec<ParsedOption>,
+    /// AVOptions belonging to `AVCodecContext` or codec private classes.
+    pub codec_opts: Dictionary,
+    /// AVOptions belonging to `AVFormatContext` or format private classes.
+    pub format_opts: Dictionary,
+    /// AVOptions belonging to the `SwsContext` of the scale filters.
+    pub sws_dict: Dictionary,
+    /// AVOptions belonging to the `SwrContext` of the aresample filters.
+    pub swr_opts: Dictionary,
+    /// AVOptions belonging to the libavresample `AVAudioResampleContext`.
+    pub resample_opts: Dictionary,
+}
+
+impl From<OptionGroup<'static>> for ParsedGroup {
+    fn from(g: OptionGroup<'static>) -> Self {
+        ParsedGroup {
+            arg: g.arg,
+            opts: g.opts.into_iter().map(ParsedOption::from).collect(),
+            codec_opts: g.codec_opts,
+            format_opts: g.format_opts,
+            sws_dict: g.sws_dict,
+            swr_opts: g.swr_opts,
+            resample_opts: g.resample_opts,
+        }
+    }
+}
+
+/// Result of [`parse`].
+#[derive(Debug, Clone)]
+pub struct ParsedCommand {
+    pub global: ParsedGroup,
+    pub inputs: Vec<ParsedGroup>,
+    pub outputs: Vec<ParsedGroup>,
+}
+
+/// Parse an ffmpeg command line, `args[0]` is the program name. The arguments
+/// can be `String`s, or `OsString`s from [`std::env::args_os`] for filenames
+/// which aren't UTF-8.
+///
+/// This only splits the command line and checks the option values, nothing
+/// is applied: the global options with process wide effects, e.g.
+/// `-loglevel`, `-report`, `-timelimit` or `-h`, are returned in
+/// [`ParsedCommand::global`] without being run. Files are not opened.
+///
+/// An `@file` argument is replaced by the arguments read from the file,
+/// the [`ParsedOption::location`] of those options tells where they come from.
+/// A leading `@@` stands for a literal `@`, e.g. `-i @@in.mp4`.
+pub fn parse(args: &[impl AsRef<OsStr>]) -> Result<ParsedCommand, ParseError> {
+    let args: Vec<OsString> = args.iter().map(|arg| arg.as_ref().to_owned()).collect();
+    let mut octx = ffmpeg_opt::split_options(&args)?;
+    cmdutils::check_optgroup(&octx.global_opts)?;
+    let mut take = |i: usize| -> Vec<ParsedGroup> {
+        octx.groups[i]
+            .groups
+            .drain(..)
+            .map(ParsedGroup::from)
+            .collect()
+    };
+    let outputs = take(ffmpeg_opt::OptGroup::GroupOutFile as usize);
+    let inputs = take(ffmpeg_opt::OptGroup::GroupInFile as usize);
+    Ok(ParsedCommand {
+        global: ParsedGroup::from(octx.global_opts),
+        inputs,
+        outputs,
+    })
+}
+
+#[cfg(test)]
+mod lib_tests {
+    use super::*;
+    use rusty_ffmpeg::ffi;
+
+    #[test]
+    fn global_callbacks_are_not_run() {
+        let level = unsafe { ffi::av_log_get_level() };
+        let command =
+            parse(&["ffcli", "-loglevel", "quiet", "-h", "-y", "-i", "in", "out"]).unwrap();
+        assert_eq!(unsafe { ffi::av_log_get_level() }, level);
+        let keys: Vec<_> = command.global.opts.iter().map(|o| o.key.as_str()).collect();
+        assert_eq!(keys, ["loglevel", "h", "y"]);
+    }
+
+    #[test]
+    fn groups_are_owned_data() {
+        let command = parse(&[
+            "ffcli",
+            "-nostdin",
+            "-i",
+            "in",
+            "-c:v",
+            "libx264",
+            "-sws_flags",
+            "lanczos",
+            "-dither_method",
+            "triangular",
+            "out",
+        ])
+        .unwrap();
+        assert_eq!(
+            command.global.opts,
+            [ParsedOption {
+                name: "stdin".to_owned(),
+                key: "nostdin".to_owned(),
+                value: "0".into(),
+                index: 1,
+                location: None,
+            }]
+        );
+        let output = &command.outputs[0];
+        assert_eq!(output.opts[0].name, "c");
+        assert_eq!(output.opts[0].key, "c:v");
+        assert_eq!(output.opts[0].value, "libx264");
+        assert_eq!(output.sws_dict.get("sws_flags"), Some("lanczos"));
+        assert_eq!(output.swr_opts.get("dither_method"), Some("triangular"));
+    }
+
+    #[test]
+    fn global_values_are_checked() {
+        let err = parse(&["ffcli", "-vstats_version", "two", "-i", "in", "out"]).unwrap_err();
+        assert!(
+            matches!(err, ParseError::InvalidNumber { index: 1, .. }),
+            "{:?}",
+            err
+        );
+    }
+}
@@ -1,29 +1,31 @@
-// This is need for those global values in FFmpeg
-#![allow(non_upper_case_globals)]
 // This will be finally removed, but in development stage it's useful
 #![allow(unused_variables)]
-use libc::{c_char, c_void};
+use log::{debug, error, info, warn};
 use memoffset::offset_of;
 use once_cell::sync::Lazy;
+use rusty_ffmpeg::{avutil::error::*, ffi};
+
+use std::{ffi::CString, fs};
 
 use crate::{
     cmdutils::{
-        OptionDef, OptionFlag, OptionGroup, OptionGroupDef, OptionGroupList, OptionKV,
-        OptionOperation, OptionParseContext,
+        self, Listing, OptionDef, OptionFlag, OptionGroup, OptionGroupDef, OptionGroupList,
+        OptionKV, OptionOperation, OptionParseContext, SpecifierOpt, SpecifierOptValue,
+    },
+    completions,
+    dict::DictFlag,
+    ffmpeg::{
+        self, OptionsContext, Session, ABORT_ON_FLAG_EMPTY_OUTPUT, ABORT_ON_FLAG_EMPTY_OUTPUT_STREAM, VSYNC_AUTO,
+        VSYNC_CFR, VSYNC_DROP, VSYNC_PASSTHROUGH, VSYNC_VFR,
     },
-    ffmpeg::OptionsContext,
+    ffmpeg_filter::FilterGraph,
+    ffmpeg_opt::{self, parse_audio_channel_map, parse_stream_map},
 };
 
-macro_rules! void {
-    ($x: expr) => {
-        unsafe { &raw mut $x as *mut c_void }
-    };
-}
-
 macro_rules! option_operation {
-    (dst_ptr => $operation: expr) => {
+    (session => $operation: ident) => {
         OptionOperation {
-            dst_ptr: void!($operation),
+            off: offset_of!(Session, $operation),
         }
     };
     (func_arg => $operation: expr) => {
@@ -40,10 +42,10 @@ macro_rules! option_operation {
 
 macro_rules! option_def {
     // It looks stupid but matching with `$flag: ident $(| $flags: ident)*` early is for disambiguity.
-    ($name: literal, $flag: ident $(| $flags: ident)*, dst_ptr => $operation: expr, $help: literal) => {
+    ($name: literal, $flag: ident $(| $flags: ident)*, session => $operation: ident, $help: literal) => {
         option_def! (
             @inner $name, $flag $(| $flags)*,
-            option_operation!(dst_ptr => $operation),
+            option_operation!(session => $operation),
             $help, None
         )
     };
@@ -61,10 +63,10 @@ macro_rules! option_def {
             $help, None
         )
     };
-    ($name: literal, $flag: ident $(| $flags: ident)*, dst_ptr => $operation: expr, $help: literal, $argname: literal) => {
+    ($name: literal, $flag: ident $(| $flags: ident)*, session => $operation: ident, $help: literal, $argname: literal) => {
         option_def! (
             @inner $name, $flag $(| $flags)*,
-            option_operation!(dst_ptr => $operation),
+            option_operation!(session => $operation),
             $help, Some($argname)
         )
     };
@@ -125,7 +127,7 @@ pub static GROUPS: Lazy<[OptionGroupDef; 2]> = Lazy::new(|| {
 /// 1. remove all other codes except the `options`
 /// 2. remove unnecessary lines like comments and empty line and `#ifdef #ifndef #endif` things
 /// 3. `\option_def!("` => `option_def!("`
-/// 4. `, *\{ *&` => `, dst_ptr => `
+/// 4. `, *\{ *&` => `, session => `
 /// 5. `, *\{ .off *= OFFSET\(` => `, off => `
 /// 6. `, *\{ .func_arg = ` => `, func_arg => `
 /// 7. `\},\n*    option_def!\(` => `),\n    option_def!(`
@@ -135,7 +137,7 @@ pub static GROUPS: Lazy<[OptionGroupDef; 2]> = Lazy::new(|| {
 /// 11. `"\n *"` => `| `
 /// 12. then hand tweak inharmonious codes
 /// 13. `,? \),` => `),`
-pub static OPTIONS: Lazy<[OptionDef; 179]> = Lazy::new(|| {
+pub static OPTIONS: Lazy<[OptionDef; 182]> = Lazy::new(|| {
     [
         // Common options
         option_def!("L",            OPT_EXIT,               func_arg => show_license,     "show license"),
@@ -159,20 +161,23 @@ pub static OPTIONS: Lazy<[OptionDef; 179]> = Lazy::new(|| {
         option_def!("layouts",      OPT_EXIT,               func_arg => show_layouts,     "show standard channel layouts"),
         option_def!("sample_fmts",  OPT_EXIT,               func_arg => show_sample_fmts, "show available audio sample formats"),
         option_def!("colors",       OPT_EXIT,               func_arg => show_colors,      "show available color names"),
+        option_def!("print_format", HAS_ARG | OPT_EXPERT,   func_arg => opt_print_format, "set the output format of the listing options (default or json)", "format"),
+        option_def!("generate_completions", OPT_EXIT | HAS_ARG, func_arg => opt_generate_completions, "generate a shell completion script (bash, zsh or fish)", "shell"),
+        option_def!("generate_manpage", OPT_EXIT,           func_arg => opt_generate_manpage, "generate a roff man page"),
         option_def!("loglevel",     HAS_ARG,                func_arg => opt_loglevel,     "set logging level", "loglevel"),
         option_def!("v",            HAS_ARG,                func_arg => opt_loglevel,     "set logging level", "loglevel"),
         option_def!("report",       NONE,                   func_arg => opt_report,       "generate a report"),
         option_def!("max_alloc",    HAS_ARG,                func_arg => opt_max_alloc,    "set maximum size of a single allocated block",   "bytes"),
         option_def!("cpuflags",     HAS_ARG | OPT_EXPERT,   func_arg => opt_cpuflags,       "force specific cpu flags",         "flags"),
-        option_def!("hide_banner",  OPT_BOOL | OPT_EXPERT,  dst_ptr => hide_banner,         "do not show program banner",       "hide_banner"),
+        option_def!("hide_banner",  OPT_BOOL | OPT_EXPERT,  session => hide_banner,         "do not show program banner",       "hide_banner"),
         option_def!("sources",      OPT_EXIT | HAS_ARG,     func_arg => show_sources,       "list sources of the input device", "device"),
         option_def!("sinks",        OPT_EXIT | HAS_ARG,     func_arg => show_sinks,         "list sinks of the output device",  "device"),
         // FFmpeg main options
         option_def!("f", HAS_ARG | OPT_STRING | OPT_OFFSET | OPT_INPUT | OPT_OUTPUT, off => format, "force format", "fmt"),
-        option_def!("y", OPT_BOOL, dst_ptr => file_overwrite, "overwrite output files"),
-        option_def!("n", OPT_BOOL, dst_ptr => no_file_overwrite, "never overwrite output files"),
-        option_def!("ignore_unknown", OPT_BOOL, dst_ptr => ignore_unknown_streams, "Ignore unknown stream types"),
-        option_def!("copy_unknown", OPT_BOOL | OPT_EXPERT, dst_ptr => copy_unknown_streams, "Copy unknown stream types"),
+        option_def!("y", OPT_BOOL, session => file_overwrite, "overwrite output files"),
+        option_def!("n", OPT_BOOL, session => no_file_overwrite, "never overwrite output files"),
+        option_def!("ignore_unknown", OPT_BOOL, session => ignore_unknown_streams, "Ignore unknown stream types"),
+        option_def!("copy_unknown", OPT_BOOL | OPT_EXPERT, session => copy_unknown_streams, "Copy unknown stream types"),
         option_def!("c", HAS_ARG | OPT_STRING | OPT_SPEC | OPT_INPUT | OPT_OUTPUT, off => codec_names, "codec name", "codec"),
         option_def!("codec", HAS_ARG | OPT_STRING | OPT_SPEC | OPT_INPUT | OPT_OUTPUT, off => codec_names, "codec name", "codec"),
         option_def!("pre", HAS_ARG | OPT_STRING | OPT_SPEC | OPT_OUTPUT, off => presets, "preset name", "preset"),
@@ -193,28 +198,28 @@ pub static OPTIONS: Lazy<[OptionDef; 179]> = Lazy::new(|| {
         option_def!("metadata", HAS_ARG | OPT_STRING | OPT_SPEC | OPT_OUTPUT, off => metadata, "add metadata", "string=string"),
         option_def!("program", HAS_ARG | OPT_STRING | OPT_SPEC | OPT_OUTPUT, off => program, "add program with specified streams", "title=string:st=number..."),
         option_def!("dframes", HAS_ARG | OPT_PERFILE | OPT_EXPERT | OPT_OUTPUT, func_arg => opt_data_frames, "set the number of data frames to output", "number"),
-        option_def!("benchmark", OPT_BOOL | OPT_EXPERT, dst_ptr => do_benchmark, "add timings for benchmarking"),
-        option_def!("benchmark_all", OPT_BOOL | OPT_EXPERT, dst_ptr => do_benchmark_all, "add timings for each task"),
-        option_def!("progress", HAS_ARG | OPT_EXPERT, func_arg => opt_progress, "write program-readable progress information", "url"),
-        option_def!("stdin", OPT_BOOL | OPT_EXPERT, dst_ptr => stdin_interaction, "enable or disable interaction on standard input"),
+        option_def!("benchmark", OPT_BOOL | OPT_EXPERT, session => do_benchmark, "add timings for benchmarking"),
+        option_def!("benchmark_all", OPT_BOOL | OPT_EXPERT, session => do_benchmark_all, "add timings for each task"),
+        option_def!("progress", HAS_ARG | OPT_EXPERT, func_arg => opt_unsupported, "write program-readable progress information", "url"),
+        option_def!("stdin", OPT_BOOL | OPT_EXPERT, session => stdin_interaction, "enable or disable interaction on standard input"),
         option_def!("timelimit", HAS_ARG | OPT_EXPERT, func_arg => opt_timelimit, "set max runtime in seconds in CPU user time", "limit"),
-        option_def!("dump", OPT_BOOL | OPT_EXPERT, dst_ptr => do_pkt_dump, "dump each input packet"),
-        option_def!("hex", OPT_BOOL | OPT_EXPERT, dst_ptr => do_hex_dump, "when dumping packets, also dump the payload"),
+        option_def!("dump", OPT_BOOL | OPT_EXPERT, session => do_pkt_dump, "dump each input packet"),
+        option_def!("hex", OPT_BOOL | OPT_EXPERT, session => do_hex_dump, "when dumping packets, also dump the payload"),
         option_def!("re", OPT_BOOL | OPT_EXPERT | OPT_OFFSET | OPT_INPUT, off => rate_emu, "read input at native frame rate", ""),
         option_def!("target", HAS_ARG | OPT_PERFILE | OPT_OUTPUT, func_arg => opt_target, "specify target file type (\"vcd\", \"svcd\", \"dvd\", \"dv\" or \"dv50\" | with optional prefixes \"pal-\", \"ntsc-\" or \"film-\")", "type"),
         option_def!("vsync", HAS_ARG | OPT_EXPERT, func_arg => opt_vsync, "video sync method", ""),
-        option_def!("frame_drop_threshold", HAS_ARG | OPT_FLOAT | OPT_EXPERT, dst_ptr => frame_drop_threshold, "frame drop threshold", ""),
-        option_def!("async", HAS_ARG | OPT_INT | OPT_EXPERT, dst_ptr => audio_sync_method, "audio sync method", ""),
-        option_def!("adrift_threshold", HAS_ARG | OPT_FLOAT | OPT_EXPERT, dst_ptr => audio_drift_threshold, "audio drift threshold", "threshold"),
-        option_def!("copyts", OPT_BOOL | OPT_EXPERT, dst_ptr => copy_ts, "copy timestamps"),
-        option_def!("start_at_zero", OPT_BOOL | OPT_EXPERT, dst_ptr => start_at_zero, "shift input timestamps to start at 0 when using copyts"),
-        option_def!("copytb", HAS_ARG | OPT_INT | OPT_EXPERT, dst_ptr => copy_tb, "copy input stream time base when stream copying", "mode"),
+        option_def!("frame_drop_threshold", HAS_ARG | OPT_FLOAT | OPT_EXPERT, session => frame_drop_threshold, "frame drop threshold", ""),
+        option_def!("async", HAS_ARG | OPT_INT | OPT_EXPERT, session => audio_sync_method, "audio sync method", ""),
+        option_def!("adrift_threshold", HAS_ARG | OPT_FLOAT | OPT_EXPERT, session => audio_drift_threshold, "audio drift threshold", "threshold"),
+        option_def!("copyts", OPT_BOOL | OPT_EXPERT, session => copy_ts, "copy timestamps"),
+        option_def!("start_at_zero", OPT_BOOL | OPT_EXPERT, session => start_at_zero, "shift input timestamps to start at 0 when using copyts"),
+        option_def!("copytb", HAS_ARG | OPT_INT | OPT_EXPERT, session => copy_tb, "copy input stream time base when stream copying", "mode"),
         option_def!("shortest", OPT_BOOL | OPT_EXPERT | OPT_OFFSET | OPT_OUTPUT, off => shortest, "finish encoding within shortest input"),
         option_def!("bitexact", OPT_BOOL | OPT_EXPERT | OPT_OFFSET | OPT_OUTPUT | OPT_INPUT, off => bitexact, "bitexact mode"),
         option_def!("apad", OPT_STRING | HAS_ARG | OPT_SPEC | OPT_OUTPUT, off => apad, "audio pad", ""),
-        option_def!("dts_delta_threshold", HAS_ARG | OPT_FLOAT | OPT_EXPERT, dst_ptr => dts_delta_threshold, "timestamp discontinuity delta threshold", "threshold"),
-        option_def!("dts_error_threshold", HAS_ARG | OPT_FLOAT | OPT_EXPERT, dst_ptr => dts_error_threshold, "timestamp error delta threshold", "threshold"),
-        option_def!("xerror", OPT_BOOL | OPT_EXPERT, dst_ptr => exit_on_error, "exit on error", "error"),
+        option_def!("dts_delta_threshold", HAS_ARG | OPT_FLOAT | OPT_EXPERT, session => dts_delta_threshold, "timestamp discontinuity delta threshold", "threshold"),
+        option_def!("dts_error_threshold", HAS_ARG | OPT_FLOAT | OPT_EXPERT, session => dts_error_threshold, "timestamp error delta threshold", "threshold"),
+        option_def!("xerror", OPT_BOOL | OPT_EXPERT, session => exit_on_error, "exit on error", "error"),
         option_def!("abort_on", HAS_ARG | OPT_EXPERT, func_arg => opt_abort_on, "abort on the specified condition flags", "flags"),
         option_def!("copyinkf", OPT_BOOL | OPT_EXPERT | OPT_SPEC | OPT_OUTPUT, off => copy_initial_nonkeyframes, "copy initial non-keyframes"),
         option_def!("copypriorss", OPT_INT | HAS_ARG | OPT_EXPERT | OPT_SPEC | OPT_OUTPUT, off => copy_prior_start, "copy or discard frames before start time"),
@@ -224,30 +229,30 @@ pub static OPTIONS: Lazy<[OptionDef; 179]> = Lazy::new(|| {
         option_def!("qscale", HAS_ARG | OPT_EXPERT | OPT_PERFILE | OPT_OUTPUT, func_arg => opt_qscale, "use fixed quality scale (VBR)", "q"),
         option_def!("profile", HAS_ARG | OPT_EXPERT | OPT_PERFILE | OPT_OUTPUT, func_arg => opt_profile, "set profile", "profile"),
         option_def!("filter", HAS_ARG | OPT_STRING | OPT_SPEC | OPT_OUTPUT, off => filters, "set stream filtergraph", "filter_graph"),
-        option_def!("filter_threads", HAS_ARG | OPT_INT, dst_ptr => filter_nbthreads, "number of non-complex filter threads"),
+        option_def!("filter_threads", HAS_ARG | OPT_INT, session => filter_nbthreads, "number of non-complex filter threads"),
         option_def!("filter_script", HAS_ARG | OPT_STRING | OPT_SPEC | OPT_OUTPUT, off => filter_scripts, "read stream filtergraph description from a file", "filename"),
         option_def!("reinit_filter", HAS_ARG | OPT_INT | OPT_SPEC | OPT_INPUT, off => reinit_filters, "reinit filtergraph on input parameter changes", ""),
         option_def!("filter_complex", HAS_ARG | OPT_EXPERT, func_arg => opt_filter_complex, "create a complex filtergraph", "graph_description"),
-        option_def!("filter_complex_threads", HAS_ARG | OPT_INT, dst_ptr => filter_complex_nbthreads, "number of threads for -filter_complex"),
+        option_def!("filter_complex_threads", HAS_ARG | OPT_INT, session => filter_complex_nbthreads, "number of threads for -filter_complex"),
         option_def!("lavfi", HAS_ARG | OPT_EXPERT, func_arg => opt_filter_complex, "create a complex filtergraph", "graph_description"),
         option_def!("filter_complex_script", HAS_ARG | OPT_EXPERT, func_arg => opt_filter_complex_script, "read complex filtergraph description from a file", "filename"),
-        option_def!("stats", OPT_BOOL, dst_ptr => print_stats, "print progress report during encoding"),
+        option_def!("stats", OPT_BOOL, session => print_stats, "print progress report during encoding"),
         option_def!("attach", HAS_ARG | OPT_PERFILE | OPT_EXPERT | OPT_OUTPUT, func_arg => opt_attach, "add an attachment to the output file", "filename"),
         option_def!("dump_attachment", HAS_ARG | OPT_STRING | OPT_SPEC | OPT_EXPERT | OPT_INPUT, off => dump_attachment, "extract an attachment into a file", "filename"),
         option_def!("stream_loop", OPT_INT | HAS_ARG | OPT_EXPERT | OPT_INPUT | OPT_OFFSET, off => loops, "set number of times input stream shall be looped", "loop count"),
-        option_def!("debug_ts", OPT_BOOL | OPT_EXPERT, dst_ptr => debug_ts, "print timestamp debugging info"),
-        option_def!("max_error_rate", HAS_ARG | OPT_FLOAT, dst_ptr => max_error_rate, "ratio of errors (0.0: no errors, 1.0: 100% errors) above which ffmpeg returns an error instead of success.", "maximum error rate"),
+        option_def!("debug_ts", OPT_BOOL | OPT_EXPERT, session => debug_ts, "print timestamp debugging info"),
+        option_def!("max_error_rate", HAS_ARG | OPT_FLOAT, session => max_error_rate, "ratio of errors (0.0: no errors, 1.0: 100% errors) above which ffmpeg returns an error instead of success.", "maximum error rate"),
         option_def!("discard", OPT_STRING | HAS_ARG | OPT_SPEC | OPT_INPUT, off => discard, "discard", ""),
         option_def!("disposition", OPT_STRING | HAS_ARG | OPT_SPEC | OPT_OUTPUT, off => disposition, "disposition", ""),
         option_def!("thread_queue_size", HAS_ARG | OPT_INT | OPT_OFFSET | OPT_EXPERT | OPT_INPUT, off => thread_queue_size, "set the maximum number of queued packets from the demuxer"),
-        option_def!("find_stream_info", OPT_BOOL | OPT_PERFILE | OPT_INPUT | OPT_EXPERT, dst_ptr => find_stream_info, "read and decode the streams to fill missing information with heuristics"),
+        option_def!("find_stream_info", OPT_BOOL | OPT_PERFILE | OPT_INPUT | OPT_EXPERT, session => find_stream_info, "read and decode the streams to fill missing information with heuristics"),
         option_def!("vframes", OPT_VIDEO | HAS_ARG  | OPT_PERFILE | OPT_OUTPUT, func_arg => opt_video_frames, "set the number of video frames to output", "number"),
         option_def!("r", OPT_VIDEO | HAS_ARG  | OPT_STRING | OPT_SPEC | OPT_INPUT | OPT_OUTPUT, off => frame_rates, "set frame rate (Hz value, fraction or abbreviation)", "rate"),
         option_def!("s", OPT_VIDEO | HAS_ARG | OPT_SUBTITLE | OPT_STRING | OPT_SPEC | OPT_INPUT | OPT_OUTPUT, off => frame_sizes, "set frame size (WxH or abbreviation)", "size"),
         option_def!("aspect", OPT_VIDEO | HAS_ARG  | OPT_STRING | OPT_SPEC | OPT_OUTPUT, off => frame_aspect_ratios, "set aspect ratio (4:3, 16:9 or 1.3333, 1.7777)", "aspect"),
         option_def!("pix_fmt", OPT_VIDEO | HAS_ARG | OPT_EXPERT  | OPT_STRING | OPT_SPEC | OPT_INPUT | OPT_OUTPUT, off => frame_pix_fmts, "set pixel format", "format"),
-        option_def!("bits_per_raw_sample", OPT_VIDEO | OPT_INT | HAS_ARG, dst_ptr => frame_bits_per_raw_sample, "set the number of bits per raw sample", "number"),
-        option_def!("intra", OPT_VIDEO | OPT_BOOL | OPT_EXPERT, dst_ptr => intra_only, "deprecated use -g 1"),
+        option_def!("bits_per_raw_sample", OPT_VIDEO | OPT_INT | HAS_ARG, session => frame_bits_per_raw_sample, "set the number of bits per raw sample", "number"),
+        option_def!("intra", OPT_VIDEO | OPT_BOOL | OPT_EXPERT, session => intra_only, "deprecated use -g 1"),
         option_def!("vn", OPT_VIDEO | OPT_BOOL  | OPT_OFFSET | OPT_INPUT | OPT_OUTPUT, off => video_disable, "disable video"),
         option_def!("rc_override", OPT_VIDEO | HAS_ARG | OPT_EXPERT  | OPT_STRING | OPT_SPEC | OPT_OUTPUT, off => rc_overrides, "rate control override for specific intervals", "override"),
         option_def!("vcodec", OPT_VIDEO | HAS_ARG  | OPT_PERFILE | OPT_INPUT | OPT_OUTPUT, func_arg => opt_video_codec, "force video codec ('copy' to copy stream)", "codec"),
@@ -256,18 +261,18 @@ pub static OPTIONS: Lazy<[OptionDef; 179]> = Lazy::new(|| {
         option_def!("timecode", OPT_VIDEO | HAS_ARG | OPT_PERFILE | OPT_OUTPUT, func_arg => opt_timecode, "set initial TimeCode value.", "hh:mm:ss[:;.]ff"),
         option_def!("pass", OPT_VIDEO | HAS_ARG | OPT_SPEC | OPT_INT | OPT_OUTPUT, off => pass, "select the pass number (1 to 3)", "n"),
         option_def!("passlogfile", OPT_VIDEO | HAS_ARG | OPT_STRING | OPT_EXPERT | OPT_SPEC | OPT_OUTPUT, off => passlogfiles, "select two pass log file name prefix", "prefix"),
-        option_def!("deinterlace", OPT_VIDEO | OPT_BOOL | OPT_EXPERT, dst_ptr => do_deinterlace, "this option is deprecated, use the yadif filter instead"),
-        option_def!("psnr", OPT_VIDEO | OPT_BOOL | OPT_EXPERT, dst_ptr => do_psnr, "calculate PSNR of compressed frames"),
-        option_def!("vstats", OPT_VIDEO | OPT_EXPERT , func_arg => opt_vstats, "dump video coding statistics to file"),
-        option_def!("vstats_file", OPT_VIDEO | HAS_ARG | OPT_EXPERT , func_arg => opt_vstats_file, "dump video coding statistics to file", "file"),
-        option_def!("vstats_version", OPT_VIDEO | OPT_INT | HAS_ARG | OPT_EXPERT , dst_ptr => vstats_version, "Version of the vstats format to use."),
+        option_def!("deinterlace", OPT_VIDEO | OPT_BOOL | OPT_EXPERT, session => do_deinterlace, "this option is deprecated, use the yadif filter instead"),
+        option_def!("psnr", OPT_VIDEO | OPT_BOOL | OPT_EXPERT, session => do_psnr, "calculate PSNR of compressed frames"),
+        option_def!("vstats", OPT_VIDEO | OPT_EXPERT , func_arg => opt_unsupported, "dump video coding statistics to file"),
+        option_def!("vstats_file", OPT_VIDEO | HAS_ARG | OPT_EXPERT , func_arg => opt_unsupported, "dump video coding statistics to file", "file"),
+        option_def!("vstats_version", OPT_VIDEO | OPT_INT | HAS_ARG | OPT_EXPERT , session => vstats_version, "Version of the vstats format to use."),
         option_def!("vf", OPT_VIDEO | HAS_ARG  | OPT_PERFILE | OPT_OUTPUT, func_arg => opt_video_filters, "set video filters", "filter_graph"),
         option_def!("intra_matrix", OPT_VIDEO | HAS_ARG | OPT_EXPERT  | OPT_STRING | OPT_SPEC | OPT_OUTPUT, off => intra_matrices, "specify intra matrix coeffs", "matrix"),
         option_def!("inter_matrix", OPT_VIDEO | HAS_ARG | OPT_EXPERT  | OPT_STRING | OPT_SPEC | OPT_OUTPUT, off => inter_matrices, "specify inter matrix coeffs", "matrix"),
         option_def!("chroma_intra_matrix", OPT_VIDEO | HAS_ARG | OPT_EXPERT  | OPT_STRING | OPT_SPEC | OPT_OUTPUT, off => chroma_intra_matrices, "specify intra matrix coeffs", "matrix"),
         option_def!("top", OPT_VIDEO | HAS_ARG | OPT_EXPERT  | OPT_INT| OPT_SPEC | OPT_INPUT | OPT_OUTPUT, off => top_field_first, "top=1/bottom=0/auto=-1 field first", ""),
         option_def!("vtag", OPT_VIDEO | HAS_ARG | OPT_EXPERT  | OPT_PERFILE | OPT_INPUT | OPT_OUTPUT, func_arg => opt_old2new, "force video tag/fourcc", "fourcc/tag"),
-        option_def!("qphist", OPT_VIDEO | OPT_BOOL | OPT_EXPERT , dst_ptr => qp_hist, "show QP histogram"),
+        option_def!("qphist", OPT_VIDEO | OPT_BOOL | OPT_EXPERT , session => qp_hist, "show QP histogram"),
         option_def!("force_fps", OPT_VIDEO | OPT_BOOL | OPT_EXPERT  | OPT_SPEC | OPT_OUTPUT, off => force_fps, "force the selected framerate, disable the best supported framerate selection"),
         option_def!("streamid", OPT_VIDEO | HAS_ARG | OPT_EXPERT | OPT_PERFILE | OPT_OUTPUT, func_arg => opt_streamid, "set the value of an outfile streamid", "streamIndex:value"),
         option_def!("force_key_frames", OPT_VIDEO | OPT_STRING | HAS_ARG | OPT_EXPERT | OPT_SPEC | OPT_OUTPUT, off => forced_key_frames, "force key frames at specified timestamps", "timestamps"),
@@ -276,7 +281,7 @@ pub static OPTIONS: Lazy<[OptionDef; 179]> = Lazy::new(|| {
         option_def!("hwaccel", OPT_VIDEO | OPT_STRING | HAS_ARG | OPT_EXPERT | OPT_SPEC | OPT_INPUT, off => hwaccels, "use HW accelerated decoding", "hwaccel name"),
         option_def!("hwaccel_device", OPT_VIDEO | OPT_STRING | HAS_ARG | OPT_EXPERT | OPT_SPEC | OPT_INPUT, off => hwaccel_devices, "select a device for HW acceleration", "devicename"),
         option_def!("hwaccel_output_format", OPT_VIDEO | OPT_STRING | HAS_ARG | OPT_EXPERT | OPT_SPEC | OPT_INPUT, off => hwaccel_output_formats, "select output format used with HW accelerated decoding", "format"),
-        option_def!("videotoolbox_pixfmt", HAS_ARG | OPT_STRING | OPT_EXPERT, dst_ptr => videotoolbox_pixfmt, ""),
+        option_def!("videotoolbox_pixfmt", HAS_ARG | OPT_STRING | OPT_EXPERT, session => videotoolbox_pixfmt, ""),
         option_def!("hwaccels", OPT_EXIT, func_arg => show_hwaccels, "show available HW acceleration methods"),
         option_def!("autorotate", HAS_ARG | OPT_BOOL | OPT_SPEC | OPT_EXPERT | OPT_INPUT, off => autorotate, "automatically insert correct rotate filters"),
         option_def!("aframes", OPT_AUDIO | HAS_ARG  | OPT_PERFILE | OPT_OUTPUT, func_arg => opt_audio_frames, "set the number of audio frames to output", "number"),
@@ -286,7 +291,7 @@ pub static OPTIONS: Lazy<[OptionDef; 179]> = Lazy::new(|| {
         option_def!("an", OPT_AUDIO | OPT_BOOL | OPT_OFFSET | OPT_INPUT | OPT_OUTPUT, off => audio_disable, "disable audio"),
         option_def!("acodec", OPT_AUDIO | HAS_ARG  | OPT_PERFILE | OPT_INPUT | OPT_OUTPUT, func_arg => opt_audio_codec, "force audio codec ('copy' to copy stream)", "codec"),
         option_def!("atag", OPT_AUDIO | HAS_ARG  | OPT_EXPERT | OPT_PERFILE | OPT_OUTPUT, func_arg => opt_old2new, "force audio tag/fourcc", "fourcc/tag"),
-        option_def!("vol", OPT_AUDIO | HAS_ARG  | OPT_INT, dst_ptr => audio_volume, "change audio volume (256=normal)" , "volume"),
+        option_def!("vol", OPT_AUDIO | HAS_ARG  | OPT_INT, session => audio_volume, "change audio volume (256=normal)" , "volume"),
         option_def!("sample_fmt", OPT_AUDIO | HAS_ARG  | OPT_EXPERT | OPT_SPEC | OPT_STRING | OPT_INPUT | OPT_OUTPUT, off => sample_fmts, "set sample format", "format"),
         option_def!("channel_layout", OPT_AUDIO | HAS_ARG  | OPT_EXPERT | OPT_PERFILE | OPT_INPUT | OPT_OUTPUT, func_arg => opt_channel_layout, "set channel layout", "layout"),
         option_def!("af", OPT_AUDIO | HAS_ARG  | OPT_PERFILE | OPT_OUTPUT, func_arg => opt_audio_filters, "set audio filters", "filter_graph"),
@@ -296,9 +301,9 @@ pub static OPTIONS: Lazy<[OptionDef; 179]> = Lazy::new(|| {
         option_def!("stag", OPT_SUBTITLE | HAS_ARG  | OPT_EXPERT  | OPT_PERFILE | OPT_OUTPUT, func_arg => opt_old2new, "force subtitle tag/fourcc", "fourcc/tag"),
         option_def!("fix_sub_duration", OPT_BOOL | OPT_EXPERT | OPT_SUBTITLE | OPT_SPEC | OPT_INPUT, off => fix_sub_duration, "fix subtitles duration"),
         option_def!("canvas_size", OPT_SUBTITLE | HAS_ARG | OPT_STRING | OPT_SPEC | OPT_INPUT, off => canvas_sizes, "set canvas size (WxH or abbreviation)", "size"),
-        option_def!("vc", HAS_ARG | OPT_EXPERT | OPT_VIDEO, func_arg => opt_video_channel, "deprecated, use -channel", "channel"),
-        option_def!("tvstd", HAS_ARG | OPT_EXPERT | OPT_VIDEO, func_arg => opt_video_standard, "deprecated, use -standard", "standard"),
-        option_def!("isync", OPT_BOOL | OPT_EXPERT, dst_ptr => input_sync, "this option is deprecated and does nothing", ""),
+        option_def!("vc", HAS_ARG | OPT_EXPERT | OPT_VIDEO | OPT_PERFILE | OPT_INPUT, func_arg => opt_video_channel, "deprecated, use -channel", "channel"),
+        option_def!("tvstd", HAS_ARG | OPT_EXPERT | OPT_VIDEO | OPT_PERFILE | OPT_INPUT, func_arg => opt_video_standard, "deprecated, use -standard", "standard"),
+        option_def!("isync", OPT_BOOL | OPT_EXPERT, session => input_sync, "this option is deprecated and does nothing", ""),
         option_def!("muxdelay", OPT_FLOAT | HAS_ARG | OPT_EXPERT | OPT_OFFSET | OPT_OUTPUT, off => mux_max_delay, "set the maximum demux-decode delay", "seconds"),
         option_def!("muxpreload", OPT_FLOAT | HAS_ARG | OPT_EXPERT | OPT_OFFSET | OPT_OUTPUT, off => mux_preload, "set the initial demux-decode delay", "seconds"),
         option_def!("sdp_file", HAS_ARG | OPT_EXPERT | OPT_OUTPUT, func_arg => opt_sdp_file, "specify a file in which to print sdp information", "file"),
@@ -314,332 +319,742 @@ pub static OPTIONS: Lazy<[OptionDef; 179]> = Lazy::new(|| {
         option_def!("max_muxing_queue_size", HAS_ARG | OPT_INT | OPT_SPEC | OPT_EXPERT | OPT_OUTPUT, off => max_muxing_queue_size, "maximum number of packets that can be buffered while waiting for all streams to initialize", "packets"),
         option_def!("dcodec", HAS_ARG | OPT_DATA | OPT_PERFILE | OPT_EXPERT | OPT_INPUT | OPT_OUTPUT, func_arg => opt_data_codec, "force data codec ('copy' to copy stream)", "codec"),
         option_def!("dn", OPT_BOOL | OPT_VIDEO | OPT_OFFSET | OPT_INPUT | OPT_OUTPUT, off => data_disable, "disable data"),
-        option_def!("vaapi_device", HAS_ARG | OPT_EXPERT, func_arg => opt_vaapi_device, "set VAAPI hardware device (DRM path or X11 display name)", "device"),
-        option_def!("qsv_device", HAS_ARG | OPT_STRING | OPT_EXPERT, dst_ptr => qsv_device, "set QSV hardware device (DirectX adapter index, DRM path or X11 display name)", "device"),
-        option_def!("init_hw_device", HAS_ARG | OPT_EXPERT, func_arg => opt_init_hw_device, "initialise hardware device", "args"),
-        option_def!("filter_hw_device", HAS_ARG | OPT_EXPERT, func_arg => opt_filter_hw_device, "set hardware device used when filtering", "device"),
+        option_def!("vaapi_device", HAS_ARG | OPT_EXPERT, func_arg => opt_unsupported, "set VAAPI hardware device (DRM path or X11 display name)", "device"),
+        option_def!("qsv_device", HAS_ARG | OPT_STRING | OPT_EXPERT, session => qsv_device, "set QSV hardware device (DirectX adapter index, DRM path or X11 display name)", "device"),
+        option_def!("init_hw_device", HAS_ARG | OPT_EXPERT, func_arg => opt_unsupported, "initialise hardware device", "args"),
+        option_def!("filter_hw_device", HAS_ARG | OPT_EXPERT, func_arg => opt_unsupported, "set hardware device used when filtering", "device"),
     ]
 });
 
-// TODO need this be enum?
-const VSYNC_AUTO: isize = -1;
-
-// In ffmpeg.h as extern value, TODO extern it
-pub static mut videotoolbox_pixfmt: *mut c_char = std::ptr::null_mut();
-
-// In cmdutils.c
-pub static mut hide_banner: bool = false;
-
-// In ffmpeg_qsv.c
-pub static mut qsv_device: *mut c_char = std::ptr::null_mut();
-
-// In ffmpeg_opt.c
-pub static mut intra_only: isize = 0;
-pub static mut file_overwrite: isize = 0;
-pub static mut no_file_overwrite: isize = 0;
-pub static mut do_psnr: isize = 0;
-pub static mut input_sync: isize = 0;
-pub static mut input_stream_potentially_available: isize = 0;
-pub static mut ignore_unknown_streams: isize = 0;
-pub static mut copy_unknown_streams: isize = 0;
-pub static mut find_stream_info: isize = 1;
-
-pub static mut audio_drift_threshold: f32 = 0.1;
-pub static mut dts_delta_threshold: f32 = 10.;
-pub static mut dts_error_threshold: f32 = 3600. * 30.;
-
-pub static mut audio_volume: isize = 256;
-pub static mut audio_sync_method: isize = 0;
-pub static mut video_sync_method: isize = VSYNC_AUTO;
-pub static mut frame_drop_threshold: f32 = 0.;
-pub static mut do_deinterlace: isize = 0;
-pub static mut do_benchmark: isize = 0;
-pub static mut do_benchmark_all: isize = 0;
-pub static mut do_hex_dump: isize = 0;
-pub static mut do_pkt_dump: isize = 0;
-pub static mut copy_ts: isize = 0;
-pub static mut start_at_zero: isize = 0;
-pub static mut copy_tb: isize = -1;
-pub static mut debug_ts: isize = 0;
-pub static mut exit_on_error: isize = 0;
-pub static mut abort_on_flags: isize = 0;
-pub static mut print_stats: isize = -1;
-pub static mut qp_hist: isize = 0;
-pub static mut stdin_interaction: isize = 1;
-pub static mut frame_bits_per_raw_sample: isize = 0;
-pub static mut max_error_rate: f32 = 2. / 3.;
-pub static mut filter_nbthreads: isize = 0;
-pub static mut filter_complex_nbthreads: isize = 0;
-pub static mut vstats_version: isize = 2;
-
 // In cmdutils.c in random order
-fn show_license(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    print!(
-        "This is free software; you can redistribute it and/or\n
-    modify it under the terms of the GNU Lesser General Public\n
-    License as published by the Free Software Foundation; either\n
-    version 2.1 of the License, or (at your option) any later version.\n
-    \n
-    This is distributed in the hope that it will be useful,\n
-    but WITHOUT ANY WARRANTY; without even the implied warranty of\n
-    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU\n
-    Lesser General Public License for more details.\n
-    \n
-    You should have received a copy of the GNU Lesser General Public\n
-    License along with this program; if not, write to the Free Software\n
-    Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA\n"
-    );
+fn show_license(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    print!("{}", cmdutils::license_info());
     0
 }
 
-fn show_help(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    println!("<help message>");
+fn show_help(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    let mut topic = arg.splitn(2, '=');
+    let (topic, name) = (topic.next().unwrap(), topic.next());
+    let help = match topic {
+        "decoder" => cmdutils::codec_help(name, false),
+        "encoder" => cmdutils::codec_help(name, true),
+        "demuxer" => cmdutils::demuxer_help(name),
+        "muxer" => cmdutils::muxer_help(name),
+        "protocol" => cmdutils::protocol_help(name),
+        "filter" => cmdutils::filter_help(name),
+        "bsf" => cmdutils::bsf_help(name),
+        _ => {
+            ffmpeg_opt::show_help_default(topic);
+            return 0;
+        }
+    };
+    match help {
+        Ok(help) => print!("{}", help),
+        Err(e) => error!("{}", e),
+    }
     0
 }
 
-fn show_version(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    println!("<version message>");
+fn show_version(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    print!("{}", cmdutils::version_info());
     0
 }
 
-fn show_buildconf(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    println!("<buildconf message>");
+fn show_buildconf(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    print!("{}", cmdutils::buildconf_info());
     0
 }
 
-fn show_formats(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    println!("<formats message>");
+fn show_formats(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    print!("{}", cmdutils::listing(Listing::Formats, arg, session.print_format));
     0
 }
 
-fn show_muxers(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    println!("<muxers message>");
+fn show_muxers(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    print!("{}", cmdutils::listing(Listing::Muxers, arg, session.print_format));
     0
 }
 
-fn show_demuxers(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    println!("<demuxers message>");
+fn show_demuxers(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    print!("{}", cmdutils::listing(Listing::Demuxers, arg, session.print_format));
     0
 }
 
-fn show_devices(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    println!("<devices message>");
+fn show_devices(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    print!("{}", cmdutils::listing(Listing::Devices, arg, session.print_format));
     0
 }
 
-fn show_codecs(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    println!("<codecs message>");
+fn show_codecs(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    print!("{}", cmdutils::listing(Listing::Codecs, arg, session.print_format));
     0
 }
 
-fn show_decoders(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    println!("<decoders message>");
+fn show_decoders(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    print!("{}", cmdutils::listing(Listing::Decoders, arg, session.print_format));
     0
 }
 
-fn show_encoders(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    println!("<encoders message>");
+fn show_encoders(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    print!("{}", cmdutils::listing(Listing::Encoders, arg, session.print_format));
     0
 }
 
-fn show_bsfs(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    println!("<bsfs message>");
+fn show_bsfs(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    print!("{}", cmdutils::listing(Listing::Bsfs, arg, session.print_format));
     0
 }
 
-fn show_protocols(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    println!("<protocols message>");
+fn show_protocols(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    print!("{}", cmdutils::listing(Listing::Protocols, arg, session.print_format));
     0
 }
 
-fn show_filters(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    println!("<filers message>");
+fn show_filters(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    print!("{}", cmdutils::listing(Listing::Filters, arg, session.print_format));
     0
 }
 
-fn show_pix_fmts(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    println!("<pix_fmts message>");
+fn show_pix_fmts(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    print!("{}", cmdutils::listing(Listing::PixFmts, arg, session.print_format));
     0
 }
 
-fn show_layouts(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    println!("<layouts message>");
+fn show_layouts(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    print!("{}", cmdutils::listing(Listing::Layouts, arg, session.print_format));
     0
 }
 
-fn show_sample_fmts(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    println!("<sample_fmts message>");
+fn show_sample_fmts(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    print!("{}", cmdutils::listing(Listing::SampleFmts, arg, session.print_format));
     0
 }
 
-fn show_colors(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    println!("<colors message>");
+fn show_colors(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    print!("{}", cmdutils::listing(Listing::Colors, arg, session.print_format));
     0
 }
 
-fn opt_loglevel(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    println!("<loglevel message>");
-    0
+fn opt_print_format(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    match arg.parse() {
+        Ok(print_format) => {
+            session.print_format = print_format;
+            0
+        }
+        Err(e) => {
+            error!("{}", e);
+            AVERROR(ffi::EINVAL as i32) as i64
+        }
+    }
 }
 
-fn opt_report(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    println!("<report message>");
-    0
+fn opt_generate_completions(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    match arg.parse() {
+        Ok(shell) => {
+            print!("{}", completions::completions(shell));
+            0
+        }
+        Err(e) => {
+            error!("{}", e);
+            AVERROR(ffi::EINVAL as i32) as i64
+        }
+    }
 }
 
-fn opt_max_alloc(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    println!("<max_alloc message>");
+fn opt_generate_manpage(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    print!("{}", completions::manpage());
     0
 }
 
-fn opt_cpuflags(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    println!("<cpuflags message>");
-    0
+fn opt_loglevel(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    match cmdutils::set_loglevel(arg) {
+        Ok(()) => 0,
+        Err(e) => {
+            error!("{}", e);
+            AVERROR(ffi::EINVAL as i32) as i64
+        }
+    }
 }
 
-fn show_sources(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    println!("<sources message>");
-    0
+fn opt_report(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    match cmdutils::init_report(None) {
+        Ok(()) => 0,
+        Err(e) => {
+            error!("{}", e);
+            AVERROR(ffi::EINVAL as i32) as i64
+        }
+    }
 }
 
-fn show_sinks(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    println!("<sinks message>");
+fn opt_max_alloc(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    match arg.parse::<usize>() {
+        Ok(max) => {
+            unsafe { ffi::av_max_alloc(max as u64) };
+            0
+        }
+        Err(_) => {
+            error!("Invalid max_alloc \"{}\".", arg);
+            AVERROR(ffi::EINVAL as i32) as i64
+        }
+    }
+}
+
+fn opt_cpuflags(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    let arg_c = match CString::new(arg) {
+        Ok(arg_c) => arg_c,
+        Err(_) => return AVERROR(ffi::EINVAL as i32) as i64,
+    };
+    unsafe {
+        let mut flags = ffi::av_get_cpu_flags() as libc::c_uint;
+        let ret = ffi::av_parse_cpu_caps(&mut flags, arg_c.as_ptr());
+        if ret < 0 {
+            return ret as i64;
+        }
+        ffi::av_force_cpu_flags(flags as libc::c_int);
+    }
     0
 }
 
-fn opt_timelimit(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
+fn show_sources(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    match cmdutils::device_list(false, arg) {
+        Ok(list) => {
+            print!("{}", list);
+            0
+        }
+        Err(e) => {
+            error!("{}", e);
+            AVERROR(ffi::EINVAL as i32) as i64
+        }
+    }
 }
 
-// In ffmpeg_opt.c, in corresponding order
-fn show_hwaccels(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
+fn show_sinks(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    match cmdutils::device_list(true, arg) {
+        Ok(list) => {
+            print!("{}", list);
+            0
+        }
+        Err(e) => {
+            error!("{}", e);
+            AVERROR(ffi::EINVAL as i32) as i64
+        }
+    }
 }
 
-fn opt_abort_on(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
+fn opt_timelimit(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    let lim = match parse_number_or_err(opt, arg, OptionFlag::OPT_INT64, 0., i32::MAX as f64) {
+        Ok(lim) => lim,
+        Err(e) => return e,
+    };
+    #[cfg(unix)]
+    {
+        let lim = lim as libc::rlim_t;
+        let rl = libc::rlimit {
+            rlim_cur: lim,
+            rlim_max: lim + 1,
+        };
+        if unsafe { libc::setrlimit(libc::RLIMIT_CPU, &rl) } != 0 {
+            error!("setrlimit: {}", std::io::Error::last_os_error());
+        }
+    }
+    #[cfg(not(unix))]
+    warn!("-{} not implemented on this OS", opt);
+    0
 }
-fn opt_sameq(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
+
+/// Hardware devices, `-vstats` and `-progress` aren't handled by the
+/// transcoding yet, so they fail rather than being silently ignored.
+fn opt_unsupported(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    error!("Option '{}' is not supported yet.", opt);
+    AVERROR_PATCHWELCOME as i64
 }
-fn opt_video_channel(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
+
+/// `parse_number()` for the callbacks, the error is logged.
+fn parse_number_or_err(opt: &str, arg: &str, num_type: OptionFlag, min: f64, max: f64) -> Result<f64, i64> {
+    cmdutils::parse_number(0, opt, arg, num_type, min, max).map_err(|e| {
+        error!("{}", e);
+        AVERROR(ffi::EINVAL as i32) as i64
+    })
 }
-fn opt_video_standard(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
+
+// In ffmpeg_opt.c, in corresponding order
+fn show_hwaccels(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    print!("{}", cmdutils::listing(Listing::HWAccels, arg, session.print_format));
+    0
 }
-fn opt_audio_codec(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
+
+fn opt_abort_on(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    let lookup = |name: &str| match name {
+        "empty_output" => Some(ABORT_ON_FLAG_EMPTY_OUTPUT as i64),
+        "empty_output_stream" => Some(ABORT_ON_FLAG_EMPTY_OUTPUT_STREAM as i64),
+        _ => None,
+    };
+    match ffmpeg::eval_flags(arg, session.abort_on_flags as i64, lookup) {
+        Some(flags) => {
+            session.abort_on_flags = flags as isize;
+            0
+        }
+        None => {
+            error!("Unable to parse option value \"{}\"", arg);
+            AVERROR(ffi::EINVAL as i32) as i64
+        }
+    }
 }
-fn opt_video_codec(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
+fn opt_sameq(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    error!(
+        "Option '{}' was removed. If you are looking for an option to preserve the quality \
+         (which is not what -{} was for), use -qscale 0 or an equivalent quality factor option.",
+        opt, opt
+    );
+    AVERROR(ffi::EINVAL as i32) as i64
 }
-fn opt_subtitle_codec(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
+fn opt_video_channel(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    warn!("This option is deprecated, use -channel.");
+    cmdutils::opt_default(optctx.unwrap().g, "channel", arg) as i64
 }
-fn opt_data_codec(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
+fn opt_video_standard(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    warn!("This option is deprecated, use -standard.");
+    cmdutils::opt_default(optctx.unwrap().g, "standard", arg) as i64
 }
-fn opt_map(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
+fn opt_audio_codec(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    cmdutils::parse_option(session, optctx, "codec:a", arg, &*OPTIONS)
 }
-fn opt_attach(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
+fn opt_video_codec(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    cmdutils::parse_option(session, optctx, "codec:v", arg, &*OPTIONS)
 }
-fn opt_map_channel(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
+fn opt_subtitle_codec(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    cmdutils::parse_option(session, optctx, "codec:s", arg, &*OPTIONS)
 }
-fn opt_sdp_file(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
+fn opt_data_codec(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    cmdutils::parse_option(session, optctx, "codec:d", arg, &*OPTIONS)
 }
-fn opt_vaapi_device(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
+fn opt_map(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    let o = optctx.unwrap();
+    match parse_stream_map(&session.input_files, &mut o.stream_maps, arg) {
+        Ok(()) => 0,
+        Err(e) => {
+            error!("{}", e);
+            AVERROR(ffi::EINVAL as i32) as i64
+        }
+    }
 }
-fn opt_init_hw_device(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
+fn opt_attach(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    let o = optctx.unwrap();
+    o.attachments.push(arg.to_owned());
+    0
 }
-fn opt_filter_hw_device(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
+fn opt_map_channel(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    let o = optctx.unwrap();
+    match parse_audio_channel_map(&session.input_files, &mut o.audio_channel_maps, arg) {
+        Ok(()) => 0,
+        Err(e) => {
+            error!("{}", e);
+            AVERROR(ffi::EINVAL as i32) as i64
+        }
+    }
 }
-
-fn opt_recording_timestamp(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
+fn opt_sdp_file(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    session.sdp_filename = arg.to_owned();
+    0
 }
 
-fn opt_streamid(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
+fn opt_recording_timestamp(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    let o = optctx.unwrap();
+    let recording_timestamp = match cmdutils::parse_time_str(arg, false) {
+        Ok(us) => (us / 1_000_000) as libc::time_t,
+        Err(_) => {
+            error!("Invalid date specification for {}: {}", opt, arg);
+            return AVERROR(ffi::EINVAL as i32) as i64;
+        }
+    };
+    let mut time: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::gmtime_r(&recording_timestamp, &mut time) }.is_null() {
+        return -1;
+    }
+    let creation_time = format!(
+        "creation_time={:04}-{:02}-{:02}T{:02}:{:02}:{:02}+0000",
+        time.tm_year + 1900,
+        time.tm_mon + 1,
+        time.tm_mday,
+        time.tm_hour,
+        time.tm_min,
+        time.tm_sec
+    );
+    o.metadata.push(SpecifierOpt {
+        specifier: String::new(),
+        u: SpecifierOptValue::Str(creation_time.into()),
+    });
+    warn!("{} is deprecated, set the 'creation_time' metadata tag instead.", opt);
+    0
 }
 
-fn opt_target(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
-}
-fn opt_vstats_file(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
-}
-fn opt_vstats(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
-}
-fn opt_video_frames(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
-}
-fn opt_audio_frames(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
-}
-fn opt_data_frames(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
-}
-fn opt_default_new(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
-}
-fn opt_preset(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
-}
-fn opt_old2new(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
-}
-fn opt_bitrate(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
-}
-fn opt_qscale(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
-}
-fn opt_profile(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
+const MAX_STREAMS: usize = 1024;
+
+fn opt_streamid(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    let o = optctx.unwrap();
+    let (idx, value) = match arg.find(':') {
+        Some(i) => (&arg[..i], &arg[i + 1..]),
+        None => {
+            error!(
+                "Invalid value '{}' for option '{}', required syntax is 'index:value'",
+                arg, opt
+            );
+            return AVERROR(ffi::EINVAL as i32) as i64;
+        }
+    };
+    let idx = match parse_number_or_err(opt, idx, OptionFlag::OPT_INT, 0., (MAX_STREAMS - 1) as f64) {
+        Ok(idx) => idx as usize,
+        Err(e) => return e,
+    };
+    let value = match parse_number_or_err(opt, value, OptionFlag::OPT_INT, 0., i32::MAX as f64) {
+        Ok(value) => value as isize,
+        Err(e) => return e,
+    };
+    if o.streamid_map.len() <= idx {
+        o.streamid_map.resize(idx + 1, 0);
+    }
+    o.streamid_map[idx] = value;
+    0
 }
-fn opt_video_filters(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
+
+/// The norm of `-target`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Norm {
+    Pal,
+    Ntsc,
+    Film,
 }
-fn opt_audio_filters(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
+
+fn opt_target(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    let o = optctx.unwrap();
+    let (norm, arg) = if let Some(arg) = arg.strip_prefix("pal-") {
+        (Some(Norm::Pal), arg)
+    } else if let Some(arg) = arg.strip_prefix("ntsc-") {
+        (Some(Norm::Ntsc), arg)
+    } else if let Some(arg) = arg.strip_prefix("film-") {
+        (Some(Norm::Film), arg)
+    } else {
+        // Try to determine PAL/NTSC by peeking in the input files
+        let norm = session.input_files.iter().find_map(|ifile| unsafe {
+            let ic = &*ifile.ctx;
+            (0..ic.nb_streams as usize).find_map(|i| {
+                let st = &**ic.streams.add(i);
+                if (*st.codecpar).codec_type != ffi::AVMediaType_AVMEDIA_TYPE_VIDEO || st.time_base.num == 0 {
+                    return None;
+                }
+                match st.time_base.den as i64 * 1000 / st.time_base.num as i64 {
+                    25000 => Some(Norm::Pal),
+                    29970 | 23976 => Some(Norm::Ntsc),
+                    _ => None,
+                }
+            })
+        });
+        if let Some(norm) = norm {
+            info!("Assuming {} for target.", if norm == Norm::Pal { "PAL" } else { "NTSC" });
+        }
+        (norm, arg)
+    };
+    let norm = match norm {
+        Some(norm) => norm,
+        None => {
+            error!(
+                "Could not determine norm (PAL/NTSC/NTSC-Film) for target.\n\
+                 Please prefix target with \"pal-\", \"ntsc-\" or \"film-\",\n\
+                 or set a framerate with \"-r xxx\"."
+            );
+            return AVERROR(ffi::EINVAL as i32) as i64;
+        }
+    };
+    let pal = norm == Norm::Pal;
+    let frame_rate = match norm {
+        Norm::Pal => "25",
+        Norm::Ntsc => "30000/1001",
+        Norm::Film => "24000/1001",
+    };
+
+    // The AVOptions of the target don't override the ones of the user.
+    let mut defaults = OptionGroup::new_anonymous();
+    let mut default = |opt: &str, arg: &str| {
+        cmdutils::opt_default(&mut defaults, opt, arg);
+    };
+    let mut set = |opt: &str, arg: &str| {
+        cmdutils::parse_option(session, Some(&mut *o), opt, arg, &*OPTIONS);
+    };
+    match arg {
+        "vcd" => {
+            set("codec:v", "mpeg1video");
+            set("codec:a", "mp2");
+            set("f", "vcd");
+
+            set("s", if pal { "352x288" } else { "352x240" });
+            set("r", frame_rate);
+            default("g", if pal { "15" } else { "18" });
+
+            default("b:v", "1150000");
+            default("maxrate:v", "1150000");
+            default("minrate:v", "1150000");
+            default("bufsize:v", "327680"); // 40*1024*8;
+
+            default("b:a", "224000");
+            set("ar", "44100");
+            set("ac", "2");
+
+            default("packetsize", "2324");
+            default("muxrate", "1411200"); // 2352 * 75 * 8;
+
+            // We have to offset the PTS, so that it is consistent with the SCR.
+            // SCR starts at 36000, but the first two packs contain only padding
+            // and the first pack from the other stream, respectively, may also have
+            // been written before.
+            // So the real data starts at SCR 36000+3*1200.
+            o.mux_preload = (36000 + 3 * 1200) as f32 / 90000.; // 0.44
+        }
+        "svcd" => {
+            set("codec:v", "mpeg2video");
+            set("codec:a", "mp2");
+            set("f", "svcd");
+
+            set("s", if pal { "480x576" } else { "480x480" });
+            set("r", frame_rate);
+            set("pix_fmt", "yuv420p");
+            default("g", if pal { "15" } else { "18" });
+
+            default("b:v", "2040000");
+            default("maxrate:v", "2516000");
+            default("minrate:v", "0"); // 1145000;
+            default("bufsize:v", "1835008"); // 224*1024*8;
+            default("scan_offset", "1");
+
+            default("b:a", "224000");
+            set("ar", "44100");
+
+            default("packetsize", "2324");
+        }
+        "dvd" => {
+            set("codec:v", "mpeg2video");
+            set("codec:a", "ac3");
+            set("f", "dvd");
+
+            set("s", if pal { "720x576" } else { "720x480" });
+            set("r", frame_rate);
+            set("pix_fmt", "yuv420p");
+            default("g", if pal { "15" } else { "18" });
+
+            default("b:v", "6000000");
+            default("maxrate:v", "9000000");
+            default("minrate:v", "0"); // 1500000;
+            default("bufsize:v", "1835008"); // 224*1024*8;
+
+            // from www.mpucoder.com: DVD sectors contain 2048 bytes of data,
+            // this is also the size of one pack.
+            default("packetsize", "2048");
+            // from mplex project: data_rate = 1260000. mux_rate = data_rate * 8
+            default("muxrate", "10080000");
+
+            default("b:a", "448000");
+            set("ar", "48000");
+        }
+        _ if arg.starts_with("dv") => {
+            set("f", "dv");
+
+            set("s", if pal { "720x576" } else { "720x480" });
+            set(
+                "pix_fmt",
+                if arg.starts_with("dv50") {
+                    "yuv422p"
+                } else if pal {
+                    "yuv420p"
+                } else {
+                    "yuv411p"
+                },
+            );
+            set("r", frame_rate);
+
+            set("ar", "48000");
+            set("ac", "2");
+        }
+        _ => {
+            error!("Unknown target: {}", arg);
+            return AVERROR(ffi::EINVAL as i32) as i64;
+        }
+    }
+
+    for (key, value) in defaults.codec_opts.iter() {
+        o.g.codec_opts.set(key, value, DictFlag::DONT_OVERWRITE);
+    }
+    for (key, value) in defaults.format_opts.iter() {
+        o.g.format_opts.set(key, value, DictFlag::DONT_OVERWRITE);
+    }
+    0
 }
-fn opt_vsync(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
+fn opt_video_frames(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    cmdutils::parse_option(session, optctx, "frames:v", arg, &*OPTIONS)
+}
+fn opt_audio_frames(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    cmdutils::parse_option(session, optctx, "frames:a", arg, &*OPTIONS)
+}
+fn opt_data_frames(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    cmdutils::parse_option(session, optctx, "frames:d", arg, &*OPTIONS)
+}
+fn opt_preset(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    let o = optctx.unwrap();
+    // -vpre looks for the presets of the video codec too, -fpre takes a path
+    let codec_name = ffmpeg_opt::match_per_type_opt(&o.codec_names, &opt[..1])
+        .and_then(|u| u.as_str())
+        .map(str::to_owned);
+    let (filename, content) =
+        match cmdutils::get_preset_file(arg, opt.starts_with('f'), codec_name.as_deref()) {
+            Some(preset) => preset,
+            None => {
+                if arg.starts_with("libx264-lossless") {
+                    error!("Please use -preset <speed> -qp 0");
+                } else {
+                    error!("File for preset '{}' not found", arg);
+                }
+                return AVERROR(ffi::EINVAL as i32) as i64;
+            }
+        };
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = match line.find('=') {
+            Some(i) if i > 0 && i + 1 < line.len() => (&line[..i], &line[i + 1..]),
+            _ => {
+                error!("{}: Invalid syntax: '{}'", filename.display(), line);
+                return AVERROR(ffi::EINVAL as i32) as i64;
+            }
+        };
+        debug!("ffpreset[{}]: set '{}' = '{}'", filename.display(), key, value);
+        match key {
+            "acodec" => opt_audio_codec(session, Some(&mut *o), key, value),
+            "vcodec" => opt_video_codec(session, Some(&mut *o), key, value),
+            "scodec" => opt_subtitle_codec(session, Some(&mut *o), key, value),
+            "dcodec" => opt_data_codec(session, Some(&mut *o), key, value),
+            _ => {
+                if cmdutils::opt_default(o.g, key, value) < 0 {
+                    error!(
+                        "{}: Invalid option or argument: '{}', parsed as '{}' = '{}'",
+                        filename.display(),
+                        line,
+                        key,
+                        value
+                    );
+                    return AVERROR(ffi::EINVAL as i32) as i64;
+                }
+                0
+            }
+        };
+    }
+    0
 }
-fn opt_timecode(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
+fn opt_old2new(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    // -vtag is -tag:v, -absf is -bsf:a
+    let (media_type, name) = opt.split_at(1);
+    cmdutils::parse_option(session, optctx, &format!("{}:{}", name, media_type), arg, &*OPTIONS)
+}
+fn opt_bitrate(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    let o = optctx.unwrap();
+    match opt {
+        "ab" => o.g.codec_opts.set("b:a", arg, DictFlag::NONE),
+        "b" => {
+            warn!("Please use -b:a or -b:v, -b is ambiguous");
+            o.g.codec_opts.set("b:v", arg, DictFlag::NONE);
+        }
+        _ => o.g.codec_opts.set(opt, arg, DictFlag::NONE),
+    }
+    0
 }
-fn opt_channel_layout(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
+fn opt_qscale(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    if opt == "qscale" {
+        warn!("Please use -q:a or -q:v, -qscale is ambiguous");
+        return cmdutils::parse_option(session, optctx, "q:v", arg, &*OPTIONS);
+    }
+    // -qscale:a is -q:a
+    let spec = opt.get("qscale".len()..).unwrap_or("");
+    cmdutils::parse_option(session, optctx, &format!("q{}", spec), arg, &*OPTIONS)
+}
+fn opt_profile(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    let o = optctx.unwrap();
+    if opt == "profile" {
+        warn!("-profile for audio/video is deprecated, use -profile:a or -profile:v");
+        o.g.codec_opts.set("profile:v", arg, DictFlag::NONE);
+    } else {
+        o.g.codec_opts.set(opt, arg, DictFlag::NONE);
+    }
+    0
 }
-fn opt_audio_qscale(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
+fn opt_video_filters(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    cmdutils::parse_option(session, optctx, "filter:v", arg, &*OPTIONS)
+}
+fn opt_audio_filters(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    cmdutils::parse_option(session, optctx, "filter:a", arg, &*OPTIONS)
+}
+fn opt_vsync(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    session.video_sync_method = match arg.to_ascii_lowercase().as_str() {
+        "cfr" => VSYNC_CFR,
+        "vfr" => VSYNC_VFR,
+        "passthrough" => VSYNC_PASSTHROUGH,
+        "drop" => VSYNC_DROP,
+        _ => match parse_number_or_err("vsync", arg, OptionFlag::OPT_INT, VSYNC_AUTO as f64, VSYNC_VFR as f64) {
+            Ok(vsync) => vsync as isize,
+            Err(e) => return e,
+        },
+    };
+    0
 }
-fn opt_filter_complex(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
+fn opt_timecode(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    let o = optctx.unwrap();
+    let tcr = format!("timecode={}", arg);
+    let ret = cmdutils::parse_option(session, Some(&mut *o), "metadata:g", &tcr, &*OPTIONS);
+    if ret >= 0 {
+        o.g.codec_opts.set("gop_timecode", arg, DictFlag::NONE);
+    }
+    ret
 }
-fn opt_filter_complex_script(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
+fn opt_channel_layout(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    let o = optctx.unwrap();
+    let layout = match CString::new(arg) {
+        Ok(arg_c) => unsafe { ffi::av_get_channel_layout(arg_c.as_ptr()) },
+        Err(_) => 0,
+    };
+    if layout == 0 {
+        error!("Unknown channel layout: {}", arg);
+        return AVERROR(ffi::EINVAL as i32) as i64;
+    }
+    let ret = cmdutils::opt_default(o.g, opt, &layout.to_string());
+    if ret < 0 {
+        return ret as i64;
+    }
+    // set 'ac' option based on channel layout
+    let channels = unsafe { ffi::av_get_channel_layout_nb_channels(layout) };
+    let ac = format!("ac{}", opt.find(':').map_or("", |i| &opt[i..]));
+    cmdutils::parse_option(session, Some(o), &ac, &channels.to_string(), &*OPTIONS)
+}
+fn opt_audio_qscale(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    cmdutils::parse_option(session, optctx, "q:a", arg, &*OPTIONS)
+}
+fn opt_filter_complex(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    let index = session.filtergraphs.len();
+    session.filtergraphs.push(FilterGraph::new(index, arg));
+    session.input_stream_potentially_available = 1;
+    0
 }
-
-fn opt_progress(optctx: *mut c_void, opt: &str, arg: &str) -> i64 {
-    unimplemented!()
+fn opt_filter_complex_script(session: &mut Session, optctx: Option<&mut OptionsContext>, opt: &str, arg: &str) -> i64 {
+    match fs::read_to_string(arg) {
+        Ok(graph_desc) => opt_filter_complex(session, optctx, opt, &graph_desc),
+        Err(e) => {
+            error!("Error opening file {}: {}.", arg, e);
+            AVERROR(ffi::EINVAL as i32) as i64
+        }
+    }
 }
 
 #[cfg(test)]
 mod command_tests {
     use super::*;
 
-    fn opt_cpuflags(_: *mut c_void, _: &str, _: &str) -> i64 {
+    fn opt_cpuflags(_: &mut Session, _: Option<&mut OptionsContext>, _: &str, _: &str) -> i64 {
         0
     }
 
@@ -0,0 +1,498 @@
+//! Stream specifiers, this corresponds to `match_stream_specifier()` and
+//! `avformat_match_stream_specifier()` in libavformat/utils.c.
+//!
+//! Specifiers are parsed into a [`StreamSpecifier`] while splitting the
+//! command line, so malformed ones are rejected before any file is opened,
+//! and matched against [`StreamInfo`], a lightweight description of a stream
+//! which can be built from an opened `AVFormatContext` or by hand.
+use rusty_ffmpeg::ffi;
+
+use std::{slice, str::FromStr};
+
+use crate::dict::Dictionary;
+
+/// Stream types which can be selected in a specifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaType {
+    Video,
+    Audio,
+    Subtitle,
+    Data,
+    Attachment,
+}
+
+impl MediaType {
+    pub fn from_av(ty: ffi::AVMediaType) -> Option<Self> {
+        match ty {
+            ffi::AVMediaType_AVMEDIA_TYPE_VIDEO => Some(MediaType::Video),
+            ffi::AVMediaType_AVMEDIA_TYPE_AUDIO => Some(MediaType::Audio),
+            ffi::AVMediaType_AVMEDIA_TYPE_SUBTITLE => Some(MediaType::Subtitle),
+            ffi::AVMediaType_AVMEDIA_TYPE_DATA => Some(MediaType::Data),
+            ffi::AVMediaType_AVMEDIA_TYPE_ATTACHMENT => Some(MediaType::Attachment),
+            _ => None,
+        }
+    }
+
+    pub fn to_av(self) -> ffi::AVMediaType {
+        match self {
+            MediaType::Video => ffi::AVMediaType_AVMEDIA_TYPE_VIDEO,
+            MediaType::Audio => ffi::AVMediaType_AVMEDIA_TYPE_AUDIO,
+            MediaType::Subtitle => ffi::AVMediaType_AVMEDIA_TYPE_SUBTITLE,
+            MediaType::Data => ffi::AVMediaType_AVMEDIA_TYPE_DATA,
+            MediaType::Attachment => ffi::AVMediaType_AVMEDIA_TYPE_ATTACHMENT,
+        }
+    }
+}
+
+/// Names and values of `AV_DISPOSITION_*`, as accepted by `disp:`.
+pub const DISPOSITIONS: [(&str, u32); 17] = [
+    ("default", 0x0001),
+    ("dub", 0x0002),
+    ("original", 0x0004),
+    ("comment", 0x0008),
+    ("lyrics", 0x0010),
+    ("karaoke", 0x0020),
+    ("forced", 0x0040),
+    ("hearing_impaired", 0x0080),
+    ("visual_impaired", 0x0100),
+    ("clean_effects", 0x0200),
+    ("attached_pic", 0x0400),
+    ("timed_thumbnails", 0x0800),
+    ("captions", 0x10000),
+    ("descriptions", 0x20000),
+    ("metadata", 0x40000),
+    ("dependent", 0x80000),
+    ("still_image", 0x100000),
+];
+
+pub const DISPOSITION_DEFAULT: u32 = 0x0001;
+pub const DISPOSITION_ATTACHED_PIC: u32 = 0x0400;
+
+/// The part of a specifier which ends it, everything before it narrows the
+/// set of candidate streams.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamSelector {
+    /// Every stream which passes the filters.
+    All,
+    /// The n-th stream which passes the filters, e.g. `v:1` or `1`.
+    Index(i64),
+    /// `#id` or `i:id`, the format-specific stream id.
+    Id(i64),
+    /// `m:key[:value]`
+    Metadata { key: String, value: Option<String> },
+    /// `u`, streams whose codec parameters are known.
+    Usable,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamSpecifier {
+    /// `v`, `a`, `s`, `d`, `t`; `V` also sets `no_attached_pic`.
+    pub media_type: Option<MediaType>,
+    pub no_attached_pic: bool,
+    /// `p:program_id`
+    pub program_id: Option<i64>,
+    /// `disp:flag[+flag...]`, as a mask of `AV_DISPOSITION_*`.
+    pub disposition: Option<u32>,
+    pub selector: StreamSelector,
+}
+
+/// Lightweight description of a stream, enough to match specifiers and to
+/// pick the default streams of an output file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StreamInfo {
+    /// Index in the format context.
+    pub index: usize,
+    /// Format-specific stream id.
+    pub id: i64,
+    /// `None` for unknown types.
+    pub media_type: Option<MediaType>,
+    pub disposition: u32,
+    pub metadata: Dictionary,
+    /// The codec and its main parameters are known.
+    pub usable: bool,
+    /// Number of audio channels, 0 for other streams.
+    pub channels: usize,
+    /// Video frame size, 0 for other streams.
+    pub width: usize,
+    pub height: usize,
+    /// Frames decoded by `avformat_find_stream_info()`.
+    pub codec_info_nb_frames: usize,
+    /// Ids of the programs containing this stream.
+    pub programs: Vec<i64>,
+}
+
+impl StreamInfo {
+    /// Describe every stream of an opened format context.
+    pub unsafe fn from_format_context(s: *const ffi::AVFormatContext) -> Vec<StreamInfo> {
+        let s = match s.as_ref() {
+            Some(s) => s,
+            None => return vec![],
+        };
+        let programs = if s.programs.is_null() {
+            &[][..]
+        } else {
+            slice::from_raw_parts(s.programs, s.nb_programs as usize)
+        };
+        let streams = if s.streams.is_null() {
+            &[][..]
+        } else {
+            slice::from_raw_parts(s.streams, s.nb_streams as usize)
+        };
+        streams
+            .iter()
+            .map(|st| {
+                let st = st.as_ref().unwrap();
+                let par = st.codecpar.as_ref().unwrap();
+                let usable = par.codec_id != ffi::AVCodecID_AV_CODEC_ID_NONE
+                    && match par.codec_type {
+                        ffi::AVMediaType_AVMEDIA_TYPE_AUDIO => {
+                            par.sample_rate != 0
+                                && par.channels != 0
+                                && par.format != ffi::AVSampleFormat_AV_SAMPLE_FMT_NONE
+                        }
+                        ffi::AVMediaType_AVMEDIA_TYPE_VIDEO => {
+                            par.width != 0
+                                && par.height != 0
+                                && par.format != ffi::AVPixelFormat_AV_PIX_FMT_NONE
+                        }
+                        ffi::AVMediaType_AVMEDIA_TYPE_UNKNOWN => false,
+                        _ => true,
+                    };
+                let stream_programs = programs
+                    .iter()
+                    .map(|p| p.as_ref().unwrap())
+                    .filter(|p| {
+                        slice::from_raw_parts(p.stream_index, p.nb_stream_indexes as usize)
+                            .contains(&(st.index as libc::c_uint))
+                    })
+                    .map(|p| p.id as i64)
+                    .collect();
+                StreamInfo {
+                    index: st.index as usize,
+                    id: st.id as i64,
+                    media_type: MediaType::from_av(par.codec_type),
+                    disposition: st.disposition as u32,
+                    metadata: Dictionary::from_av_dict(st.metadata),
+                    usable,
+                    channels: par.channels as usize,
+                    width: par.width as usize,
+                    height: par.height as usize,
+                    codec_info_nb_frames: st.codec_info_nb_frames as usize,
+                    programs: stream_programs,
+                }
+            })
+            .collect()
+    }
+}
+
+/// `strtol(s, &end, 0)`: optional sign, then hex with `0x`, octal with a
+/// leading `0`, or decimal. Returns the value and the unparsed rest.
+pub fn strtol(s: &str) -> Option<(i64, &str)> {
+    let (negative, digits) = match s.as_bytes().first() {
+        Some(b'-') => (true, &s[1..]),
+        Some(b'+') => (false, &s[1..]),
+        _ => (false, s),
+    };
+    let (radix, digits) = if digits.starts_with("0x") || digits.starts_with("0X") {
+        (16, &digits[2..])
+    } else if digits.starts_with('0') && digits.len() > 1 {
+        (8, &digits[1..])
+    } else {
+        (10, digits)
+    };
+    let end = digits
+        .find(|c: char| !c.is_digit(radix))
+        .unwrap_or_else(|| digits.len());
+    if end == 0 {
+        // "0" alone, or a "0x" without hex digits which strtol reads as "0".
+        return if radix != 10 {
+            Some((0, &digits[..]))
+        } else {
+            None
+        };
+    }
+    let value = i64::from_str_radix(&digits[..end], radix).ok()?;
+    Some((if negative { -value } else { value }, &digits[end..]))
+}
+
+/// Parse an integer which must take the whole string.
+fn parse_whole_int(s: &str) -> Option<i64> {
+    match strtol(s) {
+        Some((value, "")) => Some(value),
+        _ => None,
+    }
+}
+
+impl FromStr for StreamSpecifier {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, String> {
+        let mut result = StreamSpecifier {
+            media_type: None,
+            no_attached_pic: false,
+            program_id: None,
+            disposition: None,
+            selector: StreamSelector::All,
+        };
+        let mut rest = spec;
+        // Consume the ':' between two parts, the end of spec is fine too.
+        let next_part = |rest: &str, what: &str| -> Result<usize, String> {
+            match rest.as_bytes().first() {
+                None => Ok(0),
+                Some(b':') => Ok(1),
+                _ => Err(format!("expected ':' after {} in '{}'", what, spec)),
+            }
+        };
+
+        while !rest.is_empty() {
+            let first = rest.as_bytes()[0];
+            if first.is_ascii_digit() {
+                let index = parse_whole_int(rest)
+                    .ok_or_else(|| format!("invalid stream index '{}'", rest))?;
+                result.selector = StreamSelector::Index(index);
+                break;
+            } else if rest.starts_with("disp:") {
+                if result.disposition.is_some() {
+                    return Err(format!("duplicated disposition in '{}'", spec));
+                }
+                rest = &rest[5..];
+                let end = rest.find(':').unwrap_or_else(|| rest.len());
+                let mut mask = 0;
+                for name in rest[..end].split('+') {
+                    mask |= DISPOSITIONS
+                        .iter()
+                        .find(|(n, _)| *n == name)
+                        .map(|(_, value)| *value)
+                        .ok_or_else(|| format!("unknown disposition '{}'", name))?;
+                }
+                result.disposition = Some(mask);
+                rest = &rest[end..];
+                rest = &rest[next_part(rest, "disposition")?..];
+            } else if b"vasdtV".contains(&first) {
+                if result.media_type.is_some() {
+                    return Err(format!("duplicated stream type in '{}'", spec));
+                }
+                result.media_type = Some(match first {
+                    b'v' | b'V' => MediaType::Video,
+                    b'a' => MediaType::Audio,
+                    b's' => MediaType::Subtitle,
+                    b'd' => MediaType::Data,
+                    _ => MediaType::Attachment,
+                });
+                result.no_attached_pic = first == b'V';
+                rest = &rest[1..];
+                rest = &rest[next_part(rest, "stream type")?..];
+            } else if rest.starts_with("p:") {
+                if result.program_id.is_some() {
+                    return Err(format!("duplicated program in '{}'", spec));
+                }
+                let (id, after) = strtol(&rest[2..])
+                    .ok_or_else(|| format!("invalid program id in '{}'", spec))?;
+                result.program_id = Some(id);
+                rest = &after[next_part(after, "program id")?..];
+            } else if first == b'#' || rest.starts_with("i:") {
+                let id = &rest[if first == b'#' { 1 } else { 2 }..];
+                let id =
+                    parse_whole_int(id).ok_or_else(|| format!("invalid stream id '{}'", id))?;
+                result.selector = StreamSelector::Id(id);
+                break;
+            } else if rest.starts_with("m:") {
+                let mut kv = rest[2..].splitn(2, ':');
+                let key = kv.next().unwrap();
+                if key.is_empty() {
+                    return Err(format!("empty metadata key in '{}'", spec));
+                }
+                result.selector = StreamSelector::Metadata {
+                    key: key.to_owned(),
+                    value: kv.next().map(|v| v.to_owned()),
+                };
+                break;
+            } else if rest == "u" {
+                result.selector = StreamSelector::Usable;
+                break;
+            } else {
+                return Err(format!("unexpected '{}' in '{}'", rest, spec));
+            }
+        }
+        Ok(result)
+    }
+}
+
+impl StreamSpecifier {
+    /// Whether the specifier has no part other than a stream index.
+    fn is_plain_index(&self) -> bool {
+        self.media_type.is_none() && self.program_id.is_none() && self.disposition.is_none()
+    }
+
+    /// Everything except the stream index.
+    fn matches_filters(&self, st: &StreamInfo) -> bool {
+        if let Some(media_type) = self.media_type {
+            if st.media_type != Some(media_type) {
+                return false;
+            }
+            if self.no_attached_pic && st.disposition & DISPOSITION_ATTACHED_PIC != 0 {
+                return false;
+            }
+        }
+        if let Some(program_id) = self.program_id {
+            if !st.programs.contains(&program_id) {
+                return false;
+            }
+        }
+        if let Some(mask) = self.disposition {
+            if st.disposition & mask != mask {
+                return false;
+            }
+        }
+        match &self.selector {
+            StreamSelector::All | StreamSelector::Index(_) => true,
+            StreamSelector::Id(id) => st.id == *id,
+            StreamSelector::Metadata { key, value } => match st.metadata.get(key) {
+                Some(v) => value.as_ref().map_or(true, |value| value == v),
+                None => false,
+            },
+            StreamSelector::Usable => st.usable,
+        }
+    }
+
+    /// Whether `streams[index]` is selected, `streams` are all streams of the
+    /// file, in order.
+    pub fn matches(&self, streams: &[StreamInfo], index: usize) -> bool {
+        let st = match streams.get(index) {
+            Some(st) => st,
+            None => return false,
+        };
+        match self.selector {
+            StreamSelector::Index(n) if self.is_plain_index() => st.index as i64 == n,
+            StreamSelector::Index(n) => {
+                // The n-th stream passing the filters must be `st`.
+                n >= 0
+                    && streams
+                        .iter()
+                        .filter(|candidate| self.matches_filters(candidate))
+                        .nth(n as usize)
+                        .map_or(false, |candidate| candidate.index == st.index)
+            }
+            _ => self.matches_filters(st),
+        }
+    }
+}
+
+#[cfg(test)]
+mod stream_specifier_tests {
+    use super::*;
+
+    fn stream(index: usize, media_type: MediaType) -> StreamInfo {
+        StreamInfo {
+            index,
+            id: 0x100 + index as i64,
+            media_type: Some(media_type),
+            usable: true,
+            ..Default::default()
+        }
+    }
+
+    fn streams() -> Vec<StreamInfo> {
+        let mut streams = vec![
+            stream(0, MediaType::Video),
+            stream(1, MediaType::Audio),
+            stream(2, MediaType::Audio),
+            stream(3, MediaType::Subtitle),
+            stream(4, MediaType::Video),
+        ];
+        streams[2]
+            .metadata
+            .set("language", "eng", Default::default());
+        streams[2].programs = vec![1];
+        streams[4].programs = vec![1];
+        streams[4].disposition = DISPOSITION_ATTACHED_PIC;
+        streams[3].usable = false;
+        streams
+    }
+
+    fn matched(spec: &str) -> Vec<usize> {
+        let spec: StreamSpecifier = spec.parse().unwrap();
+        let streams = streams();
+        (0..streams.len())
+            .filter(|&i| spec.matches(&streams, i))
+            .collect()
+    }
+
+    #[test]
+    fn parse_specifiers() {
+        let spec: StreamSpecifier = "p:1:a:0".parse().unwrap();
+        assert_eq!(spec.program_id, Some(1));
+        assert_eq!(spec.media_type, Some(MediaType::Audio));
+        assert_eq!(spec.selector, StreamSelector::Index(0));
+
+        let spec: StreamSpecifier = "m:language:eng".parse().unwrap();
+        assert_eq!(
+            spec.selector,
+            StreamSelector::Metadata {
+                key: "language".to_owned(),
+                value: Some("eng".to_owned())
+            }
+        );
+
+        let spec: StreamSpecifier = "disp:default+forced:s".parse().unwrap();
+        assert_eq!(spec.disposition, Some(0x41));
+        assert_eq!(spec.media_type, Some(MediaType::Subtitle));
+
+        assert_eq!(
+            "#0x101".parse::<StreamSpecifier>().unwrap().selector,
+            StreamSelector::Id(0x101)
+        );
+        assert_eq!(
+            "i:257".parse::<StreamSpecifier>().unwrap().selector,
+            StreamSelector::Id(257)
+        );
+    }
+
+    #[test]
+    fn reject_malformed() {
+        for spec in &[
+            "x",
+            "v0",
+            "v:a",
+            "p:",
+            "p:x",
+            "#",
+            "#1x",
+            "1:v",
+            "m:",
+            "u:0",
+            "disp:bogus",
+            "vv",
+        ] {
+            assert!(spec.parse::<StreamSpecifier>().is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn match_streams() {
+        assert_eq!(matched(""), vec![0, 1, 2, 3, 4]);
+        assert_eq!(matched("2"), vec![2]);
+        assert_eq!(matched("v"), vec![0, 4]);
+        assert_eq!(matched("V"), vec![0]);
+        assert_eq!(matched("a:1"), vec![2]);
+        assert_eq!(matched("a:2"), Vec::<usize>::new());
+        assert_eq!(matched("p:1"), vec![2, 4]);
+        assert_eq!(matched("p:1:1"), vec![4]);
+        assert_eq!(matched("p:1:v"), vec![4]);
+        assert_eq!(matched("#0x103"), vec![3]);
+        assert_eq!(matched("m:language"), vec![2]);
+        assert_eq!(matched("m:language:fre"), Vec::<usize>::new());
+        assert_eq!(matched("u"), vec![0, 1, 2, 4]);
+        assert_eq!(matched("disp:attached_pic"), vec![4]);
+    }
+
+    #[test]
+    fn strtol_like_c() {
+        assert_eq!(strtol("12:v"), Some((12, ":v")));
+        assert_eq!(strtol("0x1f"), Some((31, "")));
+        assert_eq!(strtol("010"), Some((8, "")));
+        assert_eq!(strtol("0"), Some((0, "")));
+        assert_eq!(strtol("-3"), Some((-3, "")));
+        assert_eq!(strtol("v"), None);
+    }
+}